@@ -20,29 +20,29 @@ pub fn main() {
 
     println!("offset_f {}", offset_f);
 
-    let offset_node: usize = unsafe {
+    let offset_userptr: usize = unsafe {
         let c = std::mem::MaybeUninit::uninit();
         let c_ptr: *const vm_object_t = c.as_ptr();
 
         // cast to u8 pointers so we get offset in bytes
         let c_u8_ptr = c_ptr as *const u8;
-        let f_u8_ptr = std::ptr::addr_of!((*c_ptr).node) as *const u8;
+        let f_u8_ptr = std::ptr::addr_of!((*c_ptr).userptr) as *const u8;
 
         f_u8_ptr.offset_from(c_u8_ptr) as usize
     };
 
-    println!("offset_node {}", offset_node);
+    println!("offset_userptr {}", offset_userptr);
 
-    let offset_user_node: usize = unsafe {
+    let offset_handle: usize = unsafe {
         let c = std::mem::MaybeUninit::uninit();
         let c_ptr: *const vm_object_t = c.as_ptr();
 
         // cast to u8 pointers so we get offset in bytes
         let c_u8_ptr = c_ptr as *const u8;
-        let f_u8_ptr = std::ptr::addr_of!((*c_ptr).user_node) as *const u8;
+        let f_u8_ptr = std::ptr::addr_of!((*c_ptr).handle) as *const u8;
 
         f_u8_ptr.offset_from(c_u8_ptr) as usize
     };
 
-    println!("offset_user_node {}", offset_user_node);
+    println!("offset_handle {}", offset_handle);
 }
@@ -0,0 +1,704 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use crate::hsakmttypes::HSA_QUEUE_TYPE::HSA_QUEUE_COMPUTE_AQL;
+use crate::queues::Queue;
+use std::marker::PhantomData;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+/* AQL packets as in hsa.h. Every packet is 64 bytes and starts with a
+ * 16 bit header, the packet processor only looks at a slot once its
+ * header has a type other than HSA_PACKET_TYPE_INVALID.
+ */
+pub const AQL_PACKET_SIZE: usize = 64;
+
+pub const HSA_PACKET_TYPE_VENDOR_SPECIFIC: u16 = 0;
+pub const HSA_PACKET_TYPE_INVALID: u16 = 1;
+pub const HSA_PACKET_TYPE_KERNEL_DISPATCH: u16 = 2;
+pub const HSA_PACKET_TYPE_BARRIER_AND: u16 = 3;
+pub const HSA_PACKET_TYPE_AGENT_DISPATCH: u16 = 4;
+pub const HSA_PACKET_TYPE_BARRIER_OR: u16 = 5;
+
+pub const HSA_FENCE_SCOPE_NONE: u16 = 0;
+pub const HSA_FENCE_SCOPE_AGENT: u16 = 1;
+pub const HSA_FENCE_SCOPE_SYSTEM: u16 = 2;
+
+pub const HSA_PACKET_HEADER_TYPE: u16 = 0;
+pub const HSA_PACKET_HEADER_BARRIER: u16 = 8;
+pub const HSA_PACKET_HEADER_SCACQUIRE_FENCE_SCOPE: u16 = 9;
+pub const HSA_PACKET_HEADER_SCRELEASE_FENCE_SCOPE: u16 = 11;
+
+pub const HSA_PACKET_HEADER_WIDTH_TYPE: u16 = 8;
+pub const HSA_PACKET_HEADER_WIDTH_BARRIER: u16 = 1;
+pub const HSA_PACKET_HEADER_WIDTH_SCACQUIRE_FENCE_SCOPE: u16 = 2;
+pub const HSA_PACKET_HEADER_WIDTH_SCRELEASE_FENCE_SCOPE: u16 = 2;
+
+pub const HSA_KERNEL_DISPATCH_PACKET_SETUP_DIMENSIONS: u16 = 0;
+pub const HSA_KERNEL_DISPATCH_PACKET_SETUP_WIDTH_DIMENSIONS: u16 = 2;
+
+/* AmdFormat of AMD vendor specific packets */
+pub const HSA_AMD_PACKET_TYPE_BARRIER_VALUE: u8 = 2;
+
+pub const HSA_SIGNAL_CONDITION_EQ: u32 = 0;
+pub const HSA_SIGNAL_CONDITION_NE: u32 = 1;
+pub const HSA_SIGNAL_CONDITION_LT: u32 = 2;
+pub const HSA_SIGNAL_CONDITION_GTE: u32 = 3;
+
+fn field(value: u16, offset: u16, width: u16) -> u16 {
+    (value & ((1 << width) - 1)) << offset
+}
+
+fn get_field(header: u16, offset: u16, width: u16) -> u16 {
+    (header >> offset) & ((1 << width) - 1)
+}
+
+pub fn hsa_packet_header(
+    packet_type: u16,
+    barrier: bool,
+    acquire_fence_scope: u16,
+    release_fence_scope: u16,
+) -> u16 {
+    field(
+        packet_type,
+        HSA_PACKET_HEADER_TYPE,
+        HSA_PACKET_HEADER_WIDTH_TYPE,
+    ) | field(
+        barrier as u16,
+        HSA_PACKET_HEADER_BARRIER,
+        HSA_PACKET_HEADER_WIDTH_BARRIER,
+    ) | field(
+        acquire_fence_scope,
+        HSA_PACKET_HEADER_SCACQUIRE_FENCE_SCOPE,
+        HSA_PACKET_HEADER_WIDTH_SCACQUIRE_FENCE_SCOPE,
+    ) | field(
+        release_fence_scope,
+        HSA_PACKET_HEADER_SCRELEASE_FENCE_SCOPE,
+        HSA_PACKET_HEADER_WIDTH_SCRELEASE_FENCE_SCOPE,
+    )
+}
+
+pub fn hsa_packet_header_type(header: u16) -> u16 {
+    get_field(header, HSA_PACKET_HEADER_TYPE, HSA_PACKET_HEADER_WIDTH_TYPE)
+}
+
+pub fn hsa_packet_header_barrier(header: u16) -> bool {
+    get_field(
+        header,
+        HSA_PACKET_HEADER_BARRIER,
+        HSA_PACKET_HEADER_WIDTH_BARRIER,
+    ) != 0
+}
+
+pub fn hsa_packet_header_acquire_fence_scope(header: u16) -> u16 {
+    get_field(
+        header,
+        HSA_PACKET_HEADER_SCACQUIRE_FENCE_SCOPE,
+        HSA_PACKET_HEADER_WIDTH_SCACQUIRE_FENCE_SCOPE,
+    )
+}
+
+pub fn hsa_packet_header_release_fence_scope(header: u16) -> u16 {
+    get_field(
+        header,
+        HSA_PACKET_HEADER_SCRELEASE_FENCE_SCOPE,
+        HSA_PACKET_HEADER_WIDTH_SCRELEASE_FENCE_SCOPE,
+    )
+}
+
+/* Handle of an HSA signal, 0 for none */
+pub type hsa_signal_t = u64;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct hsa_kernel_dispatch_packet_t {
+    pub header: u16,
+    pub setup: u16,
+    pub workgroup_size_x: u16,
+    pub workgroup_size_y: u16,
+    pub workgroup_size_z: u16,
+    pub reserved0: u16,
+    pub grid_size_x: u32,
+    pub grid_size_y: u32,
+    pub grid_size_z: u32,
+    pub private_segment_size: u32,
+    pub group_segment_size: u32,
+    pub kernel_object: u64,
+    pub kernarg_address: u64,
+    pub reserved2: u64,
+    pub completion_signal: hsa_signal_t,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct hsa_agent_dispatch_packet_t {
+    pub header: u16,
+    pub type_: u16,
+    pub reserved0: u32,
+    pub return_address: u64,
+    pub arg: [u64; 4],
+    pub reserved2: u64,
+    pub completion_signal: hsa_signal_t,
+}
+
+/* Waits for all dep_signal to reach 0 */
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct hsa_barrier_and_packet_t {
+    pub header: u16,
+    pub reserved0: u16,
+    pub reserved1: u32,
+    pub dep_signal: [hsa_signal_t; 5],
+    pub reserved2: u64,
+    pub completion_signal: hsa_signal_t,
+}
+
+/* Waits for any dep_signal to reach 0 */
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct hsa_barrier_or_packet_t {
+    pub header: u16,
+    pub reserved0: u16,
+    pub reserved1: u32,
+    pub dep_signal: [hsa_signal_t; 5],
+    pub reserved2: u64,
+    pub completion_signal: hsa_signal_t,
+}
+
+/* Vendor specific packet, AmdFormat says how the rest is laid out */
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct hsa_amd_vendor_packet_t {
+    pub header: u16,
+    pub AmdFormat: u8,
+    pub reserved: u8,
+    pub payload: [u32; 15],
+}
+
+/* Waits until (signal value & mask) cond value holds */
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct hsa_amd_barrier_value_packet_t {
+    pub header: u16,
+    pub AmdFormat: u8,
+    pub reserved: u8,
+    pub reserved0: u32,
+    pub signal: hsa_signal_t,
+    pub value: i64,
+    pub mask: i64,
+    pub cond: u32,
+    pub reserved1: u32,
+    pub reserved2: u64,
+    pub reserved3: u64,
+    pub completion_signal: hsa_signal_t,
+}
+
+/* A 64 byte packet whose first 16 bits are the header. The first dword,
+ * header and the 16 bits after it, is what gets published atomically.
+ */
+pub unsafe trait AqlPacket: Copy {
+    fn header(&self) -> u16;
+
+    fn header_dword(&self) -> u32 {
+        unsafe { *(self as *const Self as *const u32) }
+    }
+}
+
+macro_rules! aql_packet {
+    ($($t:ty),*) => {
+        $(
+            const _: () = assert!(std::mem::size_of::<$t>() == AQL_PACKET_SIZE);
+
+            unsafe impl AqlPacket for $t {
+                fn header(&self) -> u16 {
+                    self.header
+                }
+            }
+        )*
+    };
+}
+
+aql_packet!(
+    hsa_kernel_dispatch_packet_t,
+    hsa_agent_dispatch_packet_t,
+    hsa_barrier_and_packet_t,
+    hsa_barrier_or_packet_t,
+    hsa_amd_vendor_packet_t,
+    hsa_amd_barrier_value_packet_t
+);
+
+impl hsa_kernel_dispatch_packet_t {
+    /* Dispatch of a dims dimensional grid, with dimensions past dims
+     * set to 1
+     */
+    pub fn new(
+        dims: u16,
+        grid_size: [u32; 3],
+        workgroup_size: [u16; 3],
+        kernel_object: u64,
+        kernarg_address: u64,
+    ) -> Self {
+        let grid = |i: usize| {
+            if i < dims as usize {
+                grid_size[i].max(1)
+            } else {
+                1
+            }
+        };
+        let workgroup = |i: usize| {
+            if i < dims as usize {
+                workgroup_size[i].max(1)
+            } else {
+                1
+            }
+        };
+
+        Self {
+            setup: field(
+                dims,
+                HSA_KERNEL_DISPATCH_PACKET_SETUP_DIMENSIONS,
+                HSA_KERNEL_DISPATCH_PACKET_SETUP_WIDTH_DIMENSIONS,
+            ),
+            workgroup_size_x: workgroup(0),
+            workgroup_size_y: workgroup(1),
+            workgroup_size_z: workgroup(2),
+            grid_size_x: grid(0),
+            grid_size_y: grid(1),
+            grid_size_z: grid(2),
+            kernel_object,
+            kernarg_address,
+            ..Default::default()
+        }
+    }
+}
+
+/* Producer side of the ring of an AQL queue. Any number of threads may
+ * submit at the same time: slots are reserved by moving the write index
+ * forward, the packet body is written and the header is stored last
+ * with release ordering, so the packet processor never sees a half
+ * written packet. The doorbell is then rung with the packet's index.
+ *
+ * The ring borrows the memory of the queue for 'q.
+ */
+#[derive(Debug)]
+pub struct AqlRing<'q> {
+    base: *mut u8,
+    /* Number of slots, a power of 2 */
+    size: u64,
+    read_index: *const AtomicU64,
+    write_index: *const AtomicU64,
+    doorbell: *mut std::os::raw::c_void,
+    /* 8, or 4 for the 32 bit doorbells before SOC15 */
+    doorbell_size: u32,
+    queue: PhantomData<&'q Queue>,
+}
+
+/* The ring is shared with the packet processor anyway: the indexes and
+ * headers are only accessed atomically, the doorbell with volatile
+ * stores, and a packet body is only written by the producer that
+ * reserved its slot.
+ */
+unsafe impl Send for AqlRing<'_> {}
+unsafe impl Sync for AqlRing<'_> {}
+
+impl<'q> AqlRing<'q> {
+    /* ring holds size_bytes bytes, a power of 2 number of packets. The
+     * indexes are 64 bit packet counts that never wrap. The doorbell,
+     * if any, is doorbell_size bytes. All of it must stay valid for 'q.
+     */
+    pub unsafe fn new(
+        ring: *mut std::os::raw::c_void,
+        size_bytes: u64,
+        read_index: *mut u64,
+        write_index: *mut u64,
+        doorbell: *mut std::os::raw::c_void,
+        doorbell_size: u32,
+    ) -> Option<Self> {
+        let size = size_bytes / AQL_PACKET_SIZE as u64;
+
+        if ring.is_null()
+            || read_index.is_null()
+            || write_index.is_null()
+            || !size_bytes.is_multiple_of(AQL_PACKET_SIZE as u64)
+            || !size.is_power_of_two()
+            || (doorbell_size != 4 && doorbell_size != 8)
+        {
+            return None;
+        }
+
+        Some(Self {
+            base: ring as *mut u8,
+            size,
+            read_index: read_index as *const AtomicU64,
+            write_index: write_index as *const AtomicU64,
+            doorbell,
+            doorbell_size,
+            queue: PhantomData,
+        })
+    }
+
+    /* Ring of a queue made by hsakmt_queue_create */
+    pub unsafe fn from_queue(q: &'q Queue) -> Option<Self> {
+        if q.Type != HSA_QUEUE_COMPUTE_AQL {
+            return None;
+        }
+
+        Self::new(
+            q.RingBase,
+            q.RingSize,
+            q.read_pointer(),
+            q.write_pointer(),
+            q.doorbell(),
+            q.doorbell_size(),
+        )
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /* Mark every slot invalid, before the queue is first used */
+    pub unsafe fn init(&self) {
+        for i in 0..self.size {
+            self.header(i)
+                .store(HSA_PACKET_TYPE_INVALID as u32, Ordering::Relaxed);
+        }
+
+        fence(Ordering::Release);
+    }
+
+    fn slot(&self, index: u64) -> *mut u8 {
+        unsafe {
+            self.base
+                .add(((index & (self.size - 1)) as usize) * AQL_PACKET_SIZE)
+        }
+    }
+
+    fn header(&self, index: u64) -> &AtomicU32 {
+        unsafe { &*(self.slot(index) as *const AtomicU32) }
+    }
+
+    pub fn read_index(&self) -> u64 {
+        unsafe { (*self.read_index).load(Ordering::Acquire) }
+    }
+
+    pub fn write_index(&self) -> u64 {
+        unsafe { (*self.write_index).load(Ordering::Acquire) }
+    }
+
+    /* Reserve count consecutive slots and return the index of the first,
+     * or None if the packet processor hasn't freed enough of the ring
+     */
+    pub fn reserve(&self, count: u64) -> Option<u64> {
+        if count == 0 || count > self.size {
+            return None;
+        }
+
+        let write_index = unsafe { &*self.write_index };
+        let mut index = write_index.load(Ordering::Relaxed);
+
+        loop {
+            /* index may be stale and behind the read index */
+            if index + count > self.read_index() + self.size {
+                return None;
+            }
+
+            match write_index.compare_exchange_weak(
+                index,
+                index + count,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(index),
+                Err(current) => index = current,
+            }
+        }
+    }
+
+    /* Write packet to reserved slot index. Everything but the first dword
+     * is copied first, the first dword is then stored atomically.
+     */
+    pub unsafe fn write<P: AqlPacket>(&self, index: u64, packet: &P) {
+        let slot = self.slot(index);
+
+        std::ptr::copy_nonoverlapping(
+            (packet as *const P as *const u8).add(4),
+            slot.add(4),
+            AQL_PACKET_SIZE - 4,
+        );
+
+        self.header(index)
+            .store(packet.header_dword(), Ordering::Release);
+    }
+
+    /* Tell the packet processor packets up to index are there. 32 bit
+     * doorbells get the low half of the index.
+     */
+    pub unsafe fn ring_doorbell(&self, index: u64) {
+        if self.doorbell.is_null() {
+            return;
+        }
+
+        fence(Ordering::Release);
+        if self.doorbell_size == 4 {
+            std::ptr::write_volatile(self.doorbell as *mut u32, index as u32);
+        } else {
+            std::ptr::write_volatile(self.doorbell as *mut u64, index);
+        }
+    }
+
+    /* Reserve a slot, publish packet in it and ring the doorbell.
+     * Returns the packet's index.
+     */
+    pub unsafe fn submit<P: AqlPacket>(&self, packet: &P) -> Option<u64> {
+        let index = self.reserve(1)?;
+
+        self.write(index, packet);
+        self.ring_doorbell(index);
+
+        Some(index)
+    }
+
+    /* submit for packets that must be consecutive in the ring */
+    pub unsafe fn submit_all<P: AqlPacket>(&self, packets: &[P]) -> Option<u64> {
+        let index = self.reserve(packets.len() as u64)?;
+
+        for (i, packet) in packets.iter().enumerate() {
+            self.write(index + i as u64, packet);
+        }
+        self.ring_doorbell(index + packets.len() as u64 - 1);
+
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::UnsafeCell;
+    use std::mem::offset_of;
+
+    #[test]
+    fn test_aql_header() {
+        let header = hsa_packet_header(
+            HSA_PACKET_TYPE_KERNEL_DISPATCH,
+            true,
+            HSA_FENCE_SCOPE_SYSTEM,
+            HSA_FENCE_SCOPE_SYSTEM,
+        );
+
+        assert_eq!(header, 0x1502);
+        assert_eq!(
+            hsa_packet_header_type(header),
+            HSA_PACKET_TYPE_KERNEL_DISPATCH
+        );
+        assert!(hsa_packet_header_barrier(header));
+        assert_eq!(
+            hsa_packet_header_acquire_fence_scope(header),
+            HSA_FENCE_SCOPE_SYSTEM
+        );
+        assert_eq!(
+            hsa_packet_header_release_fence_scope(header),
+            HSA_FENCE_SCOPE_SYSTEM
+        );
+
+        let header = hsa_packet_header(
+            HSA_PACKET_TYPE_BARRIER_AND,
+            false,
+            HSA_FENCE_SCOPE_AGENT,
+            HSA_FENCE_SCOPE_NONE,
+        );
+        assert_eq!(header, 0x0203);
+        assert!(!hsa_packet_header_barrier(header));
+    }
+
+    #[test]
+    fn test_aql_layout() {
+        assert_eq!(offset_of!(hsa_kernel_dispatch_packet_t, grid_size_x), 12);
+        assert_eq!(
+            offset_of!(hsa_kernel_dispatch_packet_t, private_segment_size),
+            24
+        );
+        assert_eq!(offset_of!(hsa_kernel_dispatch_packet_t, kernel_object), 32);
+        assert_eq!(
+            offset_of!(hsa_kernel_dispatch_packet_t, completion_signal),
+            56
+        );
+        assert_eq!(offset_of!(hsa_agent_dispatch_packet_t, arg), 16);
+        assert_eq!(offset_of!(hsa_barrier_and_packet_t, dep_signal), 8);
+        assert_eq!(offset_of!(hsa_barrier_or_packet_t, completion_signal), 56);
+        assert_eq!(offset_of!(hsa_amd_barrier_value_packet_t, cond), 32);
+        assert_eq!(
+            offset_of!(hsa_amd_barrier_value_packet_t, completion_signal),
+            56
+        );
+
+        let mut packet =
+            hsa_kernel_dispatch_packet_t::new(2, [256, 4, 9], [64, 2, 8], 0x1000, 0x2000);
+        assert_eq!(packet.setup, 2);
+        assert_eq!((packet.grid_size_y, packet.grid_size_z), (4, 1));
+        assert_eq!((packet.workgroup_size_y, packet.workgroup_size_z), (2, 1));
+
+        packet.header = 0x1502;
+        assert_eq!(packet.header_dword(), 0x0002_1502);
+    }
+
+    /* Ring of size packets in host memory, with its indexes and doorbell */
+    /* Ring, indexes and doorbell of a queue in host memory. What the
+     * ring writes is behind UnsafeCell or atomics, so a test may look
+     * at it, and play the packet processor, while the ring is in use.
+     */
+    struct HostRing {
+        ring: Vec<UnsafeCell<[u64; 8]>>,
+        /* Read index, write index and doorbell */
+        indexes: [AtomicU64; 3],
+    }
+
+    impl HostRing {
+        fn new(size: usize) -> Self {
+            Self {
+                ring: (0..size).map(|_| UnsafeCell::new([0; 8])).collect(),
+                indexes: Default::default(),
+            }
+        }
+
+        fn aql_ring(&self, doorbell_size: u32) -> AqlRing<'_> {
+            unsafe {
+                let ring = AqlRing::new(
+                    self.ring.as_ptr() as *mut std::os::raw::c_void,
+                    (self.ring.len() * AQL_PACKET_SIZE) as u64,
+                    self.indexes[0].as_ptr(),
+                    self.indexes[1].as_ptr(),
+                    self.indexes[2].as_ptr() as *mut std::os::raw::c_void,
+                    doorbell_size,
+                )
+                .unwrap();
+
+                ring.init();
+                ring
+            }
+        }
+
+        fn index(&self, i: usize) -> u64 {
+            self.indexes[i].load(Ordering::Acquire)
+        }
+
+        fn packet<P: AqlPacket>(&self, index: usize) -> P {
+            unsafe { *(self.ring[index].get() as *const P) }
+        }
+    }
+
+    #[test]
+    fn test_aql_ring_submit() {
+        let host = HostRing::new(4);
+        let ring = host.aql_ring(8);
+
+        let mut dispatch = hsa_kernel_dispatch_packet_t::new(1, [64, 0, 0], [64, 0, 0], 0x1000, 0);
+        dispatch.header = hsa_packet_header(
+            HSA_PACKET_TYPE_KERNEL_DISPATCH,
+            false,
+            HSA_FENCE_SCOPE_SYSTEM,
+            HSA_FENCE_SCOPE_SYSTEM,
+        );
+        let barrier = hsa_barrier_and_packet_t {
+            header: hsa_packet_header(
+                HSA_PACKET_TYPE_BARRIER_AND,
+                true,
+                HSA_FENCE_SCOPE_NONE,
+                HSA_FENCE_SCOPE_AGENT,
+            ),
+            dep_signal: [1, 2, 0, 0, 0],
+            completion_signal: 3,
+            ..Default::default()
+        };
+
+        unsafe {
+            assert_eq!(ring.submit(&dispatch), Some(0));
+            assert_eq!(ring.submit(&barrier), Some(1));
+            assert_eq!(ring.submit_all(&[dispatch, dispatch]), Some(2));
+
+            /* Full until the packet processor moves the read index */
+            assert_eq!(ring.submit(&barrier), None);
+        }
+        assert_eq!(host.index(1), 4);
+        assert_eq!(host.index(2), 3);
+        assert_eq!(host.packet::<hsa_kernel_dispatch_packet_t>(0), dispatch);
+        assert_eq!(host.packet::<hsa_barrier_and_packet_t>(1), barrier);
+
+        host.indexes[0].store(2, Ordering::Release);
+        unsafe {
+            assert_eq!(ring.submit(&barrier), Some(4));
+            assert_eq!(ring.submit_all(&[barrier, barrier]), None);
+        }
+        assert_eq!(host.packet::<hsa_barrier_and_packet_t>(0), barrier);
+        assert_eq!(host.packet::<hsa_kernel_dispatch_packet_t>(3), dispatch);
+
+        /* Read index seen ahead of the write index */
+        host.indexes[0].store(6, Ordering::Release);
+        assert_eq!(ring.reserve(1), Some(5));
+        assert_eq!(ring.reserve(4), Some(6));
+        assert_eq!(ring.reserve(1), None);
+    }
+
+    #[test]
+    fn test_aql_ring_doorbell_size() {
+        let host = HostRing::new(4);
+        let barrier = hsa_barrier_and_packet_t {
+            header: HSA_PACKET_TYPE_BARRIER_AND,
+            ..Default::default()
+        };
+
+        unsafe {
+            assert!(AqlRing::new(
+                host.ring.as_ptr() as *mut std::os::raw::c_void,
+                4 * AQL_PACKET_SIZE as u64,
+                host.indexes[0].as_ptr(),
+                host.indexes[1].as_ptr(),
+                host.indexes[2].as_ptr() as *mut std::os::raw::c_void,
+                2,
+            )
+            .is_none());
+
+            /* Doorbells before SOC15 are 32 bit, the next dword is left alone */
+            host.indexes[2].store(u64::MAX, Ordering::Release);
+            let ring = host.aql_ring(4);
+            assert_eq!(ring.submit_all(&[barrier, barrier]), Some(0));
+        }
+        assert_eq!(host.index(2), 0xffff_ffff_0000_0001);
+    }
+
+    #[test]
+    fn test_aql_ring_producers() {
+        let host = HostRing::new(64);
+        let ring = host.aql_ring(8);
+
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let ring = &ring;
+
+                s.spawn(move || {
+                    for i in 0..16 {
+                        let mut packet = hsa_kernel_dispatch_packet_t::new(
+                            1,
+                            [1, 0, 0],
+                            [1, 0, 0],
+                            t * 16 + i,
+                            0,
+                        );
+                        packet.header = HSA_PACKET_TYPE_KERNEL_DISPATCH;
+
+                        unsafe { ring.submit(&packet).unwrap() };
+                    }
+                });
+            }
+        });
+
+        assert_eq!(host.index(1), 64);
+
+        let mut objects: Vec<u64> = (0..64)
+            .map(|i| host.packet::<hsa_kernel_dispatch_packet_t>(i))
+            .inspect(|p| assert_eq!(p.header, HSA_PACKET_TYPE_KERNEL_DISPATCH))
+            .map(|p| p.kernel_object)
+            .collect();
+        objects.sort();
+        assert_eq!(objects, (0..64).collect::<Vec<u64>>());
+    }
+}
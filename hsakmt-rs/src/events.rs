@@ -0,0 +1,830 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::HsakmtStatus;
+use crate::hsakmttypes::HsakmtStatus::{
+    HSAKMT_STATUS_ERROR, HSAKMT_STATUS_INVALID_HANDLE, HSAKMT_STATUS_INVALID_PARAMETER,
+    HSAKMT_STATUS_SUCCESS, HSAKMT_STATUS_WAIT_FAILURE, HSAKMT_STATUS_WAIT_TIMEOUT,
+};
+use crate::hsakmttypes::HSA_EVENTID_HW_EXCEPTION_CAUSE::{
+    HSA_EVENTID_HW_EXCEPTION_ECC, HSA_EVENTID_HW_EXCEPTION_GPU_HANG,
+};
+use crate::hsakmttypes::HSA_EVENTTYPE::{
+    HSA_EVENTTYPE_DEBUG_EVENT, HSA_EVENTTYPE_HW_EXCEPTION, HSA_EVENTTYPE_MEMORY,
+    HSA_EVENTTYPE_SIGNAL,
+};
+use crate::hsakmttypes::{
+    HsaEvent, HsaEventData, HsaEventDataUnion, HsaEventDescriptor, HsaHwException, HSA_EVENTTYPE,
+};
+use crate::kfd_ioctl::{
+    kfd_event_data, kfd_hsa_hw_exception_data, kfd_ioctl_create_event_args,
+    kfd_ioctl_destroy_event_args, kfd_ioctl_reset_event_args, kfd_ioctl_set_event_args,
+    kfd_ioctl_wait_events_args, AMDKFD_IOC_CREATE_EVENT, AMDKFD_IOC_DESTROY_EVENT,
+    AMDKFD_IOC_RESET_EVENT, AMDKFD_IOC_SET_EVENT, AMDKFD_IOC_WAIT_EVENTS, KFD_HW_EXCEPTION_ECC,
+    KFD_IOC_WAIT_RESULT_COMPLETE, KFD_IOC_WAIT_RESULT_TIMEOUT, KFD_SIGNAL_EVENT_LIMIT,
+};
+use crate::libhsakmt::hsakmt_ioctl;
+use libc::{mmap, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
+
+/* Value of an event slot that isn't signaled */
+pub const UNSIGNALED_EVENT_SLOT: u64 = u64::MAX;
+
+/* Events KFD raises itself, the process can only wait on them. Debug
+ * events behave as signal events.
+ */
+pub fn hsakmt_is_system_event_type(Type: HSA_EVENTTYPE) -> bool {
+    Type != HSA_EVENTTYPE_SIGNAL && Type != HSA_EVENTTYPE_DEBUG_EVENT
+}
+
+/* The event KFD created for EventDesc. slot is the address of its
+ * signal slot in the events page, 0 if it has none.
+ */
+pub fn hsakmt_event_from_kfd(
+    EventDesc: &HsaEventDescriptor,
+    args: &kfd_ioctl_create_event_args,
+    slot: u64,
+) -> HsaEvent {
+    let EventData = match EventDesc {
+        HsaEventDescriptor::Signal { SyncVar } => HsaEventDataUnion::SyncVar(*SyncVar),
+        _ => HsaEventDataUnion::None,
+    };
+
+    HsaEvent {
+        EventId: args.event_id,
+        EventData: HsaEventData {
+            EventType: EventDesc.EventType(),
+            EventData,
+            HWData1: args.event_id as u64,
+            HWData2: slot,
+            HWData3: args.event_trigger_data,
+        },
+    }
+}
+
+pub fn hsakmt_decode_hw_exception(
+    data: &kfd_hsa_hw_exception_data,
+    node_id: u32,
+) -> HsaHwException {
+    HsaHwException {
+        NodeId: node_id,
+        ResetType: data.reset_type,
+        ResetCause: if data.reset_cause == KFD_HW_EXCEPTION_ECC {
+            HSA_EVENTID_HW_EXCEPTION_ECC
+        } else {
+            HSA_EVENTID_HW_EXCEPTION_GPU_HANG
+        },
+        MemoryLost: data.memory_lost,
+    }
+}
+
+pub unsafe fn kfd_create_event(fd: i32, args: &mut kfd_ioctl_create_event_args) -> HsakmtStatus {
+    let err = hsakmt_ioctl(
+        fd,
+        AMDKFD_IOC_CREATE_EVENT,
+        args as *mut _ as *mut std::os::raw::c_void,
+    );
+
+    if err != 0 {
+        return HSAKMT_STATUS_ERROR;
+    }
+
+    HSAKMT_STATUS_SUCCESS
+}
+
+pub unsafe fn kfd_destroy_event(fd: i32, event_id: u32) -> HsakmtStatus {
+    let mut args = kfd_ioctl_destroy_event_args { event_id, pad: 0 };
+
+    let err = hsakmt_ioctl(
+        fd,
+        AMDKFD_IOC_DESTROY_EVENT,
+        &mut args as *mut _ as *mut std::os::raw::c_void,
+    );
+
+    if err != 0 {
+        return HSAKMT_STATUS_ERROR;
+    }
+
+    HSAKMT_STATUS_SUCCESS
+}
+
+pub unsafe fn kfd_set_event(fd: i32, event_id: u32) -> HsakmtStatus {
+    let mut args = kfd_ioctl_set_event_args { event_id, pad: 0 };
+
+    let err = hsakmt_ioctl(
+        fd,
+        AMDKFD_IOC_SET_EVENT,
+        &mut args as *mut _ as *mut std::os::raw::c_void,
+    );
+
+    if err != 0 {
+        return HSAKMT_STATUS_ERROR;
+    }
+
+    HSAKMT_STATUS_SUCCESS
+}
+
+pub unsafe fn kfd_reset_event(fd: i32, event_id: u32) -> HsakmtStatus {
+    let mut args = kfd_ioctl_reset_event_args { event_id, pad: 0 };
+
+    let err = hsakmt_ioctl(
+        fd,
+        AMDKFD_IOC_RESET_EVENT,
+        &mut args as *mut _ as *mut std::os::raw::c_void,
+    );
+
+    if err != 0 {
+        return HSAKMT_STATUS_ERROR;
+    }
+
+    HSAKMT_STATUS_SUCCESS
+}
+
+/* Wait until all of events, or any of them, are signaled, for at most
+ * Milliseconds. KFD fills in the exception data of the events.
+ */
+pub unsafe fn kfd_wait_events(
+    fd: i32,
+    events: &mut [kfd_event_data],
+    WaitOnAll: bool,
+    Milliseconds: u32,
+) -> HsakmtStatus {
+    let mut args = kfd_ioctl_wait_events_args {
+        events_ptr: events.as_mut_ptr() as u64,
+        num_events: events.len() as u32,
+        wait_for_all: WaitOnAll as u32,
+        timeout: Milliseconds,
+        wait_result: 0,
+    };
+
+    let err = hsakmt_ioctl(
+        fd,
+        AMDKFD_IOC_WAIT_EVENTS,
+        &mut args as *mut _ as *mut std::os::raw::c_void,
+    );
+
+    if err != 0 {
+        return HSAKMT_STATUS_ERROR;
+    }
+
+    match args.wait_result {
+        KFD_IOC_WAIT_RESULT_COMPLETE => HSAKMT_STATUS_SUCCESS,
+        KFD_IOC_WAIT_RESULT_TIMEOUT => HSAKMT_STATUS_WAIT_TIMEOUT,
+        _ => HSAKMT_STATUS_WAIT_FAILURE,
+    }
+}
+
+impl HsakmtGlobals {
+    /* Map the events page KFD allocated for this process at offset. Old
+     * kernels only have one page of slots.
+     */
+    unsafe fn hsakmt_map_events_page(&mut self, offset: u64) -> HsakmtStatus {
+        let mut size = KFD_SIGNAL_EVENT_LIMIT as usize * 8;
+
+        let mut page = mmap(
+            std::ptr::null_mut(),
+            size,
+            PROT_WRITE | PROT_READ,
+            MAP_SHARED,
+            self.hsakmt_kfd_fd,
+            offset as libc::off_t,
+        );
+        if page == MAP_FAILED {
+            size = self.PAGE_SIZE() as usize;
+
+            page = mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_WRITE | PROT_READ,
+                MAP_SHARED,
+                self.hsakmt_kfd_fd,
+                offset as libc::off_t,
+            );
+        }
+
+        if page == MAP_FAILED {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        self.events.events_page = page as *mut u64;
+        self.events.event_limit = (size / 8) as u32;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* Create an event. Signal events get a slot in the events page the
+     * GPU can signal them through, see HsaEventData.HWData2. On dGPUs the
+     * page is allocated here and handed to KFD, on APUs KFD allocates it
+     * and it gets mapped after the first event.
+     */
+    pub unsafe fn hsaKmtCreateEvent(
+        &mut self,
+        EventDesc: &HsaEventDescriptor,
+        ManualReset: bool,
+        IsSignaled: bool,
+        Event: &mut *mut HsaEvent,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        *Event = std::ptr::null_mut();
+
+        let mut args = kfd_ioctl_create_event_args {
+            event_type: EventDesc.EventType() as u32,
+            node_id: EventDesc.NodeId(),
+            auto_reset: !ManualReset as u32,
+            ..Default::default()
+        };
+
+        let mut new_page: *mut std::os::raw::c_void = std::ptr::null_mut();
+
+        if self.hsakmt_is_dgpu && self.events.events_page.is_null() {
+            new_page =
+                self.allocate_exec_aligned_memory(KFD_SIGNAL_EVENT_LIMIT * 8, 0, true, false, true);
+            if new_page.is_null() {
+                return HSAKMT_STATUS_ERROR;
+            }
+
+            self.events.events_page = new_page as *mut u64;
+            self.events.event_limit = KFD_SIGNAL_EVENT_LIMIT;
+
+            self.hsakmt_fmm_get_handle(new_page, &mut args.event_page_offset);
+        }
+
+        if kfd_create_event(self.hsakmt_kfd_fd, &mut args) != HSAKMT_STATUS_SUCCESS {
+            /* KFD didn't take the page, the next event gives it again */
+            if !new_page.is_null() {
+                self.free_exec_aligned_memory(new_page, KFD_SIGNAL_EVENT_LIMIT * 8);
+                self.events.events_page = std::ptr::null_mut();
+                self.events.event_limit = 0;
+            }
+
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        if self.events.events_page.is_null()
+            && args.event_page_offset > 0
+            && self.hsakmt_map_events_page(args.event_page_offset) != HSAKMT_STATUS_SUCCESS
+        {
+            println!("failed to map the events page");
+            kfd_destroy_event(self.hsakmt_kfd_fd, args.event_id);
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        let slot = if args.event_page_offset > 0 && args.event_slot_index < self.events.event_limit
+        {
+            self.events.events_page.add(args.event_slot_index as usize) as u64
+        } else {
+            0
+        };
+
+        let e = hsakmt_event_from_kfd(EventDesc, &args, slot);
+
+        if IsSignaled && !hsakmt_is_system_event_type(e.EventData.EventType) {
+            kfd_set_event(self.hsakmt_kfd_fd, e.EventId);
+        }
+
+        *Event = Box::into_raw(Box::new(e));
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsaKmtDestroyEvent(&mut self, Event: *mut HsaEvent) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if Event.is_null() {
+            return HSAKMT_STATUS_INVALID_HANDLE;
+        }
+
+        if kfd_destroy_event(self.hsakmt_kfd_fd, (*Event).EventId) != HSAKMT_STATUS_SUCCESS {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        drop(Box::from_raw(Event));
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsaKmtSetEvent(&self, Event: *mut HsaEvent) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if Event.is_null() {
+            return HSAKMT_STATUS_INVALID_HANDLE;
+        }
+
+        /* Although the spec doesn't say, don't allow system-defined events
+         * to be signaled.
+         */
+        if hsakmt_is_system_event_type((*Event).EventData.EventType) {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        kfd_set_event(self.hsakmt_kfd_fd, (*Event).EventId)
+    }
+
+    pub unsafe fn hsaKmtResetEvent(&self, Event: *mut HsaEvent) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if Event.is_null() {
+            return HSAKMT_STATUS_INVALID_HANDLE;
+        }
+
+        /* Although the spec doesn't say, don't allow system-defined events
+         * to be signaled.
+         */
+        if hsakmt_is_system_event_type((*Event).EventData.EventType) {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        kfd_reset_event(self.hsakmt_kfd_fd, (*Event).EventId)
+    }
+
+    /* KFD has no way to query the state of an event without waiting on
+     * it, the state is only known through the wait functions.
+     */
+    pub unsafe fn hsaKmtQueryEventState(&self, Event: *mut HsaEvent) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if Event.is_null() {
+            return HSAKMT_STATUS_INVALID_HANDLE;
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsaKmtWaitOnEvent(
+        &mut self,
+        Event: *mut HsaEvent,
+        Milliseconds: u32,
+    ) -> HsakmtStatus {
+        self.hsaKmtWaitOnEvent_Ext(Event, Milliseconds, None)
+    }
+
+    pub unsafe fn hsaKmtWaitOnEvent_Ext(
+        &mut self,
+        Event: *mut HsaEvent,
+        Milliseconds: u32,
+        event_age: Option<&mut u64>,
+    ) -> HsakmtStatus {
+        if Event.is_null() {
+            return HSAKMT_STATUS_INVALID_HANDLE;
+        }
+
+        self.hsaKmtWaitOnMultipleEvents_Ext(
+            &[Event],
+            true,
+            Milliseconds,
+            event_age.map(std::slice::from_mut),
+        )
+    }
+
+    pub unsafe fn hsaKmtWaitOnMultipleEvents(
+        &mut self,
+        Events: &[*mut HsaEvent],
+        WaitOnAll: bool,
+        Milliseconds: u32,
+    ) -> HsakmtStatus {
+        self.hsaKmtWaitOnMultipleEvents_Ext(Events, WaitOnAll, Milliseconds, None)
+    }
+
+    /* Wait until all of Events are signaled if WaitOnAll, any of them
+     * otherwise, for at most Milliseconds: HSA_EVENTTIMEOUT_IMMEDIATE
+     * only polls, HSA_EVENTTIMEOUT_INFINITE never times out. Memory and
+     * HW exception events get their exception data filled in.
+     *
+     * event_age has an entry per event. For signal events it is the age
+     * last seen going in and the current age coming out, so a signal
+     * that happened since the last wait isn't missed.
+     */
+    pub unsafe fn hsaKmtWaitOnMultipleEvents_Ext(
+        &mut self,
+        Events: &[*mut HsaEvent],
+        WaitOnAll: bool,
+        Milliseconds: u32,
+        mut event_age: Option<&mut [u64]>,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if Events.is_empty() || Events.iter().any(|e| e.is_null()) {
+            return HSAKMT_STATUS_INVALID_HANDLE;
+        }
+
+        if event_age
+            .as_ref()
+            .is_some_and(|age| age.len() < Events.len())
+        {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let mut event_data = vec![kfd_event_data::default(); Events.len()];
+
+        for (i, e) in Events.iter().enumerate() {
+            event_data[i].event_id = (**e).EventId;
+
+            if let Some(age) = event_age.as_ref() {
+                if (**e).EventData.EventType == HSA_EVENTTYPE_SIGNAL {
+                    event_data[i].data.signal_event_data.last_event_age = age[i];
+                }
+            }
+        }
+
+        let result = kfd_wait_events(self.hsakmt_kfd_fd, &mut event_data, WaitOnAll, Milliseconds);
+
+        if result == HSAKMT_STATUS_SUCCESS {
+            for (i, e) in Events.iter().enumerate() {
+                let e = &mut **e;
+
+                if e.EventData.EventType == HSA_EVENTTYPE_MEMORY
+                    && event_data[i].data.memory_exception_data.gpu_id != 0
+                {
+                    let exception = self
+                        .hsakmt_analyze_memory_exception(&event_data[i].data.memory_exception_data);
+
+                    e.EventData.EventData = HsaEventDataUnion::MemoryAccessFault(exception.Fault);
+                } else if e.EventData.EventType == HSA_EVENTTYPE_HW_EXCEPTION
+                    && event_data[i].data.hw_exception_data.gpu_id != 0
+                {
+                    let data = event_data[i].data.hw_exception_data;
+                    let mut node_id = 0;
+                    self.gpuid_to_nodeid(data.gpu_id, &mut node_id);
+
+                    e.EventData.EventData =
+                        HsaEventDataUnion::HwException(hsakmt_decode_hw_exception(&data, node_id));
+                }
+            }
+        }
+
+        if let Some(age) = event_age.as_mut() {
+            for (i, e) in Events.iter().enumerate() {
+                if (**e).EventData.EventType == HSA_EVENTTYPE_SIGNAL {
+                    age[i] = event_data[i].data.signal_event_data.last_event_age;
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hsakmttypes::HsaSyncVar;
+    use crate::hsakmttypes::HSA_EVENTTYPE::{
+        HSA_EVENTTYPE_DEVICESTATECHANGE, HSA_EVENTTYPE_NODECHANGE, HSA_EVENTTYPE_QUEUE_EVENT,
+    };
+    use crate::kfd_ioctl::AMDKFD_IOC_CREATE_EVENT;
+    use crate::kfd_ioctl::{
+        kfd_event_data_union, KFD_HW_EXCEPTION_PER_ENGINE_RESET, KFD_IOC_EVENT_HW_EXCEPTION,
+        KFD_IOC_EVENT_SIGNAL,
+    };
+    use crate::libhsakmt::fake_kfd::{
+        fake_kfd_globals, fake_kfd_globals_close, FakeKfd, FAKE_EVENTS_PAGE_OFFSET,
+    };
+    use crate::libhsakmt::hsakmt_set_ioctl_hook;
+
+    #[test]
+    fn test_event_descriptor() {
+        let signal = HsaEventDescriptor::Signal {
+            SyncVar: HsaSyncVar {
+                UserData: 0x1000,
+                SyncVarSize: 8,
+            },
+        };
+        let hw = HsaEventDescriptor::HwException { NodeId: 2 };
+
+        assert_eq!(signal.EventType(), HSA_EVENTTYPE_SIGNAL);
+        assert_eq!(signal.NodeId(), 0);
+        assert_eq!(hw.EventType(), HSA_EVENTTYPE_HW_EXCEPTION);
+        assert_eq!(hw.NodeId(), 2);
+        assert_eq!(
+            HsaEventDescriptor::NodeChange.EventType(),
+            HSA_EVENTTYPE_NODECHANGE
+        );
+        assert_eq!(
+            HsaEventDescriptor::DeviceStateChange { NodeId: 1 }.EventType(),
+            HSA_EVENTTYPE_DEVICESTATECHANGE
+        );
+        assert_eq!(
+            HsaEventDescriptor::QueueEvent { NodeId: 1 }.EventType(),
+            HSA_EVENTTYPE_QUEUE_EVENT
+        );
+
+        assert!(!hsakmt_is_system_event_type(HSA_EVENTTYPE_SIGNAL));
+        assert!(!hsakmt_is_system_event_type(HSA_EVENTTYPE_DEBUG_EVENT));
+        assert!(hsakmt_is_system_event_type(HSA_EVENTTYPE_MEMORY));
+
+        let args = kfd_ioctl_create_event_args {
+            event_type: KFD_IOC_EVENT_SIGNAL,
+            event_id: 5,
+            event_trigger_data: 5,
+            event_slot_index: 5,
+            ..Default::default()
+        };
+        let e = hsakmt_event_from_kfd(&signal, &args, 0x7000_0028);
+        assert_eq!(e.EventId, 5);
+        assert_eq!(
+            e.EventData,
+            HsaEventData {
+                EventType: HSA_EVENTTYPE_SIGNAL,
+                EventData: HsaEventDataUnion::SyncVar(HsaSyncVar {
+                    UserData: 0x1000,
+                    SyncVarSize: 8,
+                }),
+                HWData1: 5,
+                HWData2: 0x7000_0028,
+                HWData3: 5,
+            }
+        );
+
+        let data = kfd_hsa_hw_exception_data {
+            reset_type: KFD_HW_EXCEPTION_PER_ENGINE_RESET,
+            reset_cause: KFD_HW_EXCEPTION_ECC,
+            memory_lost: 1,
+            gpu_id: 0x1234,
+        };
+        assert_eq!(
+            hsakmt_decode_hw_exception(&data, 1),
+            HsaHwException {
+                NodeId: 1,
+                ResetType: KFD_HW_EXCEPTION_PER_ENGINE_RESET,
+                ResetCause: HSA_EVENTID_HW_EXCEPTION_ECC,
+                MemoryLost: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_wait_events() {
+        let kfd = FakeKfd::install(FakeKfd::default());
+
+        unsafe {
+            let mut ids = vec![];
+            for _ in 0..2 {
+                let mut args = kfd_ioctl_create_event_args::default();
+                assert_eq!(kfd_create_event(-1, &mut args), HSAKMT_STATUS_SUCCESS);
+                ids.push(args.event_id);
+            }
+            assert_eq!(ids, vec![0, 1]);
+
+            let mut events: Vec<kfd_event_data> = ids
+                .iter()
+                .map(|id| kfd_event_data {
+                    event_id: *id,
+                    ..Default::default()
+                })
+                .collect();
+
+            assert_eq!(
+                kfd_wait_events(-1, &mut events, false, 0),
+                HSAKMT_STATUS_WAIT_TIMEOUT
+            );
+
+            /* Any completes with one event signaled, all doesn't */
+            assert_eq!(kfd_set_event(-1, 1), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(
+                kfd_wait_events(-1, &mut events, false, 0),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!(
+                kfd_wait_events(-1, &mut events, true, 0),
+                HSAKMT_STATUS_WAIT_TIMEOUT
+            );
+
+            assert_eq!(kfd_set_event(-1, 0), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(
+                kfd_wait_events(-1, &mut events, true, 0),
+                HSAKMT_STATUS_SUCCESS
+            );
+
+            assert_eq!(kfd_reset_event(-1, 0), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(
+                kfd_wait_events(-1, &mut events[..1], true, 0),
+                HSAKMT_STATUS_WAIT_TIMEOUT
+            );
+
+            /* Destroyed events can't be waited on */
+            assert_eq!(kfd_destroy_event(-1, 0), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(kfd_destroy_event(-1, 0), HSAKMT_STATUS_ERROR);
+            assert_eq!(
+                kfd_wait_events(-1, &mut events, false, 0),
+                HSAKMT_STATUS_ERROR
+            );
+        }
+
+        hsakmt_set_ioctl_hook(None);
+
+        let mut kfd = kfd.borrow_mut();
+        assert!(kfd.event(0).is_none());
+        assert!(kfd.event(1).unwrap().signaled);
+    }
+
+    #[test]
+    fn test_wait_on_multiple_events_ext() {
+        use crate::fmm_types::gpu_mem_t;
+
+        let kfd = FakeKfd::install(FakeKfd::default());
+
+        let mut g = HsakmtGlobals::without_kfd();
+        g.hsakmt_kfd_open_count = 1;
+        g.fmm.gpu_mem = vec![gpu_mem_t {
+            gpu_id: 0x1234,
+            node_id: 1,
+            ..Default::default()
+        }];
+
+        let signal = HsaEventDescriptor::Signal {
+            SyncVar: HsaSyncVar {
+                UserData: 0,
+                SyncVarSize: 0,
+            },
+        };
+        let hw = HsaEventDescriptor::HwException { NodeId: 1 };
+
+        let mut args = kfd_ioctl_create_event_args {
+            event_type: KFD_IOC_EVENT_SIGNAL,
+            auto_reset: 1,
+            ..Default::default()
+        };
+        unsafe { kfd_create_event(-1, &mut args) };
+        let mut signal = hsakmt_event_from_kfd(&signal, &args, 0);
+
+        let mut args = kfd_ioctl_create_event_args {
+            event_type: KFD_IOC_EVENT_HW_EXCEPTION,
+            node_id: 1,
+            ..Default::default()
+        };
+        unsafe { kfd_create_event(-1, &mut args) };
+        let mut hw = hsakmt_event_from_kfd(&hw, &args, 0);
+        let events = [&mut signal as *mut HsaEvent, &mut hw as *mut HsaEvent];
+
+        unsafe {
+            /* Age 1 is the age the event was created with */
+            let mut ages = [1, 0];
+            assert_eq!(
+                g.hsaKmtWaitOnMultipleEvents_Ext(&events, false, 0, Some(&mut ages)),
+                HSAKMT_STATUS_WAIT_TIMEOUT
+            );
+            assert_eq!(ages, [1, 0]);
+
+            /* A signal between two waits isn't missed */
+            assert_eq!(g.hsaKmtSetEvent(events[0]), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(
+                g.hsaKmtWaitOnMultipleEvents_Ext(&events, false, 0, Some(&mut ages)),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!(ages, [2, 0]);
+
+            assert_eq!(
+                g.hsaKmtWaitOnMultipleEvents_Ext(&events, false, 0, Some(&mut ages)),
+                HSAKMT_STATUS_WAIT_TIMEOUT
+            );
+            assert_eq!(ages, [2, 0]);
+            assert_eq!(hw.EventData.EventData, HsaEventDataUnion::None);
+
+            /* Too few ages */
+            assert_eq!(
+                g.hsaKmtWaitOnMultipleEvents_Ext(&events, false, 0, Some(&mut ages[..1])),
+                HSAKMT_STATUS_INVALID_PARAMETER
+            );
+
+            kfd.borrow_mut().event(hw.EventId).unwrap().exception = Some(kfd_event_data_union {
+                hw_exception_data: kfd_hsa_hw_exception_data {
+                    reset_type: 1,
+                    reset_cause: KFD_HW_EXCEPTION_ECC,
+                    memory_lost: 1,
+                    gpu_id: 0x1234,
+                },
+            });
+            assert_eq!(
+                g.hsaKmtWaitOnMultipleEvents_Ext(&events, false, 0, Some(&mut ages)),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!(ages, [2, 0]);
+            assert_eq!(
+                hw.EventData.EventData,
+                HsaEventDataUnion::HwException(HsaHwException {
+                    NodeId: 1,
+                    ResetType: 1,
+                    ResetCause: HSA_EVENTID_HW_EXCEPTION_ECC,
+                    MemoryLost: 1,
+                })
+            );
+        }
+
+        hsakmt_set_ioctl_hook(None);
+    }
+
+    #[test]
+    fn test_create_event() {
+        let kfd = FakeKfd::install(FakeKfd {
+            failing: vec![AMDKFD_IOC_CREATE_EVENT],
+            ..Default::default()
+        });
+
+        let signal = HsaEventDescriptor::Signal {
+            SyncVar: HsaSyncVar {
+                UserData: 0,
+                SyncVarSize: 0,
+            },
+        };
+        let hw = HsaEventDescriptor::HwException { NodeId: 1 };
+        let mut event: *mut HsaEvent = std::ptr::null_mut();
+
+        unsafe {
+            let mut g = fake_kfd_globals(9, 0, 0);
+
+            /* The events page goes away with the event KFD refused */
+            assert_eq!(
+                g.hsaKmtCreateEvent(&signal, false, false, &mut event),
+                HSAKMT_STATUS_ERROR
+            );
+            assert!(event.is_null());
+            assert!(g.events.events_page.is_null());
+            assert_eq!(g.events.event_limit, 0);
+            assert!(kfd.borrow().bos.is_empty());
+
+            kfd.borrow_mut().failing.clear();
+
+            /* dGPUs give KFD a page of their own */
+            assert_eq!(
+                g.hsaKmtCreateEvent(&signal, false, true, &mut event),
+                HSAKMT_STATUS_SUCCESS
+            );
+            let page = g.events.events_page;
+            assert!(!page.is_null());
+            assert_eq!(g.events.event_limit, KFD_SIGNAL_EVENT_LIMIT);
+            assert_eq!(kfd.borrow().bos.len(), 1);
+            assert_eq!(kfd.borrow().events_page, Some(kfd.borrow().bos[0].handle));
+
+            let id = (*event).EventId;
+            assert_eq!((*event).EventData.HWData2, page.add(id as usize) as u64);
+            assert!(kfd.borrow_mut().event(id).unwrap().signaled);
+
+            /* System events aren't signaled at creation */
+            let mut exception: *mut HsaEvent = std::ptr::null_mut();
+            assert_eq!(
+                g.hsaKmtCreateEvent(&hw, true, true, &mut exception),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!(g.events.events_page, page);
+            assert_eq!(kfd.borrow().bos.len(), 1);
+            assert!(
+                !kfd.borrow_mut()
+                    .event((*exception).EventId)
+                    .unwrap()
+                    .signaled
+            );
+
+            assert_eq!(g.hsaKmtDestroyEvent(event), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(g.hsaKmtDestroyEvent(exception), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(
+                g.hsaKmtDestroyEvent(std::ptr::null_mut()),
+                HSAKMT_STATUS_INVALID_HANDLE
+            );
+            assert!(kfd.borrow().events.iter().all(|e| e.is_none()));
+
+            fake_kfd_globals_close(&g);
+
+            /* APUs map the page KFD allocated, from the KFD fd */
+            let mut g = fake_kfd_globals(9, 0, 0);
+            g.hsakmt_is_dgpu = false;
+
+            let size = KFD_SIGNAL_EVENT_LIMIT as usize * 8;
+            g.hsakmt_kfd_fd = libc::memfd_create(c"fake-kfd".as_ptr(), 0);
+            assert!(g.hsakmt_kfd_fd >= 0);
+            assert_eq!(
+                libc::ftruncate(
+                    g.hsakmt_kfd_fd,
+                    (FAKE_EVENTS_PAGE_OFFSET as usize + size) as i64
+                ),
+                0
+            );
+
+            assert_eq!(
+                g.hsaKmtCreateEvent(&signal, false, false, &mut event),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!(g.events.event_limit, KFD_SIGNAL_EVENT_LIMIT);
+
+            let id = (*event).EventId;
+            let slot = 0x1234_5678u64;
+            assert_eq!(
+                libc::pwrite(
+                    g.hsakmt_kfd_fd,
+                    &slot as *const u64 as *const std::os::raw::c_void,
+                    8,
+                    (FAKE_EVENTS_PAGE_OFFSET + id as u64 * 8) as i64,
+                ),
+                8
+            );
+            assert_eq!(*((*event).EventData.HWData2 as *const u64), slot);
+
+            assert_eq!(g.hsaKmtDestroyEvent(event), HSAKMT_STATUS_SUCCESS);
+
+            libc::munmap(g.events.events_page as *mut std::os::raw::c_void, size);
+            libc::close(g.hsakmt_kfd_fd);
+            fake_kfd_globals_close(&g);
+        }
+
+        hsakmt_set_ioctl_hook(None);
+    }
+}
@@ -0,0 +1,852 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use crate::events::{kfd_create_event, kfd_destroy_event, kfd_set_event, kfd_wait_events};
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::HsakmtStatus;
+use crate::hsakmttypes::HsakmtStatus::{
+    HSAKMT_STATUS_ERROR, HSAKMT_STATUS_NOT_SUPPORTED, HSAKMT_STATUS_SUCCESS,
+    HSAKMT_STATUS_WAIT_TIMEOUT,
+};
+use crate::hsakmttypes::HSA_EVENTTIMEOUT_INFINITE;
+use crate::kfd_ioctl::{kfd_event_data, kfd_ioctl_create_event_args, KFD_IOC_EVENT_SIGNAL};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/* Where an HsaEventWaiter waits for events. wait_any blocks until one
+ * of EventIds is signaled, Milliseconds pass or interrupt is called,
+ * and reports the events it saw signaled. An interrupted or timed out
+ * wait reports nothing. forget drops what is known about a destroyed
+ * event.
+ */
+pub trait HsaEventSource: Send + Sync {
+    fn wait_any(
+        &self,
+        EventIds: &[u32],
+        Milliseconds: u32,
+        Signaled: &mut Vec<u32>,
+    ) -> HsakmtStatus;
+
+    fn interrupt(&self);
+
+    fn forget(&self, EventId: u32);
+}
+
+/* Event age KFD starts signal events at */
+const KFD_EVENT_AGE_INITIAL: u64 = 1;
+
+#[derive(Debug)]
+struct kfd_event_ages_t {
+    /* Age of each event at the end of the last wait including it, kept
+     * until the event is forgotten
+     */
+    events: HashMap<u32, u64>,
+    /* Age of the wake event */
+    wake: u64,
+    /* Events forgotten while a wait was running */
+    forgotten: HashSet<u32>,
+}
+
+/* Signal events of KFD. A wait on several events doesn't say which ones
+ * fired, so the events are told apart by their age, which KFD bumps on
+ * every signal (KFD 1.14 and later). Ages are tracked from the
+ * creation of the event, an event signaled before its first wait here
+ * completes that wait at once.
+ *
+ * interrupt signals an event of its own that every wait includes.
+ */
+pub struct KfdEventSource {
+    fd: i32,
+    wake_event_id: u32,
+    ages: Mutex<kfd_event_ages_t>,
+}
+
+impl KfdEventSource {
+    pub unsafe fn new(fd: i32) -> Option<Self> {
+        let mut args = kfd_ioctl_create_event_args {
+            event_type: KFD_IOC_EVENT_SIGNAL,
+            auto_reset: 1,
+            ..Default::default()
+        };
+
+        if kfd_create_event(fd, &mut args) != HSAKMT_STATUS_SUCCESS {
+            return None;
+        }
+
+        Some(Self {
+            fd,
+            wake_event_id: args.event_id,
+            ages: Mutex::new(kfd_event_ages_t {
+                events: HashMap::new(),
+                wake: KFD_EVENT_AGE_INITIAL,
+                forgotten: HashSet::new(),
+            }),
+        })
+    }
+}
+
+impl HsaEventSource for KfdEventSource {
+    fn wait_any(
+        &self,
+        EventIds: &[u32],
+        Milliseconds: u32,
+        Signaled: &mut Vec<u32>,
+    ) -> HsakmtStatus {
+        let mut events: Vec<kfd_event_data> = {
+            let mut ages = self.ages.lock().unwrap();
+            ages.forgotten.clear();
+
+            EventIds
+                .iter()
+                .map(|id| (*id, *ages.events.get(id).unwrap_or(&KFD_EVENT_AGE_INITIAL)))
+                .chain(std::iter::once((self.wake_event_id, ages.wake)))
+                .map(|(id, age)| {
+                    let mut event = kfd_event_data {
+                        event_id: id,
+                        ..Default::default()
+                    };
+                    event.data.signal_event_data.last_event_age = age;
+                    event
+                })
+                .collect()
+        };
+
+        /* Not holding the lock, forget may come in while KFD waits */
+        let ret = unsafe { kfd_wait_events(self.fd, &mut events, false, Milliseconds) };
+        if ret != HSAKMT_STATUS_SUCCESS {
+            return ret;
+        }
+
+        let mut ages = self.ages.lock().unwrap();
+
+        ages.wake = unsafe { events[EventIds.len()].data.signal_event_data.last_event_age };
+
+        for event in &events[..EventIds.len()] {
+            if ages.forgotten.contains(&event.event_id) {
+                continue;
+            }
+
+            let age = unsafe { event.data.signal_event_data.last_event_age };
+            let last = ages.events.insert(event.event_id, age);
+
+            if age != last.unwrap_or(KFD_EVENT_AGE_INITIAL) {
+                Signaled.push(event.event_id);
+            }
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    fn interrupt(&self) {
+        unsafe {
+            kfd_set_event(self.fd, self.wake_event_id);
+        }
+    }
+
+    fn forget(&self, EventId: u32) {
+        let mut ages = self.ages.lock().unwrap();
+
+        ages.events.remove(&EventId);
+        ages.forgotten.insert(EventId);
+    }
+}
+
+impl Drop for KfdEventSource {
+    fn drop(&mut self) {
+        unsafe {
+            kfd_destroy_event(self.fd, self.wake_event_id);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct mock_event_state_t {
+    signaled: HashSet<u32>,
+    interrupted: bool,
+}
+
+/* Events signaled by hand, for tests of code waiting on events. Events
+ * reset once a wait saw them, like KFD auto reset events.
+ */
+#[derive(Debug, Default)]
+pub struct MockEventSource {
+    state: Mutex<mock_event_state_t>,
+    cond: Condvar,
+}
+
+impl MockEventSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn signal(&self, EventId: u32) {
+        self.state.lock().unwrap().signaled.insert(EventId);
+        self.cond.notify_all();
+    }
+}
+
+impl HsaEventSource for MockEventSource {
+    fn wait_any(
+        &self,
+        EventIds: &[u32],
+        Milliseconds: u32,
+        Signaled: &mut Vec<u32>,
+    ) -> HsakmtStatus {
+        let deadline = (Milliseconds != HSA_EVENTTIMEOUT_INFINITE)
+            .then(|| Instant::now() + Duration::from_millis(Milliseconds as u64));
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            let fired: Vec<u32> = EventIds
+                .iter()
+                .copied()
+                .filter(|id| state.signaled.contains(id))
+                .collect();
+
+            if !fired.is_empty() {
+                for id in &fired {
+                    state.signaled.remove(id);
+                }
+                Signaled.extend(fired);
+                return HSAKMT_STATUS_SUCCESS;
+            }
+
+            if state.interrupted {
+                state.interrupted = false;
+                return HSAKMT_STATUS_SUCCESS;
+            }
+
+            state = match deadline {
+                None => self.cond.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return HSAKMT_STATUS_WAIT_TIMEOUT;
+                    }
+                    self.cond.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    fn interrupt(&self) {
+        self.state.lock().unwrap().interrupted = true;
+        self.cond.notify_all();
+    }
+
+    fn forget(&self, EventId: u32) {
+        self.state.lock().unwrap().signaled.remove(&EventId);
+    }
+}
+
+struct waiter_slot_t {
+    event_id: u32,
+    deadline: Option<Instant>,
+    status: Option<HsakmtStatus>,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct waiter_state_t {
+    slots: HashMap<u64, waiter_slot_t>,
+    next_key: u64,
+    shutdown: bool,
+}
+
+impl waiter_state_t {
+    /* Complete the pending waits matching done with status, returning
+     * a waker, if one was registered yet, per completed wait
+     */
+    fn complete(
+        &mut self,
+        status: HsakmtStatus,
+        done: impl Fn(&waiter_slot_t) -> bool,
+    ) -> Vec<Option<Waker>> {
+        self.slots
+            .values_mut()
+            .filter(|slot| slot.status.is_none() && done(slot))
+            .map(|slot| {
+                slot.status = Some(status);
+                slot.waker.take()
+            })
+            .collect()
+    }
+}
+
+struct waiter_shared_t {
+    state: Mutex<waiter_state_t>,
+    cond: Condvar,
+    source: Arc<dyn HsaEventSource>,
+    /* eventfd counting completions, -1 if there is none */
+    completion_fd: i32,
+}
+
+impl waiter_shared_t {
+    fn wake(&self, wakers: Vec<Option<Waker>>) {
+        if wakers.is_empty() {
+            return;
+        }
+
+        if self.completion_fd >= 0 {
+            let one = 1u64;
+            unsafe {
+                libc::write(
+                    self.completion_fd,
+                    &one as *const u64 as *const std::os::raw::c_void,
+                    8,
+                );
+            }
+        }
+
+        for waker in wakers.into_iter().flatten() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for waiter_shared_t {
+    fn drop(&mut self) {
+        if self.completion_fd >= 0 {
+            unsafe {
+                libc::close(self.completion_fd);
+            }
+        }
+    }
+}
+
+/* Milliseconds until deadline, rounded up so a wait doesn't end just
+ * before it
+ */
+fn wait_milliseconds(deadline: Instant, now: Instant) -> u32 {
+    let left = deadline
+        .saturating_duration_since(now)
+        .as_micros()
+        .div_ceil(1000);
+
+    left.min((HSA_EVENTTIMEOUT_INFINITE - 1) as u128) as u32
+}
+
+/* The ids of a failed wait_any that fail on their own, with their
+ * status. KFD fails the whole wait if one of the ids is bad, so the
+ * ids are polled one by one, the signals seen meanwhile go to
+ * Signaled. If no id fails alone, all of them take the failure.
+ */
+fn waiter_failed_ids(
+    source: &dyn HsaEventSource,
+    EventIds: &[u32],
+    ret: HsakmtStatus,
+    Signaled: &mut Vec<u32>,
+) -> Vec<(u32, HsakmtStatus)> {
+    let mut failed = vec![];
+
+    if EventIds.len() > 1 {
+        for id in EventIds {
+            match source.wait_any(&[*id], 0, Signaled) {
+                HSAKMT_STATUS_SUCCESS | HSAKMT_STATUS_WAIT_TIMEOUT => {}
+                status => failed.push((*id, status)),
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        failed = EventIds.iter().map(|id| (*id, ret)).collect();
+    }
+
+    failed
+}
+
+fn waiter_reaper(shared: Arc<waiter_shared_t>) {
+    loop {
+        let (ids, timeout) = {
+            let mut state = shared.state.lock().unwrap();
+
+            loop {
+                if state.shutdown {
+                    return;
+                }
+
+                let now = Instant::now();
+                let expired = state.complete(HSAKMT_STATUS_WAIT_TIMEOUT, |slot| {
+                    slot.deadline.is_some_and(|deadline| deadline <= now)
+                });
+                if !expired.is_empty() {
+                    drop(state);
+                    shared.wake(expired);
+                    state = shared.state.lock().unwrap();
+                    continue;
+                }
+
+                if state.slots.values().any(|slot| slot.status.is_none()) {
+                    break;
+                }
+
+                state = shared.cond.wait(state).unwrap();
+            }
+
+            let now = Instant::now();
+            let pending = state.slots.values().filter(|slot| slot.status.is_none());
+
+            let mut ids: Vec<u32> = pending.clone().map(|slot| slot.event_id).collect();
+            ids.sort_unstable();
+            ids.dedup();
+
+            let timeout = pending
+                .filter_map(|slot| slot.deadline)
+                .min()
+                .map_or(HSA_EVENTTIMEOUT_INFINITE, |deadline| {
+                    wait_milliseconds(deadline, now)
+                });
+
+            (ids, timeout)
+        };
+
+        let mut signaled = vec![];
+        let ret = shared.source.wait_any(&ids, timeout, &mut signaled);
+
+        /* Deadlines are checked on the next round */
+        let failed = match ret {
+            HSAKMT_STATUS_SUCCESS | HSAKMT_STATUS_WAIT_TIMEOUT => vec![],
+            _ => waiter_failed_ids(shared.source.as_ref(), &ids, ret, &mut signaled),
+        };
+
+        let wakers = {
+            let mut state = shared.state.lock().unwrap();
+
+            let mut wakers = state.complete(HSAKMT_STATUS_SUCCESS, |slot| {
+                signaled.contains(&slot.event_id)
+            });
+            for (id, status) in failed {
+                wakers.extend(state.complete(status, |slot| slot.event_id == id));
+            }
+            wakers
+        };
+
+        shared.wake(wakers);
+    }
+}
+
+/* Waits for events on a thread of its own, completing an
+ * HsaEventFuture per wait. The futures work with any executor, and
+ * completion_fd lets poll/epoll based loops learn about completions
+ * too.
+ *
+ * All waits for an event complete when it fires, and fail if waiting
+ * on it fails. Dropping a future cancels its wait, dropping the waiter
+ * completes the waits left with HSAKMT_STATUS_ERROR.
+ */
+pub struct HsaEventWaiter {
+    shared: Arc<waiter_shared_t>,
+    reaper: Option<JoinHandle<()>>,
+}
+
+impl HsaEventWaiter {
+    /* None if the thread couldn't be started */
+    pub fn new(source: Arc<dyn HsaEventSource>) -> Option<Self> {
+        let completion_fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+
+        let shared = Arc::new(waiter_shared_t {
+            state: Mutex::new(waiter_state_t::default()),
+            cond: Condvar::new(),
+            source,
+            completion_fd,
+        });
+
+        let reaper = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("hsakmt-events".to_string())
+                .spawn(move || waiter_reaper(shared))
+                .ok()?
+        };
+
+        Some(Self {
+            shared,
+            reaper: Some(reaper),
+        })
+    }
+
+    /* Wait for EventId to be signaled */
+    pub fn wait(&self, EventId: u32) -> HsaEventFuture {
+        self.wait_timeout(EventId, HSA_EVENTTIMEOUT_INFINITE)
+    }
+
+    /* Wait for EventId to be signaled for at most Milliseconds, the
+     * future gives HSAKMT_STATUS_WAIT_TIMEOUT if it isn't
+     */
+    pub fn wait_timeout(&self, EventId: u32, Milliseconds: u32) -> HsaEventFuture {
+        let deadline = (Milliseconds != HSA_EVENTTIMEOUT_INFINITE)
+            .then(|| Instant::now() + Duration::from_millis(Milliseconds as u64));
+
+        let key = {
+            let mut state = self.shared.state.lock().unwrap();
+
+            let key = state.next_key;
+            state.next_key += 1;
+            state.slots.insert(
+                key,
+                waiter_slot_t {
+                    event_id: EventId,
+                    deadline,
+                    status: None,
+                    waker: None,
+                },
+            );
+            key
+        };
+
+        /* The reaper may be blocked in a wait without this event */
+        self.shared.cond.notify_one();
+        self.shared.source.interrupt();
+
+        HsaEventFuture {
+            shared: self.shared.clone(),
+            key,
+        }
+    }
+
+    /* eventfd incremented whenever waits complete, -1 if it couldn't be
+     * created. Read it to reset it, then poll the futures.
+     */
+    pub fn completion_fd(&self) -> i32 {
+        self.shared.completion_fd
+    }
+
+    /* Tell the waiter EventId was destroyed, so an event created later
+     * with the same id starts out unsignaled
+     */
+    pub fn event_destroyed(&self, EventId: u32) {
+        self.shared.source.forget(EventId);
+    }
+}
+
+impl Drop for HsaEventWaiter {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.shutdown = true;
+            state.complete(HSAKMT_STATUS_ERROR, |_| true)
+        };
+
+        self.shared.cond.notify_all();
+        self.shared.source.interrupt();
+
+        if let Some(reaper) = self.reaper.take() {
+            let _ = reaper.join();
+        }
+
+        self.shared.wake(wakers);
+    }
+}
+
+pub struct HsaEventFuture {
+    shared: Arc<waiter_shared_t>,
+    key: u64,
+}
+
+impl Future for HsaEventFuture {
+    type Output = HsakmtStatus;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<HsakmtStatus> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        let Some(slot) = state.slots.get_mut(&self.key) else {
+            return Poll::Ready(HSAKMT_STATUS_ERROR);
+        };
+
+        if let Some(status) = slot.status {
+            state.slots.remove(&self.key);
+            return Poll::Ready(status);
+        }
+
+        slot.waker = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+impl Drop for HsaEventFuture {
+    fn drop(&mut self) {
+        let pending = {
+            let mut state = self.shared.state.lock().unwrap();
+            state
+                .slots
+                .remove(&self.key)
+                .is_some_and(|slot| slot.status.is_none())
+        };
+
+        /* Stop waiting on the event if nobody else does */
+        if pending {
+            self.shared.source.interrupt();
+        }
+    }
+}
+
+impl HsakmtGlobals {
+    /* A waiter for the signal events of this process, see
+     * HsaEventWaiter. Needs KFD 1.14 for the event ages.
+     */
+    pub unsafe fn hsakmt_create_event_waiter(
+        &self,
+        Waiter: &mut Option<HsaEventWaiter>,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if !self.check_kfd_minor_version(14) {
+            return HSAKMT_STATUS_NOT_SUPPORTED;
+        }
+
+        let Some(source) = KfdEventSource::new(self.hsakmt_kfd_fd) else {
+            return HSAKMT_STATUS_ERROR;
+        };
+
+        let Some(waiter) = HsaEventWaiter::new(Arc::new(source)) else {
+            return HSAKMT_STATUS_ERROR;
+        };
+
+        *Waiter = Some(waiter);
+
+        HSAKMT_STATUS_SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::kfd_create_event;
+    use crate::libhsakmt::fake_kfd::FakeKfd;
+    use crate::libhsakmt::hsakmt_set_ioctl_hook;
+    use std::task::Wake;
+
+    struct thread_waker_t(std::thread::Thread);
+
+    impl Wake for thread_waker_t {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(thread_waker_t(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            std::thread::park();
+        }
+    }
+
+    #[test]
+    fn test_event_waiter() {
+        let source = Arc::new(MockEventSource::new());
+        let waiter = HsaEventWaiter::new(source.clone()).unwrap();
+
+        let a = waiter.wait(3);
+        let b = waiter.wait(3);
+        let c = waiter.wait(4);
+
+        {
+            let source = source.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                source.signal(3);
+            });
+        }
+
+        assert_eq!(block_on(a), HSAKMT_STATUS_SUCCESS);
+        assert_eq!(block_on(b), HSAKMT_STATUS_SUCCESS);
+
+        let mut count = 0u64;
+        let n = unsafe {
+            libc::read(
+                waiter.completion_fd(),
+                &mut count as *mut u64 as *mut std::os::raw::c_void,
+                8,
+            )
+        };
+        assert_eq!(n, 8);
+        assert!(count >= 1);
+
+        assert_eq!(
+            block_on(waiter.wait_timeout(5, 10)),
+            HSAKMT_STATUS_WAIT_TIMEOUT
+        );
+
+        /* Cancelled waits are forgotten */
+        drop(waiter.wait(6));
+        assert_eq!(waiter.shared.state.lock().unwrap().slots.len(), 1);
+
+        /* Waits left when the waiter goes away fail */
+        drop(waiter);
+        assert_eq!(block_on(c), HSAKMT_STATUS_ERROR);
+    }
+
+    /* Events failing every wait including bad, like KFD does for an
+     * unknown event id
+     */
+    struct bad_event_source_t {
+        mock: MockEventSource,
+        bad: u32,
+    }
+
+    impl HsaEventSource for bad_event_source_t {
+        fn wait_any(
+            &self,
+            EventIds: &[u32],
+            Milliseconds: u32,
+            Signaled: &mut Vec<u32>,
+        ) -> HsakmtStatus {
+            if EventIds.contains(&self.bad) {
+                return HSAKMT_STATUS_ERROR;
+            }
+
+            self.mock.wait_any(EventIds, Milliseconds, Signaled)
+        }
+
+        fn interrupt(&self) {
+            self.mock.interrupt();
+        }
+
+        fn forget(&self, EventId: u32) {
+            self.mock.forget(EventId);
+        }
+    }
+
+    #[test]
+    fn test_event_waiter_bad_event() {
+        let source = Arc::new(bad_event_source_t {
+            mock: MockEventSource::new(),
+            bad: 7,
+        });
+        let waiter = HsaEventWaiter::new(source.clone()).unwrap();
+
+        let good = waiter.wait(3);
+        let bad = waiter.wait(7);
+
+        /* Only the wait on the bad event fails */
+        assert_eq!(block_on(bad), HSAKMT_STATUS_ERROR);
+
+        source.mock.signal(3);
+        assert_eq!(block_on(good), HSAKMT_STATUS_SUCCESS);
+    }
+
+    /* auto reset signal event of the fake KFD */
+    fn fake_kfd_event() -> u32 {
+        let mut args = kfd_ioctl_create_event_args {
+            event_type: KFD_IOC_EVENT_SIGNAL,
+            auto_reset: 1,
+            ..Default::default()
+        };
+
+        unsafe { assert_eq!(kfd_create_event(-1, &mut args), HSAKMT_STATUS_SUCCESS) };
+
+        args.event_id
+    }
+
+    #[test]
+    fn test_kfd_event_source() {
+        let kfd = FakeKfd::install(FakeKfd::default());
+
+        let source = unsafe { KfdEventSource::new(-1).unwrap() };
+        let ids = [fake_kfd_event(), fake_kfd_event()];
+        let mut signaled = vec![];
+
+        assert_eq!(
+            source.wait_any(&ids, 0, &mut signaled),
+            HSAKMT_STATUS_WAIT_TIMEOUT
+        );
+
+        kfd.borrow_mut().signal(ids[1]);
+        assert_eq!(
+            source.wait_any(&ids, 0, &mut signaled),
+            HSAKMT_STATUS_SUCCESS
+        );
+        assert_eq!(signaled, vec![ids[1]]);
+
+        /* An interrupt signals nothing and wakes a single wait */
+        for _ in 0..2 {
+            signaled.clear();
+            source.interrupt();
+            assert_eq!(
+                source.wait_any(&ids, 0, &mut signaled),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert!(signaled.is_empty());
+        }
+        assert_eq!(
+            source.wait_any(&ids, 0, &mut signaled),
+            HSAKMT_STATUS_WAIT_TIMEOUT
+        );
+
+        kfd.borrow_mut().signal(ids[0]);
+        assert_eq!(
+            source.wait_any(&ids, 0, &mut signaled),
+            HSAKMT_STATUS_SUCCESS
+        );
+        assert_eq!(signaled, vec![ids[0]]);
+
+        drop(source);
+        hsakmt_set_ioctl_hook(None);
+
+        /* The wake event went with the source */
+        assert_eq!(kfd.borrow().events.iter().flatten().count(), 2);
+    }
+
+    #[test]
+    fn test_kfd_event_source_same_event() {
+        let kfd = FakeKfd::install(FakeKfd::default());
+
+        let source = unsafe { KfdEventSource::new(-1).unwrap() };
+        let (a, b) = (fake_kfd_event(), fake_kfd_event());
+        let mut signaled = vec![];
+
+        kfd.borrow_mut().signal(a);
+        assert_eq!(
+            source.wait_any(&[a], 0, &mut signaled),
+            HSAKMT_STATUS_SUCCESS
+        );
+        assert_eq!(signaled, vec![a]);
+
+        /* Waits on other events don't lose the age of a */
+        signaled.clear();
+        kfd.borrow_mut().signal(b);
+        assert_eq!(
+            source.wait_any(&[b], 0, &mut signaled),
+            HSAKMT_STATUS_SUCCESS
+        );
+        assert_eq!(signaled, vec![b]);
+
+        /* A signal is seen by one wait only */
+        signaled.clear();
+        assert_eq!(
+            source.wait_any(&[a], 0, &mut signaled),
+            HSAKMT_STATUS_WAIT_TIMEOUT
+        );
+        assert!(signaled.is_empty());
+
+        kfd.borrow_mut().signal(a);
+        assert_eq!(
+            source.wait_any(&[a], 0, &mut signaled),
+            HSAKMT_STATUS_SUCCESS
+        );
+        assert_eq!(signaled, vec![a]);
+
+        /* A new event with the id of a destroyed one starts over */
+        signaled.clear();
+        source.forget(a);
+        kfd.borrow_mut().event(a).unwrap().age = KFD_EVENT_AGE_INITIAL;
+        assert_eq!(
+            source.wait_any(&[a], 0, &mut signaled),
+            HSAKMT_STATUS_WAIT_TIMEOUT
+        );
+
+        drop(source);
+        hsakmt_set_ioctl_hook(None);
+    }
+}
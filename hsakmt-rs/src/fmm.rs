@@ -1,42 +1,74 @@
-#![allow(non_camel_case_types, dead_code, non_snake_case)]
+#![allow(
+    non_camel_case_types,
+    dead_code,
+    non_snake_case,
+    non_upper_case_globals
+)]
 #![allow(unused_assignments)]
 
+use crate::fmm_accounting::fmm_heap_type;
+use crate::fmm_accounting::fmm_heap_type::{FMM_HEAP_IMPORTED, FMM_HEAP_SYSTEM, FMM_HEAP_VA_ONLY};
+use crate::fmm_config::FmmConfig;
+use crate::fmm_numa::fmm_numa_policy_t;
 use crate::fmm_types::svm_aperture_type::{SVM_COHERENT, SVM_DEFAULT};
+use crate::fmm_types::HSA_APERTURE::{
+    HSA_APERTURE_CPUVM, HSA_APERTURE_DGPU, HSA_APERTURE_DGPU_ALT, HSA_APERTURE_GPUVM,
+    HSA_APERTURE_MEMHANDLE, HSA_APERTURE_UNSUPPORTED,
+};
 use crate::fmm_types::{
-    gpu_mem_t, manageable_aperture_ops_t, manageable_aperture_t, vm_object_t, HsakmtGlobalsArgs,
+    fmm_memory_policy_t, gpu_mem_t, manageable_aperture_ops_t, manageable_aperture_t, vm_area_t,
+    vm_object_t, HsaApertureInfo, HsaSharedMemoryStruct, HsakmtGlobalsArgs, DRM_FIRST_RENDER_NODE,
+    DRM_LAST_RENDER_NODE,
 };
 use crate::globals::HsakmtGlobals;
-use crate::hsakmttypes::HsakmtStatus::{HSAKMT_STATUS_ERROR, HSAKMT_STATUS_SUCCESS};
+use crate::hsakmttypes::HsakmtStatus::{
+    HSAKMT_STATUS_ERROR, HSAKMT_STATUS_INVALID_HANDLE, HSAKMT_STATUS_INVALID_NODE_UNIT,
+    HSAKMT_STATUS_INVALID_PARAMETER, HSAKMT_STATUS_MEMORY_NOT_REGISTERED, HSAKMT_STATUS_NO_MEMORY,
+    HSAKMT_STATUS_SUCCESS,
+};
 use crate::hsakmttypes::{
-    HsaMemFlagSt, HsaMemFlagUnion, HsaMemFlags, HsakmtStatus, ALIGN_UP, GFX_VERSION_VEGA10,
-    GPU_HUGE_PAGE_SIZE, HSA_ENGINE_ID, HSA_GET_GFX_VERSION_FULL, MIN, PORT_VPTR_TO_UINT64,
+    HsaMemFlagSt, HsaMemFlagUnion, HsaMemFlags, HsaSharedMemoryHandle, HsakmtStatus, ALIGN_UP,
+    GFX_VERSION_VEGA10, GPU_HUGE_PAGE_SIZE, HSA_ENGINE_ID, HSA_GET_GFX_VERSION_FULL, MAX, MIN,
+    PORT_VPTR_TO_UINT64,
 };
 use crate::kfd_ioctl::{
-    kfd_ioctl_acquire_vm_args, kfd_ioctl_alloc_memory_of_gpu_args,
+    kfd_ioctl_acquire_vm_args, kfd_ioctl_alloc_memory_of_gpu_args, kfd_ioctl_export_dmabuf_args,
     kfd_ioctl_free_memory_of_gpu_args, kfd_ioctl_get_process_apertures_new_args,
-    kfd_ioctl_set_memory_policy_args, kfd_process_device_apertures, AMDKFD_IOC_FREE_MEMORY_OF_GPU,
-    KFD_IOC_ALLOC_MEM_FLAGS_COHERENT, KFD_IOC_ALLOC_MEM_FLAGS_EXT_COHERENT,
+    kfd_ioctl_import_dmabuf_args, kfd_ioctl_ipc_export_handle_args,
+    kfd_ioctl_ipc_import_handle_args, kfd_ioctl_map_memory_to_gpu_args,
+    kfd_ioctl_set_memory_policy_args, kfd_ioctl_set_scratch_backing_va_args, kfd_ioctl_svm_args,
+    kfd_ioctl_svm_attribute, kfd_ioctl_unmap_memory_from_gpu_args, kfd_process_device_apertures,
+    AMDKFD_IOC_ALLOC_MEMORY_OF_GPU, AMDKFD_IOC_EXPORT_DMABUF, AMDKFD_IOC_FREE_MEMORY_OF_GPU,
+    AMDKFD_IOC_IMPORT_DMABUF, AMDKFD_IOC_IPC_EXPORT_HANDLE, AMDKFD_IOC_IPC_IMPORT_HANDLE,
+    AMDKFD_IOC_MAP_MEMORY_TO_GPU, AMDKFD_IOC_SET_MEMORY_POLICY, AMDKFD_IOC_SET_SCRATCH_BACKING_VA,
+    AMDKFD_IOC_SVM, AMDKFD_IOC_UNMAP_MEMORY_FROM_GPU, KFD_IOCTL_SVM_ATTR_ACCESS_IN_PLACE,
+    KFD_IOCTL_SVM_OP_SET_ATTR, KFD_IOC_ALLOC_MEM_FLAGS_AQL_QUEUE_MEM,
+    KFD_IOC_ALLOC_MEM_FLAGS_COHERENT, KFD_IOC_ALLOC_MEM_FLAGS_CONTIGUOUS_BEST_EFFORT,
+    KFD_IOC_ALLOC_MEM_FLAGS_DOORBELL, KFD_IOC_ALLOC_MEM_FLAGS_EXECUTABLE,
+    KFD_IOC_ALLOC_MEM_FLAGS_EXT_COHERENT, KFD_IOC_ALLOC_MEM_FLAGS_GTT,
     KFD_IOC_ALLOC_MEM_FLAGS_MMIO_REMAP, KFD_IOC_ALLOC_MEM_FLAGS_NO_SUBSTITUTE,
-    KFD_IOC_ALLOC_MEM_FLAGS_PUBLIC, KFD_IOC_ALLOC_MEM_FLAGS_USERPTR, KFD_IOC_ALLOC_MEM_FLAGS_VRAM,
+    KFD_IOC_ALLOC_MEM_FLAGS_PUBLIC, KFD_IOC_ALLOC_MEM_FLAGS_UNCACHED,
+    KFD_IOC_ALLOC_MEM_FLAGS_USERPTR, KFD_IOC_ALLOC_MEM_FLAGS_VRAM,
     KFD_IOC_ALLOC_MEM_FLAGS_WRITABLE, KFD_IOC_CACHE_POLICY_COHERENT,
     KFD_IOC_CACHE_POLICY_NONCOHERENT,
 };
 use crate::libhsakmt::hsakmt_ioctl;
-use crate::rbtree::{
-    hsakmt_rbtree_delete, hsakmt_rbtree_insert, hsakmt_rbtree_prev, print_tree, rbtree_init,
-    rbtree_node_t, rbtree_t,
-};
-use crate::rbtree_amd::{rbtree_key, rbtree_lookup_nearest, rbtree_min_max, LEFT, LKP_ALL, RIGHT};
+use amdgpu_drm_sys::bindings::amdgpu_device_handle;
 use libc::{
-    getenv, isalnum, mmap, munmap, off_t, strcmp, strerror, EINVAL, MAP_ANONYMOUS, MAP_FAILED,
-    MAP_FIXED, MAP_FIXED_NOREPLACE, MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED, MPOL_DEFAULT,
-    PROT_NONE, PROT_READ, PROT_WRITE,
+    close, isalnum, madvise, mmap, munmap, off_t, strerror, EINVAL, ENOMEM, MADV_DONTFORK,
+    MAP_ANONYMOUS, MAP_FAILED, MAP_FIXED, MAP_FIXED_NOREPLACE, MAP_NORESERVE, MAP_PRIVATE,
+    MAP_SHARED, MPOL_DEFAULT, O_CLOEXEC, O_RDWR, PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE,
 };
 use numa_sys::numaif_bindings::mbind;
-use std::ffi::CString;
 
 pub const NON_VALID_GPU_ID: usize = 0;
 
+/* SH_HIDDEN_PRIVATE_BASE takes the scratch backing VA in 64KB units */
+pub const SCRATCH_ALIGN: u64 = 0x10000;
+
+/* Tonga and Carrizo need 32KB aligned VAs for their fragment size */
+pub const TONGA_PAGE_SIZE: u64 = 0x8000;
+
 // #define START_NON_CANONICAL_ADDR (1ULL << 47)
 // #define END_NON_CANONICAL_ADDR (~0UL - (1UL << 47))
 pub const START_NON_CANONICAL_ADDR: u64 = 1 << 47;
@@ -95,7 +127,7 @@ pub unsafe fn VOID_PTRS_SUB(
 }
 
 pub unsafe fn aperture_allocate_area(
-    app: &manageable_aperture_t,
+    app: &mut manageable_aperture_t,
     address: *mut std::os::raw::c_void,
     MemorySizeInBytes: u64,
     hsakmt_globals: HsakmtGlobalsArgs,
@@ -108,15 +140,16 @@ pub unsafe fn aperture_allocate_area(
 }
 
 pub unsafe fn aperture_release_area(
-    app: &manageable_aperture_t,
+    app: &mut manageable_aperture_t,
     address: *mut std::os::raw::c_void,
     MemorySizeInBytes: u64,
+    hsakmt_globals: HsakmtGlobalsArgs,
 ) {
     let some_f = app
         .ops
         .release_area
         .expect("aperture_release_area not found");
-    some_f(app, address, MemorySizeInBytes);
+    some_f(app, address, MemorySizeInBytes, hsakmt_globals);
 }
 
 pub unsafe fn hsakmt_mmap_allocate_aligned(
@@ -199,8 +232,21 @@ pub unsafe fn hsakmt_mmap_allocate_aligned(
     addr
 }
 
+/* Grow align to the next power-of-2 the size covers, up to max_align, so
+ * that big buffers can use big fragments: 4KB buffers stay page aligned,
+ * 64KB buffers get 64KB alignment and 2MB buffers land on huge pages.
+ * Alignment that is already at or above max_align is left alone.
+ */
+pub fn fmm_va_alignment(size: u64, mut align: u64, max_align: u64) -> u64 {
+    while align > 0 && align < max_align && size >= (align << 1) {
+        align <<= 1;
+    }
+
+    align
+}
+
 pub unsafe fn mmap_aperture_allocate_aligned(
-    aper: &manageable_aperture_t,
+    aper: &mut manageable_aperture_t,
     address: *mut std::os::raw::c_void,
     size: u64,
     mut align: u64,
@@ -245,9 +291,7 @@ pub unsafe fn mmap_aperture_allocate_aligned(
      * improves the time for memory allocation and mapping. But it might lose
      * performance when GFX access it, specially for big allocations (>3GB).
      */
-    while align < alignment_size && size >= (align << 1) {
-        align <<= 1;
-    }
+    align = fmm_va_alignment(size, MAX(align, page_size), alignment_size);
 
     /* Add padding to guarantee proper alignment and leave guard
      * pages on both sides
@@ -267,9 +311,10 @@ pub unsafe fn mmap_aperture_allocate_aligned(
 }
 
 pub unsafe fn mmap_aperture_release(
-    aper: &manageable_aperture_t,
+    aper: &mut manageable_aperture_t,
     addr: *mut std::os::raw::c_void,
     size: u64,
+    _hsakmt_globals: HsakmtGlobalsArgs,
 ) {
     if !aper.is_cpu_accessible {
         println!("MMap Aperture must be CPU accessible");
@@ -283,6 +328,273 @@ pub unsafe fn mmap_aperture_release(
     munmap(addr, size as usize);
 }
 
+pub unsafe fn vm_create_and_init_area(
+    start: *mut std::os::raw::c_void,
+    end: *mut std::os::raw::c_void,
+) -> *mut vm_area_t {
+    Box::into_raw(Box::new(vm_area_t {
+        start,
+        end,
+        next: std::ptr::null_mut(),
+        prev: std::ptr::null_mut(),
+    }))
+}
+
+pub unsafe fn vm_remove_area(app: &mut manageable_aperture_t, area: *mut vm_area_t) {
+    let next = (*area).next;
+    let prev = (*area).prev;
+
+    if prev.is_null() {
+        /* The first element */
+        app.vm_ranges = next;
+    } else {
+        (*prev).next = next;
+    }
+
+    if !next.is_null() {
+        /* If not the last element */
+        (*next).prev = prev;
+    }
+
+    drop(Box::from_raw(area));
+}
+
+pub unsafe fn vm_split_area(
+    _app: &mut manageable_aperture_t,
+    area: *mut vm_area_t,
+    address: *mut std::os::raw::c_void,
+    MemorySizeInBytes: u64,
+) {
+    /*
+     * The existing area is split to: [area->start, address - 1]
+     * and [address + MemorySizeInBytes, area->end]
+     */
+    let new_area = vm_create_and_init_area(VOID_PTR_ADD(address, MemorySizeInBytes), (*area).end);
+
+    /* Shrink the existing area */
+    (*area).end = VOID_PTR_SUB(address, 1);
+
+    (*new_area).next = (*area).next;
+    (*new_area).prev = area;
+
+    if !(*area).next.is_null() {
+        (*(*area).next).prev = new_area;
+    }
+
+    (*area).next = new_area;
+}
+
+pub unsafe fn vm_find(
+    app: &manageable_aperture_t,
+    address: *mut std::os::raw::c_void,
+) -> *mut vm_area_t {
+    let mut cur = app.vm_ranges;
+
+    /* Look up the appropriate address range containing the given address */
+    while !cur.is_null() {
+        if (*cur).start <= address && (*cur).end >= address {
+            break;
+        }
+        cur = (*cur).next;
+    }
+
+    cur
+}
+
+pub fn vm_align_area_size(app: &manageable_aperture_t, size: u64, page_size: u64) -> u64 {
+    size + app.guard_pages as u64 * page_size
+}
+
+/*
+ * Assumes that fmm_mutex is locked on entry.
+ */
+pub unsafe fn reserved_aperture_allocate_aligned(
+    app: &mut manageable_aperture_t,
+    address: *mut std::os::raw::c_void,
+    mut MemorySizeInBytes: u64,
+    mut align: u64,
+    hsakmt_globals: HsakmtGlobalsArgs,
+) -> *mut std::os::raw::c_void {
+    let page_size = hsakmt_globals.page_size as u64;
+    let orig_align = align;
+    let mut offset: u64 = 0;
+
+    if align < app.align {
+        align = app.align;
+    }
+
+    if align < page_size {
+        align = page_size;
+    }
+
+    /* Align big buffers to the next power-of-2 up to huge page
+     * size for flexible fragment size TLB optimizations
+     */
+    align = fmm_va_alignment(MemorySizeInBytes, align, GPU_HUGE_PAGE_SIZE as u64);
+
+    /* If no specific alignment was requested, align the end of
+     * buffers instead of the start. For fragment optimizations,
+     * aligning the start or the end achieves the same effect.
+     * Aligning the end produces a smaller gap between allocations
+     */
+    if orig_align == 0 {
+        offset = (align - (MemorySizeInBytes & (align - 1))) & (align - 1);
+    }
+
+    MemorySizeInBytes = vm_align_area_size(app, MemorySizeInBytes, page_size);
+
+    /* Find a big enough "hole" in the address space */
+    let mut cur: *mut vm_area_t = std::ptr::null_mut();
+    let mut next = app.vm_ranges;
+    let mut start = if !address.is_null() {
+        address
+    } else {
+        (ALIGN_UP(app.base as u64, align) + offset) as *mut std::os::raw::c_void
+    };
+
+    while !next.is_null() {
+        if (*next).start > start && VOID_PTRS_SUB((*next).start, start) >= MemorySizeInBytes {
+            break;
+        }
+
+        cur = next;
+        next = (*next).next;
+
+        if address.is_null() {
+            start = (ALIGN_UP((*cur).end as u64 + 1, align) + offset) as *mut std::os::raw::c_void;
+        }
+    }
+
+    if start < app.base || start > app.limit {
+        return std::ptr::null_mut();
+    }
+
+    if next.is_null() && VOID_PTRS_SUB(app.limit, start) + 1 < MemorySizeInBytes {
+        /* No hole found and not enough space after the last area */
+        return std::ptr::null_mut();
+    }
+
+    if !cur.is_null() && !address.is_null() && (address as u64) <= (*cur).end as u64 {
+        /* Required address is not free or overlaps */
+        return std::ptr::null_mut();
+    }
+
+    if !cur.is_null() && VOID_PTR_ADD((*cur).end, 1) == start {
+        /* extend existing area */
+        (*cur).end = VOID_PTR_ADD(start, MemorySizeInBytes - 1);
+    } else {
+        /* create a new area between cur and next */
+        let new_area = vm_create_and_init_area(start, VOID_PTR_ADD(start, MemorySizeInBytes - 1));
+
+        (*new_area).next = next;
+        (*new_area).prev = cur;
+
+        if !cur.is_null() {
+            (*cur).next = new_area;
+        } else {
+            app.vm_ranges = new_area;
+        }
+
+        if !next.is_null() {
+            (*next).prev = new_area;
+        }
+    }
+
+    /* Merge adjacent areas if possible */
+    if !next.is_null() && VOID_PTR_ADD(start, MemorySizeInBytes) == (*next).start {
+        let area = (*next).prev;
+
+        (*area).end = (*next).end;
+        vm_remove_area(app, next);
+    }
+
+    start
+}
+
+pub unsafe fn reserved_aperture_release(
+    app: &mut manageable_aperture_t,
+    address: *mut std::os::raw::c_void,
+    mut MemorySizeInBytes: u64,
+    hsakmt_globals: HsakmtGlobalsArgs,
+) {
+    let page_size = hsakmt_globals.page_size as u64;
+
+    MemorySizeInBytes = vm_align_area_size(app, MemorySizeInBytes, page_size);
+
+    let area = vm_find(app, address);
+    if area.is_null() {
+        return;
+    }
+
+    let SizeOfRegion = VOID_PTRS_SUB((*area).end, (*area).start) + 1;
+
+    /* check if block is whole region or part of it */
+    if SizeOfRegion == MemorySizeInBytes {
+        vm_remove_area(app, area);
+    } else if SizeOfRegion > MemorySizeInBytes {
+        if (*area).start == address {
+            /* shrink from the start */
+            (*area).start = VOID_PTR_ADD((*area).start, MemorySizeInBytes);
+        } else if VOID_PTRS_SUB((*area).end, address) + 1 == MemorySizeInBytes {
+            /* shrink from the end */
+            (*area).end = VOID_PTR_SUB((*area).end, MemorySizeInBytes);
+        } else {
+            /* split the area */
+            vm_split_area(app, area, address, MemorySizeInBytes);
+        }
+    }
+
+    if app.is_cpu_accessible {
+        /* Reset NUMA policy */
+        mbind(
+            address,
+            MemorySizeInBytes,
+            MPOL_DEFAULT,
+            std::ptr::null_mut(),
+            0,
+            0,
+        );
+
+        /* Remove any CPU mapping, but keep the address range reserved */
+        let mmap_ret = mmap(
+            address,
+            MemorySizeInBytes as usize,
+            PROT_NONE,
+            MAP_ANONYMOUS | MAP_NORESERVE | MAP_PRIVATE | MAP_FIXED,
+            -1,
+            0,
+        );
+
+        let errno = std::io::Error::last_os_error().raw_os_error().unwrap();
+
+        if mmap_ret == MAP_FAILED && errno == ENOMEM {
+            /* When mmap count reaches max_map_count, any mmap will
+             * fail. Reduce the count with munmap then map it as
+             * NORESERVE immediately.
+             */
+            munmap(address, MemorySizeInBytes as usize);
+            mmap(
+                address,
+                MemorySizeInBytes as usize,
+                PROT_NONE,
+                MAP_ANONYMOUS | MAP_NORESERVE | MAP_PRIVATE | MAP_FIXED,
+                -1,
+                0,
+            );
+        }
+    }
+}
+
+pub const reserved_aperture_ops: manageable_aperture_ops_t = manageable_aperture_ops_t {
+    allocate_area_aligned: Some(reserved_aperture_allocate_aligned),
+    release_area: Some(reserved_aperture_release),
+};
+
+pub const mmap_aperture_ops: manageable_aperture_ops_t = manageable_aperture_ops_t {
+    allocate_area_aligned: Some(mmap_aperture_allocate_aligned),
+    release_area: Some(mmap_aperture_release),
+};
+
 pub fn aperture_is_valid(
     app_base: *mut std::os::raw::c_void,
     app_limit: *mut std::os::raw::c_void,
@@ -295,7 +607,7 @@ pub fn aperture_is_valid(
 
 /* Wrapper functions to call aperture-specific VA management functions */
 pub unsafe fn aperture_allocate_area_aligned(
-    app: &manageable_aperture_t,
+    app: &mut manageable_aperture_t,
     address: *mut std::os::raw::c_void,
     MemorySizeInBytes: u64,
     align: u64,
@@ -337,6 +649,27 @@ pub unsafe fn fmm_translate_ioc_to_hsa_flags(ioc_flags: u32) -> HsaMemFlags {
     mflags
 }
 
+pub unsafe fn fmm_translate_hsa_to_ioc_flags(flags: &HsaMemFlags) -> u32 {
+    let mut ioc_flags: u32 = 0;
+
+    if flags.st.ui32.AQLQueueMemory > 0 {
+        ioc_flags |=
+            (KFD_IOC_ALLOC_MEM_FLAGS_AQL_QUEUE_MEM | KFD_IOC_ALLOC_MEM_FLAGS_UNCACHED) as u32;
+    }
+
+    if flags.st.ui32.ReadOnly == 0 {
+        ioc_flags |= KFD_IOC_ALLOC_MEM_FLAGS_WRITABLE as u32;
+    }
+
+    /* TODO: Since, ROCr interfaces doesn't allow caller to set page
+     * permissions, mark all user allocations with exec permission.
+     * Check for flags.ui32.ExecuteAccess once ROCr is ready.
+     */
+    ioc_flags |= KFD_IOC_ALLOC_MEM_FLAGS_EXECUTABLE as u32;
+
+    ioc_flags
+}
+
 pub fn vm_create_and_init_object(
     start: *mut std::os::raw::c_void,
     size: u64,
@@ -351,20 +684,12 @@ pub fn vm_create_and_init_object(
     object.userptr_size = 0;
     object.size = size;
     object.handle = handle;
-    object.registered_device_id_array_size = 0;
-    object.mapped_device_id_array_size = 0;
-    object.registered_device_id_array = std::ptr::null_mut();
-    object.mapped_device_id_array = std::ptr::null_mut();
-    object.registered_node_id_array = std::ptr::null_mut();
-    object.mapped_node_id_array = std::ptr::null_mut();
     object.registration_count = 0;
     object.mapping_count = 0;
     object.mflags = mflags;
     object.metadata = std::ptr::null_mut();
     object.user_data = std::ptr::null_mut();
     object.is_imported_kfd_bo = false;
-    object.node.key = rbtree_key(start as u64, size as i64);
-    object.user_node.key = rbtree_key(0, 0);
     // }
 
     object
@@ -377,21 +702,18 @@ pub unsafe fn aperture_allocate_object(
     MemorySizeInBytes: u64,
     mflags: HsaMemFlags,
 ) -> *mut vm_object_t {
-    // let new_object: *mut vm_object_t = std::ptr::null_mut();
-
     /* Allocate new object */
-    let mut new_object = vm_create_and_init_object(new_address, MemorySizeInBytes, handle, mflags);
-
-    // if (!new_object) {
-    //     println!("vm_create_and_init_object null");
-    //     return std::ptr::null_mut();
-    // }
-
-    println!("new_object.node {:#?}", new_object.node);
+    let new_object = Box::into_raw(Box::new(vm_create_and_init_object(
+        new_address,
+        MemorySizeInBytes,
+        handle,
+        mflags,
+    )));
 
-    hsakmt_rbtree_insert(&mut app.tree, &mut new_object.node);
+    app.tree
+        .insert(new_address as u64, MemorySizeInBytes, new_object);
 
-    &mut new_object as *mut vm_object_t
+    new_object
 }
 
 pub fn two_apertures_overlap(
@@ -408,7 +730,7 @@ pub unsafe fn reserve_address(
     addr: *mut std::os::raw::c_void,
     len: u64,
 ) -> *mut std::os::raw::c_void {
-    if len <= 0 {
+    if len == 0 {
         return std::ptr::null_mut();
     }
 
@@ -428,146 +750,19 @@ pub unsafe fn reserve_address(
     ret_addr
 }
 
-// #define vm_object_tree(app, is_userptr)				\
-// ((is_userptr) ? &(app)->user_tree : &(app)->tree)
-
-pub fn vm_object_tree<'a>(
-    app: &'a mut manageable_aperture_t<'a>,
-    is_userptr: i32,
-) -> &'a mut rbtree_t {
-    if is_userptr > 0 {
-        &mut app.user_tree
-    } else {
-        &mut app.tree
-    }
-}
-
-// #define container_of(ptr, type, member) ({			\
-// char *__mptr = (void *)(ptr);			\
-// ((type *)(__mptr - offsetof(type, member))); })
-//
-// #define rb_entry(ptr, type, member)				\
-// container_of(ptr, type, member)
-
-// #define vm_object_entry(n, is_userptr) ({			\
-// (is_userptr) == 0 ?				\
-// rb_entry(n, vm_object_t, node) :		\
-// rb_entry(n, vm_object_t, user_node); })
-
-pub unsafe fn vm_object_entry(n: *mut rbtree_node_t, is_userptr: i32) -> *mut vm_object_t {
-    let ptr = n as *mut std::os::raw::c_void as *mut u8;
-
-    if is_userptr == 0 {
-        // rb_entry(n, node)
-
-        let offset_node: usize = {
-            let c = std::mem::MaybeUninit::uninit();
-            let c_ptr: *const vm_object_t = c.as_ptr();
-
-            // cast to u8 pointers so we get offset in bytes
-            let c_u8_ptr = c_ptr as *const u8;
-            let f_u8_ptr = std::ptr::addr_of!((*c_ptr).node) as *const u8;
-
-            f_u8_ptr.offset_from(c_u8_ptr) as usize
-        };
-
-        let v = ptr.sub(offset_node);
-        v as *mut vm_object_t
-    } else {
-        // rb_entry(n, user_node)
-
-        let offset_user_node: usize = {
-            let c = std::mem::MaybeUninit::uninit();
-            let c_ptr: *const vm_object_t = c.as_ptr();
-
-            // cast to u8 pointers so we get offset in bytes
-            let c_u8_ptr = c_ptr as *const u8;
-            let f_u8_ptr = std::ptr::addr_of!((*c_ptr).user_node) as *const u8;
-
-            f_u8_ptr.offset_from(c_u8_ptr) as usize
-        };
-
-        let v = ptr.sub(offset_user_node);
-        v as *mut vm_object_t
-    }
-
-    // std::ptr::null_mut()
-}
-
 pub unsafe fn vm_find_object_by_address_userptr_range(
     app: &mut manageable_aperture_t,
     address: *mut std::os::raw::c_void,
     is_userptr: i32,
 ) -> *mut vm_object_t {
-    let mut cur: *mut vm_object_t = std::ptr::null_mut();
-
-    // let tree = vm_object_tree(app, is_userptr);
-    let tree = if is_userptr > 0 {
-        &mut app.user_tree
-    } else {
-        &mut app.tree
-    };
-
-    let mut key = rbtree_key(address as u64, 0);
-
-    let mut rn = rbtree_lookup_nearest(tree, &key, LKP_ALL() as u32, RIGHT as i32);
-
-    let mut ln: *mut rbtree_node_t = std::ptr::null_mut();
-    let mut start: *mut std::os::raw::c_void;
-    let mut size: u64;
-
-    /* all nodes might sit on left side of *address*, in this case rn is NULL.
-     * So pick up the rightest one as rn.
-     */
-    if rn.is_null() {
-        rn = rbtree_min_max(tree, RIGHT as i32);
-    }
-
     if is_userptr > 0 {
         /* userptr might overlap. Need walk through the tree from right to left as only left nodes
          * can obtain the *address*
          */
-        ln = rbtree_min_max(tree, LEFT as i32);
+        app.user_tree.find_containing(address as u64, true)
     } else {
-        /* if key->size is -1, it match the node with start <= address.
-         * if key->size is 0, it match the node with start < address.
-         */
-        key = rbtree_key(address as u64, -1);
-        ln = rbtree_lookup_nearest(tree, &key, LKP_ALL() as u32, LEFT as i32);
-    }
-    if ln.is_null() {
-        return std::ptr::null_mut();
-    }
-
-    while !rn.is_null() {
-        cur = vm_object_entry(rn, is_userptr);
-
-        let cur_st = &mut (*cur);
-
-        if is_userptr == 0 {
-            start = cur_st.start;
-            size = cur_st.size;
-        } else {
-            start = cur_st.userptr;
-            size = cur_st.userptr_size;
-        }
-
-        let b = (address as u64) < ((start as u64) + size);
-
-        if address >= start && b {
-            break;
-        }
-
-        cur = std::ptr::null_mut();
-
-        if ln == rn {
-            break;
-        }
-
-        rn = hsakmt_rbtree_prev(tree, rn);
+        app.tree.find_containing(address as u64, false)
     }
-
-    cur /* NULL if not found */
 }
 
 pub unsafe fn vm_find_object_by_userptr_range(
@@ -590,65 +785,16 @@ pub unsafe fn vm_find_object_by_address_userptr(
     size: u64,
     is_userptr: i32,
 ) -> *mut vm_object_t {
-    let mut cur: *mut vm_object_t = std::ptr::null_mut();
-
     let aperture = &mut (*app);
 
-    // let tree = vm_object_tree(app, is_userptr);
     let tree = if is_userptr > 0 {
-        &mut aperture.user_tree
+        &aperture.user_tree
     } else {
-        &mut aperture.tree
+        &aperture.tree
     };
 
-    let key = rbtree_key(address as u64, size as i64);
-
-    let mut start: *mut std::os::raw::c_void;
-    let mut s: u64 = 0;
-
-    // println!("vm_find_object_by_address_userptr address is null {}", address.is_null());
-
-    /* rbtree_lookup_nearest(,,,RIGHT) will return a node with
-     * its size >= key.size and its address >= key.address
-     * if there are two nodes with format(address, size),
-     * (0x100, 16) and (0x110, 8). the key is (0x100, 0),
-     * then node (0x100, 16) will be returned.
-     */
-
-    print_tree(tree);
-
-    // let n = rbtree_lookup_nearest(tree, &key, LKP_ALL() as u32, RIGHT as i32);
-
-    // if !n.is_null() {
-    //     cur = vm_object_entry(n, is_userptr);
-    //     let cur_st = &mut (*cur);
-    //
-    //     if is_userptr == 0 {
-    //         start = cur_st.start;
-    //         s = cur_st.size;
-    //     } else {
-    //         start = cur_st.userptr;
-    //         s = cur_st.userptr_size;
-    //     }
-    //
-    //     if start != address {
-    //         return std::ptr::null_mut();
-    //     }
-    //
-    //     if size > 0 {
-    //         return if size == s { cur } else { std::ptr::null_mut() };
-    //     }
-    //
-    //     /* size is 0, make sure there is only one node whose address == key.address*/
-    //     let key = rbtree_key(address as u64, -1);
-    //     let rn = rbtree_lookup_nearest(tree, &key, LKP_ALL() as u32, LEFT as i32);
-    //
-    //     if rn != n {
-    //         return std::ptr::null_mut();
-    //     }
-    // }
-
-    cur /* NULL if not found */
+    /* size 0 matches the only object starting at address */
+    tree.find_exact(address as u64, size)
 }
 
 pub unsafe fn vm_find_object_by_userptr(
@@ -667,24 +813,70 @@ pub unsafe fn vm_find_object_by_address(
     vm_find_object_by_address_userptr(app, address, size, 0)
 }
 
+pub unsafe fn fmm_map_to_cpu(
+    mem: *mut std::os::raw::c_void,
+    size: u64,
+    host_access: bool,
+    fd: i32,
+    mmap_offset: u64,
+) -> *mut std::os::raw::c_void {
+    let flag = MAP_SHARED | MAP_FIXED;
+    let prot = if host_access {
+        PROT_READ | PROT_WRITE
+    } else {
+        PROT_NONE
+    };
+
+    let ret = mmap(mem, size as usize, prot, flag, fd, mmap_offset as off_t);
+
+    if ret != MAP_FAILED {
+        /* This madvise() call is needed to avoid additional references
+         * to mapped BOs in child processes that can prevent freeing
+         * memory in the parent process and lead to out-of-memory
+         * conditions.
+         */
+        madvise(mem, size as usize, MADV_DONTFORK);
+    }
+
+    ret
+}
+
+fn add_device_ids_to_mapped_array(object: &mut vm_object_t, device_ids_array: &[u32]) {
+    object
+        .mapped_device_id_array
+        .extend_from_slice(device_ids_array);
+}
+
+fn remove_device_ids_from_mapped_array(object: &mut vm_object_t, device_ids_array: &[u32]) {
+    object
+        .mapped_device_id_array
+        .retain(|id| !device_ids_array.contains(id));
+}
+
+/* Prefetch memory on APUs with dummy-reads, one read per page */
+pub unsafe fn fmm_check_user_memory(addr: *const std::os::raw::c_void, size: u64, page_size: u64) {
+    let mut ptr = addr as u64;
+    let end = ptr + size;
+
+    while ptr < end {
+        std::ptr::read_volatile(ptr as *const u8);
+        ptr = ALIGN_UP(ptr + 1, page_size);
+    }
+}
+
 impl HsakmtGlobals {
-    // TODO complete fn get_vm_alignment
     pub fn get_vm_alignment(&self, device_id: u32) -> u32 {
-        let page_size = self.PAGE_SIZE();
+        let mut page_size = 0;
 
-        if device_id >= 0x6920 && device_id <= 0x6939 {
+        if (0x6920..=0x6939).contains(&device_id) {
             /* Tonga */
-            // page_size = TONGA_PAGE_SIZE;
-        } else if device_id >= 0x9870 && device_id <= 0x9877 {
+            page_size = TONGA_PAGE_SIZE;
+        } else if (0x9870..=0x9877).contains(&device_id) {
             /* Carrizo */
-            // page_size = TONGA_PAGE_SIZE;
-        } else {
-            // println!("device_id no apply get_vm_alignment {}", device_id);
+            page_size = TONGA_PAGE_SIZE;
         }
 
-        // MAX(PAGE_SIZE, page_size);
-        // MAX tmp1 > tmp2 ? tmp1 : tmp2
-        page_size as u32
+        MAX(self.PAGE_SIZE() as u64, page_size) as u32
     }
 
     pub unsafe fn get_process_apertures(
@@ -737,8 +929,18 @@ impl HsakmtGlobals {
         -1
     }
 
+    pub fn gpu_mem_find_by_node_id(&self, node_id: u32) -> i32 {
+        for (i, gpu_m) in self.fmm.gpu_mem.iter().enumerate() {
+            if gpu_m.gpu_id != NON_VALID_GPU_ID as u32 && gpu_m.node_id == node_id {
+                return i as i32;
+            }
+        }
+
+        -1
+    }
+
     pub unsafe fn fmm_set_memory_policy(
-        &self,
+        &mut self,
         gpu_id: u32,
         default_policy: i32,
         alt_policy: i32,
@@ -756,18 +958,38 @@ impl HsakmtGlobals {
 
         let hsakmt_kfd_fd = self.hsakmt_kfd_fd;
 
-        let p_1 = ('K' as u64) << (0 + 8);
-        let p_2 =
-            ((std::mem::size_of::<kfd_ioctl_set_memory_policy_args>()) << ((0 + 8) + 8)) as u64;
-
-        let AMDKFD_IOC_SET_MEMORY_POLICY =
-            ((1) << (((0 + 8) + 8) + 14)) | p_1 | ((0x04) << 0) | p_2;
-
-        hsakmt_ioctl(
+        let r = hsakmt_ioctl(
             hsakmt_kfd_fd,
             AMDKFD_IOC_SET_MEMORY_POLICY,
             &mut args as *mut _ as *mut std::os::raw::c_void,
-        )
+        );
+
+        if r != 0 {
+            return r;
+        }
+
+        /* Keep the policy in effect for debugging coherence issues */
+        let gpu_mem_id = self.gpu_mem_find_by_gpu_id(gpu_id);
+        if gpu_mem_id >= 0 {
+            self.fmm.gpu_mem[gpu_mem_id as usize].memory_policy = Some(fmm_memory_policy_t {
+                default_policy: default_policy as u32,
+                alternate_policy: alt_policy as u32,
+                alternate_aperture_base: alt_base as u64,
+                alternate_aperture_size: alt_size,
+            });
+        }
+
+        r
+    }
+
+    /* Memory policy in effect on node_id, None if none was set */
+    pub fn hsakmt_fmm_get_memory_policy(&self, node_id: u32) -> Option<fmm_memory_policy_t> {
+        let gpu_mem_id = self.gpu_mem_find_by_node_id(node_id);
+        if gpu_mem_id < 0 {
+            return None;
+        }
+
+        self.fmm.gpu_mem[gpu_mem_id as usize].memory_policy
     }
 
     pub fn fmm_init_rbtree(&mut self) {
@@ -780,31 +1002,24 @@ impl HsakmtGlobals {
         // int i = gpu_mem_count;
         // let mut i = hsakmt_fmm_global_gpu_mem_count_get();
         let svm_default = SVM_DEFAULT as usize;
+        let svm_coherent = SVM_COHERENT as usize;
 
-        // if (once++ == 0) {
-        rbtree_init(&mut svm.apertures[svm_default].tree);
-        rbtree_init(&mut svm.apertures[svm_default].user_tree);
-        rbtree_init(&mut svm.apertures[svm_default].tree);
-        rbtree_init(&mut svm.apertures[svm_default].user_tree);
-        rbtree_init(&mut cpuvm_aperture.tree);
-        rbtree_init(&mut cpuvm_aperture.user_tree);
-        rbtree_init(&mut mem_handle_aperture.tree);
-        rbtree_init(&mut mem_handle_aperture.user_tree);
-        // }
+        svm.apertures[svm_default].tree.clear();
+        svm.apertures[svm_default].user_tree.clear();
+        svm.apertures[svm_coherent].tree.clear();
+        svm.apertures[svm_coherent].user_tree.clear();
+        cpuvm_aperture.tree.clear();
+        cpuvm_aperture.user_tree.clear();
+        mem_handle_aperture.tree.clear();
+        mem_handle_aperture.user_tree.clear();
 
-        // while i != 0 {
-        // 	rbtree_init(&gpu_mem[i].scratch_physical.tree);
-        // 	rbtree_init(&gpu_mem[i].scratch_physical.user_tree);
-        // 	rbtree_init(&gpu_mem[i].gpuvm_aperture.tree);
-        // 	rbtree_init(&gpu_mem[i].gpuvm_aperture.user_tree);
-        //     i -= 1;
-        // }
+        self.fmm.accounting.get_mut().clear();
 
         for g_m in gpu_mem {
-            rbtree_init(&mut g_m.scratch_physical.tree);
-            rbtree_init(&mut g_m.scratch_physical.user_tree);
-            rbtree_init(&mut g_m.gpuvm_aperture.tree);
-            rbtree_init(&mut g_m.gpuvm_aperture.user_tree);
+            g_m.scratch_physical.tree.clear();
+            g_m.scratch_physical.user_tree.clear();
+            g_m.gpuvm_aperture.tree.clear();
+            g_m.gpuvm_aperture.user_tree.clear();
         }
     }
 
@@ -865,10 +1080,7 @@ impl HsakmtGlobals {
         self.fmm.svm.apertures[svm_default].align = align as u64;
         self.fmm.svm.apertures[svm_default].guard_pages = guard_pages;
         self.fmm.svm.apertures[svm_default].is_cpu_accessible = true;
-        self.fmm.svm.apertures[svm_default].ops = manageable_aperture_ops_t {
-            allocate_area_aligned: Some(mmap_aperture_allocate_aligned),
-            release_area: Some(mmap_aperture_release),
-        };
+        self.fmm.svm.apertures[svm_default].ops = mmap_aperture_ops;
 
         let svm_coherent = SVM_COHERENT as usize;
 
@@ -890,7 +1102,7 @@ impl HsakmtGlobals {
         // println!("aperture_allocate_area addr {}", addr.is_null());
 
         if !addr.is_null() {
-            aperture_release_area(&aperture, addr, page_size as u64);
+            aperture_release_area(aperture, addr, page_size as u64, g_args);
             let aperture = &mut self.fmm.svm.apertures[svm_default];
 
             self.fmm.svm.dgpu_aperture = aperture as *mut _ as *mut manageable_aperture_t;
@@ -997,70 +1209,59 @@ impl HsakmtGlobals {
 
         let mut map_size = 0;
 
-        loop {
-            if !found && len >= SVM_MIN_VM_SIZE {
-                addr = base as *mut std::os::raw::c_void;
-
-                loop {
-                    if addr as u64 + ((len + 1) >> 1) - 1 <= limit {
-                        break;
-                    }
-
-                    let top: u64 = MIN((addr as u64) + len, limit + 1);
-
-                    map_size = (top - addr as u64) & !(page_size as u64 - 1);
-                    if map_size < SVM_MIN_VM_SIZE {
-                        break;
-                    }
-
-                    ret_addr = reserve_address(addr, map_size);
-
-                    if ret_addr.is_null() {
-                        break;
-                    }
-
-                    if ret_addr as u64 + ((len + 1) >> 1) - 1 <= limit {
-                        /* At least half the returned address
-                         * space is GPU addressable, we'll
-                         * take it
-                         */
-                        break;
-                    }
+        while !found && len >= SVM_MIN_VM_SIZE {
+            addr = base as *mut std::os::raw::c_void;
 
-                    munmap(ret_addr, map_size as usize);
-                    ret_addr = std::ptr::null_mut();
+            while addr as u64 + ((len + 1) >> 1) - 1 <= limit {
+                let top: u64 = MIN((addr as u64) + len, limit + 1);
 
-                    addr = ((addr as u64) + ADDR_INC as u64) as *mut std::os::raw::c_void;
+                map_size = (top - addr as u64) & !(page_size as u64 - 1);
+                if map_size < SVM_MIN_VM_SIZE {
+                    break;
                 }
 
+                ret_addr = reserve_address(addr, map_size);
                 if ret_addr.is_null() {
-                    println!("Failed to reserve {} GB for SVM ...", len >> 30);
-                    continue;
+                    break;
                 }
 
-                len = (len + 1) >> 1;
+                if ret_addr as u64 + ((len + 1) >> 1) - 1 <= limit {
+                    /* At least half the returned address
+                     * space is GPU addressable, we'll
+                     * take it
+                     */
+                    break;
+                }
 
-                if ret_addr as u64 + SVM_MIN_VM_SIZE - 1 > limit {
-                    /* addressable size is less than the minimum */
-                    println!(
-                        "Got {} GB for SVM at {:?} with only {} GB usable ...\n",
-                        map_size >> 30,
-                        ret_addr,
-                        (limit - ret_addr as u64) >> 30
-                    );
+                munmap(ret_addr, map_size as usize);
+                ret_addr = std::ptr::null_mut();
 
-                    munmap(ret_addr, map_size as usize);
+                addr = ((addr as u64) + ADDR_INC as u64) as *mut std::os::raw::c_void;
+            }
 
-                    ret_addr = std::ptr::null_mut();
+            if ret_addr.is_null() {
+                println!("Failed to reserve {} GB for SVM ...", len >> 30);
+                len = (len + 1) >> 1;
+                continue;
+            }
 
-                    continue;
-                } else {
-                    found = true;
-                    break;
-                }
+            if ret_addr as u64 + SVM_MIN_VM_SIZE - 1 > limit {
+                /* addressable size is less than the minimum */
+                println!(
+                    "Got {} GB for SVM at {:?} with only {} GB usable ...",
+                    map_size >> 30,
+                    ret_addr,
+                    (limit as i64 - ret_addr as i64) >> 30
+                );
+
+                munmap(ret_addr, map_size as usize);
+                ret_addr = std::ptr::null_mut();
+
+                len = (len + 1) >> 1;
+                continue;
             }
 
-            break;
+            found = true;
         }
 
         if !found {
@@ -1092,10 +1293,7 @@ impl HsakmtGlobals {
         self.fmm.svm.apertures[svm_default].align = align as u64;
         self.fmm.svm.apertures[svm_default].guard_pages = guard_pages;
         self.fmm.svm.apertures[svm_default].is_cpu_accessible = true;
-        self.fmm.svm.apertures[svm_default].ops = manageable_aperture_ops_t {
-            allocate_area_aligned: None,
-            release_area: None,
-        };
+        self.fmm.svm.apertures[svm_default].ops = reserved_aperture_ops;
 
         /* Use the first 1/4 of the dGPU aperture as
          * alternate aperture for coherent access.
@@ -1117,10 +1315,7 @@ impl HsakmtGlobals {
         self.fmm.svm.apertures[svm_coherent].align = align as u64;
         self.fmm.svm.apertures[svm_coherent].guard_pages = guard_pages;
         self.fmm.svm.apertures[svm_coherent].is_cpu_accessible = true;
-        self.fmm.svm.apertures[svm_coherent].ops = manageable_aperture_ops_t {
-            allocate_area_aligned: None,
-            release_area: None,
-        };
+        self.fmm.svm.apertures[svm_coherent].ops = reserved_aperture_ops;
 
         self.fmm.svm.apertures[svm_default].base =
             VOID_PTR_ADD(self.fmm.svm.apertures[svm_coherent].limit, 1);
@@ -1142,7 +1337,6 @@ impl HsakmtGlobals {
         HSAKMT_STATUS_SUCCESS
     }
 
-    // TODO init_mem_handle_aperture
     pub unsafe fn init_mem_handle_aperture(&mut self, align: u32, guard_pages: u32) -> bool {
         let mut found = false;
 
@@ -1150,10 +1344,7 @@ impl HsakmtGlobals {
         self.fmm.mem_handle_aperture.align = align as u64;
         self.fmm.mem_handle_aperture.guard_pages = guard_pages;
         self.fmm.mem_handle_aperture.is_cpu_accessible = false;
-        self.fmm.mem_handle_aperture.ops = manageable_aperture_ops_t {
-            allocate_area_aligned: None,
-            release_area: None,
-        };
+        self.fmm.mem_handle_aperture.ops = reserved_aperture_ops;
 
         while PORT_VPTR_TO_UINT64(self.fmm.mem_handle_aperture.base) < END_NON_CANONICAL_ADDR - 1 {
             found = true;
@@ -1296,20 +1487,12 @@ impl HsakmtGlobals {
         }
 
         /* if allocate vram-only, use an invalid VA */
-        if aperture == &self.fmm.mem_handle_aperture {
-            println!("allocate vram-only, use an invalid VA");
+        if std::ptr::eq(aperture, &self.fmm.mem_handle_aperture) {
             args.va_addr = 0 as *mut u64;
         }
 
         let hsakmt_kfd_fd = self.hsakmt_kfd_fd;
 
-        let p_1 = ('K' as u64) << (0 + 8);
-        let p_2 =
-            ((std::mem::size_of::<kfd_ioctl_alloc_memory_of_gpu_args>()) << ((0 + 8) + 8)) as u64;
-
-        let AMDKFD_IOC_ALLOC_MEMORY_OF_GPU =
-            ((2 | 1) << (((0 + 8) + 8) + 14)) | p_1 | ((0x16) << 0) | p_2;
-
         let r = hsakmt_ioctl(
             hsakmt_kfd_fd,
             AMDKFD_IOC_ALLOC_MEMORY_OF_GPU,
@@ -1361,6 +1544,13 @@ impl HsakmtGlobals {
             return std::ptr::null_mut();
         }
 
+        self.fmm_account_object(
+            aperture,
+            vm_obj,
+            gpu_id,
+            fmm_heap_type::from_ioc_flags(ioc_flags),
+        );
+
         // println!("mmap_offset {}", mmap_offset);
         // if *mmap_offset > 0 {
         *mmap_offset = args.mmap_offset;
@@ -1425,7 +1615,7 @@ impl HsakmtGlobals {
              * allocation of memory in device failed.
              * Release region in aperture
              */
-            aperture_release_area(aperture, mem, MemorySizeInBytes);
+            aperture_release_area(aperture, mem, MemorySizeInBytes, g_args);
 
             /* Assign NULL to mem to indicate failure to calling function */
             mem = std::ptr::null_mut();
@@ -1447,12 +1637,19 @@ impl HsakmtGlobals {
         let object_st = &mut *(object);
 
         /* Free allocations inside the object */
+        self.fmm_unaccount_object(object);
 
-        hsakmt_rbtree_delete(&mut aperture.tree, &mut object_st.node);
+        aperture
+            .tree
+            .remove(object_st.start as u64, object_st.size, object);
 
         if !object_st.userptr.is_null() {
-            hsakmt_rbtree_delete(&mut aperture.user_tree, &mut object_st.user_node);
+            aperture
+                .user_tree
+                .remove(object_st.userptr as u64, object_st.userptr_size, object);
         }
+
+        drop(Box::from_raw(object));
     }
 
     pub unsafe fn __fmm_release(
@@ -1500,7 +1697,12 @@ impl HsakmtGlobals {
             return -errno;
         }
 
-        aperture_release_area(aperture, object_st.start, object_st.size);
+        let g_args = HsakmtGlobalsArgs {
+            page_size: self.PAGE_SIZE(),
+            fmm_svm_alignment_order: self.fmm.svm.alignment_order,
+        };
+
+        aperture_release_area(aperture, object_st.start, object_st.size, g_args);
         self.vm_remove_object(aperture, object);
 
         0
@@ -1547,17 +1749,11 @@ impl HsakmtGlobals {
             }
         }
 
-        if aper.is_null() {
-            if self.fmm.svm.dgpu_aperture.is_null() {
-                panic!("TODO no_svm");
-                // goto no_svm;
-            }
-
+        if aper.is_null() && !self.fmm.svm.dgpu_aperture.is_null() {
             if addr >= (*self.fmm.svm.dgpu_aperture).base
                 && (addr <= (*self.fmm.svm.dgpu_aperture).limit)
             {
                 aper = self.fmm.svm.dgpu_aperture;
-                // println!("aperture_error is null {}", aper.is_null());
             } else if (addr >= (*self.fmm.svm.dgpu_alt_aperture).base)
                 && (addr <= (*self.fmm.svm.dgpu_alt_aperture).limit)
             {
@@ -1568,321 +1764,1801 @@ impl HsakmtGlobals {
             }
         }
 
-        println!("aperture_error is null {}", aper.is_null());
-
-        let mmap_aperture_ops = manageable_aperture_ops_t {
-            allocate_area_aligned: Some(mmap_aperture_allocate_aligned),
-            release_area: Some(mmap_aperture_release),
-        };
-
         let page_size = self.PAGE_SIZE();
 
-        // pthread_mutex_lock(&aper->fmm_mutex);
-        if range {
-            let aper_st = &mut (*aper);
-            /* mmap_apertures can have userptrs in them. Try to
-             * look up addresses as userptrs first to sort out any
-             * ambiguity of multiple overlapping mappings at
-             * different GPU addresses.
-             */
-            if userptr || aper_st.ops == mmap_aperture_ops {
-                // obj = vm_find_object_by_userptr_range(aper_st, addr);
-            }
-
-            if obj.is_null() && !userptr {
-                // obj = vm_find_object_by_address_range(aper_st, addr);
-            }
-        } else {
+        if !aper.is_null() {
             let aper_st = &mut (*aper);
 
-            if userptr || aper_st.ops == mmap_aperture_ops {
-                // obj = vm_find_object_by_userptr(aper_st, addr, size);
-            }
+            if range {
+                /* mmap_apertures can have userptrs in them. Try to
+                 * look up addresses as userptrs first to sort out any
+                 * ambiguity of multiple overlapping mappings at
+                 * different GPU addresses.
+                 */
+                if userptr || aper_st.ops == mmap_aperture_ops {
+                    obj = vm_find_object_by_userptr_range(aper_st, addr);
+                }
 
-            if obj.is_null() && !userptr {
-                // println!("aperture_error is null {}", aper.is_null());
+                if obj.is_null() && !userptr {
+                    obj = vm_find_object_by_address_range(aper_st, addr);
+                }
+            } else {
+                if userptr || aper_st.ops == mmap_aperture_ops {
+                    obj = vm_find_object_by_userptr(aper_st, addr, size);
+                }
 
-                let page_offset = (addr as u64) & (page_size as u64 - 1);
+                if obj.is_null() && !userptr {
+                    let page_offset = (addr as u64) & (page_size as u64 - 1);
 
-                let page_addr =
-                    (addr as *mut u8).sub(page_offset as usize) as *mut std::os::raw::c_void;
-                // println!("vm_find_object addr is null {}", addr.is_null());
-                // println!("page_addr is null {}", page_addr.is_null());
+                    let page_addr =
+                        (addr as *mut u8).sub(page_offset as usize) as *mut std::os::raw::c_void;
 
-                obj = vm_find_object_by_address(aper, page_addr, 0);
-                /* If we find a userptr here, it's a match on
-                 * the aligned GPU address. Make sure that the
-                 * page offset and size match too.
-                 */
-                if !obj.is_null() {
-                    let obj_st = &mut (*obj);
-                    let b_1 = obj_st.userptr as u64 & (page_size as u64 - 1);
+                    obj = vm_find_object_by_address(aper, page_addr, 0);
+                    /* If we find a userptr here, it's a match on
+                     * the aligned GPU address. Make sure that the
+                     * page offset and size match too.
+                     */
+                    if !obj.is_null() && !(*obj).userptr.is_null() {
+                        let obj_st = &mut (*obj);
+                        let b_1 = obj_st.userptr as u64 & (page_size as u64 - 1);
 
-                    if b_1 != page_offset || (size > 0 && size != obj_st.userptr_size) {
-                        obj = std::ptr::null_mut();
+                        if b_1 != page_offset || (size > 0 && size != obj_st.userptr_size) {
+                            obj = std::ptr::null_mut();
+                        }
                     }
                 }
             }
         }
 
-        //     let hsakmt_is_dgpu = true;
-        //
-        // // no_svm:
-        // 	if obj.is_null() && !hsakmt_is_dgpu {
-        //         panic!("TODO no_svm");
-        // 		/* On APUs try finding it in the CPUVM aperture */
-        // 		// if (aper)
-        // 		// 	pthread_mutex_unlock(&aper->fmm_mutex);
-        //         //
-        // 		// aper = &cpuvm_aperture;
-        //         //
-        // 		// pthread_mutex_lock(&aper->fmm_mutex);
-        // 		// if (range)
-        // 		// 	obj = vm_find_object_by_address_range(aper, addr);
-        // 		// else
-        // 		// 	obj = vm_find_object_by_address(aper, addr, 0);
-        // 	}
-
-        println!("here no error");
-
-        println!("ob is null {:?}", obj.is_null());
-        println!("aper is null {:?}", aper.is_null());
+        // no_svm:
+        if obj.is_null() && !self.hsakmt_is_dgpu {
+            /* On APUs try finding it in the CPUVM aperture */
+            aper = &mut self.fmm.cpuvm_aperture;
+
+            if range {
+                obj = vm_find_object_by_address_range(&mut *aper, addr);
+            } else {
+                obj = vm_find_object_by_address(aper, addr, 0);
+            }
+        }
 
         if !obj.is_null() {
-            // println!("here");
             *out_aper = aper;
             return obj;
         }
 
-        // println!("here");
-
         std::ptr::null_mut()
     }
 
-    pub unsafe fn hsakmt_fmm_map_to_gpu(
-        &mut self,
+    pub unsafe fn _fmm_map_to_gpu(
+        &self,
+        aperture: *mut manageable_aperture_t,
         address: *mut std::os::raw::c_void,
-        size: u64,
-        gpuvm_address: *mut u64,
+        _size: u64,
+        obj: *mut vm_object_t,
+        nodes_to_map: &[u32],
     ) -> HsakmtStatus {
-        let mut aperture: *mut manageable_aperture_t = std::ptr::null_mut();
+        let mut object = obj;
 
-        /* Special handling for scratch memory */
-        for i in 0..self.fmm.gpu_mem.len() {
-            if self.fmm.gpu_mem[i].gpu_id != NON_VALID_GPU_ID as u32
-                && address >= self.fmm.gpu_mem[i].scratch_physical.base
-                && address <= self.fmm.gpu_mem[i].scratch_physical.limit
-            {
-                panic!("TODO _fmm_map_to_gpu_scratch");
-                // return _fmm_map_to_gpu_scratch(gpu_mem[i].gpu_id,
-                //                                &gpu_mem[i].scratch_physical,
-                //                                address, size);
-                // return HSAKMT_STATUS_SUCCESS;
+        if object.is_null() {
+            /* Find the object to retrieve the handle */
+            object = vm_find_object_by_address(aperture, address, 0);
+            if object.is_null() {
+                return HSAKMT_STATUS_INVALID_PARAMETER;
             }
         }
 
-        // panic!("TODO complete hsakmt_fmm_map_to_gpu");
+        let object_st = &mut *object;
 
-        println!("address is null {}", address.is_null());
-        let object = self.vm_find_object(address, size, &mut aperture);
-        println!("vm_find_object is_null {}", object.is_null());
-        println!("aperture is_null {}", aperture.is_null());
-
-        // if (!object && !hsakmt_is_svm_api_supported) {
-        //     if (!hsakmt_is_dgpu) {
-        //         /* Prefetch memory on APUs with dummy-reads */
-        //         fmm_check_user_memory(address, size);
-        //         return HSAKMT_STATUS_SUCCESS;
-        //     }
-        //     pr_err("Object not found at %p\n", address);
-        //     return HSAKMT_STATUS_INVALID_PARAMETER;
-        // }
-        // /* Successful vm_find_object returns with the aperture locked */
-        //
-        // /* allocate VA only */
-        // if (object && object->handle == 0) {
-        //     pthread_mutex_unlock(&aperture->fmm_mutex);
-        //     return HSAKMT_STATUS_INVALID_PARAMETER;
-        // }
-        //
-        // /* allocate buffer only, should be mapped by GEM API */
-        //     if (aperture && (aperture == &mem_handle_aperture)) {
-        //     pthread_mutex_unlock(&aperture->fmm_mutex);
-        //     return HSAKMT_STATUS_INVALID_PARAMETER;
-        // }
-        //
-        // if (aperture && (aperture == &cpuvm_aperture)) {
-        //     /* Prefetch memory on APUs with dummy-reads */
-        //     fmm_check_user_memory(address, size);
-        //     ret = HSAKMT_STATUS_SUCCESS;
-        // } else if ((hsakmt_is_svm_api_supported && !object) || (object && (object->userptr))) {
-        //     ret = _fmm_map_to_gpu_userptr(address, size, gpuvm_address, object, NULL, 0);
-        // } else if (aperture) {
-        //     ret = _fmm_map_to_gpu(aperture, address, size, object, NULL, 0);
-        //     /* Update alternate GPUVM address only for
-        //      * CPU-invisible apertures on old APUs
-        //      */
-        //     if (ret == HSAKMT_STATUS_SUCCESS && gpuvm_address && !aperture->is_cpu_accessible)
-        //         *gpuvm_address = VOID_PTRS_SUB(object->start, aperture->base);
-        // }
-        //
-        // if (object)
-        //     pthread_mutex_unlock(&aperture->fmm_mutex);
+        /* For a memory region that is registered by user pointer, changing
+         * mapping nodes is not allowed, so we don't need to check the mapping
+         * nodes or map if it's already mapped. Just increase the reference.
+         */
+        if !object_st.userptr.is_null() && object_st.mapping_count > 0 {
+            object_st.mapping_count += 1;
+            return HSAKMT_STATUS_SUCCESS;
+        }
+
+        let device_ids: Vec<u32> = if !nodes_to_map.is_empty() {
+            /* If specified, map the requested */
+            nodes_to_map.to_vec()
+        } else if !object_st.registered_device_id_array.is_empty() {
+            /* otherwise map all registered */
+            object_st.registered_device_id_array.clone()
+        } else {
+            /* not specified, not registered: map all GPUs */
+            self.fmm.all_gpu_id_array.clone()
+        };
+
+        let mut args = kfd_ioctl_map_memory_to_gpu_args {
+            handle: object_st.handle,
+            device_ids_array_ptr: device_ids.as_ptr() as u64,
+            n_devices: device_ids.len() as u32,
+            n_success: 0,
+        };
+
+        let ret = hsakmt_ioctl(
+            self.hsakmt_kfd_fd,
+            AMDKFD_IOC_MAP_MEMORY_TO_GPU,
+            &mut args as *mut _ as *mut std::os::raw::c_void,
+        );
+
+        add_device_ids_to_mapped_array(object_st, &device_ids[..args.n_success as usize]);
+        self.fmm_account_mapping(object);
+
+        object_st.mapping_count = 1;
+        /* Mapping changed and lifecycle of object->mapped_node_id_array
+         * terminates here. Free it and allocate on next query
+         */
+        object_st.mapped_node_id_array.clear();
+
+        if ret != 0 {
+            return HSAKMT_STATUS_ERROR;
+        }
 
         HSAKMT_STATUS_SUCCESS
     }
 
-    pub unsafe fn map_mmio(
-        &mut self,
-        node_id: u32,
+    pub unsafe fn _fmm_map_to_gpu_scratch(
+        &self,
         gpu_id: u32,
-        mmap_fd: i32,
-    ) -> *mut std::os::raw::c_void {
-        // FIXME unsafe ptr
-        let aperture_ptr = self.fmm.svm.dgpu_alt_aperture;
-
-        let aperture = &mut *(aperture_ptr);
-        // println!("aperture {:#?}", aperture);
+        aperture: *mut manageable_aperture_t,
+        address: *mut std::os::raw::c_void,
+        size: u64,
+    ) -> HsakmtStatus {
+        /* Retrieve gpu_mem id according to gpu_id */
+        let gpu_mem_id = self.gpu_mem_find_by_gpu_id(gpu_id);
+        if gpu_mem_id < 0 {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
 
-        let mut vm_obj: *mut vm_object_t = std::ptr::null_mut();
+        if !self.hsakmt_is_dgpu {
+            return HSAKMT_STATUS_SUCCESS; /* Nothing to do on APU */
+        }
 
-        let mut mflags = HsaMemFlags {
-            st: HsaMemFlagUnion { Value: 0 },
-        };
+        /* sanity check the address */
+        if address < (*aperture).base || VOID_PTR_ADD(address, size - 1) > (*aperture).limit {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
 
         let mut mmap_offset: u64 = 0;
 
-        /* Allocate physical memory and vm object*/
-        let ioc_flags = KFD_IOC_ALLOC_MEM_FLAGS_MMIO_REMAP
-            | KFD_IOC_ALLOC_MEM_FLAGS_WRITABLE
-            | KFD_IOC_ALLOC_MEM_FLAGS_COHERENT;
-
-        let page_size = self.PAGE_SIZE();
-
-        let mem = self.__fmm_allocate_device(
+        /* allocate object within the scratch backing aperture */
+        let obj = self.fmm_allocate_memory_object(
             gpu_id,
-            std::ptr::null_mut(),
-            page_size as u64,
-            aperture,
+            address,
+            size,
+            &mut *aperture,
             &mut mmap_offset,
-            ioc_flags as u32,
-            0,
-            &mut vm_obj,
+            (KFD_IOC_ALLOC_MEM_FLAGS_VRAM | KFD_IOC_ALLOC_MEM_FLAGS_WRITABLE) as u32,
         );
+        if obj.is_null() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
 
-        if mem.is_null() || vm_obj.is_null() {
-            println!("error mem {} vm_obj {}", mem.is_null(), vm_obj.is_null());
+        /* Create a CPU mapping for the debugger */
+        let mmap_ret = fmm_map_to_cpu(
+            address,
+            size,
+            false,
+            self.fmm.gpu_mem[gpu_mem_id as usize].drm_render_fd,
+            mmap_offset,
+        );
+        if mmap_ret == MAP_FAILED {
+            self.__fmm_release(obj, aperture);
+            return HSAKMT_STATUS_ERROR;
+        }
 
-            return std::ptr::null_mut();
+        /* map to GPU */
+        let ret = self._fmm_map_to_gpu(aperture, address, size, std::ptr::null_mut(), &[gpu_id]);
+        if ret != HSAKMT_STATUS_SUCCESS {
+            self.__fmm_release(obj, aperture);
         }
 
-        // println!("mem {} vm_obj {}", mem.is_null(), vm_obj.is_null());
+        ret
+    }
 
-        mflags.st.Value = 0;
-        mflags.st.ui32.NonPaged = 1;
-        mflags.st.ui32.HostAccess = 1;
+    /* Make a range of system memory accessible to the GPUs in place
+     * through the SVM API
+     */
+    pub unsafe fn fmm_map_mem_svm_api(
+        &self,
+        address: *mut std::os::raw::c_void,
+        size: u64,
+        nodes_to_map: &[u32],
+    ) -> HsakmtStatus {
+        let nodes_to_map = if nodes_to_map.is_empty() {
+            &self.fmm.all_gpu_id_array[..]
+        } else {
+            nodes_to_map
+        };
 
-        let vm_obj_st = &mut (*vm_obj);
+        let nattr = nodes_to_map.len();
+        let s_attr = std::mem::size_of::<kfd_ioctl_svm_attribute>() * nattr;
+
+        /* The attributes follow the arguments */
+        let mut buf = vec![
+            0u64;
+            (std::mem::size_of::<kfd_ioctl_svm_args>() + s_attr)
+                .div_ceil(std::mem::size_of::<u64>())
+        ];
+        let args = buf.as_mut_ptr() as *mut kfd_ioctl_svm_args;
+
+        (*args).start_addr = address as u64;
+        (*args).size = size;
+        (*args).op = KFD_IOCTL_SVM_OP_SET_ATTR;
+        (*args).nattr = nattr as u32;
+
+        let attrs = (*args).attrs.as_mut_ptr();
+        for (i, gpu_id) in nodes_to_map.iter().enumerate() {
+            *attrs.add(i) = kfd_ioctl_svm_attribute {
+                type_: KFD_IOCTL_SVM_ATTR_ACCESS_IN_PLACE,
+                value: *gpu_id,
+            };
+        }
 
-        vm_obj_st.mflags = mflags;
-        vm_obj_st.node_id = node_id;
+        if hsakmt_ioctl(
+            self.hsakmt_kfd_fd,
+            AMDKFD_IOC_SVM + ((s_attr as u64) << 16),
+            args as *mut std::os::raw::c_void,
+        ) != 0
+        {
+            return HSAKMT_STATUS_ERROR;
+        }
 
-        let page_size = self.PAGE_SIZE();
+        HSAKMT_STATUS_SUCCESS
+    }
 
-        // println!("Map for CPU access mmap_offset {}", mmap_offset);
+    /* Map system memory registered as userptr, or through the SVM API
+     * when it is not registered. The GPUVM address is the CPU address.
+     */
+    pub unsafe fn _fmm_map_to_gpu_userptr(
+        &self,
+        addr: *mut std::os::raw::c_void,
+        size: u64,
+        gpuvm_addr: *mut u64,
+        object: *mut vm_object_t,
+        nodes_to_map: &[u32],
+    ) -> HsakmtStatus {
+        let page_size = self.PAGE_SIZE() as u64;
+        let page_offset = addr as u64 & (page_size - 1);
 
-        /* Map for CPU access*/
-        let ret = mmap(
-            mem,
-            page_size as usize,
-            PROT_READ | PROT_WRITE,
-            MAP_SHARED | MAP_FIXED,
-            mmap_fd,
-            mmap_offset as off_t,
-        );
+        let aperture = self.fmm.svm.dgpu_aperture;
 
-        // println!("mmap ret {:?} {}", ret, ret.is_null());
+        /* Map and return the GPUVM address adjusted by the offset
+         * from the start of the page
+         */
+        let (svm_addr, ret) = if object.is_null() && self.hsakmt_is_svm_api_supported {
+            let svm_addr = (addr as u64 - page_offset) as *mut std::os::raw::c_void;
+            let svm_size = ALIGN_UP(size + page_offset, page_size);
+
+            (
+                svm_addr,
+                self.fmm_map_mem_svm_api(svm_addr, svm_size, nodes_to_map),
+            )
+        } else if !object.is_null() {
+            let svm_addr = (*object).start;
+            let svm_size = (*object).size;
+
+            (
+                svm_addr,
+                self._fmm_map_to_gpu(aperture, svm_addr, svm_size, object, nodes_to_map),
+            )
+        } else {
+            println!("Object is null and SVM API is not supported");
+            return HSAKMT_STATUS_ERROR;
+        };
 
-        if ret == MAP_FAILED {
-            let errno = std::io::Error::last_os_error().raw_os_error().unwrap();
+        if ret == HSAKMT_STATUS_SUCCESS && !gpuvm_addr.is_null() {
+            *gpuvm_addr = svm_addr as u64 + page_offset;
+        }
 
-            // println!("mmap MAP_FAILED, mmap_fd {} -> {:?} {}", mmap_fd, ret, errno);
-            self.__fmm_release(vm_obj, aperture);
-            panic!(
-                "mmap MAP_FAILED, mmap_fd {} -> {:?} {}",
-                mmap_fd, ret, errno
-            );
+        ret
+    }
 
-            // return std::ptr::null_mut();
-        }
-        //
-        // println!("continue");
+    pub unsafe fn hsakmt_fmm_map_to_gpu(
+        &mut self,
+        address: *mut std::os::raw::c_void,
+        size: u64,
+        gpuvm_address: *mut u64,
+    ) -> HsakmtStatus {
+        let mut aperture: *mut manageable_aperture_t = std::ptr::null_mut();
 
-        /* Map for GPU access*/
-        let ret = self.hsakmt_fmm_map_to_gpu(mem, page_size as u64, std::ptr::null_mut());
+        /* Special handling for scratch memory */
+        for i in 0..self.fmm.gpu_mem.len() {
+            if self.fmm.gpu_mem[i].gpu_id != NON_VALID_GPU_ID as u32
+                && address >= self.fmm.gpu_mem[i].scratch_physical.base
+                && address <= self.fmm.gpu_mem[i].scratch_physical.limit
+            {
+                let gpu_id = self.fmm.gpu_mem[i].gpu_id;
+                let scratch_physical: *mut manageable_aperture_t =
+                    &mut self.fmm.gpu_mem[i].scratch_physical;
 
-        if ret != HSAKMT_STATUS_SUCCESS {
-            println!("hsakmt_fmm_map_to_gpu error {:?}", ret);
-            self.__fmm_release(vm_obj, aperture);
-            return std::ptr::null_mut();
+                return self._fmm_map_to_gpu_scratch(gpu_id, scratch_physical, address, size);
+            }
         }
 
-        mem
-        // std::ptr::null_mut()
-    }
+        let object = self.vm_find_object(address, size, &mut aperture);
 
-    pub unsafe fn hsakmt_fmm_init_process_apertures(&mut self, NumNodes: u32) -> HsakmtStatus {
-        let guardPages: u32 = 1;
+        if object.is_null() && !self.hsakmt_is_svm_api_supported {
+            if !self.hsakmt_is_dgpu {
+                /* Prefetch memory on APUs with dummy-reads */
+                fmm_check_user_memory(address, size, self.PAGE_SIZE() as u64);
+                return HSAKMT_STATUS_SUCCESS;
+            }
+            println!("Object not found at {:?}", address);
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
 
-        let zero_str = CString::new("0").unwrap();
+        /* allocate VA only */
+        if !object.is_null() && (*object).handle == 0 {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
 
-        /* If HSA_DISABLE_CACHE is set to a non-0 value, disable caching */
-        let env_str = CString::new("HSA_DISABLE_CACHE").unwrap();
-        let disableCache = getenv(env_str.as_ptr());
-        let b = !disableCache.is_null() && strcmp(disableCache, zero_str.as_ptr()) == 0;
-        self.fmm.svm.disable_cache = b;
+        /* allocate buffer only, should be mapped by GEM API */
+        if std::ptr::eq(aperture, &self.fmm.mem_handle_aperture) {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
 
-        /* If HSA_USERPTR_FOR_PAGED_MEM is not set or set to a non-0
-         * value, enable userptr for all paged memory allocations
-         */
-        // let env_str = CString::new("HSA_USERPTR_FOR_PAGED_MEM").unwrap();
-        // let pagedUserptr = getenv(env_str.as_ptr());
-        // svm.userptr_for_paged_mem = !pagedUserptr.is_null() || strcmp(pagedUserptr, zero_str.as_ptr()) == 0;
+        let mut ret = HSAKMT_STATUS_SUCCESS;
+
+        if std::ptr::eq(aperture, &self.fmm.cpuvm_aperture) {
+            /* Prefetch memory on APUs with dummy-reads */
+            fmm_check_user_memory(address, size, self.PAGE_SIZE() as u64);
+        } else if (self.hsakmt_is_svm_api_supported && object.is_null())
+            || (!object.is_null() && !(*object).userptr.is_null())
+        {
+            ret = self._fmm_map_to_gpu_userptr(address, size, gpuvm_address, object, &[]);
+        } else if !aperture.is_null() {
+            ret = self._fmm_map_to_gpu(aperture, address, size, object, &[]);
+            /* Update alternate GPUVM address only for
+             * CPU-invisible apertures on old APUs
+             */
+            if ret == HSAKMT_STATUS_SUCCESS
+                && !gpuvm_address.is_null()
+                && !(*aperture).is_cpu_accessible
+            {
+                *gpuvm_address = VOID_PTRS_SUB((*object).start, (*aperture).base);
+            }
+        }
 
-        /* If HSA_CHECK_USERPTR is set to a non-0 value, check all userptrs
-         * when they are registered
-         */
-        let env_str = CString::new("HSA_CHECK_USERPTR").unwrap();
-        let checkUserptr = getenv(env_str.as_ptr());
-        self.fmm.svm.check_userptr =
-            !checkUserptr.is_null() && strcmp(checkUserptr, zero_str.as_ptr()) == 0;
+        ret
+    }
+
+    pub unsafe fn _fmm_unmap_from_gpu(
+        &self,
+        aperture: *mut manageable_aperture_t,
+        address: *mut std::os::raw::c_void,
+        device_ids_array: &[u32],
+        obj: *mut vm_object_t,
+    ) -> i32 {
+        let page_offset = (address as u64) & (self.PAGE_SIZE() as u64 - 1);
+
+        /* Find the object to retrieve the handle */
+        let mut object = obj;
+        if object.is_null() {
+            object = vm_find_object_by_address(
+                aperture,
+                (address as *mut u8).sub(page_offset as usize) as *mut std::os::raw::c_void,
+                0,
+            );
+            if object.is_null() {
+                return -1;
+            }
+        }
+
+        let object_st = &mut *object;
+
+        if !object_st.userptr.is_null() && object_st.mapping_count > 1 {
+            object_st.mapping_count -= 1;
+            return 0;
+        }
+
+        let device_ids: Vec<u32> = if !device_ids_array.is_empty() {
+            device_ids_array.to_vec()
+        } else if !object_st.mapped_device_id_array.is_empty() {
+            object_st.mapped_device_id_array.clone()
+        } else {
+            /*
+             * When unmap exits here it should return failing error code as the user tried to
+             * unmap already unmapped buffer. Currently we returns success as KFDTEST and RT
+             * need to deploy the change on there side before thunk fails on this case.
+             */
+            return 0;
+        };
+
+        let mut args = kfd_ioctl_unmap_memory_from_gpu_args {
+            handle: object_st.handle,
+            device_ids_array_ptr: device_ids.as_ptr() as u64,
+            n_devices: device_ids.len() as u32,
+            n_success: 0,
+        };
+
+        let ret = hsakmt_ioctl(
+            self.hsakmt_kfd_fd,
+            AMDKFD_IOC_UNMAP_MEMORY_FROM_GPU,
+            &mut args as *mut _ as *mut std::os::raw::c_void,
+        );
+
+        remove_device_ids_from_mapped_array(object_st, &device_ids[..args.n_success as usize]);
+        self.fmm_account_mapping(object);
+
+        object_st.mapped_node_id_array.clear();
+        object_st.mapping_count = 0;
+
+        ret
+    }
+
+    pub unsafe fn _fmm_unmap_from_gpu_scratch(
+        &self,
+        gpu_id: u32,
+        aperture: *mut manageable_aperture_t,
+        address: *mut std::os::raw::c_void,
+    ) -> i32 {
+        /* Retrieve gpu_mem id according to gpu_id */
+        let gpu_mem_id = self.gpu_mem_find_by_gpu_id(gpu_id);
+        if gpu_mem_id < 0 {
+            return -1;
+        }
+
+        if !self.hsakmt_is_dgpu {
+            return 0; /* Nothing to do on APU */
+        }
+
+        /* Find the object to retrieve the handle and size */
+        let object = vm_find_object_by_address(aperture, address, 0);
+        if object.is_null() {
+            return -EINVAL;
+        }
+
+        let object_st = &mut *object;
+
+        if object_st.mapped_device_id_array.is_empty() {
+            return 0;
+        }
+
+        /* unmap from GPU */
+        let device_ids = object_st.mapped_device_id_array.clone();
+
+        let mut args = kfd_ioctl_unmap_memory_from_gpu_args {
+            handle: object_st.handle,
+            device_ids_array_ptr: device_ids.as_ptr() as u64,
+            n_devices: device_ids.len() as u32,
+            n_success: 0,
+        };
+
+        let ret = hsakmt_ioctl(
+            self.hsakmt_kfd_fd,
+            AMDKFD_IOC_UNMAP_MEMORY_FROM_GPU,
+            &mut args as *mut _ as *mut std::os::raw::c_void,
+        );
+
+        /* unmap from CPU while keeping the address space reserved */
+        mmap(
+            address,
+            object_st.size as usize,
+            PROT_NONE,
+            MAP_ANONYMOUS | MAP_NORESERVE | MAP_PRIVATE | MAP_FIXED,
+            -1,
+            0,
+        );
+
+        remove_device_ids_from_mapped_array(object_st, &device_ids[..args.n_success as usize]);
+        self.fmm_account_mapping(object);
+
+        object_st.mapped_node_id_array.clear();
+
+        if ret != 0 {
+            return ret;
+        }
+
+        /* free object in scratch backing aperture */
+        self.__fmm_release(object, aperture)
+    }
+
+    pub unsafe fn hsakmt_fmm_unmap_from_gpu(
+        &mut self,
+        address: *mut std::os::raw::c_void,
+    ) -> HsakmtStatus {
+        let mut aperture: *mut manageable_aperture_t = std::ptr::null_mut();
+
+        /* Special handling for scratch memory */
+        for i in 0..self.fmm.gpu_mem.len() {
+            if self.fmm.gpu_mem[i].gpu_id != NON_VALID_GPU_ID as u32
+                && address >= self.fmm.gpu_mem[i].scratch_physical.base
+                && address <= self.fmm.gpu_mem[i].scratch_physical.limit
+            {
+                let gpu_id = self.fmm.gpu_mem[i].gpu_id;
+                let scratch_physical: *mut manageable_aperture_t =
+                    &mut self.fmm.gpu_mem[i].scratch_physical;
+
+                return if self._fmm_unmap_from_gpu_scratch(gpu_id, scratch_physical, address) != 0 {
+                    HSAKMT_STATUS_ERROR
+                } else {
+                    HSAKMT_STATUS_SUCCESS
+                };
+            }
+        }
+
+        let object = self.vm_find_object(address, 0, &mut aperture);
+        if object.is_null() {
+            /* On APUs GPU unmapping of system memory is a no-op */
+            return if !self.hsakmt_is_dgpu || self.hsakmt_is_svm_api_supported {
+                HSAKMT_STATUS_SUCCESS
+            } else {
+                HSAKMT_STATUS_INVALID_PARAMETER
+            };
+        }
+
+        let ret = if std::ptr::eq(aperture, &self.fmm.cpuvm_aperture) {
+            /* On APUs GPU unmapping of system memory is a no-op */
+            0
+        } else {
+            self._fmm_unmap_from_gpu(aperture, address, &[], object)
+        };
+
+        if ret != 0 {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsakmt_fmm_allocate_scratch(
+        &mut self,
+        gpu_id: u32,
+        address: *mut std::os::raw::c_void,
+        MemorySizeInBytes: u64,
+    ) -> *mut std::os::raw::c_void {
+        /* Retrieve gpu_mem id according to gpu_id */
+        let gpu_mem_id = self.gpu_mem_find_by_gpu_id(gpu_id);
+        if gpu_mem_id < 0 {
+            return std::ptr::null_mut();
+        }
+        let gpu_mem_id = gpu_mem_id as usize;
+
+        /* Fragment alignment of the ASIC, but at least what the scratch
+         * base register can address
+         */
+        let device_id = self.fmm.gpu_mem[gpu_mem_id].device_id;
+        let align = MAX(self.get_vm_alignment(device_id) as u64, SCRATCH_ALIGN);
+        let aligned_size = ALIGN_UP(MemorySizeInBytes, align);
+
+        {
+            let aperture_phy = &self.fmm.gpu_mem[gpu_mem_id].scratch_physical;
+            if !aperture_phy.base.is_null() || !aperture_phy.limit.is_null() {
+                /* Scratch was already allocated for this GPU */
+                return std::ptr::null_mut();
+            }
+        }
+
+        let g_args = HsakmtGlobalsArgs {
+            page_size: self.PAGE_SIZE(),
+            fmm_svm_alignment_order: self.fmm.svm.alignment_order,
+        };
+
+        /* Allocate address space for scratch backing */
+        let mem = if self.hsakmt_is_dgpu {
+            aperture_allocate_area_aligned(
+                &mut *self.fmm.svm.dgpu_aperture,
+                address,
+                aligned_size,
+                align,
+                g_args,
+            )
+        } else {
+            if !address.is_null() {
+                return std::ptr::null_mut();
+            }
+
+            hsakmt_mmap_allocate_aligned(
+                PROT_NONE,
+                MAP_ANONYMOUS | MAP_NORESERVE | MAP_PRIVATE,
+                aligned_size,
+                align,
+                0,
+                std::ptr::null_mut(),
+                i64::MAX as *mut std::os::raw::c_void,
+                g_args,
+            )
+        };
+
+        if mem.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        /* Remember scratch backing aperture for later */
+        let aperture_phy = &mut self.fmm.gpu_mem[gpu_mem_id].scratch_physical;
+        aperture_phy.base = mem;
+        aperture_phy.limit = VOID_PTR_ADD(mem, aligned_size - 1);
+        aperture_phy.is_cpu_accessible = true;
+
+        /* Program SH_HIDDEN_PRIVATE_BASE */
+        let mut args = kfd_ioctl_set_scratch_backing_va_args {
+            va_addr: (mem as u64) >> 16,
+            gpu_id,
+            pad: 0,
+        };
+
+        if hsakmt_ioctl(
+            self.hsakmt_kfd_fd,
+            AMDKFD_IOC_SET_SCRATCH_BACKING_VA,
+            &mut args as *mut _ as *mut std::os::raw::c_void,
+        ) != 0
+        {
+            self.fmm_release_scratch(gpu_id);
+            return std::ptr::null_mut();
+        }
+
+        mem
+    }
+
+    pub unsafe fn fmm_release_scratch(&mut self, gpu_id: u32) {
+        let gpu_mem_id = self.gpu_mem_find_by_gpu_id(gpu_id);
+        if gpu_mem_id < 0 {
+            return;
+        }
+        let gpu_mem_id = gpu_mem_id as usize;
+
+        let aperture: *mut manageable_aperture_t =
+            &mut self.fmm.gpu_mem[gpu_mem_id].scratch_physical;
+
+        let base = (*aperture).base;
+        let size = VOID_PTRS_SUB((*aperture).limit, base) + 1;
+
+        if self.hsakmt_is_dgpu {
+            /* unmap and remove all remaining objects */
+            loop {
+                let obj = (*aperture).tree.any();
+                if obj.is_null() {
+                    break;
+                }
+
+                self._fmm_unmap_from_gpu_scratch(gpu_id, aperture, (*obj).start);
+
+                /* An object that could not be unmapped is still in the
+                 * tree. Free it anyway so that the loop makes progress.
+                 */
+                if (*aperture).tree.any() == obj && self.__fmm_release(obj, aperture) != 0 {
+                    self.vm_remove_object(aperture, obj);
+                }
+            }
+
+            let g_args = HsakmtGlobalsArgs {
+                page_size: self.PAGE_SIZE(),
+                fmm_svm_alignment_order: self.fmm.svm.alignment_order,
+            };
+
+            /* release address space */
+            aperture_release_area(&mut *self.fmm.svm.dgpu_aperture, base, size, g_args);
+        } else {
+            /* release address space */
+            munmap(base, size as usize);
+        }
+
+        /* invalidate scratch backing aperture */
+        (*aperture).base = std::ptr::null_mut();
+        (*aperture).limit = std::ptr::null_mut();
+    }
+
+    pub unsafe fn fmm_find_aperture(
+        &mut self,
+        address: *const std::os::raw::c_void,
+        info: &mut HsaApertureInfo,
+    ) -> *mut manageable_aperture_t<'static> {
+        let mut aperture: *mut manageable_aperture_t = std::ptr::null_mut();
+        let mut _info = HsaApertureInfo::default();
+
+        let address = address as *mut std::os::raw::c_void;
+
+        if address >= self.fmm.mem_handle_aperture.base
+            && address <= self.fmm.mem_handle_aperture.limit
+        {
+            aperture = &mut self.fmm.mem_handle_aperture;
+            _info.type_ = HSA_APERTURE_MEMHANDLE;
+        } else if self.hsakmt_is_dgpu {
+            if address >= (*self.fmm.svm.dgpu_aperture).base
+                && address <= (*self.fmm.svm.dgpu_aperture).limit
+            {
+                aperture = self.fmm.svm.dgpu_aperture;
+                _info.type_ = HSA_APERTURE_DGPU;
+            } else if address >= (*self.fmm.svm.dgpu_alt_aperture).base
+                && address <= (*self.fmm.svm.dgpu_alt_aperture).limit
+            {
+                aperture = self.fmm.svm.dgpu_alt_aperture;
+                _info.type_ = HSA_APERTURE_DGPU_ALT;
+            } else {
+                /* Not in SVM, it can be system memory registered by userptr */
+                aperture = self.fmm.svm.dgpu_aperture;
+                _info.type_ = HSA_APERTURE_DGPU;
+            }
+        } else {
+            /* APU */
+            if !self.fmm.svm.dgpu_aperture.is_null()
+                && address >= (*self.fmm.svm.dgpu_aperture).base
+                && address <= (*self.fmm.svm.dgpu_aperture).limit
+            {
+                aperture = self.fmm.svm.dgpu_aperture;
+                _info.type_ = HSA_APERTURE_DGPU;
+            } else {
+                /* gpuvm_aperture */
+                for i in 0..self.fmm.gpu_mem.len() {
+                    if address >= self.fmm.gpu_mem[i].gpuvm_aperture.base
+                        && address <= self.fmm.gpu_mem[i].gpuvm_aperture.limit
+                    {
+                        aperture = &mut self.fmm.gpu_mem[i].gpuvm_aperture;
+                        _info.type_ = HSA_APERTURE_GPUVM;
+                        _info.idx = i as u32;
+                    }
+                }
+            }
+
+            if aperture.is_null() {
+                /* Not in GPUVM */
+                aperture = &mut self.fmm.cpuvm_aperture;
+                _info.type_ = HSA_APERTURE_CPUVM;
+            }
+        }
+
+        *info = _info;
+
+        aperture
+    }
+
+    pub fn fmm_get_aperture(
+        &mut self,
+        info: HsaApertureInfo,
+    ) -> *mut manageable_aperture_t<'static> {
+        match info.type_ {
+            HSA_APERTURE_DGPU => self.fmm.svm.dgpu_aperture,
+            HSA_APERTURE_DGPU_ALT => self.fmm.svm.dgpu_alt_aperture,
+            HSA_APERTURE_GPUVM => match self.fmm.gpu_mem.get_mut(info.idx as usize) {
+                Some(gpu_mem) => &mut gpu_mem.gpuvm_aperture,
+                None => std::ptr::null_mut(),
+            },
+            HSA_APERTURE_CPUVM => &mut self.fmm.cpuvm_aperture,
+            HSA_APERTURE_MEMHANDLE => &mut self.fmm.mem_handle_aperture,
+            HSA_APERTURE_UNSUPPORTED => std::ptr::null_mut(),
+        }
+    }
+
+    pub unsafe fn hsakmt_fmm_share_memory(
+        &mut self,
+        MemoryAddress: *mut std::os::raw::c_void,
+        SizeInBytes: u64,
+        SharedMemoryHandle: &mut HsaSharedMemoryHandle,
+    ) -> HsakmtStatus {
+        let mut gpu_id: u32 = 0;
+        let mut ApeInfo = HsaApertureInfo::default();
+
+        let page_shift = self.hsakmt_page_shift as u64;
+
+        if SizeInBytes >= (1u64 << (u32::BITS as u64 + page_shift)) {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let aperture = self.fmm_find_aperture(MemoryAddress, &mut ApeInfo);
+        if aperture.is_null() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let obj = vm_find_object_by_address(aperture, MemoryAddress, 0);
+        if obj.is_null() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let obj_st = &mut (*obj);
+
+        let r = self.hsakmt_validate_nodeid(obj_st.node_id, &mut gpu_id);
+        if r != HSAKMT_STATUS_SUCCESS {
+            return r;
+        }
+
+        if gpu_id == 0 && self.hsakmt_is_dgpu {
+            /* Sharing non paged system memory. Use first GPU which was
+             * used during allocation. See fmm_allocate_host_gpu()
+             */
+            gpu_id = self.fmm.gpu_mem[0].gpu_id;
+        }
+
+        /* The share handle lives as long as the exporting buffer, hand
+         * out the same one if this buffer was already exported.
+         */
+        if let Some(handle) = obj_st.shared_handle {
+            let mut SharedMemoryStruct = HsaSharedMemoryStruct::from_handle(&handle);
+
+            if SharedMemoryStruct.ExportGpuId == gpu_id && SharedMemoryStruct.ApeInfo == ApeInfo {
+                SharedMemoryStruct.SizeInPages = (SizeInBytes >> page_shift) as u32;
+                *SharedMemoryHandle = SharedMemoryStruct.to_handle();
+
+                return HSAKMT_STATUS_SUCCESS;
+            }
+        }
+
+        let mut exportArgs = kfd_ioctl_ipc_export_handle_args {
+            handle: obj_st.handle,
+            gpu_id,
+            ..Default::default()
+        };
+
+        let r = hsakmt_ioctl(
+            self.hsakmt_kfd_fd,
+            AMDKFD_IOC_IPC_EXPORT_HANDLE,
+            &mut exportArgs as *mut _ as *mut std::os::raw::c_void,
+        );
+        if r != 0 {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        let SharedMemoryStruct = HsaSharedMemoryStruct {
+            ShareHandle: exportArgs.share_handle,
+            ApeInfo,
+            SizeInPages: (SizeInBytes >> page_shift) as u32,
+            ExportGpuId: gpu_id,
+        };
+
+        *SharedMemoryHandle = SharedMemoryStruct.to_handle();
+        obj_st.shared_handle = Some(*SharedMemoryHandle);
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsakmt_fmm_register_shared_memory(
+        &mut self,
+        SharedMemoryHandle: &HsaSharedMemoryHandle,
+        SizeInBytes: &mut u64,
+        MemoryAddress: &mut *mut std::os::raw::c_void,
+        gpu_id_array: Vec<u32>,
+    ) -> HsakmtStatus {
+        let SharedMemoryStruct = HsaSharedMemoryStruct::from_handle(SharedMemoryHandle);
+
+        let page_shift = self.hsakmt_page_shift as u64;
+        let size = (SharedMemoryStruct.SizeInPages as u64) << page_shift;
+
+        let mut importArgs = kfd_ioctl_ipc_import_handle_args {
+            share_handle: SharedMemoryStruct.ShareHandle,
+            gpu_id: SharedMemoryStruct.ExportGpuId,
+            ..Default::default()
+        };
+
+        let aperture = self.fmm_get_aperture(SharedMemoryStruct.ApeInfo);
+        if aperture.is_null() || (*aperture).ops.allocate_area_aligned.is_none() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let g_args = HsakmtGlobalsArgs {
+            page_size: self.PAGE_SIZE(),
+            fmm_svm_alignment_order: self.fmm.svm.alignment_order,
+        };
+
+        let reservedMem =
+            aperture_allocate_area(&mut *aperture, std::ptr::null_mut(), size, g_args);
+        if reservedMem.is_null() {
+            return HSAKMT_STATUS_NO_MEMORY;
+        }
+
+        importArgs.va_addr = reservedMem as u64;
+
+        let r = hsakmt_ioctl(
+            self.hsakmt_kfd_fd,
+            AMDKFD_IOC_IPC_IMPORT_HANDLE,
+            &mut importArgs as *mut _ as *mut std::os::raw::c_void,
+        );
+        if r != 0 {
+            aperture_release_area(&mut *aperture, reservedMem, size, g_args);
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        let mflags = fmm_translate_ioc_to_hsa_flags(importArgs.flags);
+        let host_access = mflags.st.ui32.HostAccess > 0;
+
+        let obj =
+            aperture_allocate_object(&mut *aperture, reservedMem, importArgs.handle, size, mflags);
+        self.fmm_account_object(aperture, obj, importArgs.gpu_id, FMM_HEAP_IMPORTED);
+
+        if importArgs.mmap_offset > 0 {
+            let gpu_mem_id = self.gpu_mem_find_by_gpu_id(importArgs.gpu_id);
+
+            let ret = if gpu_mem_id < 0 {
+                MAP_FAILED
+            } else {
+                (*obj).node_id = self.fmm.gpu_mem[gpu_mem_id as usize].node_id;
+                let map_fd = self.fmm.gpu_mem[gpu_mem_id as usize].drm_render_fd;
+
+                fmm_map_to_cpu(
+                    reservedMem,
+                    size,
+                    host_access,
+                    map_fd,
+                    importArgs.mmap_offset,
+                )
+            };
+
+            if ret == MAP_FAILED {
+                self.vm_remove_object(aperture, obj);
+                aperture_release_area(&mut *aperture, reservedMem, size, g_args);
+
+                let mut freeArgs = kfd_ioctl_free_memory_of_gpu_args {
+                    handle: importArgs.handle as *mut u64,
+                };
+                hsakmt_ioctl(
+                    self.hsakmt_kfd_fd,
+                    AMDKFD_IOC_FREE_MEMORY_OF_GPU,
+                    &mut freeArgs as *mut _ as *mut std::os::raw::c_void,
+                );
+
+                return HSAKMT_STATUS_ERROR;
+            }
+        }
+
+        *MemoryAddress = reservedMem;
+        *SizeInBytes = size;
+
+        (*obj).registered_device_id_array = gpu_id_array;
+        (*obj).is_imported_kfd_bo = true;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsakmt_fmm_deregister_memory(
+        &mut self,
+        address: *mut std::os::raw::c_void,
+    ) -> HsakmtStatus {
+        let mut aperture: *mut manageable_aperture_t = std::ptr::null_mut();
+
+        let object = self.vm_find_object(address, 0, &mut aperture);
+        if object.is_null() {
+            return HSAKMT_STATUS_MEMORY_NOT_REGISTERED;
+        }
+
+        let object_st = &mut (*object);
+
+        if !object_st.metadata.is_null()
+            || !object_st.userptr.is_null()
+            || object_st.is_imported_kfd_bo
+        {
+            /* An object with metadata is an imported graphics
+             * buffer. Deregistering imported graphics buffers or
+             * userptrs means releasing the BO.
+             */
+            self.__fmm_release(object, aperture);
+            return HSAKMT_STATUS_SUCCESS;
+        }
+
+        if object_st.registered_device_id_array.is_empty() {
+            return HSAKMT_STATUS_MEMORY_NOT_REGISTERED;
+        }
+
+        object_st.registered_device_id_array.clear();
+        object_st.registered_node_id_array.clear();
+        object_st.registration_count = 0;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub fn gpuid_to_nodeid(&self, gpu_id: u32, node_id: &mut u32) -> HsakmtStatus {
+        let gpu_mem_id = self.gpu_mem_find_by_gpu_id(gpu_id);
+        if gpu_mem_id < 0 {
+            return HSAKMT_STATUS_INVALID_NODE_UNIT;
+        }
+
+        *node_id = self.fmm.gpu_mem[gpu_mem_id as usize].node_id;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn fmm_allocate_va(
+        &self,
+        gpu_id: u32,
+        address: *mut std::os::raw::c_void,
+        size: u64,
+        aperture: *mut manageable_aperture_t,
+        alignment: u64,
+        mflags: HsaMemFlags,
+    ) -> *mut std::os::raw::c_void {
+        let g_args = HsakmtGlobalsArgs {
+            page_size: self.PAGE_SIZE(),
+            fmm_svm_alignment_order: self.fmm.svm.alignment_order,
+        };
+
+        let mem = aperture_allocate_area_aligned(&mut *aperture, address, size, alignment, g_args);
+        if mem.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let vm_obj = aperture_allocate_object(&mut *aperture, mem, 0, size, mflags);
+        self.gpuid_to_nodeid(gpu_id, &mut (*vm_obj).node_id);
+        self.fmm_account_object(aperture, vm_obj, gpu_id, FMM_HEAP_VA_ONLY);
+
+        mem
+    }
+
+    pub unsafe fn hsakmt_fmm_allocate_device(
+        &mut self,
+        gpu_id: u32,
+        _node_id: u32,
+        address: *mut std::os::raw::c_void,
+        MemorySizeInBytes: u64,
+        alignment: u64,
+        mflags: HsaMemFlags,
+    ) -> *mut std::os::raw::c_void {
+        let mut ioc_flags = KFD_IOC_ALLOC_MEM_FLAGS_VRAM as u32;
+        let mut vm_obj: *mut vm_object_t = std::ptr::null_mut();
+        let mut mmap_offset: u64 = 0;
+
+        /* Retrieve gpu_mem id according to gpu_id */
+        let gpu_mem_id = self.gpu_mem_find_by_gpu_id(gpu_id);
+        if gpu_mem_id < 0 {
+            return std::ptr::null_mut();
+        }
+        let gpu_mem_id = gpu_mem_id as usize;
+
+        let mut size = MemorySizeInBytes;
+
+        if mflags.st.ui32.HostAccess > 0 {
+            ioc_flags |= KFD_IOC_ALLOC_MEM_FLAGS_PUBLIC as u32;
+        }
+
+        ioc_flags |= fmm_translate_hsa_to_ioc_flags(&mflags);
+
+        let mut aperture: *mut manageable_aperture_t =
+            if self.hsakmt_topology_is_svm_needed(&self.fmm.gpu_mem[gpu_mem_id].EngineId) {
+                if mflags.st.ui32.AQLQueueMemory > 0 {
+                    size = MemorySizeInBytes * 2;
+                }
+                self.fmm.svm.dgpu_aperture
+            } else {
+                &mut self.fmm.gpu_mem[gpu_mem_id].gpuvm_aperture
+            };
+
+        /* special case for va allocation without vram alloc */
+        if mflags.st.ui32.OnlyAddress > 0 {
+            return self.fmm_allocate_va(gpu_id, address, size, aperture, alignment, mflags);
+        }
+
+        /* special case for vram allocation without addr */
+        if mflags.st.ui32.NoAddress > 0 {
+            aperture = &mut self.fmm.mem_handle_aperture;
+        }
+
+        if mflags.st.ui32.CoarseGrain == 0 || self.fmm.svm.disable_cache {
+            ioc_flags |= KFD_IOC_ALLOC_MEM_FLAGS_COHERENT as u32;
+        }
+
+        if mflags.st.ui32.Uncached > 0 || self.fmm.svm.disable_cache {
+            ioc_flags |= KFD_IOC_ALLOC_MEM_FLAGS_UNCACHED as u32;
+        }
+
+        if mflags.st.ui32.Contiguous > 0 {
+            ioc_flags |= KFD_IOC_ALLOC_MEM_FLAGS_CONTIGUOUS_BEST_EFFORT as u32;
+        }
+
+        let mem = self.__fmm_allocate_device(
+            gpu_id,
+            address,
+            size,
+            aperture,
+            &mut mmap_offset,
+            ioc_flags,
+            alignment,
+            &mut vm_obj,
+        );
+
+        if mem.is_null() || vm_obj.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        /* Store memory allocation flags, not ioc flags */
+        (*vm_obj).mflags = mflags;
+        self.gpuid_to_nodeid(gpu_id, &mut (*vm_obj).node_id);
+
+        /* Handle-only allocations have no CPU address to map until
+         * they are bound, see hsakmt_fmm_bind_memory_handle
+         */
+        if mflags.st.ui32.NoAddress > 0 {
+            return mem;
+        }
+
+        let map_fd = self.fmm.gpu_mem[gpu_mem_id].drm_render_fd;
+        let (prot, flag) = if mflags.st.ui32.HostAccess > 0 {
+            (PROT_READ | PROT_WRITE, MAP_SHARED | MAP_FIXED)
+        } else {
+            (PROT_NONE, MAP_PRIVATE | MAP_FIXED)
+        };
+
+        let ret = mmap(
+            mem,
+            MemorySizeInBytes as usize,
+            prot,
+            flag,
+            map_fd,
+            mmap_offset as off_t,
+        );
+
+        if ret == MAP_FAILED {
+            self.__fmm_release(vm_obj, aperture);
+            return std::ptr::null_mut();
+        }
+
+        /*
+         * This madvise() call is needed to avoid additional references
+         * to mapped BOs in child processes that can prevent freeing
+         * memory in the parent process and lead to out-of-memory
+         * conditions.
+         */
+        madvise(mem, MemorySizeInBytes as usize, MADV_DONTFORK);
+
+        mem
+    }
+
+    /* System memory on APUs is plain anonymous memory the GPU reaches
+     * through the IOMMU, tracked in the CPUVM aperture
+     */
+    pub unsafe fn fmm_allocate_host_cpu(
+        &mut self,
+        node_id: u32,
+        address: *mut std::os::raw::c_void,
+        MemorySizeInBytes: u64,
+        mflags: HsaMemFlags,
+    ) -> *mut std::os::raw::c_void {
+        let mut mmap_prot = PROT_READ;
+
+        if !address.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        if mflags.st.ui32.ExecuteAccess > 0 {
+            mmap_prot |= PROT_EXEC;
+        }
+
+        if mflags.st.ui32.ReadOnly == 0 {
+            mmap_prot |= PROT_WRITE;
+        }
+
+        /* mmap will return a pointer with alignment equal to
+         * sysconf(_SC_PAGESIZE).
+         */
+        let mem = mmap(
+            std::ptr::null_mut(),
+            MemorySizeInBytes as usize,
+            mmap_prot,
+            MAP_ANONYMOUS | MAP_PRIVATE,
+            -1,
+            0,
+        );
+
+        if mem == MAP_FAILED {
+            return std::ptr::null_mut();
+        }
+
+        if self.fmm_bind_host_mem(node_id, mem, MemorySizeInBytes, mflags) != 0 {
+            munmap(mem, MemorySizeInBytes as usize);
+            return std::ptr::null_mut();
+        }
+
+        let aperture: *mut manageable_aperture_t = &mut self.fmm.cpuvm_aperture;
+        let vm_obj = aperture_allocate_object(&mut *aperture, mem, 0, MemorySizeInBytes, mflags);
+
+        /* APU systems only have one CPU node */
+        (*vm_obj).node_id = 0;
+        self.fmm_account_object(aperture, vm_obj, 0, FMM_HEAP_SYSTEM);
+
+        mem
+    }
+
+    /* System memory on dGPUs lives in the SVM apertures. Paged memory is
+     * anonymous memory registered as a userptr BO, bound to a NUMA node
+     * before it is first touched. Non-paged memory is a GTT BO, its pages
+     * come from the device's NUMA node in the kernel, so a NUMA policy
+     * naming nodes can't be honored and GTT BOs are refused under one
+     * unless mflags has NoNUMABind.
+     */
+    pub unsafe fn fmm_allocate_host_gpu(
+        &mut self,
+        mut gpu_id: u32,
+        node_id: u32,
+        address: *mut std::os::raw::c_void,
+        MemorySizeInBytes: u64,
+        mflags: HsaMemFlags,
+    ) -> *mut std::os::raw::c_void {
+        let mut mmap_offset: u64 = 0;
+        let mut vm_obj: *mut vm_object_t = std::ptr::null_mut();
+        let mut ioc_flags: u32 = 0;
+        let mut size = MemorySizeInBytes;
+
+        /* CPU nodes use the first GPU to create the BO */
+        if gpu_id == 0 {
+            let Some(first) = self
+                .fmm
+                .gpu_mem
+                .iter()
+                .find(|g| g.gpu_id != NON_VALID_GPU_ID as u32)
+            else {
+                return std::ptr::null_mut();
+            };
+
+            gpu_id = first.gpu_id;
+        }
+
+        let gpu_mem_id = self.gpu_mem_find_by_gpu_id(gpu_id);
+        if gpu_mem_id < 0 {
+            return std::ptr::null_mut();
+        }
+        let gpu_mem_id = gpu_mem_id as usize;
+
+        let aperture = if mflags.st.ui32.CoarseGrain > 0 {
+            self.fmm.svm.dgpu_aperture
+        } else {
+            /* always coherent */
+            self.fmm.svm.dgpu_alt_aperture
+        };
+
+        if aperture.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        ioc_flags |= fmm_translate_hsa_to_ioc_flags(&mflags);
+
+        if mflags.st.ui32.AQLQueueMemory > 0 {
+            size = MemorySizeInBytes * 2;
+        }
+
+        let mem = if mflags.st.ui32.NonPaged == 0 && self.fmm.svm.userptr_for_paged_mem {
+            let g_args = HsakmtGlobalsArgs {
+                page_size: self.PAGE_SIZE(),
+                fmm_svm_alignment_order: self.fmm.svm.alignment_order,
+            };
+
+            let mem = aperture_allocate_area(&mut *aperture, address, size, g_args);
+            if mem.is_null() {
+                return std::ptr::null_mut();
+            }
+
+            /* Map anonymous pages */
+            let ret = mmap(
+                mem,
+                MemorySizeInBytes as usize,
+                PROT_READ | PROT_WRITE,
+                MAP_ANONYMOUS | MAP_PRIVATE | MAP_FIXED,
+                -1,
+                0,
+            );
+
+            if ret == MAP_FAILED
+                || self.fmm_bind_host_mem(node_id, mem, MemorySizeInBytes, mflags) != 0
+            {
+                aperture_release_area(&mut *aperture, mem, size, g_args);
+                return std::ptr::null_mut();
+            }
+
+            /* Mappings in the DGPU aperture don't need to be copied on
+             * fork. This avoids MMU notifiers and evictions due to user
+             * memory mappings on fork.
+             */
+            madvise(mem, MemorySizeInBytes as usize, MADV_DONTFORK);
+
+            /* Create userptr BO */
+            mmap_offset = mem as u64;
+            ioc_flags |= KFD_IOC_ALLOC_MEM_FLAGS_USERPTR as u32;
+
+            vm_obj = self.fmm_allocate_memory_object(
+                gpu_id,
+                mem,
+                size,
+                &mut *aperture,
+                &mut mmap_offset,
+                ioc_flags,
+            );
+
+            if vm_obj.is_null() {
+                aperture_release_area(&mut *aperture, mem, size, g_args);
+                return std::ptr::null_mut();
+            }
+
+            mem
+        } else {
+            if mflags.st.ui32.NoNUMABind == 0
+                && matches!(
+                    self.fmm.numa_policy,
+                    fmm_numa_policy_t::FMM_NUMA_BIND(_) | fmm_numa_policy_t::FMM_NUMA_INTERLEAVE(_)
+                )
+            {
+                return std::ptr::null_mut();
+            }
+
+            ioc_flags |= KFD_IOC_ALLOC_MEM_FLAGS_GTT as u32;
+
+            let mem = self.__fmm_allocate_device(
+                gpu_id,
+                address,
+                size,
+                aperture,
+                &mut mmap_offset,
+                ioc_flags,
+                0,
+                &mut vm_obj,
+            );
+
+            if mem.is_null() || vm_obj.is_null() {
+                return std::ptr::null_mut();
+            }
+
+            if mflags.st.ui32.HostAccess > 0 {
+                let map_fd = if mmap_offset >= (1 << 40) {
+                    self.hsakmt_kfd_fd
+                } else {
+                    self.fmm.gpu_mem[gpu_mem_id].drm_render_fd
+                };
+
+                let ret = fmm_map_to_cpu(mem, MemorySizeInBytes, true, map_fd, mmap_offset);
+
+                if ret == MAP_FAILED {
+                    self.__fmm_release(vm_obj, aperture);
+                    return std::ptr::null_mut();
+                }
+            }
+
+            mem
+        };
+
+        /* Store memory allocation flags, not ioc flags */
+        (*vm_obj).mflags = mflags;
+        self.gpuid_to_nodeid(gpu_id, &mut (*vm_obj).node_id);
+
+        mem
+    }
+
+    /* System memory for node_id, a CPU node or the GPU that will use it.
+     * Placement follows the NUMA policy, see hsakmt_fmm_set_numa_policy,
+     * except for the GTT BOs of dGPUs, which the kernel places near the
+     * device: they fail under a policy binding or interleaving nodes,
+     * unless mflags has NoNUMABind.
+     */
+    pub unsafe fn hsakmt_fmm_allocate_host(
+        &mut self,
+        gpu_id: u32,
+        node_id: u32,
+        address: *mut std::os::raw::c_void,
+        MemorySizeInBytes: u64,
+        mflags: HsaMemFlags,
+    ) -> *mut std::os::raw::c_void {
+        if self.hsakmt_is_dgpu {
+            return self.fmm_allocate_host_gpu(gpu_id, node_id, address, MemorySizeInBytes, mflags);
+        }
+
+        self.fmm_allocate_host_cpu(node_id, address, MemorySizeInBytes, mflags)
+    }
+
+    /* Bind a handle-only (NoAddress) allocation to a virtual address
+     *
+     * The buffer behind MemoryHandle is exported as a DMA-buf and
+     * imported again at an address in the SVM or GPUVM aperture. If
+     * *MemoryAddress is not NULL the buffer is bound at exactly that
+     * address. The bound mapping holds its own reference to the buffer
+     * and is released with hsakmt_fmm_release; the handle stays valid
+     * until it is released as well.
+     */
+    pub unsafe fn hsakmt_fmm_bind_memory_handle(
+        &mut self,
+        MemoryHandle: *mut std::os::raw::c_void,
+        MemoryAddress: &mut *mut std::os::raw::c_void,
+    ) -> HsakmtStatus {
+        let mut gpu_id: u32 = 0;
+
+        if MemoryHandle < self.fmm.mem_handle_aperture.base
+            || MemoryHandle > self.fmm.mem_handle_aperture.limit
+        {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let obj = vm_find_object_by_address(&mut self.fmm.mem_handle_aperture, MemoryHandle, 0);
+        if obj.is_null() {
+            return HSAKMT_STATUS_MEMORY_NOT_REGISTERED;
+        }
+
+        let handle = (*obj).handle;
+        let size = (*obj).size;
+        let node_id = (*obj).node_id;
+        let mflags = (*obj).mflags;
+
+        let ret = self.hsakmt_validate_nodeid(node_id, &mut gpu_id);
+        if ret != HSAKMT_STATUS_SUCCESS {
+            return ret;
+        }
+
+        let gpu_mem_id = self.gpu_mem_find_by_gpu_id(gpu_id);
+        if gpu_mem_id < 0 {
+            return HSAKMT_STATUS_INVALID_NODE_UNIT;
+        }
+
+        let aperture: *mut manageable_aperture_t = if self
+            .hsakmt_topology_is_svm_needed(&self.fmm.gpu_mem[gpu_mem_id as usize].EngineId)
+        {
+            self.fmm.svm.dgpu_aperture
+        } else {
+            &mut self.fmm.gpu_mem[gpu_mem_id as usize].gpuvm_aperture
+        };
+
+        if aperture.is_null() {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        let mut exportArgs = kfd_ioctl_export_dmabuf_args {
+            handle,
+            flags: (O_CLOEXEC | O_RDWR) as u32,
+            ..Default::default()
+        };
+
+        let r = hsakmt_ioctl(
+            self.hsakmt_kfd_fd,
+            AMDKFD_IOC_EXPORT_DMABUF,
+            &mut exportArgs as *mut _ as *mut std::os::raw::c_void,
+        );
+        if r != 0 {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        let dmabuf_fd = exportArgs.dmabuf_fd as i32;
+
+        let g_args = HsakmtGlobalsArgs {
+            page_size: self.PAGE_SIZE(),
+            fmm_svm_alignment_order: self.fmm.svm.alignment_order,
+        };
+
+        let mem = aperture_allocate_area_aligned(&mut *aperture, *MemoryAddress, size, 0, g_args);
+        if mem.is_null() {
+            close(dmabuf_fd);
+            return HSAKMT_STATUS_NO_MEMORY;
+        }
+
+        let mut importArgs = kfd_ioctl_import_dmabuf_args {
+            va_addr: mem as u64,
+            gpu_id,
+            dmabuf_fd: dmabuf_fd as u32,
+            ..Default::default()
+        };
+
+        let r = hsakmt_ioctl(
+            self.hsakmt_kfd_fd,
+            AMDKFD_IOC_IMPORT_DMABUF,
+            &mut importArgs as *mut _ as *mut std::os::raw::c_void,
+        );
+        if r != 0 {
+            aperture_release_area(&mut *aperture, mem, size, g_args);
+            close(dmabuf_fd);
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        let vm_obj = aperture_allocate_object(&mut *aperture, mem, importArgs.handle, size, mflags);
+        (*vm_obj).node_id = node_id;
+        self.fmm_account_object(aperture, vm_obj, gpu_id, FMM_HEAP_IMPORTED);
+        (*vm_obj).is_imported_kfd_bo = true;
+
+        if mflags.st.ui32.HostAccess > 0 {
+            let ret = mmap(
+                mem,
+                size as usize,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED | MAP_FIXED,
+                dmabuf_fd,
+                0,
+            );
+
+            if ret == MAP_FAILED {
+                self.__fmm_release(vm_obj, aperture);
+                close(dmabuf_fd);
+                return HSAKMT_STATUS_ERROR;
+            }
+
+            madvise(mem, size as usize, MADV_DONTFORK);
+        }
+
+        /* The imported BO keeps its own reference to the buffer */
+        close(dmabuf_fd);
+
+        *MemoryAddress = mem;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsakmt_fmm_release(
+        &mut self,
+        address: *mut std::os::raw::c_void,
+    ) -> HsakmtStatus {
+        let mut aperture: *mut manageable_aperture_t = std::ptr::null_mut();
+
+        /* Special handling for scratch memory */
+        for i in 0..self.fmm.gpu_mem.len() {
+            if self.fmm.gpu_mem[i].gpu_id != NON_VALID_GPU_ID as u32
+                && address >= self.fmm.gpu_mem[i].scratch_physical.base
+                && address <= self.fmm.gpu_mem[i].scratch_physical.limit
+            {
+                let gpu_id = self.fmm.gpu_mem[i].gpu_id;
+                self.fmm_release_scratch(gpu_id);
+                return HSAKMT_STATUS_SUCCESS;
+            }
+        }
+
+        let object = self.vm_find_object(address, 0, &mut aperture);
+
+        if object.is_null() {
+            return if self.hsakmt_is_svm_api_supported {
+                HSAKMT_STATUS_SUCCESS
+            } else {
+                HSAKMT_STATUS_MEMORY_NOT_REGISTERED
+            };
+        }
+
+        if std::ptr::eq(aperture, &self.fmm.cpuvm_aperture) {
+            /* APU system memory */
+            let size = (*object).size;
+
+            self.vm_remove_object(aperture, object);
+            munmap(address, size as usize);
+        } else if self.__fmm_release(object, aperture) != 0 {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* KFD handle of the allocation at address, used where KFD wants to
+     * know about memory the thunk allocated, like the events page
+     */
+    pub unsafe fn hsakmt_fmm_get_handle(
+        &mut self,
+        address: *mut std::os::raw::c_void,
+        handle: &mut u64,
+    ) -> HsakmtStatus {
+        let mut aperture: *mut manageable_aperture_t = std::ptr::null_mut();
+
+        let object = self.vm_find_object(address, 0, &mut aperture);
+        if object.is_null() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        *handle = (*object).handle;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsakmt_fmm_allocate_doorbell(
+        &mut self,
+        gpu_id: u32,
+        MemorySizeInBytes: u64,
+        doorbell_mmap_offset: u64,
+    ) -> *mut std::os::raw::c_void {
+        let gpu_mem_id = self.gpu_mem_find_by_gpu_id(gpu_id);
+        if gpu_mem_id < 0 {
+            return std::ptr::null_mut();
+        }
+
+        /* Use fine-grained aperture */
+        let aperture = self.fmm.svm.dgpu_alt_aperture;
+        if aperture.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let ioc_flags = KFD_IOC_ALLOC_MEM_FLAGS_DOORBELL
+            | KFD_IOC_ALLOC_MEM_FLAGS_WRITABLE
+            | KFD_IOC_ALLOC_MEM_FLAGS_COHERENT;
+
+        let mut vm_obj: *mut vm_object_t = std::ptr::null_mut();
+        let mut mmap_offset: u64 = 0;
+
+        let mem = self.__fmm_allocate_device(
+            gpu_id,
+            std::ptr::null_mut(),
+            MemorySizeInBytes,
+            aperture,
+            &mut mmap_offset,
+            ioc_flags as u32,
+            0,
+            &mut vm_obj,
+        );
+
+        if mem.is_null() || vm_obj.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        /* Cook up some flags for storing in the VM object */
+        let mut mflags = HsaMemFlags {
+            st: HsaMemFlagUnion { Value: 0 },
+        };
+        mflags.st.Value = 0;
+        mflags.st.ui32.NonPaged = 1;
+        mflags.st.ui32.HostAccess = 1;
+        mflags.st.ui32.Reserved = 0xBE11;
+
+        let vm_obj_st = &mut (*vm_obj);
+        vm_obj_st.mflags = mflags;
+        vm_obj_st.node_id = self.fmm.gpu_mem[gpu_mem_id as usize].node_id;
+
+        let ret = mmap(
+            mem,
+            MemorySizeInBytes as usize,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED | MAP_FIXED,
+            self.hsakmt_kfd_fd,
+            doorbell_mmap_offset as off_t,
+        );
+
+        if ret == MAP_FAILED {
+            self.__fmm_release(vm_obj, aperture);
+            return std::ptr::null_mut();
+        }
+
+        mem
+    }
+
+    pub unsafe fn map_mmio(
+        &mut self,
+        node_id: u32,
+        gpu_id: u32,
+        mmap_fd: i32,
+    ) -> *mut std::os::raw::c_void {
+        let aperture_ptr = self.fmm.svm.dgpu_alt_aperture;
+        if aperture_ptr.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let aperture = &mut *(aperture_ptr);
+        // println!("aperture {:#?}", aperture);
+
+        let mut vm_obj: *mut vm_object_t = std::ptr::null_mut();
+
+        let mut mflags = HsaMemFlags {
+            st: HsaMemFlagUnion { Value: 0 },
+        };
+
+        let mut mmap_offset: u64 = 0;
+
+        /* Allocate physical memory and vm object*/
+        let ioc_flags = KFD_IOC_ALLOC_MEM_FLAGS_MMIO_REMAP
+            | KFD_IOC_ALLOC_MEM_FLAGS_WRITABLE
+            | KFD_IOC_ALLOC_MEM_FLAGS_COHERENT;
 
-        /* If HSA_RESERVE_SVM is set to a non-0 value,
-         * enable packet capture and replay mode.
-         */
-        let env_str = CString::new("HSA_RESERVE_SVM").unwrap();
-        let reserveSvm = getenv(env_str.as_ptr());
-        self.fmm.svm.reserve_svm =
-            !reserveSvm.is_null() && strcmp(reserveSvm, zero_str.as_ptr()) == 0;
-
-        // let format_cs = CString::new("%u").unwrap();
-
-        /* Specify number of guard pages for SVM apertures, default is 1 */
-        // let env_str = CString::new("HSA_SVM_GUARD_PAGES").unwrap();
-        // let guardPagesStr = getenv(env_str.as_ptr());
-        // if !guardPagesStr.is_null() || sscanf(guardPagesStr, format_cs.as_ptr(), &guardPages) != 1 {
-        //     guardPages = 1;
-        // }
+        let page_size = self.PAGE_SIZE();
 
-        /* Sets the max VA alignment order size during mapping. By default the order
-         * size is set to 9(2MB)
-         */
-        // let env_str = CString::new("HSA_MAX_VA_ALIGN").unwrap();
-        // let maxVaAlignStr = getenv(env_str.as_ptr());
-        // if !maxVaAlignStr.is_null() || sscanf(maxVaAlignStr, format_cs.as_ptr(), &svm.alignment_order) != 1 {
-        //     svm.alignment_order = 9;
-        // }
-        self.fmm.svm.alignment_order = 9;
+        let mem = self.__fmm_allocate_device(
+            gpu_id,
+            std::ptr::null_mut(),
+            page_size as u64,
+            aperture,
+            &mut mmap_offset,
+            ioc_flags as u32,
+            0,
+            &mut vm_obj,
+        );
+
+        if mem.is_null() || vm_obj.is_null() {
+            println!("error mem {} vm_obj {}", mem.is_null(), vm_obj.is_null());
+
+            return std::ptr::null_mut();
+        }
+
+        // println!("mem {} vm_obj {}", mem.is_null(), vm_obj.is_null());
+
+        mflags.st.Value = 0;
+        mflags.st.ui32.NonPaged = 1;
+        mflags.st.ui32.HostAccess = 1;
+
+        let vm_obj_st = &mut (*vm_obj);
+
+        vm_obj_st.mflags = mflags;
+        vm_obj_st.node_id = node_id;
+
+        let page_size = self.PAGE_SIZE();
+
+        // println!("Map for CPU access mmap_offset {}", mmap_offset);
+
+        /* Map for CPU access*/
+        let ret = mmap(
+            mem,
+            page_size as usize,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED | MAP_FIXED,
+            mmap_fd,
+            mmap_offset as off_t,
+        );
+
+        // println!("mmap ret {:?} {}", ret, ret.is_null());
+
+        if ret == MAP_FAILED {
+            self.__fmm_release(vm_obj, aperture);
+            return std::ptr::null_mut();
+        }
+
+        /* Map for GPU access*/
+        let ret = self.hsakmt_fmm_map_to_gpu(mem, page_size as u64, std::ptr::null_mut());
+
+        if ret != HSAKMT_STATUS_SUCCESS {
+            println!("hsakmt_fmm_map_to_gpu error {:?}", ret);
+            self.__fmm_release(vm_obj, aperture);
+            return std::ptr::null_mut();
+        }
+
+        mem
+    }
+
+    pub unsafe fn release_mmio(&mut self) {
+        let page_size = self.PAGE_SIZE();
+
+        for gpu_mem_id in 0..self.fmm.gpu_mem.len() {
+            let mmio_base = self.fmm.gpu_mem[gpu_mem_id].mmio_aperture.base;
+
+            if mmio_base.is_null() {
+                continue;
+            }
+
+            self.hsakmt_fmm_unmap_from_gpu(mmio_base);
+            munmap(mmio_base, page_size as usize);
+            self.hsakmt_fmm_release(mmio_base);
+
+            self.fmm.gpu_mem[gpu_mem_id].mmio_aperture.base = std::ptr::null_mut();
+            self.fmm.gpu_mem[gpu_mem_id].mmio_aperture.limit = std::ptr::null_mut();
+        }
+    }
+
+    /* Remapped MMIO (HDP flush) page of a node, NULL if the kernel
+     * does not support MMIO remapping or the node has no GPU
+     */
+    pub fn hsakmt_fmm_get_mmio_base(&self, node_id: u32) -> *mut std::os::raw::c_void {
+        self.fmm
+            .gpu_mem
+            .iter()
+            .find(|gpu_m| gpu_m.gpu_id != NON_VALID_GPU_ID as u32 && gpu_m.node_id == node_id)
+            .map_or(std::ptr::null_mut(), |gpu_m| gpu_m.mmio_aperture.base)
+    }
+
+    pub fn hsakmt_fmm_get_amdgpu_device_handle(
+        &self,
+        node_id: u32,
+        DeviceHandle: &mut amdgpu_device_handle,
+    ) -> HsakmtStatus {
+        let gpu_mem_id = self.gpu_mem_find_by_node_id(node_id);
+        if gpu_mem_id < 0 {
+            return HSAKMT_STATUS_INVALID_NODE_UNIT;
+        }
+
+        let minor = self.fmm.gpu_mem[gpu_mem_id as usize].drm_render_minor as usize;
+        if !(DRM_FIRST_RENDER_NODE..=DRM_LAST_RENDER_NODE).contains(&minor) {
+            return HSAKMT_STATUS_INVALID_HANDLE;
+        }
+
+        let handle = self.fmm.amdgpu_handle[minor - DRM_FIRST_RENDER_NODE];
+        if handle.is_null() {
+            return HSAKMT_STATUS_INVALID_HANDLE;
+        }
+
+        *DeviceHandle = handle;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsakmt_fmm_destroy_process_apertures(&mut self) {
+        self.release_mmio();
+
+        self.hsakmt_fmm_report_leaks();
+        self.fmm.accounting.borrow_mut().clear();
+
+        self.fmm.gpu_mem.clear();
+        self.fmm.gpu_mem_count = 0;
+    }
+
+    pub unsafe fn hsakmt_fmm_init_process_apertures(&mut self, NumNodes: u32) -> HsakmtStatus {
+        /* Tunables from the environment, unless set with hsakmt_fmm_set_config */
+        let config = self.fmm_load_config(FmmConfig::from_env);
+        config.log();
+
+        let guardPages = config.guard_pages;
+
+        self.fmm.svm.disable_cache = config.disable_cache;
+        self.fmm.svm.userptr_for_paged_mem = config.userptr_for_paged_mem;
+        self.fmm.svm.check_userptr = config.check_userptr;
+        self.fmm.svm.reserve_svm = config.reserve_svm;
+        self.fmm.svm.alignment_order = config.alignment_order;
+        self.fmm.accounting.get_mut().capture_backtrace = config.alloc_backtrace;
 
         // let mut gpu_mem: Vec<gpu_mem_t> = Vec::with_capacity(NumNodes as usize);
 
@@ -1952,17 +3628,11 @@ impl HsakmtGlobals {
                 self.hsakmt_is_svm_api_supported = hsakmt_is_svm_api_supported;
 
                 gpu_m.scratch_physical.align = self.PAGE_SIZE() as u64;
-                gpu_m.scratch_physical.ops = manageable_aperture_ops_t {
-                    allocate_area_aligned: None,
-                    release_area: None,
-                };
+                gpu_m.scratch_physical.ops = reserved_aperture_ops;
 
                 gpu_m.gpuvm_aperture.align = self.get_vm_alignment(DeviceId as u32) as u64;
                 gpu_m.gpuvm_aperture.guard_pages = guardPages;
-                gpu_m.gpuvm_aperture.ops = manageable_aperture_ops_t {
-                    allocate_area_aligned: None,
-                    release_area: None,
-                };
+                gpu_m.gpuvm_aperture.ops = reserved_aperture_ops;
 
                 self.fmm.gpu_mem.push(gpu_m);
             }
@@ -2015,7 +3685,7 @@ impl HsakmtGlobals {
 
             let gpu_mem_id = gpu_mem_id as usize;
 
-            all_gpu_id_array.push(gpu_mem_id as u32);
+            all_gpu_id_array.push(self.fmm.gpu_mem[gpu_mem_id].gpu_id);
 
             /* Add this GPU to the usable_peer_id_arrays of all GPUs that
              * this GPU has an IO link to. This GPU can map memory
@@ -2092,12 +3762,10 @@ impl HsakmtGlobals {
                  * aperture. After subtracting the base, we
                  * don't want valid pointers to become NULL.
                  */
-                aperture_allocate_area(
-                    &self.fmm.gpu_mem[gpu_mem_id].gpuvm_aperture,
-                    std::ptr::null_mut(),
-                    self.fmm.gpu_mem[gpu_mem_id].gpuvm_aperture.align,
-                    g_args,
-                );
+                let gpuvm_aperture = &mut self.fmm.gpu_mem[gpu_mem_id].gpuvm_aperture;
+                let align = gpuvm_aperture.align;
+
+                aperture_allocate_area(gpuvm_aperture, std::ptr::null_mut(), align, g_args);
             }
 
             /* Acquire the VM from the DRM render node for KFD use */
@@ -2110,6 +3778,10 @@ impl HsakmtGlobals {
             }
         }
 
+        self.fmm.all_gpu_id_array_size =
+            (all_gpu_id_array.len() * std::mem::size_of::<u32>()) as u32;
+        self.fmm.all_gpu_id_array = all_gpu_id_array;
+
         if svm_limit > 0 {
             /* At least one GPU uses GPUVM in canonical address
              * space. Set up SVM apertures shared by all such GPUs
@@ -2128,10 +3800,10 @@ impl HsakmtGlobals {
                 }
 
                 /* Set memory policy to match the SVM apertures */
-                // let alt_base = svm.dgpu_alt_aperture_get_mut().unwrap();
-                let alt_base = &mut self.fmm.svm.apertures[SVM_DEFAULT as usize];
+                let alt_aperture = &*self.fmm.svm.dgpu_alt_aperture;
 
-                let alt_size = VOID_PTRS_SUB(alt_base.limit, alt_base.base) + 1;
+                let alt_base = alt_aperture.base;
+                let alt_size = VOID_PTRS_SUB(alt_aperture.limit, alt_aperture.base) + 1;
 
                 let d_c = if self.fmm.svm.disable_cache {
                     KFD_IOC_CACHE_POLICY_COHERENT
@@ -2139,17 +3811,15 @@ impl HsakmtGlobals {
                     KFD_IOC_CACHE_POLICY_NONCOHERENT
                 };
 
-                let a_b = alt_base as *mut _ as *mut std::os::raw::c_void;
-
                 let err = self.fmm_set_memory_policy(
                     process_aperture.gpu_id,
                     d_c as i32,
                     KFD_IOC_CACHE_POLICY_COHERENT as i32,
-                    a_b as *mut u64,
+                    alt_base as *mut u64,
                     alt_size,
                 );
 
-                if err > 0 {
+                if err != 0 {
                     println!(
                         "Failed to set mem policy for GPU {} {}",
                         process_aperture.gpu_id, err
@@ -2184,30 +3854,586 @@ impl HsakmtGlobals {
 
             // println!("hsakmt_topology_is_svm_needed yes {}", b);
 
-            let r = self.map_mmio(
+            self.fmm.gpu_mem[i].mmio_aperture.base = self.map_mmio(
                 self.fmm.gpu_mem[i].node_id,
                 self.fmm.gpu_mem[i].gpu_id,
                 hsakmt_kfd_fd,
             );
-            // println!("map_mmio r {}", r.is_null());
-
-            // self.fmm.gpu_mem[i].mmio_aperture.base = r;
-            //
-            // if !self.fmm.gpu_mem[i].mmio_aperture.base.is_null() {
-            //     let pt = (self.fmm.gpu_mem[i].mmio_aperture.base as *mut u8)
-            //         .add((page_size - 1) as usize);
-            //     let r = pt.add((page_size - 1) as usize);
-            //
-            //     self.fmm.gpu_mem[i].mmio_aperture.limit = r as *mut std::os::raw::c_void;
-            // } else {
-            //     // println!("Failed to map remapped mmio page on gpu_mem {}", g_m.gpu_id);
-            //     panic!(
-            //         "Failed to map remapped mmio page on gpu_mem {}",
-            //         self.fmm.gpu_mem[i].gpu_id
-            //     );
-            // }
+
+            if !self.fmm.gpu_mem[i].mmio_aperture.base.is_null() {
+                self.fmm.gpu_mem[i].mmio_aperture.limit =
+                    VOID_PTR_ADD(self.fmm.gpu_mem[i].mmio_aperture.base, page_size as u64 - 1);
+            } else {
+                println!(
+                    "Failed to map remapped mmio page on gpu_mem {}",
+                    self.fmm.gpu_mem[i].gpu_id
+                );
+            }
         }
 
         HSAKMT_STATUS_SUCCESS
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::QuickCheck;
+    use std::os::raw::c_void;
+
+    fn reserved_aperture(base: u64, limit: u64) -> manageable_aperture_t<'static> {
+        let mut aperture =
+            manageable_aperture_t::INIT_MANAGEABLE_APERTURE(base as usize, limit as usize);
+        aperture.align = 4096;
+        aperture.guard_pages = 0;
+        aperture
+    }
+
+    fn g_args() -> HsakmtGlobalsArgs {
+        HsakmtGlobalsArgs {
+            page_size: 4096,
+            fmm_svm_alignment_order: 9,
+        }
+    }
+
+    #[test]
+    fn test_reserved_aperture_allocate_release() {
+        let mut aperture = reserved_aperture(
+            START_NON_CANONICAL_ADDR,
+            START_NON_CANONICAL_ADDR + (1 << 30) - 1,
+        );
+
+        unsafe {
+            let a = reserved_aperture_allocate_aligned(
+                &mut aperture,
+                std::ptr::null_mut(),
+                4096,
+                4096,
+                g_args(),
+            );
+            let b = reserved_aperture_allocate_aligned(
+                &mut aperture,
+                std::ptr::null_mut(),
+                8192,
+                4096,
+                g_args(),
+            );
+
+            assert_eq!(a as u64, START_NON_CANONICAL_ADDR);
+            assert_eq!(b as u64, START_NON_CANONICAL_ADDR + 8192);
+
+            /* a and b don't touch, so there are two areas */
+            assert!(!aperture.vm_ranges.is_null());
+            assert!(!(*aperture.vm_ranges).next.is_null());
+
+            reserved_aperture_release(&mut aperture, a, 4096, g_args());
+            assert_eq!((*aperture.vm_ranges).start, b);
+            assert!((*aperture.vm_ranges).next.is_null());
+
+            /* The freed hole is reused */
+            let c = reserved_aperture_allocate_aligned(
+                &mut aperture,
+                std::ptr::null_mut(),
+                4096,
+                4096,
+                g_args(),
+            );
+            assert_eq!(c, a);
+
+            reserved_aperture_release(&mut aperture, c, 4096, g_args());
+            reserved_aperture_release(&mut aperture, b, 8192, g_args());
+            assert!(aperture.vm_ranges.is_null());
+        }
+    }
+
+    #[test]
+    fn test_fmm_va_alignment() {
+        let huge_page_size = GPU_HUGE_PAGE_SIZE as u64;
+
+        assert_eq!(fmm_va_alignment(0x1000, 0x1000, huge_page_size), 0x1000);
+        assert_eq!(fmm_va_alignment(0x10000, 0x1000, huge_page_size), 0x10000);
+        assert_eq!(fmm_va_alignment(0x1f000, 0x1000, huge_page_size), 0x10000);
+        assert_eq!(
+            fmm_va_alignment(huge_page_size, 0x1000, huge_page_size),
+            huge_page_size
+        );
+        /* capped at max_align */
+        assert_eq!(
+            fmm_va_alignment(1 << 32, 0x1000, huge_page_size),
+            huge_page_size
+        );
+        /* 4KB << 18 = 1GB for HSA_MAX_VA_ALIGN=18 */
+        assert_eq!(fmm_va_alignment(1 << 32, 0x1000, 0x1000 << 18), 1 << 30);
+        /* explicit alignment above the cap is kept */
+        assert_eq!(fmm_va_alignment(0x1000, 1 << 30, huge_page_size), 1 << 30);
+        assert_eq!(fmm_va_alignment(1 << 20, 0, huge_page_size), 0);
+    }
+
+    #[test]
+    fn test_reserved_aperture_fixed_address() {
+        let mut aperture = reserved_aperture(
+            START_NON_CANONICAL_ADDR,
+            START_NON_CANONICAL_ADDR + (1 << 30) - 1,
+        );
+        let fixed = (START_NON_CANONICAL_ADDR + (1 << 20)) as *mut std::os::raw::c_void;
+
+        unsafe {
+            let a = reserved_aperture_allocate_aligned(&mut aperture, fixed, 4096, 4096, g_args());
+            assert_eq!(a, fixed);
+
+            /* The same address can't be handed out twice */
+            let b = reserved_aperture_allocate_aligned(&mut aperture, fixed, 4096, 4096, g_args());
+            assert!(b.is_null());
+
+            /* Releasing the middle of an area splits it */
+            let c = reserved_aperture_allocate_aligned(
+                &mut aperture,
+                VOID_PTR_ADD(fixed, 4096),
+                8192,
+                4096,
+                g_args(),
+            );
+            assert_eq!(c, VOID_PTR_ADD(fixed, 4096));
+            assert!((*aperture.vm_ranges).next.is_null());
+
+            reserved_aperture_release(&mut aperture, VOID_PTR_ADD(fixed, 4096), 4096, g_args());
+            assert!(!(*aperture.vm_ranges).next.is_null());
+            assert_eq!(
+                (*(*aperture.vm_ranges).next).start,
+                VOID_PTR_ADD(fixed, 8192)
+            );
+
+            /* Allocations outside the aperture fail */
+            let d = reserved_aperture_allocate_aligned(
+                &mut aperture,
+                std::ptr::null_mut(),
+                1 << 31,
+                4096,
+                g_args(),
+            );
+            assert!(d.is_null());
+        }
+    }
+
+    const PROP_PAGE_SIZE: u64 = 4096;
+
+    /* Allocation request: (kind, pages, align order). Kinds 0 to 2
+     * allocate, 3 releases the oldest live allocation
+     */
+    type AllocOp = (u8, u8, u8);
+
+    fn request_size_align(pages: u8, order: u8) -> (u64, u64) {
+        let size = (pages as u64 % 64 + 1) * PROP_PAGE_SIZE;
+        let align = match order % 4 {
+            0 => 0,
+            o => PROP_PAGE_SIZE << ((o as u64 - 1) * 4),
+        };
+
+        (size, align)
+    }
+
+    /* Live allocations, including trailing guard pages, must not overlap */
+    fn assert_no_overlap(live: &[(u64, u64, u64)]) {
+        let mut ranges: Vec<(u64, u64)> = live.iter().map(|&(a, s, g)| (a, a + s + g)).collect();
+        ranges.sort_unstable();
+
+        for w in ranges.windows(2) {
+            assert!(w[0].1 <= w[1].0, "{:#x?} overlaps {:#x?}", w[0], w[1]);
+        }
+    }
+
+    unsafe fn assert_vm_ranges_sorted(app: &manageable_aperture_t) {
+        let mut cur = app.vm_ranges;
+
+        while !cur.is_null() {
+            assert!((*cur).start <= (*cur).end);
+            assert!((*cur).start >= app.base && (*cur).end <= app.limit);
+
+            if !(*cur).next.is_null() {
+                assert!((*cur).end < (*(*cur).next).start);
+                assert_eq!((*(*cur).next).prev, cur);
+            }
+
+            cur = (*cur).next;
+        }
+    }
+
+    fn prop_reserved_aperture(guard_pages: u8, ops: Vec<AllocOp>) -> bool {
+        /* A small aperture so that allocations run out of space */
+        let base = START_NON_CANONICAL_ADDR;
+        let limit = base + (64 << 20) - 1;
+        let mut aperture = reserved_aperture(base, limit);
+        aperture.guard_pages = guard_pages as u32 % 3;
+
+        let guard = aperture.guard_pages as u64 * PROP_PAGE_SIZE;
+        let mut live: Vec<(u64, u64, u64)> = vec![];
+
+        unsafe {
+            for (kind, pages, order) in ops {
+                if kind % 4 == 3 {
+                    if !live.is_empty() {
+                        let (addr, size, _) = live.remove(0);
+                        aperture_release_area(&mut aperture, addr as *mut c_void, size, g_args());
+                    }
+                } else {
+                    let (size, align) = request_size_align(pages, order);
+                    let addr = aperture_allocate_area_aligned(
+                        &mut aperture,
+                        std::ptr::null_mut(),
+                        size,
+                        align,
+                        g_args(),
+                    );
+
+                    if addr.is_null() {
+                        continue;
+                    }
+
+                    let addr = addr as u64;
+                    assert_eq!(addr % align.max(aperture.align), 0, "misaligned");
+                    assert!(addr >= base && addr + size + guard - 1 <= limit);
+
+                    live.push((addr, size, guard));
+                }
+
+                assert_no_overlap(&live);
+                assert_vm_ranges_sorted(&aperture);
+            }
+
+            for (addr, size, _) in live {
+                aperture_release_area(&mut aperture, addr as *mut c_void, size, g_args());
+            }
+
+            /* All the address space is given back */
+            assert!(aperture.vm_ranges.is_null());
+        }
+
+        true
+    }
+
+    #[test]
+    fn test_reserved_aperture_model() {
+        QuickCheck::new()
+            .tests(200)
+            .quickcheck(prop_reserved_aperture as fn(u8, Vec<AllocOp>) -> bool);
+    }
+
+    fn prop_mmap_aperture(guard_pages: u8, ops: Vec<AllocOp>) -> bool {
+        let mut aperture = manageable_aperture_t::INIT_MANAGEABLE_APERTURE(
+            PROP_PAGE_SIZE as usize,
+            (START_NON_CANONICAL_ADDR - 1) as usize,
+        );
+        aperture.ops = mmap_aperture_ops;
+        aperture.align = PROP_PAGE_SIZE;
+        aperture.is_cpu_accessible = true;
+        aperture.guard_pages = guard_pages as u32 % 3;
+
+        /* The guard pages are unmapped again, other mappings may
+         * land on them. Only the allocations themselves are checked
+         */
+        let mut live: Vec<(u64, u64, u64)> = vec![];
+
+        unsafe {
+            for (kind, pages, order) in ops {
+                if kind % 4 == 3 {
+                    if !live.is_empty() {
+                        let (addr, size, _) = live.remove(0);
+                        aperture_release_area(&mut aperture, addr as *mut c_void, size, g_args());
+                    }
+                    continue;
+                }
+
+                let (size, align) = request_size_align(pages, order);
+                let addr = aperture_allocate_area_aligned(
+                    &mut aperture,
+                    std::ptr::null_mut(),
+                    size,
+                    align,
+                    g_args(),
+                );
+                assert!(!addr.is_null());
+
+                let addr = addr as u64;
+                assert_eq!(addr % align.max(aperture.align), 0, "misaligned");
+                assert!(addr >= aperture.base as u64);
+                assert!(addr + size - 1 <= aperture.limit as u64);
+
+                live.push((addr, size, 0));
+                assert_no_overlap(&live);
+            }
+
+            for (addr, size, _) in live {
+                aperture_release_area(&mut aperture, addr as *mut c_void, size, g_args());
+            }
+        }
+
+        true
+    }
+
+    #[test]
+    fn test_mmap_aperture_model() {
+        QuickCheck::new()
+            .tests(100)
+            .quickcheck(prop_mmap_aperture as fn(u8, Vec<AllocOp>) -> bool);
+    }
+
+    fn prop_mmap_allocate_guard_pages(pages: u8, order: u8, guard_pages: u8) -> bool {
+        let (size, align) = request_size_align(pages, order);
+        let align = align.max(PROP_PAGE_SIZE);
+        let guard_size = (guard_pages as u64 % 3) * PROP_PAGE_SIZE;
+
+        unsafe {
+            let addr = hsakmt_mmap_allocate_aligned(
+                PROT_READ | PROT_WRITE,
+                MAP_ANONYMOUS | MAP_NORESERVE | MAP_PRIVATE,
+                size,
+                align,
+                guard_size,
+                PROP_PAGE_SIZE as *mut c_void,
+                (START_NON_CANONICAL_ADDR - 1) as *mut c_void,
+                g_args(),
+            );
+            assert!(!addr.is_null());
+            assert_eq!(addr as u64 % align, 0);
+
+            /* The whole range is accessible */
+            let bytes = std::slice::from_raw_parts_mut(addr as *mut u8, size as usize);
+            bytes[0] = 1;
+            bytes[size as usize - 1] = 1;
+
+            munmap(addr, size as usize);
+
+            /* A window that can't hold the allocation is refused */
+            let addr = hsakmt_mmap_allocate_aligned(
+                PROT_NONE,
+                MAP_ANONYMOUS | MAP_NORESERVE | MAP_PRIVATE,
+                size,
+                align,
+                guard_size,
+                PROP_PAGE_SIZE as *mut c_void,
+                (PROP_PAGE_SIZE + size - 2) as *mut c_void,
+                g_args(),
+            );
+            assert!(addr.is_null());
+        }
+
+        true
+    }
+
+    #[test]
+    fn test_mmap_allocate_aligned() {
+        QuickCheck::new()
+            .tests(200)
+            .quickcheck(prop_mmap_allocate_guard_pages as fn(u8, u8, u8) -> bool);
+    }
+
+    #[test]
+    fn test_allocate_host_numa_policy() {
+        use crate::hsakmttypes::HsaMemFlagUnion;
+        use crate::kfd_ioctl::AMDKFD_IOC_ALLOC_MEMORY_OF_GPU;
+        use crate::libhsakmt::fake_kfd::{fake_kfd_globals, fake_kfd_globals_close, FakeKfd};
+        use crate::libhsakmt::hsakmt_set_ioctl_hook;
+
+        let kfd = FakeKfd::install(FakeKfd::default());
+
+        unsafe {
+            let mut g = fake_kfd_globals(9, 4, 2);
+
+            let mut mflags = HsaMemFlags {
+                st: HsaMemFlagUnion {
+                    ui32: std::mem::zeroed(),
+                },
+            };
+            mflags.st.ui32.HostAccess = 1;
+            mflags.st.ui32.NonPaged = 1;
+
+            /* GTT pages go where the kernel puts them */
+            g.hsakmt_fmm_set_numa_policy(fmm_numa_policy_t::FMM_NUMA_INTERLEAVE(vec![0, 1]));
+            let mem = g.hsakmt_fmm_allocate_host(0, 0, std::ptr::null_mut(), 4096, mflags);
+            assert!(mem.is_null());
+            assert!(kfd.borrow().requests.is_empty());
+
+            mflags.st.ui32.NoNUMABind = 1;
+            let mem = g.hsakmt_fmm_allocate_host(0, 0, std::ptr::null_mut(), 4096, mflags);
+            assert!(!mem.is_null());
+            assert_ne!(
+                kfd.borrow().bos[0].flags & KFD_IOC_ALLOC_MEM_FLAGS_GTT as u32,
+                0
+            );
+            assert_eq!(g.hsaKmtFreeMemory(mem, 4096), HSAKMT_STATUS_SUCCESS);
+
+            mflags.st.ui32.NoNUMABind = 0;
+            g.hsakmt_fmm_set_numa_policy(fmm_numa_policy_t::FMM_NUMA_CLOSEST);
+            let mem = g.hsakmt_fmm_allocate_host(0, 0, std::ptr::null_mut(), 4096, mflags);
+            assert!(!mem.is_null());
+            assert_eq!(g.hsaKmtFreeMemory(mem, 4096), HSAKMT_STATUS_SUCCESS);
+
+            /* Paged memory is a userptr BO the policy applies to */
+            g.fmm.svm.userptr_for_paged_mem = true;
+            g.hsakmt_fmm_set_numa_policy(fmm_numa_policy_t::FMM_NUMA_BIND(vec![0]));
+            mflags.st.ui32.NonPaged = 0;
+            let mem = g.hsakmt_fmm_allocate_host(0, 0, std::ptr::null_mut(), 4096, mflags);
+            assert!(!mem.is_null());
+            assert_ne!(
+                kfd.borrow().bos[0].flags & KFD_IOC_ALLOC_MEM_FLAGS_USERPTR as u32,
+                0
+            );
+            assert_eq!(g.hsaKmtFreeMemory(mem, 4096), HSAKMT_STATUS_SUCCESS);
+
+            assert_eq!(
+                kfd.borrow()
+                    .requests
+                    .iter()
+                    .filter(|r| **r == AMDKFD_IOC_ALLOC_MEMORY_OF_GPU)
+                    .count(),
+                3
+            );
+            assert!(kfd.borrow().bos.is_empty());
+
+            fake_kfd_globals_close(&g);
+        }
+
+        hsakmt_set_ioctl_hook(None);
+    }
+
+    #[test]
+    fn test_scratch_reserve_map_release() {
+        use crate::libhsakmt::fake_kfd::{
+            fake_kfd_globals, fake_kfd_globals_close, FakeKfd, FAKE_GPU_ID,
+        };
+        use crate::libhsakmt::hsakmt_set_ioctl_hook;
+
+        let kfd = FakeKfd::install(FakeKfd::default());
+
+        unsafe {
+            let mut g = fake_kfd_globals(9, 4, 2);
+
+            /* Reserve */
+            let mem = g.hsakmt_fmm_allocate_scratch(FAKE_GPU_ID, std::ptr::null_mut(), 0x5000);
+            assert!(!mem.is_null());
+            assert_eq!(mem as u64 % SCRATCH_ALIGN, 0);
+            assert_eq!(
+                g.fmm.gpu_mem[0].scratch_physical.limit,
+                VOID_PTR_ADD(mem, SCRATCH_ALIGN - 1)
+            );
+            assert_eq!(
+                kfd.borrow().scratch_backing,
+                vec![(FAKE_GPU_ID, mem as u64 >> 16)]
+            );
+            assert!(kfd.borrow().bos.is_empty());
+
+            /* Only once per GPU */
+            assert!(g
+                .hsakmt_fmm_allocate_scratch(FAKE_GPU_ID, std::ptr::null_mut(), 0x1000)
+                .is_null());
+            assert!(g
+                .hsakmt_fmm_allocate_scratch(0x4321, std::ptr::null_mut(), 0x1000)
+                .is_null());
+
+            /* Map backs part of the range with VRAM */
+            let backed = VOID_PTR_ADD(mem, 0x1000);
+            assert_eq!(
+                g.hsakmt_fmm_map_to_gpu(backed, 0x2000, std::ptr::null_mut()),
+                HSAKMT_STATUS_SUCCESS
+            );
+            {
+                let kfd = kfd.borrow();
+                assert_eq!(kfd.bos.len(), 1);
+                assert_eq!(kfd.bos[0].va, backed as u64);
+                assert_eq!(kfd.bos[0].size, 0x2000);
+                assert_ne!(kfd.bos[0].flags & KFD_IOC_ALLOC_MEM_FLAGS_VRAM as u32, 0);
+                assert_eq!(kfd.bos[0].mapped, vec![FAKE_GPU_ID]);
+            }
+
+            /* Past the end of the reservation */
+            assert_eq!(
+                g.hsakmt_fmm_map_to_gpu(
+                    VOID_PTR_ADD(mem, SCRATCH_ALIGN - 0x1000),
+                    0x2000,
+                    std::ptr::null_mut()
+                ),
+                HSAKMT_STATUS_INVALID_PARAMETER
+            );
+            assert_eq!(kfd.borrow().bos.len(), 1);
+
+            /* Unmap frees the backing */
+            assert_eq!(g.hsakmt_fmm_unmap_from_gpu(backed), HSAKMT_STATUS_SUCCESS);
+            assert!(kfd.borrow().bos.is_empty());
+
+            /* Release frees what is still mapped and the reservation */
+            assert_eq!(
+                g.hsakmt_fmm_map_to_gpu(mem, 0x1000, std::ptr::null_mut()),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!(kfd.borrow().bos.len(), 1);
+            assert_eq!(g.hsakmt_fmm_release(mem), HSAKMT_STATUS_SUCCESS);
+            assert!(kfd.borrow().bos.is_empty());
+            assert!(g.fmm.gpu_mem[0].scratch_physical.base.is_null());
+            assert!(g.fmm.gpu_mem[0].scratch_physical.limit.is_null());
+
+            /* and the GPU can reserve again */
+            let mem = g.hsakmt_fmm_allocate_scratch(FAKE_GPU_ID, std::ptr::null_mut(), 0x1000);
+            assert!(!mem.is_null());
+            assert_eq!(g.hsakmt_fmm_release(mem), HSAKMT_STATUS_SUCCESS);
+
+            fake_kfd_globals_close(&g);
+        }
+
+        hsakmt_set_ioctl_hook(None);
+    }
+
+    #[test]
+    fn test_map_to_gpu_svm_api() {
+        use crate::kfd_ioctl::kfd_ioctl_svm_attribute;
+        use crate::libhsakmt::fake_kfd::{
+            fake_kfd_globals, fake_kfd_globals_close, FakeKfd, FAKE_GPU_ID,
+        };
+        use crate::libhsakmt::hsakmt_set_ioctl_hook;
+
+        let kfd = FakeKfd::install(FakeKfd::default());
+
+        unsafe {
+            let mut g = fake_kfd_globals(9, 4, 2);
+
+            let mut buf = vec![0u8; 3 * 4096];
+            let addr = buf.as_mut_ptr().add(100) as *mut c_void;
+            let mut gpuvm_address = 0;
+
+            /* Memory nobody registered needs the SVM API on dGPUs */
+            assert_eq!(
+                g.hsakmt_fmm_map_to_gpu(addr, 4096, &mut gpuvm_address),
+                HSAKMT_STATUS_INVALID_PARAMETER
+            );
+            assert!(kfd.borrow().requests.is_empty());
+
+            g.hsakmt_is_svm_api_supported = true;
+            assert_eq!(
+                g.hsakmt_fmm_map_to_gpu(addr, 4096, &mut gpuvm_address),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!(gpuvm_address, addr as u64);
+
+            let page = addr as u64 & !4095;
+            let size = ALIGN_UP(addr as u64 - page + 4096, 4096);
+            assert_eq!(
+                kfd.borrow().svm_ranges,
+                vec![(
+                    page,
+                    size,
+                    vec![kfd_ioctl_svm_attribute {
+                        type_: KFD_IOCTL_SVM_ATTR_ACCESS_IN_PLACE,
+                        value: FAKE_GPU_ID,
+                    }]
+                )]
+            );
+
+            /* Unmapping keeps the range, KFD drops it with the pages */
+            assert_eq!(g.hsakmt_fmm_unmap_from_gpu(addr), HSAKMT_STATUS_SUCCESS);
+            assert!(kfd.borrow().bos.is_empty());
+
+            fake_kfd_globals_close(&g);
+        }
+
+        hsakmt_set_ioctl_hook(None);
+    }
+}
@@ -0,0 +1,548 @@
+#![allow(non_camel_case_types, non_snake_case, dead_code)]
+
+use crate::fmm_types::svm_aperture_type::{SVM_COHERENT, SVM_DEFAULT};
+use crate::fmm_types::{manageable_aperture_t, vm_object_t};
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::{HsaMemFlags, GPU_HUGE_PAGE_SIZE};
+use crate::kfd_ioctl::{
+    KFD_IOC_ALLOC_MEM_FLAGS_DOORBELL, KFD_IOC_ALLOC_MEM_FLAGS_MMIO_REMAP,
+    KFD_IOC_ALLOC_MEM_FLAGS_USERPTR, KFD_IOC_ALLOC_MEM_FLAGS_VRAM,
+};
+use std::backtrace::Backtrace;
+use std::collections::{BTreeMap, HashMap};
+
+/* Kind of memory backing a vm object */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum fmm_heap_type {
+    FMM_HEAP_VRAM,
+    FMM_HEAP_SYSTEM,
+    FMM_HEAP_USERPTR,
+    FMM_HEAP_DOORBELL,
+    FMM_HEAP_MMIO,
+    FMM_HEAP_IMPORTED,
+    FMM_HEAP_VA_ONLY,
+}
+
+impl fmm_heap_type {
+    pub fn from_ioc_flags(ioc_flags: u32) -> Self {
+        if (ioc_flags & KFD_IOC_ALLOC_MEM_FLAGS_VRAM as u32) > 0 {
+            fmm_heap_type::FMM_HEAP_VRAM
+        } else if (ioc_flags & KFD_IOC_ALLOC_MEM_FLAGS_USERPTR as u32) > 0 {
+            fmm_heap_type::FMM_HEAP_USERPTR
+        } else if (ioc_flags & KFD_IOC_ALLOC_MEM_FLAGS_DOORBELL as u32) > 0 {
+            fmm_heap_type::FMM_HEAP_DOORBELL
+        } else if (ioc_flags & KFD_IOC_ALLOC_MEM_FLAGS_MMIO_REMAP as u32) > 0 {
+            fmm_heap_type::FMM_HEAP_MMIO
+        } else {
+            fmm_heap_type::FMM_HEAP_SYSTEM
+        }
+    }
+}
+
+/* Aperture a vm object lives in. GPUVM and scratch apertures exist once
+ * per GPU, the node id of the account tells them apart.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum fmm_aperture_kind {
+    FMM_APERTURE_SVM_DEFAULT,
+    FMM_APERTURE_SVM_COHERENT,
+    FMM_APERTURE_GPUVM,
+    FMM_APERTURE_SCRATCH,
+    FMM_APERTURE_CPUVM,
+    FMM_APERTURE_MEM_HANDLE,
+    FMM_APERTURE_UNKNOWN,
+}
+
+/* Live memory of one account */
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct fmm_usage_t {
+    pub objects: u64,
+    pub size: u64,
+    /* size of the objects times the number of GPUs they are mapped to */
+    pub mapped_size: u64,
+}
+
+impl fmm_usage_t {
+    fn add(&mut self, other: &fmm_usage_t) {
+        self.objects += other.objects;
+        self.size += other.size;
+        self.mapped_size += other.mapped_size;
+    }
+}
+
+/* VA placement of the allocations since the apertures were initialized.
+ * Buffers of at least GPU_HUGE_PAGE_SIZE can only use 2MB fragments when
+ * their start or end falls on a huge page boundary.
+ */
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct fmm_alignment_stats_t {
+    pub allocations: u64,
+    pub huge_page_sized: u64,
+    pub huge_page_aligned: u64,
+}
+
+impl fmm_alignment_stats_t {
+    pub fn record(&mut self, address: u64, size: u64) {
+        let huge_page_size = GPU_HUGE_PAGE_SIZE as u64;
+
+        self.allocations += 1;
+
+        if size < huge_page_size {
+            return;
+        }
+
+        self.huge_page_sized += 1;
+
+        if address.is_multiple_of(huge_page_size) || (address + size).is_multiple_of(huge_page_size)
+        {
+            self.huge_page_aligned += 1;
+        }
+    }
+}
+
+/* (node id, heap, aperture) */
+pub type fmm_account_key_t = (u32, fmm_heap_type, fmm_aperture_kind);
+
+#[derive(Debug)]
+struct fmm_object_record {
+    key: fmm_account_key_t,
+    size: u64,
+    mapped_devices: u64,
+    backtrace: Option<Backtrace>,
+}
+
+#[derive(Debug, Default)]
+pub struct fmm_accounting_t {
+    /* capture a backtrace for every allocation, see HSA_ALLOC_BACKTRACE */
+    pub capture_backtrace: bool,
+    usage: BTreeMap<fmm_account_key_t, fmm_usage_t>,
+    /* keyed by the vm object address */
+    objects: HashMap<usize, fmm_object_record>,
+    pub alignment: fmm_alignment_stats_t,
+}
+
+impl fmm_accounting_t {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn clear(&mut self) {
+        self.usage.clear();
+        self.objects.clear();
+        self.alignment = fmm_alignment_stats_t::default();
+    }
+
+    pub fn account_object(
+        &mut self,
+        object: *mut vm_object_t,
+        node_id: u32,
+        heap: fmm_heap_type,
+        aperture: fmm_aperture_kind,
+        size: u64,
+    ) {
+        /* An object is accounted once, a stale record means it was
+         * freed without going through unaccount_object
+         */
+        self.unaccount_object(object);
+
+        let key = (node_id, heap, aperture);
+        let usage = self.usage.entry(key).or_default();
+        usage.objects += 1;
+        usage.size += size;
+
+        let backtrace = if self.capture_backtrace {
+            Some(Backtrace::force_capture())
+        } else {
+            None
+        };
+
+        self.objects.insert(
+            object as usize,
+            fmm_object_record {
+                key,
+                size,
+                mapped_devices: 0,
+                backtrace,
+            },
+        );
+    }
+
+    pub fn unaccount_object(&mut self, object: *mut vm_object_t) -> bool {
+        let Some(record) = self.objects.remove(&(object as usize)) else {
+            return false;
+        };
+
+        if let Some(usage) = self.usage.get_mut(&record.key) {
+            usage.objects -= 1;
+            usage.size -= record.size;
+            usage.mapped_size -= record.size * record.mapped_devices;
+
+            if usage.objects == 0 {
+                self.usage.remove(&record.key);
+            }
+        }
+
+        true
+    }
+
+    /* Record the number of GPUs the object is currently mapped to */
+    pub fn set_mapped_devices(&mut self, object: *mut vm_object_t, mapped_devices: u64) {
+        let Some(record) = self.objects.get_mut(&(object as usize)) else {
+            return;
+        };
+
+        if let Some(usage) = self.usage.get_mut(&record.key) {
+            usage.mapped_size -= record.size * record.mapped_devices;
+            usage.mapped_size += record.size * mapped_devices;
+        }
+
+        record.mapped_devices = mapped_devices;
+    }
+
+    pub fn heap_of(&self, object: *mut vm_object_t) -> Option<fmm_heap_type> {
+        self.objects.get(&(object as usize)).map(|r| r.key.1)
+    }
+
+    pub fn backtrace_of(&self, object: *mut vm_object_t) -> Option<String> {
+        self.objects
+            .get(&(object as usize))
+            .and_then(|r| r.backtrace.as_ref())
+            .map(|b| b.to_string())
+    }
+
+    pub fn usage(&self) -> BTreeMap<fmm_account_key_t, fmm_usage_t> {
+        self.usage.clone()
+    }
+
+    pub fn usage_by_node(&self) -> BTreeMap<u32, fmm_usage_t> {
+        self.usage_by(|key| key.0)
+    }
+
+    pub fn usage_by_heap(&self) -> BTreeMap<fmm_heap_type, fmm_usage_t> {
+        self.usage_by(|key| key.1)
+    }
+
+    pub fn usage_by_aperture(&self) -> BTreeMap<fmm_aperture_kind, fmm_usage_t> {
+        self.usage_by(|key| key.2)
+    }
+
+    pub fn total(&self) -> fmm_usage_t {
+        let mut total = fmm_usage_t::default();
+
+        for usage in self.usage.values() {
+            total.add(usage);
+        }
+
+        total
+    }
+
+    fn usage_by<K: Ord>(&self, f: impl Fn(&fmm_account_key_t) -> K) -> BTreeMap<K, fmm_usage_t> {
+        let mut by: BTreeMap<K, fmm_usage_t> = BTreeMap::new();
+
+        for (key, usage) in &self.usage {
+            by.entry(f(key)).or_default().add(usage);
+        }
+
+        by
+    }
+}
+
+/* A vm object still present in one of the aperture trees */
+#[derive(Clone)]
+pub struct fmm_allocation_info_t {
+    pub address: *mut std::os::raw::c_void,
+    pub size: u64,
+    pub userptr: *mut std::os::raw::c_void,
+    pub handle: u64,
+    pub node_id: u32,
+    pub aperture: fmm_aperture_kind,
+    /* None for objects created before accounting was reset */
+    pub heap: Option<fmm_heap_type>,
+    pub flags: HsaMemFlags,
+    pub mapped_device_ids: Vec<u32>,
+    pub backtrace: Option<String>,
+}
+
+impl HsakmtGlobals {
+    pub fn fmm_aperture_kind(&self, aperture: *const manageable_aperture_t) -> fmm_aperture_kind {
+        let svm = &self.fmm.svm;
+
+        if std::ptr::eq(aperture, &svm.apertures[SVM_DEFAULT as usize]) {
+            return fmm_aperture_kind::FMM_APERTURE_SVM_DEFAULT;
+        }
+
+        if std::ptr::eq(aperture, &svm.apertures[SVM_COHERENT as usize]) {
+            return fmm_aperture_kind::FMM_APERTURE_SVM_COHERENT;
+        }
+
+        if std::ptr::eq(aperture, &self.fmm.cpuvm_aperture) {
+            return fmm_aperture_kind::FMM_APERTURE_CPUVM;
+        }
+
+        if std::ptr::eq(aperture, &self.fmm.mem_handle_aperture) {
+            return fmm_aperture_kind::FMM_APERTURE_MEM_HANDLE;
+        }
+
+        for gpu_m in &self.fmm.gpu_mem {
+            if std::ptr::eq(aperture, &gpu_m.gpuvm_aperture) {
+                return fmm_aperture_kind::FMM_APERTURE_GPUVM;
+            }
+
+            if std::ptr::eq(aperture, &gpu_m.scratch_physical) {
+                return fmm_aperture_kind::FMM_APERTURE_SCRATCH;
+            }
+        }
+
+        fmm_aperture_kind::FMM_APERTURE_UNKNOWN
+    }
+
+    /* Start accounting a vm object just inserted in aperture. Objects
+     * that don't belong to a GPU are accounted to node 0.
+     */
+    pub unsafe fn fmm_account_object(
+        &self,
+        aperture: *const manageable_aperture_t,
+        object: *mut vm_object_t,
+        gpu_id: u32,
+        heap: fmm_heap_type,
+    ) {
+        let mut node_id = 0;
+        self.gpuid_to_nodeid(gpu_id, &mut node_id);
+
+        let kind = self.fmm_aperture_kind(aperture);
+
+        let mut accounting = self.fmm.accounting.borrow_mut();
+        accounting.account_object(object, node_id, heap, kind, (*object).size);
+        accounting
+            .alignment
+            .record((*object).start as u64, (*object).size);
+    }
+
+    pub fn fmm_unaccount_object(&self, object: *mut vm_object_t) {
+        self.fmm.accounting.borrow_mut().unaccount_object(object);
+    }
+
+    /* Update the mapped size after object's mapped device set changed */
+    pub unsafe fn fmm_account_mapping(&self, object: *mut vm_object_t) {
+        let mapped_devices = (*object).mapped_device_id_array.len() as u64;
+
+        self.fmm
+            .accounting
+            .borrow_mut()
+            .set_mapped_devices(object, mapped_devices);
+    }
+
+    /* Live memory of this process per node, heap and aperture */
+    pub fn hsakmt_fmm_get_memory_usage(&self) -> BTreeMap<fmm_account_key_t, fmm_usage_t> {
+        self.fmm.accounting.borrow().usage()
+    }
+
+    /* How many big allocations landed on huge page boundaries */
+    pub fn hsakmt_fmm_get_alignment_stats(&self) -> fmm_alignment_stats_t {
+        self.fmm.accounting.borrow().alignment
+    }
+
+    /* Every aperture vm objects can live in */
+    pub fn fmm_apertures(&self) -> Vec<*const manageable_aperture_t<'static>> {
+        let mut apertures: Vec<*const manageable_aperture_t> = vec![
+            &self.fmm.svm.apertures[SVM_DEFAULT as usize],
+            &self.fmm.svm.apertures[SVM_COHERENT as usize],
+            &self.fmm.cpuvm_aperture,
+            &self.fmm.mem_handle_aperture,
+        ];
+
+        for gpu_m in &self.fmm.gpu_mem {
+            apertures.push(&gpu_m.gpuvm_aperture);
+            apertures.push(&gpu_m.scratch_physical);
+        }
+
+        apertures
+    }
+
+    /* Description of object, a vm object of aperture */
+    pub unsafe fn fmm_allocation_info(
+        &self,
+        aperture: *const manageable_aperture_t,
+        object: *mut vm_object_t,
+    ) -> fmm_allocation_info_t {
+        let accounting = self.fmm.accounting.borrow();
+        let obj = &*object;
+
+        fmm_allocation_info_t {
+            address: obj.start,
+            size: obj.size,
+            userptr: obj.userptr,
+            handle: obj.handle,
+            node_id: obj.node_id,
+            aperture: self.fmm_aperture_kind(aperture),
+            heap: accounting.heap_of(object),
+            flags: obj.mflags,
+            mapped_device_ids: obj.mapped_device_id_array.clone(),
+            backtrace: accounting.backtrace_of(object),
+        }
+    }
+
+    /* Every vm object present in any aperture tree */
+    pub unsafe fn hsakmt_fmm_get_live_allocations(&self) -> Vec<fmm_allocation_info_t> {
+        let mut allocations = vec![];
+
+        for aperture in self.fmm_apertures() {
+            for object in (*aperture).tree.objects() {
+                allocations.push(self.fmm_allocation_info(aperture, object));
+            }
+        }
+
+        allocations
+    }
+
+    /* Print every vm object still present in an aperture tree, returns
+     * the number of leaked objects
+     */
+    pub unsafe fn hsakmt_fmm_report_leaks(&self) -> usize {
+        let leaks = self.hsakmt_fmm_get_live_allocations();
+
+        if leaks.is_empty() {
+            return 0;
+        }
+
+        let total: u64 = leaks.iter().map(|leak| leak.size).sum();
+        println!(
+            "hsakmt: {} memory objects ({:#x} bytes) leaked",
+            leaks.len(),
+            total
+        );
+
+        for leak in &leaks {
+            println!(
+                "  {:?} size {:#x} node {} {:?} {:?} handle {:#x} userptr {:?} mapped {:?}",
+                leak.address,
+                leak.size,
+                leak.node_id,
+                leak.heap,
+                leak.aperture,
+                leak.handle,
+                leak.userptr,
+                leak.mapped_device_ids
+            );
+
+            if let Some(backtrace) = &leak.backtrace {
+                println!("{}", backtrace);
+            }
+        }
+
+        leaks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fmm_aperture_kind::*;
+    use fmm_heap_type::*;
+
+    fn object(i: usize) -> *mut vm_object_t {
+        i as *mut vm_object_t
+    }
+
+    #[test]
+    fn test_heap_from_ioc_flags() {
+        assert_eq!(
+            fmm_heap_type::from_ioc_flags(KFD_IOC_ALLOC_MEM_FLAGS_VRAM as u32),
+            FMM_HEAP_VRAM
+        );
+        assert_eq!(
+            fmm_heap_type::from_ioc_flags(KFD_IOC_ALLOC_MEM_FLAGS_USERPTR as u32),
+            FMM_HEAP_USERPTR
+        );
+        assert_eq!(
+            fmm_heap_type::from_ioc_flags(KFD_IOC_ALLOC_MEM_FLAGS_DOORBELL as u32),
+            FMM_HEAP_DOORBELL
+        );
+        assert_eq!(fmm_heap_type::from_ioc_flags(0), FMM_HEAP_SYSTEM);
+    }
+
+    #[test]
+    fn test_accounting() {
+        let mut accounting = fmm_accounting_t::new();
+
+        accounting.account_object(
+            object(1),
+            1,
+            FMM_HEAP_VRAM,
+            FMM_APERTURE_SVM_DEFAULT,
+            0x1000,
+        );
+        accounting.account_object(
+            object(2),
+            1,
+            FMM_HEAP_VRAM,
+            FMM_APERTURE_SVM_DEFAULT,
+            0x2000,
+        );
+        accounting.account_object(object(3), 2, FMM_HEAP_SYSTEM, FMM_APERTURE_GPUVM, 0x4000);
+
+        let by_node = accounting.usage_by_node();
+        assert_eq!(by_node[&1].objects, 2);
+        assert_eq!(by_node[&1].size, 0x3000);
+        assert_eq!(by_node[&2].size, 0x4000);
+
+        /* mapped to two GPUs, then to one */
+        accounting.set_mapped_devices(object(2), 2);
+        assert_eq!(accounting.total().mapped_size, 0x4000);
+        accounting.set_mapped_devices(object(2), 1);
+        assert_eq!(
+            accounting.usage_by_heap()[&FMM_HEAP_VRAM].mapped_size,
+            0x2000
+        );
+
+        /* freeing a mapped object drops its mappings as well */
+        assert!(accounting.unaccount_object(object(2)));
+        assert!(!accounting.unaccount_object(object(2)));
+        assert_eq!(
+            accounting.total(),
+            fmm_usage_t {
+                objects: 2,
+                size: 0x5000,
+                mapped_size: 0,
+            }
+        );
+
+        accounting.unaccount_object(object(1));
+        accounting.unaccount_object(object(3));
+        assert!(accounting.usage().is_empty());
+        assert!(accounting.usage_by_aperture().is_empty());
+    }
+
+    #[test]
+    fn test_alignment_stats() {
+        let huge_page_size = GPU_HUGE_PAGE_SIZE as u64;
+        let mut stats = fmm_alignment_stats_t::default();
+
+        stats.record(0x7f00_0000_1000, 0x1000);
+        stats.record(0x7f00_0000_0000, 4 * huge_page_size);
+        /* end aligned, as the reserved apertures place them */
+        stats.record(0x7f00_0000_1000, 2 * huge_page_size - 0x1000);
+        stats.record(0x7f00_0001_0000, huge_page_size);
+
+        assert_eq!(
+            stats,
+            fmm_alignment_stats_t {
+                allocations: 4,
+                huge_page_sized: 3,
+                huge_page_aligned: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_accounting_backtrace() {
+        let mut accounting = fmm_accounting_t::new();
+
+        accounting.account_object(object(1), 0, FMM_HEAP_SYSTEM, FMM_APERTURE_CPUVM, 0x1000);
+        assert!(accounting.backtrace_of(object(1)).is_none());
+
+        accounting.capture_backtrace = true;
+        accounting.account_object(object(2), 0, FMM_HEAP_SYSTEM, FMM_APERTURE_CPUVM, 0x1000);
+        assert!(accounting.backtrace_of(object(2)).is_some());
+    }
+}
@@ -0,0 +1,271 @@
+#![allow(non_snake_case)]
+
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::HsakmtStatus;
+use crate::hsakmttypes::HsakmtStatus::{HSAKMT_STATUS_INVALID_PARAMETER, HSAKMT_STATUS_SUCCESS};
+
+/* Default number of guard pages after each allocation in the SVM apertures */
+pub const FMM_DEFAULT_GUARD_PAGES: u32 = 1;
+pub const FMM_MAX_GUARD_PAGES: u32 = 1024;
+
+/* Default max VA alignment order, PAGE_SIZE << 9 is 2MB */
+pub const FMM_DEFAULT_ALIGNMENT_ORDER: u32 = 9;
+/* PAGE_SIZE << 18 is 1GB */
+pub const FMM_MAX_ALIGNMENT_ORDER: u32 = 18;
+
+/* HSAKMT_DEBUG_LEVEL from which debug messages are printed, as in
+ * libhsakmt
+ */
+pub const HSAKMT_DEBUG_LEVEL_DEBUG: u32 = 7;
+
+/* fmm tunables. Unless they are set with hsakmt_fmm_set_config, they
+ * are read from the environment every time the process apertures are
+ * initialized.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FmmConfig {
+    /* HSA_DISABLE_CACHE: non-0 makes all memory coherent and uncached */
+    pub disable_cache: bool,
+    /* HSA_USERPTR_FOR_PAGED_MEM: unset or non-0 uses userptr for all
+     * paged memory allocations
+     */
+    pub userptr_for_paged_mem: bool,
+    /* HSA_CHECK_USERPTR: non-0 checks all userptrs on registration */
+    pub check_userptr: bool,
+    /* HSA_RESERVE_SVM: non-0 reserves the SVM apertures even when the
+     * GPUVM address space covers the whole canonical address range
+     */
+    pub reserve_svm: bool,
+    /* HSA_SVM_GUARD_PAGES: guard pages after each SVM allocation */
+    pub guard_pages: u32,
+    /* HSA_MAX_VA_ALIGN: big buffers are aligned up to
+     * PAGE_SIZE << alignment_order
+     */
+    pub alignment_order: u32,
+    /* HSA_ALLOC_BACKTRACE: non-0 records a backtrace for every
+     * allocation, reported with the leaks on close
+     */
+    pub alloc_backtrace: bool,
+}
+
+impl Default for FmmConfig {
+    fn default() -> Self {
+        Self {
+            disable_cache: false,
+            userptr_for_paged_mem: true,
+            check_userptr: false,
+            reserve_svm: false,
+            guard_pages: FMM_DEFAULT_GUARD_PAGES,
+            alignment_order: FMM_DEFAULT_ALIGNMENT_ORDER,
+            alloc_backtrace: false,
+        }
+    }
+}
+
+/* Set to a value other than "0" */
+fn env_flag(value: Option<String>) -> bool {
+    value.is_some_and(|v| v != "0")
+}
+
+/* Leading decimal digits, like sscanf("%u") */
+fn env_u32(value: Option<String>) -> Option<u32> {
+    let value = value?;
+    let digits: String = value
+        .trim_start()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+
+    digits.parse().ok()
+}
+
+impl FmmConfig {
+    pub fn from_env() -> Self {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /* Values that don't parse or are out of range fall back to the
+     * default, as libhsakmt does
+     */
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        let mut config = Self {
+            disable_cache: env_flag(lookup("HSA_DISABLE_CACHE")),
+            userptr_for_paged_mem: lookup("HSA_USERPTR_FOR_PAGED_MEM").is_none_or(|v| v != "0"),
+            check_userptr: env_flag(lookup("HSA_CHECK_USERPTR")),
+            reserve_svm: env_flag(lookup("HSA_RESERVE_SVM")),
+            alloc_backtrace: env_flag(lookup("HSA_ALLOC_BACKTRACE")),
+            ..Default::default()
+        };
+
+        if let Some(guard_pages) = env_u32(lookup("HSA_SVM_GUARD_PAGES")) {
+            if guard_pages <= FMM_MAX_GUARD_PAGES {
+                config.guard_pages = guard_pages;
+            } else {
+                eprintln!(
+                    "HSA_SVM_GUARD_PAGES {} out of range, using {}",
+                    guard_pages, FMM_DEFAULT_GUARD_PAGES
+                );
+            }
+        }
+
+        if let Some(alignment_order) = env_u32(lookup("HSA_MAX_VA_ALIGN")) {
+            if alignment_order <= FMM_MAX_ALIGNMENT_ORDER {
+                config.alignment_order = alignment_order;
+            } else {
+                eprintln!(
+                    "HSA_MAX_VA_ALIGN {} out of range, using {}",
+                    alignment_order, FMM_DEFAULT_ALIGNMENT_ORDER
+                );
+            }
+        }
+
+        config
+    }
+
+    pub fn validate(&self) -> HsakmtStatus {
+        if self.guard_pages > FMM_MAX_GUARD_PAGES || self.alignment_order > FMM_MAX_ALIGNMENT_ORDER
+        {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* Print the configuration to stderr if HSAKMT_DEBUG_LEVEL asks for
+     * debug messages
+     */
+    pub fn log(&self) {
+        if env_u32(std::env::var("HSAKMT_DEBUG_LEVEL").ok())
+            .is_some_and(|level| level >= HSAKMT_DEBUG_LEVEL_DEBUG)
+        {
+            eprintln!("fmm config {:?}", self);
+        }
+    }
+}
+
+impl HsakmtGlobals {
+    /* Override the environment. Takes effect when the process apertures
+     * are initialized, i.e. on the next hsaKmtOpenKFD.
+     */
+    pub fn hsakmt_fmm_set_config(&mut self, config: FmmConfig) -> HsakmtStatus {
+        let ret = config.validate();
+        if ret != HSAKMT_STATUS_SUCCESS {
+            return ret;
+        }
+
+        self.fmm.config_override = Some(config);
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* Configuration in use, None before the process apertures are
+     * initialized unless it was set with hsakmt_fmm_set_config
+     */
+    pub fn hsakmt_fmm_get_config(&self) -> Option<FmmConfig> {
+        self.fmm.config.or(self.fmm.config_override)
+    }
+
+    /* Configuration for initializing the process apertures: the one set
+     * with hsakmt_fmm_set_config, or else a fresh one from load
+     */
+    pub fn fmm_load_config(&mut self, load: impl FnOnce() -> FmmConfig) -> FmmConfig {
+        let config = self.fmm.config_override.unwrap_or_else(load);
+
+        self.fmm.config = Some(config);
+
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config_of(vars: &[(&str, &str)]) -> FmmConfig {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        FmmConfig::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_fmm_config_defaults() {
+        assert_eq!(config_of(&[]), FmmConfig::default());
+        assert!(FmmConfig::default().userptr_for_paged_mem);
+        assert_eq!(FmmConfig::default().validate(), HSAKMT_STATUS_SUCCESS);
+    }
+
+    #[test]
+    fn test_fmm_config_flags() {
+        let config = config_of(&[
+            ("HSA_DISABLE_CACHE", "1"),
+            ("HSA_CHECK_USERPTR", "yes"),
+            ("HSA_RESERVE_SVM", "0"),
+            ("HSA_USERPTR_FOR_PAGED_MEM", "0"),
+        ]);
+
+        assert!(config.disable_cache);
+        assert!(config.check_userptr);
+        assert!(!config.reserve_svm);
+        assert!(!config.userptr_for_paged_mem);
+        assert!(!config.alloc_backtrace);
+
+        let config = config_of(&[
+            ("HSA_DISABLE_CACHE", "0"),
+            ("HSA_USERPTR_FOR_PAGED_MEM", "2"),
+        ]);
+        assert!(!config.disable_cache);
+        assert!(config.userptr_for_paged_mem);
+    }
+
+    #[test]
+    fn test_fmm_config_numbers() {
+        let config = config_of(&[("HSA_SVM_GUARD_PAGES", "4"), ("HSA_MAX_VA_ALIGN", "18")]);
+        assert_eq!(config.guard_pages, 4);
+        assert_eq!(config.alignment_order, 18);
+
+        /* sscanf semantics, trailing garbage is ignored */
+        let config = config_of(&[("HSA_SVM_GUARD_PAGES", " 0pages")]);
+        assert_eq!(config.guard_pages, 0);
+
+        /* not a number or out of range, keep the default */
+        let config = config_of(&[("HSA_SVM_GUARD_PAGES", "x"), ("HSA_MAX_VA_ALIGN", "40")]);
+        assert_eq!(config.guard_pages, FMM_DEFAULT_GUARD_PAGES);
+        assert_eq!(config.alignment_order, FMM_DEFAULT_ALIGNMENT_ORDER);
+
+        let config = FmmConfig {
+            alignment_order: 19,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), HSAKMT_STATUS_INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn test_fmm_load_config() {
+        let mut g = HsakmtGlobals::without_kfd();
+        assert_eq!(g.hsakmt_fmm_get_config(), None);
+
+        /* The environment is read again on every load */
+        let config = g.fmm_load_config(|| config_of(&[("HSA_SVM_GUARD_PAGES", "4")]));
+        assert_eq!(config.guard_pages, 4);
+        let config = g.fmm_load_config(|| config_of(&[]));
+        assert_eq!(config, FmmConfig::default());
+        assert_eq!(g.hsakmt_fmm_get_config(), Some(config));
+
+        /* unless the configuration was set */
+        let set = FmmConfig {
+            guard_pages: 8,
+            ..Default::default()
+        };
+        assert_eq!(g.hsakmt_fmm_set_config(set), HSAKMT_STATUS_SUCCESS);
+        assert_eq!(g.hsakmt_fmm_get_config(), Some(FmmConfig::default()));
+        assert_eq!(
+            g.fmm_load_config(|| config_of(&[("HSA_SVM_GUARD_PAGES", "4")])),
+            set
+        );
+        assert_eq!(g.hsakmt_fmm_get_config(), Some(set));
+    }
+}
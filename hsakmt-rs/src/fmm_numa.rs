@@ -0,0 +1,352 @@
+#![allow(non_camel_case_types, non_snake_case, dead_code)]
+
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::HsaMemFlags;
+use crate::hsakmttypes::HsakmtStatus;
+use crate::hsakmttypes::HsakmtStatus::{
+    HSAKMT_STATUS_ERROR, HSAKMT_STATUS_INVALID_PARAMETER, HSAKMT_STATUS_SUCCESS,
+};
+use crate::topology::gpu_get_direct_link_cpu;
+use libc::{strerror, EFAULT, EPERM};
+use numa_sys::numaif_bindings::{
+    get_mempolicy, mbind, move_pages, MPOL_BIND, MPOL_DEFAULT, MPOL_F_ADDR, MPOL_F_MEMS_ALLOWED,
+    MPOL_INTERLEAVE, MPOL_PREFERRED,
+};
+use std::collections::BTreeMap;
+use std::os::raw::{c_int, c_ulong, c_void};
+use std::sync::Once;
+
+/* Largest NUMA node id a policy can name */
+pub const FMM_NUMA_MAX_NODES: u32 = 1024;
+
+const BITS_PER_LONG: u32 = c_ulong::BITS;
+
+static MBIND_BLOCKED_LOGGED: Once = Once::new();
+
+/* Placement of host memory: GTT BOs, userptr BOs backing paged memory
+ * and CPU memory on APUs.
+ */
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum fmm_numa_policy_t {
+    /* The CPU node the GPU is directly linked to, or the requested CPU
+     * node. Preferred, or bound with NoSubstitute.
+     */
+    #[default]
+    FMM_NUMA_CLOSEST,
+    /* Bound to the given NUMA nodes */
+    FMM_NUMA_BIND(Vec<u32>),
+    /* Interleaved page by page over the given NUMA nodes */
+    FMM_NUMA_INTERLEAVE(Vec<u32>),
+    /* Left to the process policy */
+    FMM_NUMA_NONE,
+}
+
+/* Where the pages of a range actually are */
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct fmm_numa_placement_t {
+    /* MPOL_* mode and nodes of the policy at the start of the range */
+    pub mode: i32,
+    pub nodes: Vec<u32>,
+    /* Pages per NUMA node. Pages that are not present are counted
+     * under the negative errno move_pages reported for them, usually
+     * -ENOENT.
+     */
+    pub pages: BTreeMap<i32, u64>,
+}
+
+/* Bitmask with one bit per node, in the layout mbind expects */
+pub fn numa_nodemask(nodes: &[u32]) -> Vec<c_ulong> {
+    let max_node = nodes.iter().copied().max().unwrap_or(0);
+    let mut mask = vec![0; (max_node / BITS_PER_LONG) as usize + 1];
+
+    for node in nodes {
+        mask[(node / BITS_PER_LONG) as usize] |= 1 << (node % BITS_PER_LONG);
+    }
+
+    mask
+}
+
+pub fn numa_nodes_of_mask(mask: &[c_ulong]) -> Vec<u32> {
+    let mut nodes = vec![];
+
+    for (i, word) in mask.iter().enumerate() {
+        for bit in 0..BITS_PER_LONG {
+            if word & (1 << bit) != 0 {
+                nodes.push(i as u32 * BITS_PER_LONG + bit);
+            }
+        }
+    }
+
+    nodes
+}
+
+/* NUMA nodes this process may allocate from, empty if the kernel has no
+ * NUMA support
+ */
+pub unsafe fn numa_allowed_nodes() -> Vec<u32> {
+    let mut mode: c_int = 0;
+    let mut mask: Vec<c_ulong> = vec![0; (FMM_NUMA_MAX_NODES / BITS_PER_LONG) as usize];
+
+    let r = get_mempolicy(
+        &mut mode,
+        mask.as_mut_ptr(),
+        FMM_NUMA_MAX_NODES as c_ulong,
+        std::ptr::null_mut(),
+        MPOL_F_MEMS_ALLOWED,
+    );
+
+    if r != 0 {
+        return vec![];
+    }
+
+    numa_nodes_of_mask(&mask)
+}
+
+/* MPOL_* mode and nodes to apply for policy. closest is the NUMA node
+ * near the memory's user. None leaves the memory alone.
+ */
+pub fn fmm_numa_mode(
+    policy: &fmm_numa_policy_t,
+    closest: Option<u32>,
+    no_substitute: bool,
+) -> Option<(u32, Vec<u32>)> {
+    match policy {
+        fmm_numa_policy_t::FMM_NUMA_CLOSEST => {
+            let mode = if no_substitute {
+                MPOL_BIND
+            } else {
+                MPOL_PREFERRED
+            };
+
+            closest.map(|node| (mode, vec![node]))
+        }
+        fmm_numa_policy_t::FMM_NUMA_BIND(nodes) => Some((MPOL_BIND, nodes.clone())),
+        fmm_numa_policy_t::FMM_NUMA_INTERLEAVE(nodes) => Some((MPOL_INTERLEAVE, nodes.clone())),
+        fmm_numa_policy_t::FMM_NUMA_NONE => None,
+    }
+}
+
+/* Apply mode to [mem, mem + size). Returns 0 or -errno */
+pub unsafe fn bind_mem_to_numa(
+    mem: *mut c_void,
+    size: u64,
+    mode: u32,
+    nodes: &[u32],
+    no_substitute: bool,
+) -> i32 {
+    let mask = numa_nodemask(nodes);
+    let maxnode = mask.len() as c_ulong * BITS_PER_LONG as c_ulong;
+
+    let r = mbind(mem, size, mode as c_int, mask.as_ptr(), maxnode, 0);
+    if r == 0 {
+        return 0;
+    }
+
+    let errno = std::io::Error::last_os_error().raw_os_error().unwrap();
+
+    /* If applcation is running inside docker, still return ok because
+     * docker seccomp blocks mbind by default, otherwise application
+     * cannot allocate system memory.
+     */
+    if errno == EPERM {
+        MBIND_BLOCKED_LOGGED.call_once(|| println!("mbind is blocked by seccomp"));
+        return 0;
+    }
+
+    /* Ignore mbind failure if no memory available on node */
+    if !no_substitute {
+        return 0;
+    }
+
+    println!(
+        "Failed to set NUMA policy for {:?}: {:?}",
+        mem,
+        strerror(errno)
+    );
+
+    -EFAULT
+}
+
+impl HsakmtGlobals {
+    /* NUMA node closest to node_id. KFD numbers its CPU nodes like the
+     * NUMA nodes, a GPU node maps to the CPU it is linked to directly.
+     */
+    pub fn fmm_numa_closest_node(&mut self, node_id: u32) -> Option<u32> {
+        let node_props = &mut self.topology.g_props;
+
+        if node_id as usize >= node_props.len() {
+            return None;
+        }
+
+        if node_props[node_id as usize].node.KFDGpuID == 0 {
+            return Some(node_id);
+        }
+
+        let cpu = gpu_get_direct_link_cpu(node_id, node_props);
+        if cpu < 0 {
+            return None;
+        }
+
+        Some(cpu as u32)
+    }
+
+    /* Apply the NUMA policy to host memory allocated for node_id.
+     * Returns 0 or -errno.
+     */
+    pub unsafe fn fmm_bind_host_mem(
+        &mut self,
+        node_id: u32,
+        mem: *mut c_void,
+        size: u64,
+        mflags: HsaMemFlags,
+    ) -> i32 {
+        if mflags.st.ui32.NoNUMABind > 0 {
+            return 0;
+        }
+
+        let allowed = numa_allowed_nodes();
+
+        /* Nothing to choose from */
+        if allowed.len() <= 1 {
+            return 0;
+        }
+
+        let no_substitute = mflags.st.ui32.NoSubstitute > 0;
+        let closest = self
+            .fmm_numa_closest_node(node_id)
+            .filter(|node| allowed.contains(node));
+
+        let Some((mode, nodes)) = fmm_numa_mode(&self.fmm.numa_policy, closest, no_substitute)
+        else {
+            return 0;
+        };
+
+        bind_mem_to_numa(mem, size, mode, &nodes, no_substitute)
+    }
+
+    /* Policy for host allocations made after this call */
+    pub fn hsakmt_fmm_set_numa_policy(&mut self, policy: fmm_numa_policy_t) -> HsakmtStatus {
+        if let fmm_numa_policy_t::FMM_NUMA_BIND(nodes)
+        | fmm_numa_policy_t::FMM_NUMA_INTERLEAVE(nodes) = &policy
+        {
+            if nodes.is_empty() || nodes.iter().any(|node| *node >= FMM_NUMA_MAX_NODES) {
+                return HSAKMT_STATUS_INVALID_PARAMETER;
+            }
+        }
+
+        self.fmm.numa_policy = policy;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub fn hsakmt_fmm_get_numa_policy(&self) -> fmm_numa_policy_t {
+        self.fmm.numa_policy.clone()
+    }
+
+    /* Policy and page placement of [address, address + size). Pages
+     * that were never touched are reported as not present.
+     */
+    pub unsafe fn hsakmt_fmm_get_numa_placement(
+        &self,
+        address: *mut c_void,
+        size: u64,
+        placement: &mut fmm_numa_placement_t,
+    ) -> HsakmtStatus {
+        let page_size = self.PAGE_SIZE() as u64;
+
+        if address.is_null() || size == 0 || !(address as u64).is_multiple_of(page_size) {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let mut mode: c_int = MPOL_DEFAULT as c_int;
+        let mut mask: Vec<c_ulong> = vec![0; (FMM_NUMA_MAX_NODES / BITS_PER_LONG) as usize];
+
+        let r = get_mempolicy(
+            &mut mode,
+            mask.as_mut_ptr(),
+            FMM_NUMA_MAX_NODES as c_ulong,
+            address,
+            MPOL_F_ADDR,
+        );
+
+        if r != 0 {
+            let errno = std::io::Error::last_os_error().raw_os_error().unwrap();
+            println!("get_mempolicy failed: {:?}", strerror(errno));
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        let count = size.div_ceil(page_size);
+        let mut pages: Vec<*mut c_void> = (0..count)
+            .map(|i| (address as u64 + i * page_size) as *mut c_void)
+            .collect();
+        let mut status: Vec<c_int> = vec![0; count as usize];
+
+        /* No target nodes, only report where the pages are */
+        let r = move_pages(
+            0,
+            count as c_ulong,
+            pages.as_mut_ptr(),
+            std::ptr::null(),
+            status.as_mut_ptr(),
+            0,
+        );
+
+        if r != 0 {
+            let errno = std::io::Error::last_os_error().raw_os_error().unwrap();
+            println!("move_pages failed: {:?}", strerror(errno));
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        placement.mode = mode;
+        placement.nodes = numa_nodes_of_mask(&mask);
+        placement.pages.clear();
+
+        for node in status {
+            *placement.pages.entry(node).or_default() += 1;
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numa_nodemask() {
+        assert_eq!(numa_nodemask(&[0]), vec![1]);
+        assert_eq!(numa_nodemask(&[1, 3]), vec![0b1010]);
+
+        let mask = numa_nodemask(&[2, BITS_PER_LONG + 1]);
+        assert_eq!(mask, vec![0b100, 0b10]);
+        assert_eq!(numa_nodes_of_mask(&mask), vec![2, BITS_PER_LONG + 1]);
+        assert!(numa_nodes_of_mask(&[0, 0]).is_empty());
+    }
+
+    #[test]
+    fn test_fmm_numa_mode() {
+        use fmm_numa_policy_t::*;
+
+        assert_eq!(
+            fmm_numa_mode(&FMM_NUMA_CLOSEST, Some(1), false),
+            Some((MPOL_PREFERRED, vec![1]))
+        );
+        assert_eq!(
+            fmm_numa_mode(&FMM_NUMA_CLOSEST, Some(1), true),
+            Some((MPOL_BIND, vec![1]))
+        );
+        /* GPU without a direct link to a CPU */
+        assert_eq!(fmm_numa_mode(&FMM_NUMA_CLOSEST, None, true), None);
+
+        assert_eq!(
+            fmm_numa_mode(&FMM_NUMA_INTERLEAVE(vec![0, 1]), Some(1), false),
+            Some((MPOL_INTERLEAVE, vec![0, 1]))
+        );
+        assert_eq!(
+            fmm_numa_mode(&FMM_NUMA_BIND(vec![0]), Some(1), false),
+            Some((MPOL_BIND, vec![0]))
+        );
+        assert_eq!(fmm_numa_mode(&FMM_NUMA_NONE, Some(0), true), None);
+    }
+}
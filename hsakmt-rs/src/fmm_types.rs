@@ -7,21 +7,36 @@
     clippy::mixed_case_hex_literals
 )]
 
+use crate::fmm::reserved_aperture_ops;
 use crate::fmm_types::svm_aperture_type::SVM_DEFAULT;
-use crate::hsakmttypes::{HsaMemFlagUnion, HsaMemFlags, HSA_ENGINE_ID};
-use crate::rbtree::{rbtree_node_t, rbtree_s, rbtree_t};
+use crate::hsakmttypes::{HsaMemFlagUnion, HsaMemFlags, HsaSharedMemoryHandle, HSA_ENGINE_ID};
+use crate::vm_tree::vm_tree_t;
 use amdgpu_drm_sys::bindings::amdgpu_device;
+use std::marker::PhantomData;
+
+/* Cache policy of a GPU as last set with AMDKFD_IOC_SET_MEMORY_POLICY.
+ * Accesses inside the alternate aperture use alternate_policy, all
+ * others default_policy. Only pre-GFX9 GPUs select the MTYPE this way.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct fmm_memory_policy_t {
+    pub default_policy: u32, /* KFD_IOC_CACHE_POLICY_* */
+    pub alternate_policy: u32,
+    pub alternate_aperture_base: u64,
+    pub alternate_aperture_size: u64,
+}
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct vm_area<'a> {
+pub struct vm_area {
     pub start: *mut std::os::raw::c_void,
     pub end: *mut std::os::raw::c_void,
-    pub next: Option<&'a vm_area<'a>>,
-    pub prev: Option<&'a vm_area<'a>>,
+    pub next: *mut vm_area,
+    pub prev: *mut vm_area,
 }
 
-pub type vm_area_t<'a> = vm_area<'a>;
+pub type vm_area_t = vm_area;
 
+#[derive(Clone, Copy)]
 pub struct HsakmtGlobalsArgs {
     pub page_size: i32,
     pub fmm_svm_alignment_order: u32,
@@ -36,15 +51,21 @@ pub struct manageable_aperture_ops_t {
     // allocate_area_aligned: &'static fn(&[u8]) -> *mut std::os::raw::c_void,
     pub allocate_area_aligned: Option<
         unsafe fn(
-            aper: &manageable_aperture_t,
+            aper: &mut manageable_aperture_t,
             addr: *mut std::os::raw::c_void,
             size: u64,
             align: u64,
             hsakmt_global: HsakmtGlobalsArgs,
         ) -> *mut std::os::raw::c_void,
     >,
-    pub release_area:
-        Option<unsafe fn(aper: &manageable_aperture_t, addr: *mut std::os::raw::c_void, size: u64)>,
+    pub release_area: Option<
+        unsafe fn(
+            aper: &mut manageable_aperture_t,
+            addr: *mut std::os::raw::c_void,
+            size: u64,
+            hsakmt_global: HsakmtGlobalsArgs,
+        ),
+    >,
     // void *(*allocate_area_aligned)(manageable_aperture_t *aper, void *addr, uint64_t size, uint64_t align);
     // void (*release_area)(manageable_aperture_t *aper, void *addr, uint64_t size);
 }
@@ -55,46 +76,29 @@ pub struct manageable_aperture<'a> {
     pub limit: *mut std::os::raw::c_void,
     pub align: u64,
     pub guard_pages: u32,
-    pub vm_ranges: vm_area_t<'a>,
-    pub tree: rbtree_t<'a>,
-    pub user_tree: rbtree_t<'a>,
+    pub vm_ranges: *mut vm_area_t,
+    pub tree: vm_tree_t,
+    pub user_tree: vm_tree_t,
     pub is_cpu_accessible: bool,
     // ops: &'a manageable_aperture_ops_t,
     pub ops: manageable_aperture_ops_t,
+    pub _marker: PhantomData<&'a ()>,
 }
 
 impl manageable_aperture<'_> {
     pub fn INIT_MANAGEABLE_APERTURE(base_value: usize, limit_value: usize) -> Self {
-        let aperture = Self {
+        Self {
             base: base_value as *mut std::os::raw::c_void,
             limit: limit_value as *mut std::os::raw::c_void,
             align: 0,
             guard_pages: 1,
-            vm_ranges: vm_area {
-                start: std::ptr::null_mut(),
-                end: std::ptr::null_mut(),
-                next: None,
-                prev: None,
-            },
-            tree: rbtree_s {
-                root: std::ptr::null_mut(),
-                sentinel: Default::default(),
-            },
-            user_tree: rbtree_s {
-                root: std::ptr::null_mut(),
-                sentinel: Default::default(),
-            },
+            vm_ranges: std::ptr::null_mut(),
+            tree: vm_tree_t::new(),
+            user_tree: vm_tree_t::new(),
             is_cpu_accessible: false,
-            ops: manageable_aperture_ops_t {
-                allocate_area_aligned: None,
-                release_area: None,
-            },
-        };
-
-        // aperture.tree.sentinel.left = &mut aperture.tree.sentinel as *mut rbtree_node_t;
-        // aperture.tree.sentinel.right = &mut aperture.tree.sentinel as *mut rbtree_node_t;
-
-        aperture
+            ops: reserved_aperture_ops,
+            _marker: PhantomData,
+        }
     }
 }
 
@@ -221,6 +225,8 @@ pub struct gpu_mem_t<'a> {
     pub usable_peer_id_num: u32,
     pub usable_peer_id_array: Vec<u32>,
     pub drm_render_minor: u32,
+    /* None until a memory policy was set for the GPU */
+    pub memory_policy: Option<fmm_memory_policy_t>,
 }
 
 unsafe impl Send for gpu_mem_t<'_> {}
@@ -251,6 +257,7 @@ impl Default for gpu_mem_t<'_> {
             usable_peer_id_num: 0,
             usable_peer_id_array: vec![],
             drm_render_minor: 0,
+            memory_policy: None,
         }
     }
 }
@@ -312,19 +319,15 @@ pub struct vm_object {
                      	*/
     pub handle: u64, /* opaque */
     pub node_id: u32,
-    pub node: rbtree_node_t,
-    pub user_node: rbtree_node_t,
 
     pub mflags: HsaMemFlags, /* memory allocation flags */
     /* Registered nodes to map on SVM mGPU */
-    pub registered_device_id_array: *mut u32,
-    pub registered_device_id_array_size: u32,
-    pub registered_node_id_array: *mut u32,
+    pub registered_device_id_array: Vec<u32>,
+    pub registered_node_id_array: Vec<u32>,
     pub registration_count: u32, /* the same memory region can be registered multiple times */
     /* Nodes that mapped already */
-    pub mapped_device_id_array: *mut u32,
-    pub mapped_device_id_array_size: u32,
-    pub mapped_node_id_array: *mut u32,
+    pub mapped_device_id_array: Vec<u32>,
+    pub mapped_node_id_array: Vec<u32>,
     pub mapping_count: u32,
     /* Metadata of imported graphics buffers */
     pub metadata: *mut std::os::raw::c_void,
@@ -332,6 +335,8 @@ pub struct vm_object {
     pub user_data: *mut std::os::raw::c_void,
     /* Flag to indicate imported KFD buffer */
     pub is_imported_kfd_bo: bool,
+    /* IPC handle handed out for this buffer, valid until the buffer is freed */
+    pub shared_handle: Option<HsaSharedMemoryHandle>,
 }
 
 pub type vm_object_t = vm_object;
@@ -345,22 +350,130 @@ impl Default for vm_object {
             size: 0,
             handle: 0,
             node_id: 0,
-            node: Default::default(),
-            user_node: Default::default(),
             mflags: HsaMemFlags {
                 st: HsaMemFlagUnion { Value: 0 },
             },
-            registered_device_id_array: std::ptr::null_mut(),
-            registered_device_id_array_size: 0,
-            registered_node_id_array: std::ptr::null_mut(),
+            registered_device_id_array: vec![],
+            registered_node_id_array: vec![],
             registration_count: 0,
-            mapped_device_id_array: std::ptr::null_mut(),
-            mapped_device_id_array_size: 0,
-            mapped_node_id_array: std::ptr::null_mut(),
+            mapped_device_id_array: vec![],
+            mapped_node_id_array: vec![],
             mapping_count: 0,
             metadata: std::ptr::null_mut(),
             user_data: std::ptr::null_mut(),
             is_imported_kfd_bo: false,
+            shared_handle: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HSA_APERTURE {
+    HSA_APERTURE_UNSUPPORTED = 0,
+    HSA_APERTURE_DGPU,
+    HSA_APERTURE_DGPU_ALT,
+    HSA_APERTURE_GPUVM,
+    HSA_APERTURE_CPUVM,
+    HSA_APERTURE_MEMHANDLE,
+}
+
+impl HSA_APERTURE {
+    pub fn from_u32(v: u32) -> Self {
+        match v {
+            1 => HSA_APERTURE::HSA_APERTURE_DGPU,
+            2 => HSA_APERTURE::HSA_APERTURE_DGPU_ALT,
+            3 => HSA_APERTURE::HSA_APERTURE_GPUVM,
+            4 => HSA_APERTURE::HSA_APERTURE_CPUVM,
+            5 => HSA_APERTURE::HSA_APERTURE_MEMHANDLE,
+            _ => HSA_APERTURE::HSA_APERTURE_UNSUPPORTED,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HsaApertureInfo {
+    pub type_: HSA_APERTURE, /* Aperture type */
+    pub idx: u32,            /* Aperture index */
+}
+
+impl Default for HsaApertureInfo {
+    fn default() -> Self {
+        Self {
+            type_: HSA_APERTURE::HSA_APERTURE_UNSUPPORTED,
+            idx: 0,
         }
     }
 }
+
+/* Layout of the data carried in a HsaSharedMemoryHandle */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HsaSharedMemoryStruct {
+    pub ShareHandle: [u32; 4],
+    pub ApeInfo: HsaApertureInfo,
+    pub SizeInPages: u32,
+    pub ExportGpuId: u32,
+}
+
+impl HsaSharedMemoryStruct {
+    pub fn to_handle(&self) -> HsaSharedMemoryHandle {
+        let mut handle = HsaSharedMemoryHandle::default();
+
+        handle.Reserved[0..4].copy_from_slice(&self.ShareHandle);
+        handle.Reserved[4] = self.ApeInfo.type_ as u32;
+        handle.Reserved[5] = self.ApeInfo.idx;
+        handle.Reserved[6] = self.SizeInPages;
+        handle.Reserved[7] = self.ExportGpuId;
+
+        handle
+    }
+
+    pub fn from_handle(handle: &HsaSharedMemoryHandle) -> Self {
+        let mut share_handle = [0u32; 4];
+        share_handle.copy_from_slice(&handle.Reserved[0..4]);
+
+        Self {
+            ShareHandle: share_handle,
+            ApeInfo: HsaApertureInfo {
+                type_: HSA_APERTURE::from_u32(handle.Reserved[4]),
+                idx: handle.Reserved[5],
+            },
+            SizeInPages: handle.Reserved[6],
+            ExportGpuId: handle.Reserved[7],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_memory_handle_round_trip() {
+        let shared = HsaSharedMemoryStruct {
+            ShareHandle: [0x1111_2222, 0x3333_4444, 0x5555_6666, 0x7777_8888],
+            ApeInfo: HsaApertureInfo {
+                type_: HSA_APERTURE::HSA_APERTURE_DGPU_ALT,
+                idx: 3,
+            },
+            SizeInPages: 512,
+            ExportGpuId: 0xbeef,
+        };
+
+        let handle = shared.to_handle();
+        let bytes = handle.to_bytes();
+        assert_eq!(bytes.len(), HsaSharedMemoryHandle::SIZE);
+
+        let received = HsaSharedMemoryHandle::from_bytes(&bytes);
+        assert_eq!(received, handle);
+        assert_eq!(HsaSharedMemoryStruct::from_handle(&received), shared);
+
+        /* Unknown aperture types don't decode to a valid aperture */
+        let mut bytes = bytes;
+        bytes[16..20].copy_from_slice(&42u32.to_ne_bytes());
+        let received = HsaSharedMemoryHandle::from_bytes(&bytes);
+        assert_eq!(
+            HsaSharedMemoryStruct::from_handle(&received).ApeInfo.type_,
+            HSA_APERTURE::HSA_APERTURE_UNSUPPORTED
+        );
+    }
+}
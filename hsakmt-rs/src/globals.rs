@@ -8,12 +8,20 @@
 )]
 
 use crate::fmm::START_NON_CANONICAL_ADDR;
+use crate::fmm_accounting::fmm_accounting_t;
+use crate::fmm_config::FmmConfig;
+use crate::fmm_numa::fmm_numa_policy_t;
 use crate::fmm_types::{
     gpu_mem_t, manageable_aperture_t, svm_t, DRM_FIRST_RENDER_NODE, DRM_LAST_RENDER_NODE,
 };
-use crate::hsakmttypes::{node_props_t, HsaSystemProperties, HsaVersionInfo};
+use crate::hsakmttypes::{node_props_t, HsaSystemProperties, HsaVersionInfo, HSA_QUEUEID};
+use crate::hw_exceptions::{device_event_t, HsaDeviceNotificationHandler};
+use crate::memory_exceptions::HsaMemoryExceptionHandler;
+use crate::queues::process_doorbells;
 use crate::topology_utils::SysDevicesVirtualKfd;
-use amdgpu_drm_sys::bindings::amdgpu_device;
+use amdgpu_drm_sys::bindings::amdgpu_device_handle;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
 
 #[derive(Debug)]
 pub struct TopologyGlobals {
@@ -25,6 +33,14 @@ pub struct TopologyGlobals {
     pub map_user_to_sysfs_node_id: Vec<usize>,
     pub map_user_to_sysfs_node_id_size: usize,
     pub num_sysfs_nodes: usize,
+    /* GPU nodes whose device left sysfs, see
+     * hsakmt_topology_refresh_node_availability
+     */
+    pub unavailable_nodes: BTreeSet<u32>,
+    /* generation_id of the sysfs topology unavailable_nodes was last
+     * refreshed at
+     */
+    pub availability_generation: Option<u32>,
     // utils
     pub sys_devices_virtual_kfd: SysDevicesVirtualKfd,
 }
@@ -34,12 +50,29 @@ impl TopologyGlobals {
         let mut sys_devices_virtual_kfd = SysDevicesVirtualKfd::new();
         sys_devices_virtual_kfd.load_nodes();
 
+        Self::with_sysfs(sys_devices_virtual_kfd)
+    }
+
+    /* Topology of a machine without KFD, nodes are added by the tests */
+    #[cfg(test)]
+    pub fn without_sysfs() -> Self {
+        Self::with_sysfs(SysDevicesVirtualKfd {
+            platform_oem: 0,
+            platform_id: 0,
+            platform_rev: 0,
+            nodes: vec![],
+        })
+    }
+
+    fn with_sysfs(sys_devices_virtual_kfd: SysDevicesVirtualKfd) -> Self {
         Self {
             g_system: Default::default(),
             g_props: vec![],
             map_user_to_sysfs_node_id: vec![],
             map_user_to_sysfs_node_id_size: 0,
             num_sysfs_nodes: 0,
+            unavailable_nodes: BTreeSet::new(),
+            availability_generation: None,
             sys_devices_virtual_kfd,
         }
     }
@@ -48,7 +81,7 @@ impl TopologyGlobals {
 #[derive(Debug)]
 pub struct FmmGlobals<'a> {
     pub drm_render_fds: [i32; DRM_LAST_RENDER_NODE + 1 - DRM_FIRST_RENDER_NODE],
-    pub amdgpu_handle: [amdgpu_device; DRM_LAST_RENDER_NODE + 1 - DRM_FIRST_RENDER_NODE],
+    pub amdgpu_handle: [amdgpu_device_handle; DRM_LAST_RENDER_NODE + 1 - DRM_FIRST_RENDER_NODE],
     pub svm: svm_t<'a>,
     /* The other apertures are specific to each GPU. gpu_mem_t manages GPU
      * specific memory apertures.
@@ -71,14 +104,23 @@ pub struct FmmGlobals<'a> {
      * its size is 47bits.
      */
     pub mem_handle_aperture: manageable_aperture_t<'a>,
+    /* Live memory of the process, updated on every allocate, free
+     * and map of a vm object
+     */
+    pub accounting: RefCell<fmm_accounting_t>,
+    /* Tunables in use, see FmmConfig */
+    pub config: Option<FmmConfig>,
+    /* Tunables set with hsakmt_fmm_set_config */
+    pub config_override: Option<FmmConfig>,
+    /* NUMA placement of host allocations */
+    pub numa_policy: fmm_numa_policy_t,
 }
 
 impl FmmGlobals<'_> {
     pub fn new() -> Self {
         Self {
             drm_render_fds: [0; DRM_LAST_RENDER_NODE + 1 - DRM_FIRST_RENDER_NODE],
-            amdgpu_handle: [amdgpu_device { _unused: [] };
-                DRM_LAST_RENDER_NODE + 1 - DRM_FIRST_RENDER_NODE],
+            amdgpu_handle: [std::ptr::null_mut(); DRM_LAST_RENDER_NODE + 1 - DRM_FIRST_RENDER_NODE],
             svm: svm_t::default(),
             gpu_mem: vec![],
             gpu_mem_count: 0,
@@ -91,6 +133,10 @@ impl FmmGlobals<'_> {
                 START_NON_CANONICAL_ADDR as usize,
                 (START_NON_CANONICAL_ADDR + (1 << 47)) as usize,
             ),
+            accounting: RefCell::new(fmm_accounting_t::new()),
+            config: None,
+            config_override: None,
+            numa_policy: fmm_numa_policy_t::default(),
         }
     }
 }
@@ -100,11 +146,50 @@ pub struct VersionGlobals {
     pub kfd: HsaVersionInfo,
 }
 
+#[derive(Debug)]
+pub struct QueuesGlobals {
+    /* Doorbell pages mapped per node, indexed by NodeId */
+    pub doorbells: Vec<process_doorbells>,
+    /* Queues created and not yet destroyed, see hsaKmtGetQueueSnapshot */
+    pub queues: Vec<HSA_QUEUEID>,
+}
+
+#[derive(Debug)]
+pub struct EventsGlobals {
+    /* Signal slots of the events of this process, shared with KFD */
+    pub events_page: *mut u64,
+    /* Slots in events_page */
+    pub event_limit: u32,
+}
+
+pub struct ExceptionsGlobals {
+    /* KFD event signaled on GPU memory faults of this process */
+    pub memory_event_id: Option<u32>,
+    pub memory_handler: Option<HsaMemoryExceptionHandler>,
+    /* KFD events of the device notification subscriptions */
+    pub device_events: Vec<device_event_t>,
+    pub device_handler: Option<HsaDeviceNotificationHandler>,
+}
+
+impl std::fmt::Debug for ExceptionsGlobals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExceptionsGlobals")
+            .field("memory_event_id", &self.memory_event_id)
+            .field("memory_handler", &self.memory_handler.is_some())
+            .field("device_events", &self.device_events)
+            .field("device_handler", &self.device_handler.is_some())
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 pub struct HsakmtGlobals {
     pub fmm: FmmGlobals<'static>,
     pub topology: TopologyGlobals,
     pub version: VersionGlobals,
+    pub queues: QueuesGlobals,
+    pub events: EventsGlobals,
+    pub exceptions: ExceptionsGlobals,
     // HSAKMT global data
     pub hsakmt_kfd_open_count: usize,
     pub hsakmt_kfd_fd: i32,
@@ -121,15 +206,41 @@ pub struct HsakmtGlobals {
 
 impl HsakmtGlobals {
     pub fn new() -> Self {
+        Self::with_topology(TopologyGlobals::new())
+    }
+
+    /* Globals of a process that talks to a fake KFD through the ioctl
+     * hook, see hsakmt_set_ioctl_hook
+     */
+    #[cfg(test)]
+    pub fn without_kfd() -> Self {
+        Self::with_topology(TopologyGlobals::without_sysfs())
+    }
+
+    fn with_topology(topology: TopologyGlobals) -> Self {
         Self {
             fmm: FmmGlobals::new(),
-            topology: TopologyGlobals::new(),
+            topology,
             version: VersionGlobals {
                 kfd: HsaVersionInfo {
                     KernelInterfaceMajorVersion: 0,
                     KernelInterfaceMinorVersion: 0,
                 },
             },
+            queues: QueuesGlobals {
+                doorbells: vec![],
+                queues: vec![],
+            },
+            events: EventsGlobals {
+                events_page: std::ptr::null_mut(),
+                event_limit: 0,
+            },
+            exceptions: ExceptionsGlobals {
+                memory_event_id: None,
+                memory_handler: None,
+                device_events: vec![],
+                device_handler: None,
+            },
             // HSAKMT global data
             hsakmt_kfd_fd: -1,
             hsakmt_kfd_open_count: 0,
@@ -148,6 +259,11 @@ impl HsakmtGlobals {
         }
     }
 
+    /* KFD interface minor version is at least minor */
+    pub fn check_kfd_minor_version(&self, minor: u32) -> bool {
+        self.version.kfd.KernelInterfaceMinorVersion >= minor
+    }
+
     pub fn PAGE_SIZE(&self) -> i32 {
         self.hsakmt_page_size
     }
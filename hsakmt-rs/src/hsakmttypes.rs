@@ -1180,6 +1180,22 @@ pub fn ALIGN_UP(x: u64, align: u64) -> u64 {
 // Memory allocation definitions for the KFD HSA interface
 //
 
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HSA_CACHING_TYPE {
+    HSA_CACHING_CACHED = 0,
+    HSA_CACHING_NONCACHED = 1,
+    HSA_CACHING_WRITECOMBINED = 2,
+    HSA_CACHING_RESERVED = 3,
+    HSA_CACHING_NUM_CACHING,
+    HSA_CACHING_SIZE = 0xFFFFFFFF,
+}
+
+// typedef enum _HSA_PAGE_SIZE
+pub const HSA_PAGE_SIZE_4KB: u32 = 0;
+pub const HSA_PAGE_SIZE_64KB: u32 = 1; //64KB pages, not generally available in systems
+pub const HSA_PAGE_SIZE_2MB: u32 = 2;
+pub const HSA_PAGE_SIZE_1GB: u32 = 3; //1GB pages, not generally available in systems
+
 #[derive(Debug, Copy, Clone)]
 pub struct HsaMemFlagSt {
     pub NonPaged: u32,     // default = 0: pageable memory
@@ -1303,15 +1319,346 @@ impl Default for HsaMemFlagSt {
     }
 }
 
+#[derive(Clone, Copy)]
 pub union HsaMemFlagUnion {
     pub ui32: HsaMemFlagSt,
     pub Value: u32,
 }
 
+#[derive(Clone, Copy)]
 pub struct HsaMemFlags {
     pub st: HsaMemFlagUnion,
 }
 
+/*
+ * Opaque handle for memory shared between processes. The contents are
+ * only meaningful to the thunk; it can be passed to another process as
+ * raw bytes (see to_bytes/from_bytes) and imported there with
+ * hsaKmtRegisterSharedHandle. The handle stays valid for as long as the
+ * exporting allocation is alive.
+ */
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HsaSharedMemoryHandle {
+    pub Reserved: [u32; 8],
+}
+
+impl HsaSharedMemoryHandle {
+    pub const SIZE: usize = std::mem::size_of::<HsaSharedMemoryHandle>();
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+
+        for (i, v) in self.Reserved.iter().enumerate() {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&v.to_ne_bytes());
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let mut handle = Self::default();
+
+        for (i, v) in handle.Reserved.iter_mut().enumerate() {
+            *v = u32::from_ne_bytes([
+                bytes[i * 4],
+                bytes[i * 4 + 1],
+                bytes[i * 4 + 2],
+                bytes[i * 4 + 3],
+            ]);
+        }
+
+        handle
+    }
+}
+
+//
+// Event definitions for the KFD HSA interface
+//
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HSA_EVENTTYPE {
+    HSA_EVENTTYPE_SIGNAL = 0,
+    HSA_EVENTTYPE_NODECHANGE = 1,
+    HSA_EVENTTYPE_DEVICESTATECHANGE = 2,
+    HSA_EVENTTYPE_HW_EXCEPTION = 3,
+    HSA_EVENTTYPE_SYSTEM_EVENT = 4,
+    HSA_EVENTTYPE_DEBUG_EVENT = 5,
+    HSA_EVENTTYPE_PROFILE_EVENT = 6,
+    HSA_EVENTTYPE_QUEUE_EVENT = 7,
+    HSA_EVENTTYPE_MEMORY = 8,
+    HSA_EVENTTYPE_MAXID,
+    HSA_EVENTTYPE_TYPE_SIZE = 0xFFFFFFFF,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HSA_EVENTID_MEMORYFLAGS {
+    HSA_EVENTID_MEMORY_RECOVERABLE = 0, // access fault, recoverable after page adjustment
+    HSA_EVENTID_MEMORY_FATAL_PROCESS = 1, // memory access requires process context destruction, unrecoverable
+    HSA_EVENTID_MEMORY_FATAL_VM = 2, // memory access requires all GPU VA context destruction, unrecoverable
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct HsaAccessAttributeFailure {
+    pub NotPresent: u32, // Page not present or supervisor privilege
+    pub ReadOnly: u32,   // Write access to a read-only page
+    pub NoExecute: u32,  // Execute access to a page marked NX
+    pub GpuAccess: u32,  // Host access only
+    pub ECC: u32, // RAS ECC failure (notification of DRAM ECC - non-recoverable - error, if supported by HW)
+    pub Imprecise: u32, // Can't determine the exact fault address
+    pub ErrorType: u32, // Indicates RAS errors or other errors causing the access to GPU to fail
+    // 0 = no RAS error, 1 = ECC_SRAM, 2 = Link_SYNFLOOD (poison), 3 = GPU hang (not attributable to a specific cause), other values reserved
+    pub Reserved: u32, // must be 0
+}
+
+// data associated with HSA_EVENTID_MEMORY
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct HsaMemoryAccessFault {
+    pub NodeId: u32, // H-NUMA node that contains the device where the memory access occurred
+    pub VirtualAddress: u64, // virtual address this occurred on
+    pub Failure: HsaAccessAttributeFailure, // failure attribute
+    pub Flags: HSA_EVENTID_MEMORYFLAGS, // event flags
+}
+
+pub type HSA_EVENTID = u32;
+
+pub const HSA_EVENTTIMEOUT_IMMEDIATE: u32 = 0;
+pub const HSA_EVENTTIMEOUT_INFINITE: u32 = 0xFFFFFFFF;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HSA_EVENTTYPE_NODECHANGE_FLAGS {
+    HSA_EVENTTYPE_NODECHANGE_ADD = 0,
+    HSA_EVENTTYPE_NODECHANGE_REMOVE = 1,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HSA_DEVICE {
+    HSA_DEVICE_CPU = 0,
+    HSA_DEVICE_GPU = 1,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HSA_EVENTTYPE_DEVICESTATECHANGE_FLAGS {
+    HSA_EVENTTYPE_DEVICESTATUSCHANGE_START = 0, // device started (and available)
+    HSA_EVENTTYPE_DEVICESTATUSCHANGE_STOP = 1,  // device stopped (i.e. unavailable)
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HSA_EVENTID_HW_EXCEPTION_CAUSE {
+    HSA_EVENTID_HW_EXCEPTION_GPU_HANG = 0, // GPU Hang
+    HSA_EVENTID_HW_EXCEPTION_ECC = 1,      // SRAM ECC error
+}
+
+// data associated with HSA_EVENTTYPE_SIGNAL
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct HsaSyncVar {
+    pub UserData: u64,    // pointer to user mode data
+    pub SyncVarSize: u64, // size of user mode data
+}
+
+// data associated with HSA_EVENTTYPE_NODE_CHANGE
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct HsaNodeChange {
+    pub Flags: HSA_EVENTTYPE_NODECHANGE_FLAGS, // HSA node added/removed on the platform
+}
+
+// data associated with HSA_EVENTTYPE_DEVICE_STATE_CHANGE
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct HsaDeviceStateChange {
+    pub NodeId: u32,        // F-NUMA node that contains the device
+    pub Device: HSA_DEVICE, // device type: GPU or CPU
+    pub Flags: HSA_EVENTTYPE_DEVICESTATECHANGE_FLAGS, // event flags
+}
+
+// data associated with HSA_EVENTTYPE_HW_EXCEPTION
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct HsaHwException {
+    pub NodeId: u32,    // Node Id where the memory exception occured
+    pub ResetType: u32, // KFD_HW_EXCEPTION_WHOLE_GPU_RESET or KFD_HW_EXCEPTION_PER_ENGINE_RESET
+    pub ResetCause: HSA_EVENTID_HW_EXCEPTION_CAUSE,
+    pub MemoryLost: u32, // Memory contents lost due to the reset
+}
+
+/* What to create an event for. The node is the one whose device
+ * reports the event, signal events aren't tied to a node.
+ */
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HsaEventDescriptor {
+    Signal { SyncVar: HsaSyncVar },
+    NodeChange,
+    DeviceStateChange { NodeId: u32 },
+    HwException { NodeId: u32 },
+    SystemEvent { NodeId: u32 },
+    Debug { NodeId: u32 },
+    Profile { NodeId: u32 },
+    QueueEvent { NodeId: u32 },
+    Memory { NodeId: u32 },
+}
+
+impl HsaEventDescriptor {
+    pub fn EventType(&self) -> HSA_EVENTTYPE {
+        match self {
+            HsaEventDescriptor::Signal { .. } => HSA_EVENTTYPE::HSA_EVENTTYPE_SIGNAL,
+            HsaEventDescriptor::NodeChange => HSA_EVENTTYPE::HSA_EVENTTYPE_NODECHANGE,
+            HsaEventDescriptor::DeviceStateChange { .. } => {
+                HSA_EVENTTYPE::HSA_EVENTTYPE_DEVICESTATECHANGE
+            }
+            HsaEventDescriptor::HwException { .. } => HSA_EVENTTYPE::HSA_EVENTTYPE_HW_EXCEPTION,
+            HsaEventDescriptor::SystemEvent { .. } => HSA_EVENTTYPE::HSA_EVENTTYPE_SYSTEM_EVENT,
+            HsaEventDescriptor::Debug { .. } => HSA_EVENTTYPE::HSA_EVENTTYPE_DEBUG_EVENT,
+            HsaEventDescriptor::Profile { .. } => HSA_EVENTTYPE::HSA_EVENTTYPE_PROFILE_EVENT,
+            HsaEventDescriptor::QueueEvent { .. } => HSA_EVENTTYPE::HSA_EVENTTYPE_QUEUE_EVENT,
+            HsaEventDescriptor::Memory { .. } => HSA_EVENTTYPE::HSA_EVENTTYPE_MEMORY,
+        }
+    }
+
+    pub fn NodeId(&self) -> u32 {
+        match *self {
+            HsaEventDescriptor::Signal { .. } | HsaEventDescriptor::NodeChange => 0,
+            HsaEventDescriptor::DeviceStateChange { NodeId }
+            | HsaEventDescriptor::HwException { NodeId }
+            | HsaEventDescriptor::SystemEvent { NodeId }
+            | HsaEventDescriptor::Debug { NodeId }
+            | HsaEventDescriptor::Profile { NodeId }
+            | HsaEventDescriptor::QueueEvent { NodeId }
+            | HsaEventDescriptor::Memory { NodeId } => NodeId,
+        }
+    }
+}
+
+/* Event specific data, filled in by the waits for memory and HW
+ * exception events
+ */
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HsaEventDataUnion {
+    None,
+    SyncVar(HsaSyncVar),
+    NodeChangeState(HsaNodeChange),
+    DeviceState(HsaDeviceStateChange),
+    MemoryAccessFault(HsaMemoryAccessFault),
+    HwException(HsaHwException),
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct HsaEventData {
+    pub EventType: HSA_EVENTTYPE, // event type
+    pub EventData: HsaEventDataUnion,
+    // the following data entries are internal to the KFD & thunk itself.
+    pub HWData1: u64, // internal thunk store for Event data  (OsEventHandle)
+    pub HWData2: u64, // internal thunk store for Event data  (HWAddress)
+    pub HWData3: u32, // internal thunk store for Event data  (HWData)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct HsaEvent {
+    pub EventId: HSA_EVENTID,
+    pub EventData: HsaEventData,
+}
+
+//
+// Queue definitions
+//
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HSA_QUEUE_TYPE {
+    HSA_QUEUE_COMPUTE = 1,               // AMD PM4 compatible Compute Queue
+    HSA_QUEUE_SDMA = 2, // PCIe optimized SDMA Queue, used for data transport and format conversion
+    HSA_QUEUE_MULTIMEDIA_DECODE = 3, // reserved, for HSA multimedia decode queue
+    HSA_QUEUE_MULTIMEDIA_ENCODE = 4, // reserved, for HSA multimedia encode queue
+    HSA_QUEUE_SDMA_XGMI = 5, // XGMI optimized SDMA Queue
+    HSA_QUEUE_SDMA_BY_ENG_ID = 6, // PCIe optimized SDMA Queue on a specific engine
+    HSA_QUEUE_COMPUTE_OS = 11, // AMD PM4 compatible Compute Queue, OS scheduled
+    HSA_QUEUE_SDMA_OS = 12, // SDMA Queue, OS scheduled
+    HSA_QUEUE_MULTIMEDIA_DECODE_OS = 13, // reserved, OS scheduled
+    HSA_QUEUE_MULTIMEDIA_ENCODE_OS = 14, // reserved, OS scheduled
+    HSA_QUEUE_COMPUTE_AQL = 21, // HSA AQL packet compatible Compute Queue
+    HSA_QUEUE_DMA_AQL = 22, // HSA AQL packet compatible DMA Queue
+    HSA_QUEUE_DMA_AQL_XGMI = 23, // HSA AQL packet compatible XGMI optimized DMA Queue
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub enum HSA_QUEUE_PRIORITY {
+    HSA_QUEUE_PRIORITY_MINIMUM = -3,
+    HSA_QUEUE_PRIORITY_LOW = -2,
+    HSA_QUEUE_PRIORITY_BELOW_NORMAL = -1,
+    HSA_QUEUE_PRIORITY_NORMAL = 0,
+    HSA_QUEUE_PRIORITY_ABOVE_NORMAL = 1,
+    HSA_QUEUE_PRIORITY_HIGH = 2,
+    HSA_QUEUE_PRIORITY_MAXIMUM = 3,
+}
+
+impl TryFrom<i32> for HSA_QUEUE_PRIORITY {
+    type Error = ();
+
+    fn try_from(v: i32) -> Result<Self, Self::Error> {
+        use HSA_QUEUE_PRIORITY::*;
+
+        match v {
+            -3 => Ok(HSA_QUEUE_PRIORITY_MINIMUM),
+            -2 => Ok(HSA_QUEUE_PRIORITY_LOW),
+            -1 => Ok(HSA_QUEUE_PRIORITY_BELOW_NORMAL),
+            0 => Ok(HSA_QUEUE_PRIORITY_NORMAL),
+            1 => Ok(HSA_QUEUE_PRIORITY_ABOVE_NORMAL),
+            2 => Ok(HSA_QUEUE_PRIORITY_HIGH),
+            3 => Ok(HSA_QUEUE_PRIORITY_MAXIMUM),
+            _ => Err(()),
+        }
+    }
+}
+
+pub type HSA_QUEUEID = u64;
+
+#[derive(Debug, Copy, Clone)]
+pub struct HsaQueueResource {
+    pub QueueId: HSA_QUEUEID,  // queue ID
+    pub QueueDoorBell: u64,    // Doorbell address to notify HW of a new dispatch
+    pub QueueWptrValue: u64,   // virtual address to notify HW of queue write ptr value
+    pub QueueRptrValue: u64,   // virtual address updated by HW to indicate current read location
+    pub ErrorReason: *mut i64, // exception bits signalled by the queue, may be NULL
+}
+
+impl Default for HsaQueueResource {
+    fn default() -> Self {
+        Self {
+            QueueId: 0,
+            QueueDoorBell: 0,
+            QueueWptrValue: 0,
+            QueueRptrValue: 0,
+            ErrorReason: std::ptr::null_mut(),
+        }
+    }
+}
+
+/* Start of the context save area of each XCC of a queue */
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct HsaUserContextSaveAreaHeader {
+    pub ControlStackOffset: u32, // Byte offset from start of user context save area to the last saved top (lowest address) of control stack data. Must be 4 byte aligned.
+    pub ControlStackSize: u32, // Byte size of the last saved control stack data. Must be 4 byte aligned.
+    pub WaveStateOffset: u32, // Byte offset from start of user context save area to the last saved base (lowest address) of wave state data. Must be 4 byte aligned.
+    pub WaveStateSize: u32, // Byte size of the last saved wave state data. Must be 4 byte aligned.
+    pub DebugOffset: u32, // Byte offset from the start of the user context save area to the memory available for the debugger to save additional wave state.
+    pub DebugSize: u32, // Byte size of the memory available for the debugger to save additional wave state.
+    pub ErrorReason: *mut i64, // Address of the HSA signal payload for reporting the error reason bitmask.
+    pub ErrorEventId: u32,     // Event ID used for exception signalling.
+    pub Reserved1: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct HsaQueueInfo {
+    pub QueueDetailError: u32,         // Error detail, reserved
+    pub QueueTypeExtended: u32,        // Extended queue type, reserved
+    pub NumCUAssigned: u32,            // Number of bits in CUMaskInfo
+    pub CUMaskInfo: *mut u32,          // CU mask of the queue
+    pub UserContextSaveArea: *mut u32, // Start of the wave state of the last context save
+    pub SaveAreaSizeInBytes: u64,      // Bytes of wave state saved
+    pub ControlStackTop: *mut u32,     // Top (lowest address) of the saved control stack
+    pub ControlStackUsedInBytes: u64,  // Bytes of control stack saved
+    pub SaveAreaHeader: *mut HsaUserContextSaveAreaHeader, // Header of the context save area
+    pub Reserved2: u64,
+}
+
 // #define MIN(a, b) ({				\
 // typeof(a) tmp1 = (a), tmp2 = (b);	\
 // tmp1 < tmp2 ? tmp1 : tmp2; })
@@ -1326,3 +1673,18 @@ pub fn MIN(a: u64, b: u64) -> u64 {
         tmp2
     }
 }
+
+// #define MAX(a, b) ({				\
+// typeof(a) tmp1 = (a), tmp2 = (b);	\
+// tmp1 > tmp2 ? tmp1 : tmp2; })
+
+pub fn MAX(a: u64, b: u64) -> u64 {
+    let tmp1 = a;
+    let tmp2 = b;
+
+    if tmp1 > tmp2 {
+        tmp1
+    } else {
+        tmp2
+    }
+}
@@ -0,0 +1,313 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use crate::events::{
+    hsakmt_decode_hw_exception, kfd_create_event, kfd_destroy_event, kfd_wait_events,
+};
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::HsakmtStatus;
+use crate::hsakmttypes::HsakmtStatus::{
+    HSAKMT_STATUS_ERROR, HSAKMT_STATUS_INVALID_NODE_UNIT, HSAKMT_STATUS_INVALID_PARAMETER,
+    HSAKMT_STATUS_SUCCESS, HSAKMT_STATUS_WAIT_TIMEOUT,
+};
+use crate::hsakmttypes::HSA_DEVICE::HSA_DEVICE_GPU;
+use crate::hsakmttypes::HSA_EVENTTYPE::{
+    HSA_EVENTTYPE_DEVICESTATECHANGE, HSA_EVENTTYPE_HW_EXCEPTION,
+};
+use crate::hsakmttypes::HSA_EVENTTYPE_DEVICESTATECHANGE_FLAGS::{
+    HSA_EVENTTYPE_DEVICESTATUSCHANGE_START, HSA_EVENTTYPE_DEVICESTATUSCHANGE_STOP,
+};
+use crate::hsakmttypes::{HsaDeviceStateChange, HsaHwException, HSA_EVENTTYPE};
+use crate::kfd_ioctl::{kfd_event_data, kfd_hsa_hw_exception_data, kfd_ioctl_create_event_args};
+
+/* A GPU reset or hardware exception, or a GPU leaving or coming back */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HsaDeviceNotification {
+    HwException(HsaHwException),
+    DeviceStateChange(HsaDeviceStateChange),
+}
+
+pub type HsaDeviceNotificationHandler = Box<dyn FnMut(&HsaDeviceNotification)>;
+
+/* KFD event of a subscription to EventType notifications of NodeId */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct device_event_t {
+    pub NodeId: u32,
+    pub EventType: HSA_EVENTTYPE,
+    pub event_id: u32,
+}
+
+/* KFD signals every HW exception event of the process when one of its
+ * GPUs resets, so the notification only goes to the subscription of
+ * the node that reset. node_id is the node of data.gpu_id.
+ */
+pub fn hsakmt_hw_exception_notification(
+    NodeId: u32,
+    data: &kfd_hsa_hw_exception_data,
+    node_id: u32,
+) -> Option<HsaDeviceNotification> {
+    if data.gpu_id == 0 || node_id != NodeId {
+        return None;
+    }
+
+    Some(HsaDeviceNotification::HwException(
+        hsakmt_decode_hw_exception(data, node_id),
+    ))
+}
+
+/* Device state changes of the subscribed nodes among those that left
+ * (lost) or came back (back)
+ */
+pub fn hsakmt_device_state_changes(
+    subscribed: &[u32],
+    lost: &[u32],
+    back: &[u32],
+) -> Vec<HsaDeviceNotification> {
+    let change = |NodeId: u32, Flags| {
+        HsaDeviceNotification::DeviceStateChange(HsaDeviceStateChange {
+            NodeId,
+            Device: HSA_DEVICE_GPU,
+            Flags,
+        })
+    };
+
+    let stopped = lost
+        .iter()
+        .filter(|node| subscribed.contains(node))
+        .map(|node| change(*node, HSA_EVENTTYPE_DEVICESTATUSCHANGE_STOP));
+    let started = back
+        .iter()
+        .filter(|node| subscribed.contains(node))
+        .map(|node| change(*node, HSA_EVENTTYPE_DEVICESTATUSCHANGE_START));
+
+    stopped.chain(started).collect()
+}
+
+impl HsakmtGlobals {
+    /* Deliver device notifications to handler, see
+     * hsakmt_poll_device_notifications. None stops the delivery, the
+     * subscriptions stay.
+     */
+    pub fn hsakmt_set_device_notification_handler(
+        &mut self,
+        handler: Option<HsaDeviceNotificationHandler>,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        self.exceptions.device_handler = handler;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* Get EventType notifications of the GPU of NodeId:
+     * HSA_EVENTTYPE_HW_EXCEPTION for resets and the reason of them,
+     * HSA_EVENTTYPE_DEVICESTATECHANGE for the GPU leaving or coming back
+     */
+    pub unsafe fn hsakmt_subscribe_device_notifications(
+        &mut self,
+        NodeId: u32,
+        EventType: HSA_EVENTTYPE,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if EventType != HSA_EVENTTYPE_HW_EXCEPTION && EventType != HSA_EVENTTYPE_DEVICESTATECHANGE {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let mut gpu_id = 0;
+        if self.hsakmt_validate_nodeid(NodeId, &mut gpu_id) != HSAKMT_STATUS_SUCCESS || gpu_id == 0
+        {
+            return HSAKMT_STATUS_INVALID_NODE_UNIT;
+        }
+
+        if self
+            .exceptions
+            .device_events
+            .iter()
+            .any(|e| e.NodeId == NodeId && e.EventType == EventType)
+        {
+            return HSAKMT_STATUS_SUCCESS;
+        }
+
+        let mut args = kfd_ioctl_create_event_args {
+            event_type: EventType as u32,
+            node_id: NodeId,
+            auto_reset: 1,
+            ..Default::default()
+        };
+
+        if kfd_create_event(self.hsakmt_kfd_fd, &mut args) != HSAKMT_STATUS_SUCCESS {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        self.exceptions.device_events.push(device_event_t {
+            NodeId,
+            EventType,
+            event_id: args.event_id,
+        });
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsakmt_unsubscribe_device_notifications(
+        &mut self,
+        NodeId: u32,
+        EventType: HSA_EVENTTYPE,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let Some(index) = self
+            .exceptions
+            .device_events
+            .iter()
+            .position(|e| e.NodeId == NodeId && e.EventType == EventType)
+        else {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        };
+
+        let event = self.exceptions.device_events.remove(index);
+        kfd_destroy_event(self.hsakmt_kfd_fd, event.event_id)
+    }
+
+    /* Wait up to timeout_ms for a notification of the subscriptions and
+     * pass the notifications to the handler. KFD carries no data for
+     * device state changes, so the topology is checked for GPUs that
+     * left or came back after every wait, timed out or not. Returns
+     * HSAKMT_STATUS_WAIT_TIMEOUT if there was nothing.
+     */
+    pub unsafe fn hsakmt_poll_device_notifications(&mut self, timeout_ms: u32) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if self.exceptions.device_events.is_empty() {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        let mut event_data: Vec<kfd_event_data> = self
+            .exceptions
+            .device_events
+            .iter()
+            .map(|e| kfd_event_data {
+                event_id: e.event_id,
+                ..Default::default()
+            })
+            .collect();
+
+        let ret = kfd_wait_events(self.hsakmt_kfd_fd, &mut event_data, false, timeout_ms);
+        if ret != HSAKMT_STATUS_SUCCESS && ret != HSAKMT_STATUS_WAIT_TIMEOUT {
+            return ret;
+        }
+
+        let mut notifications = vec![];
+
+        for (event, data) in self.exceptions.device_events.iter().zip(&event_data) {
+            if ret != HSAKMT_STATUS_SUCCESS || event.EventType != HSA_EVENTTYPE_HW_EXCEPTION {
+                continue;
+            }
+
+            let data = data.data.hw_exception_data;
+            let mut node_id = 0;
+            if data.gpu_id == 0
+                || self.gpuid_to_nodeid(data.gpu_id, &mut node_id) != HSAKMT_STATUS_SUCCESS
+            {
+                continue;
+            }
+
+            notifications.extend(hsakmt_hw_exception_notification(
+                event.NodeId,
+                &data,
+                node_id,
+            ));
+        }
+
+        let subscribed: Vec<u32> = self
+            .exceptions
+            .device_events
+            .iter()
+            .filter(|e| e.EventType == HSA_EVENTTYPE_DEVICESTATECHANGE)
+            .map(|e| e.NodeId)
+            .collect();
+
+        let (lost, back) = self.hsakmt_topology_refresh_node_availability();
+        notifications.extend(hsakmt_device_state_changes(&subscribed, &lost, &back));
+
+        if notifications.is_empty() {
+            return ret;
+        }
+
+        for notification in &notifications {
+            if let Some(handler) = self.exceptions.device_handler.as_mut() {
+                handler(notification);
+            }
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsakmt_device_notifications_destroy(&mut self) {
+        for event in std::mem::take(&mut self.exceptions.device_events) {
+            kfd_destroy_event(self.hsakmt_kfd_fd, event.event_id);
+        }
+
+        self.exceptions.device_handler = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hsakmttypes::HSA_EVENTID_HW_EXCEPTION_CAUSE::HSA_EVENTID_HW_EXCEPTION_GPU_HANG;
+    use crate::kfd_ioctl::{
+        KFD_HW_EXCEPTION_GPU_HANG, KFD_HW_EXCEPTION_PER_ENGINE_RESET,
+        KFD_HW_EXCEPTION_WHOLE_GPU_RESET,
+    };
+
+    #[test]
+    fn test_hw_exception_notification() {
+        let data = kfd_hsa_hw_exception_data {
+            reset_type: KFD_HW_EXCEPTION_WHOLE_GPU_RESET,
+            reset_cause: KFD_HW_EXCEPTION_GPU_HANG,
+            memory_lost: 1,
+            gpu_id: 0x1234,
+        };
+
+        assert_eq!(
+            hsakmt_hw_exception_notification(2, &data, 2),
+            Some(HsaDeviceNotification::HwException(HsaHwException {
+                NodeId: 2,
+                ResetType: KFD_HW_EXCEPTION_WHOLE_GPU_RESET,
+                ResetCause: HSA_EVENTID_HW_EXCEPTION_GPU_HANG,
+                MemoryLost: 1,
+            }))
+        );
+
+        /* Another node reset, or no data */
+        assert_eq!(hsakmt_hw_exception_notification(1, &data, 2), None);
+        let data = kfd_hsa_hw_exception_data {
+            reset_type: KFD_HW_EXCEPTION_PER_ENGINE_RESET,
+            gpu_id: 0,
+            ..data
+        };
+        assert_eq!(hsakmt_hw_exception_notification(2, &data, 2), None);
+    }
+
+    #[test]
+    fn test_device_state_changes() {
+        let changes = hsakmt_device_state_changes(&[1, 2], &[2, 3], &[1]);
+
+        assert_eq!(
+            changes,
+            vec![
+                HsaDeviceNotification::DeviceStateChange(HsaDeviceStateChange {
+                    NodeId: 2,
+                    Device: HSA_DEVICE_GPU,
+                    Flags: HSA_EVENTTYPE_DEVICESTATUSCHANGE_STOP,
+                }),
+                HsaDeviceNotification::DeviceStateChange(HsaDeviceStateChange {
+                    NodeId: 1,
+                    Device: HSA_DEVICE_GPU,
+                    Flags: HSA_EVENTTYPE_DEVICESTATUSCHANGE_START,
+                }),
+            ]
+        );
+        assert!(hsakmt_device_state_changes(&[], &[2], &[1]).is_empty());
+    }
+}
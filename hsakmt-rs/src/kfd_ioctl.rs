@@ -51,6 +51,135 @@ pub struct kfd_ioctl_acquire_vm_args {
     pub gpu_id: __u32, /* to KFD */
 }
 
+pub const KFD_IOC_QUEUE_TYPE_COMPUTE: __u32 = 0x0;
+pub const KFD_IOC_QUEUE_TYPE_SDMA: __u32 = 0x1;
+pub const KFD_IOC_QUEUE_TYPE_COMPUTE_AQL: __u32 = 0x2;
+pub const KFD_IOC_QUEUE_TYPE_SDMA_XGMI: __u32 = 0x3;
+pub const KFD_IOC_QUEUE_TYPE_SDMA_BY_ENG_ID: __u32 = 0x4;
+
+pub const KFD_MAX_QUEUE_PERCENTAGE: __u32 = 100;
+pub const KFD_MAX_QUEUE_PRIORITY: __u32 = 15;
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct kfd_ioctl_create_queue_args {
+    pub ring_base_address: __u64,     /* to KFD */
+    pub write_pointer_address: __u64, /* to KFD */
+    pub read_pointer_address: __u64,  /* to KFD */
+    pub doorbell_offset: __u64,       /* from KFD */
+
+    pub ring_size: __u32,        /* to KFD */
+    pub gpu_id: __u32,           /* to KFD */
+    pub queue_type: __u32,       /* to KFD */
+    pub queue_percentage: __u32, /* to KFD */
+    pub queue_priority: __u32,   /* to KFD */
+    pub queue_id: __u32,         /* from KFD */
+
+    pub eop_buffer_address: __u64,       /* to KFD */
+    pub eop_buffer_size: __u64,          /* to KFD */
+    pub ctx_save_restore_address: __u64, /* to KFD */
+    pub ctx_save_restore_size: __u32,    /* to KFD */
+    pub ctl_stack_size: __u32,           /* to KFD */
+    pub sdma_engine_id: __u32,           /* to KFD */
+    pub pad: __u32,
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_destroy_queue_args {
+    pub queue_id: __u32, /* to KFD */
+    pub pad: __u32,
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct kfd_ioctl_update_queue_args {
+    pub ring_base_address: __u64, /* to KFD */
+
+    pub queue_id: __u32,         /* to KFD */
+    pub ring_size: __u32,        /* to KFD */
+    pub queue_percentage: __u32, /* to KFD */
+    pub queue_priority: __u32,   /* to KFD */
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_set_cu_mask_args {
+    pub queue_id: __u32,    /* to KFD */
+    pub num_cu_mask: __u32, /* to KFD */
+    pub cu_mask_ptr: __u64, /* to KFD */
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_get_queue_wave_state_args {
+    pub ctl_stack_address: __u64,   /* to KFD */
+    pub ctl_stack_used_size: __u32, /* from KFD */
+    pub save_area_used_size: __u32, /* from KFD */
+    pub queue_id: __u32,            /* to KFD */
+    pub pad: __u32,
+}
+
+pub const AMDKFD_IOC_CREATE_QUEUE: u64 =
+    AMDKFD_IOWR(0x02, std::mem::size_of::<kfd_ioctl_create_queue_args>());
+
+pub const AMDKFD_IOC_DESTROY_QUEUE: u64 =
+    AMDKFD_IOWR(0x03, std::mem::size_of::<kfd_ioctl_destroy_queue_args>());
+
+pub const AMDKFD_IOC_UPDATE_QUEUE: u64 =
+    AMDKFD_IOW(0x07, std::mem::size_of::<kfd_ioctl_update_queue_args>());
+
+pub const AMDKFD_IOC_SET_CU_MASK: u64 =
+    AMDKFD_IOW(0x1A, std::mem::size_of::<kfd_ioctl_set_cu_mask_args>());
+
+pub const AMDKFD_IOC_GET_QUEUE_WAVE_STATE: u64 = AMDKFD_IOWR(
+    0x1B,
+    std::mem::size_of::<kfd_ioctl_get_queue_wave_state_args>(),
+);
+
+/* Debugger operations, kfd_ioctl_dbg_trap_args.op */
+pub const KFD_IOC_DBG_TRAP_GET_QUEUE_SNAPSHOT: __u32 = 13;
+
+/* One queue of the process, as reported by the queue snapshot */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct kfd_queue_snapshot_entry {
+    pub exception_status: __u64,
+    pub ring_base_address: __u64,
+    pub write_pointer_address: __u64,
+    pub read_pointer_address: __u64,
+    pub ctx_save_restore_address: __u64,
+    pub queue_id: __u32,
+    pub gpu_id: __u32,
+    pub ring_size: __u32,
+    pub queue_type: __u32,
+    pub ctx_save_restore_area_size: __u32,
+    pub reserved: __u32,
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_dbg_trap_queue_snapshot_args {
+    pub exception_mask: __u64,   /* to KFD, exceptions to clear */
+    pub snapshot_buf_ptr: __u64, /* to KFD */
+    pub num_queues: __u32,       /* to KFD buffer entries, from KFD queues */
+    pub entry_size: __u32,       /* to KFD, from KFD */
+}
+
+/* The uapi struct ends in a union of the arguments of every operation,
+ * all 24 bytes. Only the queue snapshot member is used here.
+ */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_dbg_trap_args {
+    pub pid: __u32, /* to KFD */
+    pub op: __u32,  /* to KFD */
+    pub queue_snapshot: kfd_ioctl_dbg_trap_queue_snapshot_args,
+}
+
+pub const AMDKFD_IOC_DBG_TRAP: u64 =
+    AMDKFD_IOWR(0x26, std::mem::size_of::<kfd_ioctl_dbg_trap_args>());
+
 /* For kfd_ioctl_set_memory_policy_args.default_policy and alternate_policy */
 // #define KFD_IOC_CACHE_POLICY_COHERENT 0
 // #define KFD_IOC_CACHE_POLICY_NONCOHERENT 1
@@ -60,7 +189,7 @@ pub const KFD_IOC_CACHE_POLICY_COHERENT: usize = 0;
 pub const KFD_IOC_CACHE_POLICY_NONCOHERENT: usize = 1;
 
 #[repr(C)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct kfd_ioctl_set_memory_policy_args {
     pub alternate_aperture_base: *mut __u64, /* to KFD */
     pub alternate_aperture_size: __u64,      /* to KFD */
@@ -71,6 +200,11 @@ pub struct kfd_ioctl_set_memory_policy_args {
     pub pad: __u32,
 }
 
+pub const AMDKFD_IOC_SET_MEMORY_POLICY: u64 = AMDKFD_IOW(
+    0x04,
+    std::mem::size_of::<kfd_ioctl_set_memory_policy_args>(),
+);
+
 /* Allocation flags: memory types */
 pub const KFD_IOC_ALLOC_MEM_FLAGS_VRAM: usize = 1 << 0;
 pub const KFD_IOC_ALLOC_MEM_FLAGS_GTT: usize = 1 << 1;
@@ -111,6 +245,11 @@ pub struct kfd_ioctl_alloc_memory_of_gpu_args {
     pub flags: __u32,
 }
 
+pub const AMDKFD_IOC_ALLOC_MEMORY_OF_GPU: u64 = AMDKFD_IOWR(
+    0x16,
+    std::mem::size_of::<kfd_ioctl_alloc_memory_of_gpu_args>(),
+);
+
 /* Free memory allocated with kfd_ioctl_alloc_memory_of_gpu
  *
  * @handle: memory handle returned by alloc
@@ -126,3 +265,422 @@ const ioc_free_mem_gpu_2: u64 =
     ((std::mem::size_of::<kfd_ioctl_free_memory_of_gpu_args>()) << ((0 + 8) + 8)) as u64;
 pub const AMDKFD_IOC_FREE_MEMORY_OF_GPU: u64 =
     ((1) << (((0 + 8) + 8) + 14)) | ioc_free_mem_gpu_1 | ((0x17) << 0) | ioc_free_mem_gpu_2;
+
+// #define AMDKFD_IOCTL_BASE 'K'
+// #define AMDKFD_IO(nr)			_IO(AMDKFD_IOCTL_BASE, nr)
+// #define AMDKFD_IOR(nr, type)		_IOR(AMDKFD_IOCTL_BASE, nr, type)
+// #define AMDKFD_IOW(nr, type)		_IOW(AMDKFD_IOCTL_BASE, nr, type)
+// #define AMDKFD_IOWR(nr, type)		_IOWR(AMDKFD_IOCTL_BASE, nr, type)
+const _IOC_WRITE: u64 = 1;
+const _IOC_READ: u64 = 2;
+
+const fn AMDKFD_IOC(dir: u64, nr: u64, size: usize) -> u64 {
+    (dir << 30) | ((size as u64) << 16) | (('K' as u64) << 8) | nr
+}
+
+pub const fn AMDKFD_IOR(nr: u64, size: usize) -> u64 {
+    AMDKFD_IOC(_IOC_READ, nr, size)
+}
+
+pub const fn AMDKFD_IOW(nr: u64, size: usize) -> u64 {
+    AMDKFD_IOC(_IOC_WRITE, nr, size)
+}
+
+pub const fn AMDKFD_IOWR(nr: u64, size: usize) -> u64 {
+    AMDKFD_IOC(_IOC_READ | _IOC_WRITE, nr, size)
+}
+
+/* Export a memory handle for sharing with another process
+ *
+ * @handle:       memory handle of the buffer to export
+ * @share_handle: opaque handle, valid in other processes, from KFD
+ * @gpu_id:       device the buffer was allocated on
+ * @flags:        reserved, must be 0
+ */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_ipc_export_handle_args {
+    pub handle: __u64,            /* to KFD */
+    pub share_handle: [__u32; 4], /* from KFD */
+    pub gpu_id: __u32,            /* to KFD */
+    pub flags: __u32,             /* to KFD */
+}
+
+/* Import a memory handle exported by another process
+ *
+ * @handle:       memory handle of the imported buffer, from KFD
+ * @va_addr:      virtual address where the buffer will be mapped
+ * @mmap_offset:  for CPU-mapping the imported buffer, from KFD
+ * @share_handle: opaque handle returned by the exporting process
+ * @gpu_id:       device the buffer was exported from
+ * @flags:        allocation flags of the imported buffer, from KFD
+ */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_ipc_import_handle_args {
+    pub handle: __u64,            /* from KFD */
+    pub va_addr: __u64,           /* to KFD */
+    pub mmap_offset: __u64,       /* from KFD */
+    pub share_handle: [__u32; 4], /* to KFD */
+    pub gpu_id: __u32,            /* to KFD */
+    pub flags: __u32,             /* from KFD */
+}
+
+pub const AMDKFD_IOC_IPC_IMPORT_HANDLE: u64 = AMDKFD_IOWR(
+    0x80,
+    std::mem::size_of::<kfd_ioctl_ipc_import_handle_args>(),
+);
+
+pub const AMDKFD_IOC_IPC_EXPORT_HANDLE: u64 = AMDKFD_IOWR(
+    0x81,
+    std::mem::size_of::<kfd_ioctl_ipc_export_handle_args>(),
+);
+
+/* Export a buffer as a DMA-buf file descriptor
+ *
+ * @handle:    memory handle of the buffer to export
+ * @flags:     file flags for the DMA-buf fd (O_CLOEXEC, O_RDWR)
+ * @dmabuf_fd: DMA-buf file descriptor, from KFD
+ */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_export_dmabuf_args {
+    pub handle: __u64,    /* to KFD */
+    pub flags: __u32,     /* to KFD */
+    pub dmabuf_fd: __u32, /* from KFD */
+}
+
+/* Import a DMA-buf file descriptor at a virtual address
+ *
+ * @va_addr:   virtual address where the buffer will be mapped
+ * @handle:    memory handle of the imported buffer, from KFD
+ * @gpu_id:    device to import the buffer on
+ * @dmabuf_fd: DMA-buf file descriptor to import
+ */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_import_dmabuf_args {
+    pub va_addr: __u64,   /* to KFD */
+    pub handle: __u64,    /* from KFD */
+    pub gpu_id: __u32,    /* to KFD */
+    pub dmabuf_fd: __u32, /* to KFD */
+}
+
+pub const AMDKFD_IOC_IMPORT_DMABUF: u64 =
+    AMDKFD_IOWR(0x1D, std::mem::size_of::<kfd_ioctl_import_dmabuf_args>());
+
+pub const AMDKFD_IOC_EXPORT_DMABUF: u64 =
+    AMDKFD_IOWR(0x24, std::mem::size_of::<kfd_ioctl_export_dmabuf_args>());
+
+/* Set the GPU virtual address of the scratch backing memory
+ *
+ * @va_addr: address of the scratch backing, shifted right by 16 bits
+ * @gpu_id:  device identifier
+ */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_set_scratch_backing_va_args {
+    pub va_addr: __u64, /* to KFD */
+    pub gpu_id: __u32,  /* to KFD */
+    pub pad: __u32,
+}
+
+pub const AMDKFD_IOC_SET_SCRATCH_BACKING_VA: u64 = AMDKFD_IOWR(
+    0x11,
+    std::mem::size_of::<kfd_ioctl_set_scratch_backing_va_args>(),
+);
+
+/* Map memory to one or more GPUs
+ *
+ * @handle:               memory handle returned by alloc
+ * @device_ids_array_ptr: array of gpu_ids (__u32 per device)
+ * @n_devices:            number of devices in the array
+ * @n_success:            number of devices mapped successfully
+ *
+ * @n_success returns information to the caller how many devices from
+ * the start of the array have mapped the buffer successfully. It can
+ * be passed into a subsequent retry call to skip those devices. For
+ * the first call the caller should initialize it to 0.
+ *
+ * If the ioctl completes with return code 0 (success), n_success ==
+ * n_devices.
+ */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_map_memory_to_gpu_args {
+    pub handle: __u64,               /* to KFD */
+    pub device_ids_array_ptr: __u64, /* to KFD */
+    pub n_devices: __u32,            /* to KFD */
+    pub n_success: __u32,            /* to/from KFD */
+}
+
+/* Unmap memory from one or more GPUs
+ *
+ * same arguments as for mapping
+ */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_unmap_memory_from_gpu_args {
+    pub handle: __u64,               /* to KFD */
+    pub device_ids_array_ptr: __u64, /* to KFD */
+    pub n_devices: __u32,            /* to KFD */
+    pub n_success: __u32,            /* to/from KFD */
+}
+
+pub const AMDKFD_IOC_MAP_MEMORY_TO_GPU: u64 = AMDKFD_IOWR(
+    0x18,
+    std::mem::size_of::<kfd_ioctl_map_memory_to_gpu_args>(),
+);
+
+pub const AMDKFD_IOC_UNMAP_MEMORY_FROM_GPU: u64 = AMDKFD_IOWR(
+    0x19,
+    std::mem::size_of::<kfd_ioctl_unmap_memory_from_gpu_args>(),
+);
+
+/* Get the memory still available for allocation on a GPU
+ *
+ * @available: memory available for BO allocations, in bytes
+ * @gpu_id:    device identifier
+ */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_get_available_memory_args {
+    pub available: __u64, /* from KFD */
+    pub gpu_id: __u32,    /* to KFD */
+    pub pad: __u32,
+}
+
+pub const AMDKFD_IOC_AVAILABLE_MEMORY: u64 = AMDKFD_IOWR(
+    0x23,
+    std::mem::size_of::<kfd_ioctl_get_available_memory_args>(),
+);
+
+/* Shared virtual memory operations and range attributes */
+pub const KFD_IOCTL_SVM_OP_SET_ATTR: __u32 = 0;
+pub const KFD_IOCTL_SVM_OP_GET_ATTR: __u32 = 1;
+
+pub const KFD_IOCTL_SVM_ATTR_PREFERRED_LOC: __u32 = 0;
+pub const KFD_IOCTL_SVM_ATTR_PREFETCH_LOC: __u32 = 1;
+pub const KFD_IOCTL_SVM_ATTR_ACCESS: __u32 = 2;
+pub const KFD_IOCTL_SVM_ATTR_ACCESS_IN_PLACE: __u32 = 3;
+pub const KFD_IOCTL_SVM_ATTR_NO_ACCESS: __u32 = 4;
+pub const KFD_IOCTL_SVM_ATTR_SET_FLAGS: __u32 = 5;
+pub const KFD_IOCTL_SVM_ATTR_CLR_FLAGS: __u32 = 6;
+pub const KFD_IOCTL_SVM_ATTR_GRANULARITY: __u32 = 7;
+
+/* An attribute of an SVM range
+ *
+ * @type:  attribute type, see KFD_IOCTL_SVM_ATTR_*
+ * @value: attribute value, a gpu_id for the access attributes
+ */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct kfd_ioctl_svm_attribute {
+    pub type_: __u32,
+    pub value: __u32,
+}
+
+/* Set or get the attributes of a range of shared virtual memory
+ *
+ * @start_addr: start of the range, page aligned
+ * @size:       size of the range, a multiple of the page size
+ * @op:         KFD_IOCTL_SVM_OP_SET_ATTR or KFD_IOCTL_SVM_OP_GET_ATTR
+ * @nattr:      number of attributes following the arguments
+ *
+ * The attributes follow the arguments and their size is added to the
+ * size encoded in AMDKFD_IOC_SVM.
+ */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_svm_args {
+    pub start_addr: __u64, /* to KFD */
+    pub size: __u64,       /* to KFD */
+    pub op: __u32,         /* to KFD */
+    pub nattr: __u32,      /* to KFD */
+    pub attrs: [kfd_ioctl_svm_attribute; 0],
+}
+
+pub const AMDKFD_IOC_SVM: u64 = AMDKFD_IOWR(0x20, std::mem::size_of::<kfd_ioctl_svm_args>());
+
+/*
+ * The mmap offset of KFD objects encodes the object type in the top
+ * bits and the gpu_id of the device they belong to below them
+ */
+pub const KFD_MMAP_TYPE_SHIFT: u64 = 62;
+pub const KFD_MMAP_TYPE_MASK: u64 = 0x3 << KFD_MMAP_TYPE_SHIFT;
+pub const KFD_MMAP_TYPE_DOORBELL: u64 = 0x3 << KFD_MMAP_TYPE_SHIFT;
+pub const KFD_MMAP_TYPE_EVENTS: u64 = 0x2 << KFD_MMAP_TYPE_SHIFT;
+pub const KFD_MMAP_TYPE_RESERVED_MEM: u64 = 0x1 << KFD_MMAP_TYPE_SHIFT;
+pub const KFD_MMAP_TYPE_MMIO: u64 = 0x0 << KFD_MMAP_TYPE_SHIFT;
+
+pub const KFD_MMAP_GPU_ID_SHIFT: u64 = 46;
+pub const KFD_MMAP_GPU_ID_MASK: u64 = ((1 << 16) - 1) << KFD_MMAP_GPU_ID_SHIFT;
+
+pub const fn KFD_MMAP_GPU_ID(gpu_id: u32) -> u64 {
+    ((gpu_id as u64) << KFD_MMAP_GPU_ID_SHIFT) & KFD_MMAP_GPU_ID_MASK
+}
+
+/* Matching HSA_EVENTTYPE */
+pub const KFD_IOC_EVENT_SIGNAL: u32 = 0;
+pub const KFD_IOC_EVENT_NODECHANGE: u32 = 1;
+pub const KFD_IOC_EVENT_DEVICESTATECHANGE: u32 = 2;
+pub const KFD_IOC_EVENT_HW_EXCEPTION: u32 = 3;
+pub const KFD_IOC_EVENT_SYSTEM_EVENT: u32 = 4;
+pub const KFD_IOC_EVENT_DEBUG_EVENT: u32 = 5;
+pub const KFD_IOC_EVENT_PROFILE_EVENT: u32 = 6;
+pub const KFD_IOC_EVENT_QUEUE_EVENT: u32 = 7;
+pub const KFD_IOC_EVENT_MEMORY: u32 = 8;
+
+pub const KFD_IOC_WAIT_RESULT_COMPLETE: u32 = 0;
+pub const KFD_IOC_WAIT_RESULT_TIMEOUT: u32 = 1;
+pub const KFD_IOC_WAIT_RESULT_FAIL: u32 = 2;
+
+pub const KFD_SIGNAL_EVENT_LIMIT: u32 = 4096;
+
+/* For kfd_event_data.hw_exception_data.reset_type. */
+pub const KFD_HW_EXCEPTION_WHOLE_GPU_RESET: u32 = 0;
+pub const KFD_HW_EXCEPTION_PER_ENGINE_RESET: u32 = 1;
+
+/* For kfd_event_data.hw_exception_data.reset_cause. */
+pub const KFD_HW_EXCEPTION_GPU_HANG: u32 = 0;
+pub const KFD_HW_EXCEPTION_ECC: u32 = 1;
+
+/* For kfd_hsa_memory_exception_data.ErrorType */
+pub const KFD_MEM_ERR_NO_RAS: u32 = 0;
+pub const KFD_MEM_ERR_SRAM_ECC: u32 = 1;
+pub const KFD_MEM_ERR_POISON_CONSUMED: u32 = 2;
+pub const KFD_MEM_ERR_GPU_HANG: u32 = 3;
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_create_event_args {
+    pub event_page_offset: __u64,  /* from KFD */
+    pub event_trigger_data: __u32, /* from KFD - signal events only */
+    pub event_type: __u32,         /* to KFD */
+    pub auto_reset: __u32,         /* to KFD */
+    pub node_id: __u32,            /* to KFD - only valid for certain event types */
+    pub event_id: __u32,           /* from KFD */
+    pub event_slot_index: __u32,   /* from KFD */
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_destroy_event_args {
+    pub event_id: __u32, /* to KFD */
+    pub pad: __u32,
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_set_event_args {
+    pub event_id: __u32, /* to KFD */
+    pub pad: __u32,
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_reset_event_args {
+    pub event_id: __u32, /* to KFD */
+    pub pad: __u32,
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default, Copy, Clone)]
+pub struct kfd_memory_exception_failure {
+    pub NotPresent: __u32, /* Page not present or supervisor privilege */
+    pub ReadOnly: __u32,   /* Write access to a read-only page */
+    pub NoExecute: __u32,  /* Execute access to a page marked NX */
+    pub imprecise: __u32,  /* Can't determine the exact fault address */
+}
+
+/* memory exception data */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default, Copy, Clone)]
+pub struct kfd_hsa_memory_exception_data {
+    pub failure: kfd_memory_exception_failure,
+    pub va: __u64,
+    pub gpu_id: __u32,
+    /* 0 = no RAS error,
+     * 1 = ECC_SRAM,
+     * 2 = Link_SYNFLOOD (poison),
+     * 3 = GPU hang (not attributable to a specific cause),
+     * other values reserved
+     */
+    pub ErrorType: __u32,
+}
+
+/* hw exception data */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default, Copy, Clone)]
+pub struct kfd_hsa_hw_exception_data {
+    pub reset_type: __u32,
+    pub reset_cause: __u32,
+    pub memory_lost: __u32,
+    pub gpu_id: __u32,
+}
+
+/* hsa signal event data */
+#[repr(C)]
+#[derive(Debug, PartialEq, Default, Copy, Clone)]
+pub struct kfd_hsa_signal_event_data {
+    pub last_event_age: __u64, /* to and from KFD */
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union kfd_event_data_union {
+    /* From KFD */
+    pub memory_exception_data: kfd_hsa_memory_exception_data,
+    pub hw_exception_data: kfd_hsa_hw_exception_data,
+    /* To and From KFD */
+    pub signal_event_data: kfd_hsa_signal_event_data,
+}
+
+/* Event data */
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct kfd_event_data {
+    pub data: kfd_event_data_union,
+    /* pointer to an extension structure for future exception types */
+    pub kfd_event_data_ext: __u64,
+    pub event_id: __u32, /* to KFD */
+    pub pad: __u32,
+}
+
+impl Default for kfd_event_data {
+    fn default() -> Self {
+        Self {
+            data: kfd_event_data_union {
+                memory_exception_data: kfd_hsa_memory_exception_data::default(),
+            },
+            kfd_event_data_ext: 0,
+            event_id: 0,
+            pad: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Default)]
+pub struct kfd_ioctl_wait_events_args {
+    pub events_ptr: __u64,   /* pointed to struct kfd_event_data array, to KFD */
+    pub num_events: __u32,   /* to KFD */
+    pub wait_for_all: __u32, /* to KFD */
+    pub timeout: __u32,      /* to KFD */
+    pub wait_result: __u32,  /* from KFD */
+}
+
+pub const AMDKFD_IOC_CREATE_EVENT: u64 =
+    AMDKFD_IOWR(0x08, std::mem::size_of::<kfd_ioctl_create_event_args>());
+
+pub const AMDKFD_IOC_DESTROY_EVENT: u64 =
+    AMDKFD_IOW(0x09, std::mem::size_of::<kfd_ioctl_destroy_event_args>());
+
+pub const AMDKFD_IOC_SET_EVENT: u64 =
+    AMDKFD_IOW(0x0A, std::mem::size_of::<kfd_ioctl_set_event_args>());
+
+pub const AMDKFD_IOC_RESET_EVENT: u64 =
+    AMDKFD_IOW(0x0B, std::mem::size_of::<kfd_ioctl_reset_event_args>());
+
+pub const AMDKFD_IOC_WAIT_EVENTS: u64 =
+    AMDKFD_IOWR(0x0C, std::mem::size_of::<kfd_ioctl_wait_events_args>());
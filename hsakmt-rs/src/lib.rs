@@ -1,16 +1,31 @@
 #![allow(clippy::missing_safety_doc)]
 
+pub mod aql_pkt;
+pub mod events;
+pub mod events_async;
 pub mod fmm;
+pub mod fmm_accounting;
+pub mod fmm_config;
+pub mod fmm_numa;
 pub mod fmm_types;
 pub mod globals;
 pub mod hsakmttypes;
+pub mod hw_exceptions;
 pub mod kfd_ioctl;
 pub mod libhsakmt;
+pub mod memory;
+pub mod memory_exceptions;
 pub mod open_close;
+pub mod pm4_pkt;
 pub mod queues;
+pub mod queues_cu_mask;
+pub mod queues_cwsr;
+pub mod queues_info;
 pub mod rbtree;
 pub mod rbtree_amd;
+pub mod sdma_pkt;
 pub mod test_kfd_utils;
 pub mod topology;
 pub mod topology_utils;
 pub mod version;
+pub mod vm_tree;
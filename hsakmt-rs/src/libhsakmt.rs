@@ -1,4 +1,49 @@
 use libc::{ioctl, EAGAIN, EBADF, EINTR};
+#[cfg(test)]
+use std::cell::RefCell;
+
+/* A KFD answering the hooked ioctls, for tests of the API */
+#[cfg(test)]
+pub mod fake_kfd;
+
+/* Stand-in for the KFD device: gets the request and argument of every
+ * ioctl and returns what ioctl would
+ */
+#[cfg(test)]
+pub type HsakmtIoctlHook = Box<dyn FnMut(u64, *mut std::os::raw::c_void) -> i32>;
+
+#[cfg(test)]
+thread_local! {
+    static IOCTL_HOOK: RefCell<Option<HsakmtIoctlHook>> = const { RefCell::new(None) };
+}
+
+/* Send the ioctls of the calling thread to hook instead of the KFD, so
+ * the traffic of an API call can be checked against a fake device. None
+ * goes back to the KFD. Returns the previous hook. Test builds only.
+ */
+#[cfg(test)]
+pub fn hsakmt_set_ioctl_hook(hook: Option<HsakmtIoctlHook>) -> Option<HsakmtIoctlHook> {
+    IOCTL_HOOK.with_borrow_mut(|current| std::mem::replace(current, hook))
+}
+
+/* The hook is taken out while it runs, ioctls it issues itself go to the
+ * KFD instead of back into the hook
+ */
+#[cfg(test)]
+unsafe fn hsakmt_hooked_ioctl(request: u64, arg: *mut std::os::raw::c_void) -> Option<i32> {
+    let mut hook = IOCTL_HOOK.with_borrow_mut(|hook| hook.take())?;
+
+    let ret = hook(request, arg);
+
+    IOCTL_HOOK.with_borrow_mut(|current| {
+        /* unless the hook installed another one */
+        if current.is_none() {
+            *current = Some(hook);
+        }
+    });
+
+    Some(ret)
+}
 
 /* Call ioctl, restarting if it is interrupted */
 
@@ -7,6 +52,11 @@ pub unsafe fn hsakmt_ioctl(fd: i32, request: u64, arg: *mut std::os::raw::c_void
     let mut ret = 0;
     let mut errno = 0;
 
+    #[cfg(test)]
+    if let Some(ret) = hsakmt_hooked_ioctl(request, arg) {
+        return ret;
+    }
+
     loop {
         ret = ioctl(fd, request, arg);
 
@@ -0,0 +1,439 @@
+#![allow(non_snake_case)]
+
+use crate::fmm::{mmap_aperture_ops, START_NON_CANONICAL_ADDR};
+use crate::fmm_types::gpu_mem_t;
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::node_props_t;
+use crate::kfd_ioctl::{
+    kfd_event_data, kfd_event_data_union, kfd_ioctl_alloc_memory_of_gpu_args,
+    kfd_ioctl_create_event_args, kfd_ioctl_create_queue_args, kfd_ioctl_dbg_trap_args,
+    kfd_ioctl_destroy_event_args, kfd_ioctl_destroy_queue_args, kfd_ioctl_free_memory_of_gpu_args,
+    kfd_ioctl_map_memory_to_gpu_args, kfd_ioctl_reset_event_args, kfd_ioctl_set_cu_mask_args,
+    kfd_ioctl_set_event_args, kfd_ioctl_set_memory_policy_args,
+    kfd_ioctl_set_scratch_backing_va_args, kfd_ioctl_svm_args, kfd_ioctl_svm_attribute,
+    kfd_ioctl_update_queue_args, kfd_ioctl_wait_events_args, kfd_queue_snapshot_entry,
+    AMDKFD_IOC_ALLOC_MEMORY_OF_GPU, AMDKFD_IOC_CREATE_EVENT, AMDKFD_IOC_CREATE_QUEUE,
+    AMDKFD_IOC_DBG_TRAP, AMDKFD_IOC_DESTROY_EVENT, AMDKFD_IOC_DESTROY_QUEUE,
+    AMDKFD_IOC_FREE_MEMORY_OF_GPU, AMDKFD_IOC_MAP_MEMORY_TO_GPU, AMDKFD_IOC_RESET_EVENT,
+    AMDKFD_IOC_SET_CU_MASK, AMDKFD_IOC_SET_EVENT, AMDKFD_IOC_SET_MEMORY_POLICY,
+    AMDKFD_IOC_SET_SCRATCH_BACKING_VA, AMDKFD_IOC_SVM, AMDKFD_IOC_UNMAP_MEMORY_FROM_GPU,
+    AMDKFD_IOC_UPDATE_QUEUE, AMDKFD_IOC_WAIT_EVENTS, KFD_IOC_DBG_TRAP_GET_QUEUE_SNAPSHOT,
+    KFD_IOC_EVENT_DEBUG_EVENT, KFD_IOC_EVENT_SIGNAL, KFD_IOC_WAIT_RESULT_COMPLETE,
+    KFD_IOC_WAIT_RESULT_TIMEOUT, KFD_MMAP_GPU_ID, KFD_MMAP_TYPE_DOORBELL,
+};
+use crate::libhsakmt::hsakmt_set_ioctl_hook;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const FAKE_GPU_ID: u32 = 0x1234;
+
+/* mmap offset of the events page KFD allocates on APUs */
+pub const FAKE_EVENTS_PAGE_OFFSET: u64 = 0x1000;
+
+/* A BO the fake KFD handed out */
+#[derive(Debug, Clone, PartialEq)]
+pub struct FakeBo {
+    pub handle: u64,
+    pub va: u64,
+    pub size: u64,
+    pub flags: u32,
+    pub mapped: Vec<u32>,
+}
+
+#[derive(Clone, Copy)]
+pub struct FakeEvent {
+    pub event_type: u32,
+    pub auto_reset: bool,
+    pub signaled: bool,
+    /* Bumped on every signal, starting at 1 */
+    pub age: u64,
+    /* Exception data the next wait on a system event reports */
+    pub exception: Option<kfd_event_data_union>,
+}
+
+/* KFD keeping just enough queue, memory and event state to answer the
+ * ioctls of the API and to check them afterwards
+ */
+#[derive(Default)]
+pub struct FakeKfd {
+    /* Every request, in order */
+    pub requests: Vec<u64>,
+    /* Requests failing like an ioctl returning -1 */
+    pub failing: Vec<u64>,
+    pub queues: Vec<u32>,
+    pub next_queue_id: u32,
+    pub created: Vec<kfd_ioctl_create_queue_args>,
+    pub updated: Vec<kfd_ioctl_update_queue_args>,
+    pub cu_mask: Vec<u32>,
+    pub bos: Vec<FakeBo>,
+    pub next_mmap_offset: u64,
+    /* Events by id, None once destroyed */
+    pub events: Vec<Option<FakeEvent>>,
+    /* Handle of the events page the process gave, dGPUs only */
+    pub events_page: Option<u64>,
+    pub memory_policies: Vec<kfd_ioctl_set_memory_policy_args>,
+    /* Queues reported by the queue snapshot */
+    pub snapshot: Vec<kfd_queue_snapshot_entry>,
+    /* Entry size the snapshot reports, 0 for the one asked for */
+    pub snapshot_entry_size: u32,
+    /* Scratch backing VA by gpu_id, as programmed */
+    pub scratch_backing: Vec<(u32, u64)>,
+    /* SVM ranges given attributes: start, size and the attributes */
+    pub svm_ranges: Vec<(u64, u64, Vec<kfd_ioctl_svm_attribute>)>,
+}
+
+/* The size AMDKFD_IOC_* encode, variable for AMDKFD_IOC_SVM */
+const IOC_SIZE_MASK: u64 = 0x3FFF << 16;
+
+impl FakeKfd {
+    /* Answer the ioctls of the calling thread with kfd, until the hook
+     * is reset
+     */
+    pub fn install(kfd: FakeKfd) -> Rc<RefCell<FakeKfd>> {
+        let kfd = Rc::new(RefCell::new(kfd));
+        let device = kfd.clone();
+
+        hsakmt_set_ioctl_hook(Some(Box::new(move |request, arg| unsafe {
+            device.borrow_mut().ioctl(request, arg)
+        })));
+
+        kfd
+    }
+
+    pub fn bo(&mut self, handle: u64) -> Option<&mut FakeBo> {
+        self.bos.iter_mut().find(|bo| bo.handle == handle)
+    }
+
+    pub fn event(&mut self, event_id: u32) -> Option<&mut FakeEvent> {
+        self.events.get_mut(event_id as usize)?.as_mut()
+    }
+
+    /* What AMDKFD_IOC_SET_EVENT does */
+    pub fn signal(&mut self, event_id: u32) -> bool {
+        let Some(event) = self.event(event_id) else {
+            return false;
+        };
+
+        event.signaled = true;
+        event.age += 1;
+
+        true
+    }
+
+    pub unsafe fn ioctl(&mut self, request: u64, arg: *mut std::os::raw::c_void) -> i32 {
+        self.requests.push(request);
+
+        if self.failing.contains(&request) {
+            return -1;
+        }
+
+        match request {
+            AMDKFD_IOC_CREATE_QUEUE => {
+                let args = &mut *(arg as *mut kfd_ioctl_create_queue_args);
+
+                if !args.ring_size.is_power_of_two() || args.queue_type > 4 {
+                    return -1;
+                }
+
+                /* Like on SOC15, the doorbell is the first one free and
+                 * not tied to the queue id
+                 */
+                let page = KFD_MMAP_TYPE_DOORBELL | KFD_MMAP_GPU_ID(args.gpu_id);
+                let doorbell_offset = (0..)
+                    .map(|slot| page | (slot * 8))
+                    .find(|offset| {
+                        !self.created.iter().any(|c| {
+                            c.doorbell_offset == *offset && self.queues.contains(&c.queue_id)
+                        })
+                    })
+                    .unwrap();
+
+                args.queue_id = self.next_queue_id;
+                args.doorbell_offset = doorbell_offset;
+                self.next_queue_id += 1;
+                self.queues.push(args.queue_id);
+                self.created.push(args.clone());
+            }
+            AMDKFD_IOC_UPDATE_QUEUE => {
+                let args = &*(arg as *const kfd_ioctl_update_queue_args);
+
+                if !self.queues.contains(&args.queue_id) {
+                    return -1;
+                }
+                self.updated.push(args.clone());
+            }
+            AMDKFD_IOC_SET_CU_MASK => {
+                let args = &*(arg as *const kfd_ioctl_set_cu_mask_args);
+
+                if !self.queues.contains(&args.queue_id) || !args.num_cu_mask.is_multiple_of(32) {
+                    return -1;
+                }
+
+                let mask = std::slice::from_raw_parts(
+                    args.cu_mask_ptr as *const u32,
+                    (args.num_cu_mask / 32) as usize,
+                );
+                self.cu_mask = mask.to_vec();
+            }
+            AMDKFD_IOC_DESTROY_QUEUE => {
+                let args = &*(arg as *const kfd_ioctl_destroy_queue_args);
+
+                let Some(i) = self.queues.iter().position(|q| *q == args.queue_id) else {
+                    return -1;
+                };
+                self.queues.remove(i);
+            }
+            AMDKFD_IOC_DBG_TRAP => {
+                let args = &mut *(arg as *mut kfd_ioctl_dbg_trap_args);
+
+                if args.op != KFD_IOC_DBG_TRAP_GET_QUEUE_SNAPSHOT {
+                    return -1;
+                }
+
+                let snapshot = &mut args.queue_snapshot;
+                let buf = snapshot.snapshot_buf_ptr as *mut kfd_queue_snapshot_entry;
+                for (i, entry) in self.snapshot.iter().enumerate() {
+                    if i < snapshot.num_queues as usize {
+                        *buf.add(i) = *entry;
+                    }
+                }
+                snapshot.num_queues = self.snapshot.len() as u32;
+                if self.snapshot_entry_size != 0 {
+                    snapshot.entry_size = self.snapshot_entry_size;
+                }
+            }
+            AMDKFD_IOC_SET_MEMORY_POLICY => {
+                let args = &*(arg as *const kfd_ioctl_set_memory_policy_args);
+                self.memory_policies.push(args.clone());
+            }
+            AMDKFD_IOC_ALLOC_MEMORY_OF_GPU => {
+                let args = &mut *(arg as *mut kfd_ioctl_alloc_memory_of_gpu_args);
+
+                /* BOs are backed by consecutive pages of the render node */
+                let handle = self.bos.iter().map(|bo| bo.handle).max().unwrap_or(0) + 1;
+                args.handle = handle as *mut u64;
+                args.mmap_offset = self.next_mmap_offset;
+                self.next_mmap_offset += args.size;
+
+                self.bos.push(FakeBo {
+                    handle: args.handle as u64,
+                    va: args.va_addr as u64,
+                    size: args.size,
+                    flags: args.flags,
+                    mapped: vec![],
+                });
+            }
+            AMDKFD_IOC_MAP_MEMORY_TO_GPU | AMDKFD_IOC_UNMAP_MEMORY_FROM_GPU => {
+                let args = &mut *(arg as *mut kfd_ioctl_map_memory_to_gpu_args);
+                let devices = std::slice::from_raw_parts(
+                    args.device_ids_array_ptr as *const u32,
+                    args.n_devices as usize,
+                )
+                .to_vec();
+
+                let Some(bo) = self.bo(args.handle) else {
+                    return -1;
+                };
+
+                if request == AMDKFD_IOC_MAP_MEMORY_TO_GPU {
+                    bo.mapped.extend(devices);
+                } else {
+                    bo.mapped.retain(|id| !devices.contains(id));
+                }
+                args.n_success = args.n_devices;
+            }
+            AMDKFD_IOC_FREE_MEMORY_OF_GPU => {
+                let args = &*(arg as *const kfd_ioctl_free_memory_of_gpu_args);
+
+                let Some(i) = self
+                    .bos
+                    .iter()
+                    .position(|bo| bo.handle == args.handle as u64)
+                else {
+                    return -1;
+                };
+                self.bos.remove(i);
+            }
+            AMDKFD_IOC_CREATE_EVENT => {
+                let args = &mut *(arg as *mut kfd_ioctl_create_event_args);
+
+                if args.event_page_offset != 0 && self.events_page.is_none() {
+                    self.events_page = Some(args.event_page_offset);
+                }
+
+                args.event_id = self.events.len() as u32;
+                if args.event_type == KFD_IOC_EVENT_SIGNAL
+                    || args.event_type == KFD_IOC_EVENT_DEBUG_EVENT
+                {
+                    args.event_page_offset = FAKE_EVENTS_PAGE_OFFSET;
+                    args.event_slot_index = args.event_id;
+                    args.event_trigger_data = args.event_id;
+                }
+
+                self.events.push(Some(FakeEvent {
+                    event_type: args.event_type,
+                    auto_reset: args.auto_reset != 0,
+                    signaled: false,
+                    age: 1,
+                    exception: None,
+                }));
+            }
+            AMDKFD_IOC_DESTROY_EVENT => {
+                let args = &*(arg as *const kfd_ioctl_destroy_event_args);
+
+                match self.events.get_mut(args.event_id as usize) {
+                    Some(event @ Some(_)) => *event = None,
+                    _ => return -1,
+                }
+            }
+            AMDKFD_IOC_SET_EVENT => {
+                let args = &*(arg as *const kfd_ioctl_set_event_args);
+
+                if !self.signal(args.event_id) {
+                    return -1;
+                }
+            }
+            AMDKFD_IOC_RESET_EVENT => {
+                let args = &*(arg as *const kfd_ioctl_reset_event_args);
+
+                let Some(event) = self.event(args.event_id) else {
+                    return -1;
+                };
+                event.signaled = false;
+            }
+            AMDKFD_IOC_WAIT_EVENTS => return self.wait_events(arg),
+            AMDKFD_IOC_SET_SCRATCH_BACKING_VA => {
+                let args = &*(arg as *const kfd_ioctl_set_scratch_backing_va_args);
+                self.scratch_backing.push((args.gpu_id, args.va_addr));
+            }
+            _ if request & !IOC_SIZE_MASK == AMDKFD_IOC_SVM & !IOC_SIZE_MASK => {
+                let args = &*(arg as *const kfd_ioctl_svm_args);
+                let attrs =
+                    std::slice::from_raw_parts(args.attrs.as_ptr(), args.nattr as usize).to_vec();
+                self.svm_ranges.push((args.start_addr, args.size, attrs));
+            }
+            _ => return -1,
+        }
+
+        0
+    }
+
+    /* Never blocks: the wait completes at once or times out. A signal
+     * event has fired if it is signaled or its age is not the one the
+     * caller last saw, a system event if it has exception data.
+     */
+    unsafe fn wait_events(&mut self, arg: *mut std::os::raw::c_void) -> i32 {
+        let args = &mut *(arg as *mut kfd_ioctl_wait_events_args);
+        let events = std::slice::from_raw_parts_mut(
+            args.events_ptr as *mut kfd_event_data,
+            args.num_events as usize,
+        );
+
+        /* KFD refuses the whole wait if one of the events doesn't exist */
+        if events.iter().any(|e| self.event(e.event_id).is_none()) {
+            return -1;
+        }
+
+        let fired: Vec<bool> = events
+            .iter_mut()
+            .map(|e| {
+                let event = self.event(e.event_id).unwrap();
+
+                if event.event_type == KFD_IOC_EVENT_SIGNAL
+                    || event.event_type == KFD_IOC_EVENT_DEBUG_EVENT
+                {
+                    let signal = &mut e.data.signal_event_data;
+                    let last = signal.last_event_age;
+
+                    signal.last_event_age = event.age;
+                    event.signaled || (last != 0 && last != event.age)
+                } else if let Some(data) = event.exception.take() {
+                    e.data = data;
+                    true
+                } else {
+                    false
+                }
+            })
+            .collect();
+
+        let complete = if args.wait_for_all != 0 {
+            fired.iter().all(|f| *f)
+        } else {
+            fired.iter().any(|f| *f)
+        };
+
+        if complete {
+            for (e, fired) in events.iter().zip(fired) {
+                let event = self.event(e.event_id).unwrap();
+                if fired && event.auto_reset {
+                    event.signaled = false;
+                }
+            }
+        }
+
+        args.wait_result = if complete {
+            KFD_IOC_WAIT_RESULT_COMPLETE
+        } else {
+            KFD_IOC_WAIT_RESULT_TIMEOUT
+        };
+
+        0
+    }
+}
+
+/* Process on a machine with a CPU node and a dGPU node of the given
+ * gfx version behind the fake KFD. The SVM aperture is a CPU
+ * accessible window like on gfx9, BOs are mmapped from a memfd standing
+ * in for the render node. Close the render node with
+ * fake_kfd_globals_close.
+ */
+pub unsafe fn fake_kfd_globals(Major: u32, Minor: u32, Stepping: u32) -> Box<HsakmtGlobals> {
+    let mut g = Box::new(HsakmtGlobals::without_kfd());
+
+    g.hsakmt_kfd_open_count = 1;
+    g.hsakmt_page_size = 4096;
+    g.hsakmt_is_dgpu = true;
+
+    let render_fd = libc::memfd_create(c"fake-render-node".as_ptr(), 0);
+    assert!(render_fd >= 0);
+    assert_eq!(libc::ftruncate(render_fd, 1 << 20), 0);
+
+    let mut gpu = node_props_t::new();
+    gpu.node.KFDGpuID = FAKE_GPU_ID;
+    gpu.node.EngineId.ui32.Major = Major;
+    gpu.node.EngineId.ui32.Minor = Minor;
+    gpu.node.EngineId.ui32.Stepping = Stepping;
+
+    g.topology.g_system.NumNodes = 2;
+    g.topology.g_props = vec![node_props_t::new(), gpu];
+
+    let mut gpu_mem = gpu_mem_t {
+        gpu_id: FAKE_GPU_ID,
+        node_id: 1,
+        drm_render_fd: render_fd,
+        ..Default::default()
+    };
+    gpu_mem.EngineId.Value = g.topology.g_props[1].node.EngineId.Value;
+    g.fmm.gpu_mem = vec![gpu_mem];
+    g.fmm.gpu_mem_count = 1;
+    g.fmm.all_gpu_id_array = vec![FAKE_GPU_ID];
+    g.fmm.all_gpu_id_array_size = 1;
+
+    let svm = &mut g.fmm.svm.apertures[0];
+    svm.base = 4096 as *mut std::os::raw::c_void;
+    svm.limit = (START_NON_CANONICAL_ADDR - 1) as *mut std::os::raw::c_void;
+    svm.is_cpu_accessible = true;
+    svm.ops = mmap_aperture_ops;
+
+    let svm: *mut _ = svm;
+    g.fmm.svm.dgpu_aperture = svm;
+    g.fmm.svm.dgpu_alt_aperture = svm;
+
+    g.hsakmt_init_process_doorbells(2);
+
+    g
+}
+
+pub unsafe fn fake_kfd_globals_close(g: &HsakmtGlobals) {
+    libc::close(g.fmm.gpu_mem[0].drm_render_fd);
+}
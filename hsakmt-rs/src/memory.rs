@@ -0,0 +1,591 @@
+#![allow(non_snake_case)]
+
+use crate::fmm_accounting::fmm_usage_t;
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::HsakmtStatus::{
+    HSAKMT_STATUS_ERROR, HSAKMT_STATUS_INVALID_NODE_UNIT, HSAKMT_STATUS_INVALID_PARAMETER,
+    HSAKMT_STATUS_NOT_IMPLEMENTED, HSAKMT_STATUS_NOT_SUPPORTED, HSAKMT_STATUS_NO_MEMORY,
+    HSAKMT_STATUS_SUCCESS,
+};
+use crate::hsakmttypes::HSA_CACHING_TYPE::{HSA_CACHING_CACHED, HSA_CACHING_NONCACHED};
+use crate::hsakmttypes::{
+    HsaMemFlags, HsaSharedMemoryHandle, HsakmtStatus, GFX_VERSION_VEGA10, HSA_CACHING_TYPE,
+    HSA_HEAPTYPE, HSA_PAGE_SIZE_1GB, HSA_PAGE_SIZE_2MB, HSA_PAGE_SIZE_4KB, HSA_PAGE_SIZE_64KB,
+};
+use crate::kfd_ioctl::{
+    kfd_ioctl_get_available_memory_args, AMDKFD_IOC_AVAILABLE_MEMORY,
+    KFD_IOC_CACHE_POLICY_COHERENT, KFD_IOC_CACHE_POLICY_NONCOHERENT,
+};
+use crate::libhsakmt::hsakmt_ioctl;
+use amdgpu_drm_sys::bindings::{
+    amdgpu_device_handle, amdgpu_query_info, AMDGPU_INFO_GTT_USAGE, AMDGPU_INFO_VIS_VRAM_USAGE,
+    AMDGPU_INFO_VRAM_USAGE,
+};
+
+pub fn PageSizeFromFlags(pageSizeFlags: u32) -> u64 {
+    match pageSizeFlags {
+        HSA_PAGE_SIZE_4KB => 4 * 1024,
+        HSA_PAGE_SIZE_64KB => 64 * 1024,
+        HSA_PAGE_SIZE_2MB => 2 * 1024 * 1024,
+        HSA_PAGE_SIZE_1GB => 1024 * 1024 * 1024,
+        _ => 4 * 1024,
+    }
+}
+
+/* Memory of a node as seen by the topology, KFD, this process and
+ * the amdgpu driver. Values the node or the kernel can't report are
+ * None.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HsaNodeMemoryInfo {
+    /* Size of the node's memory banks from sysfs */
+    pub LocalMemSize: u64,
+    pub SystemMemSize: u64,
+    /* Memory KFD can still allocate on the node, GPU nodes only */
+    pub AvailableBytes: Option<u64>,
+    /* Memory this process allocated on the node */
+    pub ProcessUsage: fmm_usage_t,
+    /* Usage of the whole device by all processes */
+    pub VramUsage: Option<u64>,
+    pub VisibleVramUsage: Option<u64>,
+    pub GttUsage: Option<u64>,
+}
+
+unsafe fn amdgpu_query_usage(handle: amdgpu_device_handle, info_id: u32) -> Option<u64> {
+    let mut value: u64 = 0;
+
+    let r = amdgpu_query_info(
+        handle,
+        info_id,
+        std::mem::size_of::<u64>() as u32,
+        &mut value as *mut _ as *mut std::os::raw::c_void,
+    );
+
+    if r != 0 {
+        return None;
+    }
+
+    Some(value)
+}
+
+impl HsakmtGlobals {
+    pub unsafe fn hsaKmtAllocMemory(
+        &mut self,
+        PreferredNode: u32,
+        SizeInBytes: u64,
+        MemFlags: HsaMemFlags,
+        MemoryAddress: &mut *mut std::os::raw::c_void,
+    ) -> HsakmtStatus {
+        self.hsaKmtAllocMemoryAlign(PreferredNode, SizeInBytes, 0, MemFlags, MemoryAddress)
+    }
+
+    pub unsafe fn hsaKmtAllocMemoryAlign(
+        &mut self,
+        PreferredNode: u32,
+        SizeInBytes: u64,
+        Alignment: u64,
+        MemFlags: HsaMemFlags,
+        MemoryAddress: &mut *mut std::os::raw::c_void,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let mut gpu_id: u32 = 0;
+        let flags = MemFlags.st.ui32;
+
+        let page_size = self.PAGE_SIZE() as u64;
+
+        if SizeInBytes == 0 || (SizeInBytes & (page_size - 1)) > 0 {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        if flags.FixedAddress > 0 {
+            if MemoryAddress.is_null() {
+                return HSAKMT_STATUS_INVALID_PARAMETER;
+            }
+        } else {
+            *MemoryAddress = std::ptr::null_mut();
+        }
+
+        let result = self.hsakmt_validate_nodeid(PreferredNode, &mut gpu_id);
+        if result != HSAKMT_STATUS_SUCCESS {
+            println!(
+                "[hsaKmtAllocMemoryAlign] invalid node ID: {}",
+                PreferredNode
+            );
+            return result;
+        }
+
+        let page_size = PageSizeFromFlags(flags.PageSize);
+
+        if Alignment > 0 && (Alignment < page_size || !Alignment.is_power_of_two()) {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        /* CoarseGrain, ExtendedCoherent and Uncached are mutually exclusive */
+        let coherency_flags = [flags.CoarseGrain, flags.ExtendedCoherent, flags.Uncached];
+
+        if coherency_flags.iter().filter(|f| **f > 0).count() > 1 {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        if flags.Scratch > 0 {
+            *MemoryAddress = self.hsakmt_fmm_allocate_scratch(gpu_id, *MemoryAddress, SizeInBytes);
+
+            if MemoryAddress.is_null() {
+                println!(
+                    "[hsaKmtAllocMemoryAlign] failed to allocate {} bytes from scratch",
+                    SizeInBytes
+                );
+                return HSAKMT_STATUS_NO_MEMORY;
+            }
+
+            return HSAKMT_STATUS_SUCCESS;
+        }
+
+        /* GPU allocated system memory */
+        if gpu_id == 0 || flags.NonPaged == 0 || self.hsakmt_zfb_support > 0 {
+            /* Backwards compatibility hack: Allocate system memory if app
+             * asks for paged memory from a GPU node.
+             */
+            let mut MemFlags = MemFlags;
+
+            /* If allocate VRAM under ZFB mode */
+            if self.hsakmt_zfb_support > 0 && gpu_id > 0 && flags.NonPaged == 1 {
+                MemFlags.st.ui32.CoarseGrain = 1;
+            }
+
+            *MemoryAddress = self.hsakmt_fmm_allocate_host(
+                gpu_id,
+                PreferredNode,
+                *MemoryAddress,
+                SizeInBytes,
+                MemFlags,
+            );
+
+            if MemoryAddress.is_null() {
+                println!(
+                    "[hsaKmtAllocMemoryAlign] failed to allocate {} bytes from host",
+                    SizeInBytes
+                );
+                return HSAKMT_STATUS_ERROR;
+            }
+
+            return HSAKMT_STATUS_SUCCESS;
+        }
+
+        /* GPU allocated VRAM */
+        /* sanity check cannot do OnlyAddress and NoAddress alloc at same time */
+        if flags.OnlyAddress > 0 && flags.NoAddress > 0 {
+            println!("[hsaKmtAllocMemoryAlign] allocate addr-only and memory-only at same time");
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        *MemoryAddress = self.hsakmt_fmm_allocate_device(
+            gpu_id,
+            PreferredNode,
+            *MemoryAddress,
+            SizeInBytes,
+            Alignment,
+            MemFlags,
+        );
+
+        if MemoryAddress.is_null() {
+            println!(
+                "[hsaKmtAllocMemoryAlign] failed to allocate {} bytes from device",
+                SizeInBytes
+            );
+            return HSAKMT_STATUS_NO_MEMORY;
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsaKmtFreeMemory(
+        &mut self,
+        MemoryAddress: *mut std::os::raw::c_void,
+        _SizeInBytes: u64,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if MemoryAddress.is_null() {
+            println!("FIXME: freeing NULL pointer");
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        self.hsakmt_fmm_release(MemoryAddress)
+    }
+
+    /* Bind memory allocated with the NoAddress flag to a virtual
+     * address. MemoryHandle is the value returned by hsaKmtAllocMemory.
+     * If *MemoryAddress is not NULL, the memory is bound at that address.
+     * The bound address is released with hsaKmtFreeMemory, independently
+     * of the handle.
+     */
+    pub unsafe fn hsaKmtBindMemoryHandle(
+        &mut self,
+        MemoryHandle: *mut std::os::raw::c_void,
+        MemoryAddress: &mut *mut std::os::raw::c_void,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if MemoryHandle.is_null() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        self.hsakmt_fmm_bind_memory_handle(MemoryHandle, MemoryAddress)
+    }
+
+    pub unsafe fn hsaKmtShareMemory(
+        &mut self,
+        MemoryAddress: *mut std::os::raw::c_void,
+        SizeInBytes: u64,
+        SharedMemoryHandle: &mut HsaSharedMemoryHandle,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        self.hsakmt_fmm_share_memory(MemoryAddress, SizeInBytes, SharedMemoryHandle)
+    }
+
+    pub unsafe fn hsaKmtRegisterSharedHandle(
+        &mut self,
+        SharedMemoryHandle: &HsaSharedMemoryHandle,
+        MemoryAddress: &mut *mut std::os::raw::c_void,
+        SizeInBytes: &mut u64,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        self.hsaKmtRegisterSharedHandleToNodes(SharedMemoryHandle, MemoryAddress, SizeInBytes, &[])
+    }
+
+    pub unsafe fn hsaKmtRegisterSharedHandleToNodes(
+        &mut self,
+        SharedMemoryHandle: &HsaSharedMemoryHandle,
+        MemoryAddress: &mut *mut std::os::raw::c_void,
+        SizeInBytes: &mut u64,
+        NodeArray: &[u32],
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let mut gpu_id_array: Vec<u32> = vec![];
+
+        if !NodeArray.is_empty() {
+            let ret = self.hsakmt_validate_nodeid_array(&mut gpu_id_array, NodeArray);
+            if ret != HSAKMT_STATUS_SUCCESS {
+                return ret;
+            }
+        }
+
+        self.hsakmt_fmm_register_shared_memory(
+            SharedMemoryHandle,
+            SizeInBytes,
+            MemoryAddress,
+            gpu_id_array,
+        )
+    }
+
+    pub unsafe fn hsaKmtMapMemoryToGPU(
+        &mut self,
+        MemoryAddress: *mut std::os::raw::c_void,
+        MemorySizeInBytes: u64,
+        AlternateVAGPU: *mut u64,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if MemoryAddress.is_null() {
+            println!("FIXME: mapping NULL pointer");
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        if !AlternateVAGPU.is_null() {
+            *AlternateVAGPU = 0;
+        }
+
+        self.hsakmt_fmm_map_to_gpu(MemoryAddress, MemorySizeInBytes, AlternateVAGPU)
+    }
+
+    pub unsafe fn hsaKmtUnmapMemoryToGPU(
+        &mut self,
+        MemoryAddress: *mut std::os::raw::c_void,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if MemoryAddress.is_null() {
+            /* Workaround for runtime bug */
+            println!("FIXME: Unmapping NULL pointer");
+            return HSAKMT_STATUS_SUCCESS;
+        }
+
+        if self.hsakmt_fmm_unmap_from_gpu(MemoryAddress) == HSAKMT_STATUS_SUCCESS {
+            HSAKMT_STATUS_SUCCESS
+        } else {
+            HSAKMT_STATUS_ERROR
+        }
+    }
+
+    pub unsafe fn hsaKmtDeregisterMemory(
+        &mut self,
+        MemoryAddress: *mut std::os::raw::c_void,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        self.hsakmt_fmm_deregister_memory(MemoryAddress)
+    }
+
+    /* Cache policy of the default and the alternate aperture of a GPU.
+     * Only pre-GFX9 GPUs select coherence through apertures, on later
+     * ones it is chosen per allocation and this returns
+     * HSAKMT_STATUS_NOT_IMPLEMENTED.
+     */
+    pub unsafe fn hsaKmtSetMemoryPolicy(
+        &mut self,
+        Node: u32,
+        DefaultPolicy: HSA_CACHING_TYPE,
+        AlternatePolicy: HSA_CACHING_TYPE,
+        MemoryAddressAlternate: *mut std::os::raw::c_void,
+        MemorySizeInBytes: u64,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let mut gpu_id: u32 = 0;
+
+        let result = self.hsakmt_validate_nodeid(Node, &mut gpu_id);
+        if result != HSAKMT_STATUS_SUCCESS {
+            return result;
+        }
+
+        if gpu_id == 0 {
+            return HSAKMT_STATUS_INVALID_NODE_UNIT;
+        }
+
+        if self.hsakmt_get_gfxv_by_node_id(Node) >= GFX_VERSION_VEGA10 as u32 {
+            return HSAKMT_STATUS_NOT_IMPLEMENTED;
+        }
+
+        /* Only APUs have memory of the CPU as alternate aperture */
+        if self.hsakmt_is_dgpu {
+            return HSAKMT_STATUS_NOT_IMPLEMENTED;
+        }
+
+        let to_ioc_policy = |policy: HSA_CACHING_TYPE| match policy {
+            HSA_CACHING_CACHED => Some(KFD_IOC_CACHE_POLICY_COHERENT as i32),
+            HSA_CACHING_NONCACHED => Some(KFD_IOC_CACHE_POLICY_NONCOHERENT as i32),
+            _ => None,
+        };
+
+        let (Some(default_policy), Some(alt_policy)) =
+            (to_ioc_policy(DefaultPolicy), to_ioc_policy(AlternatePolicy))
+        else {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        };
+
+        let page_size = self.PAGE_SIZE() as u64;
+
+        if !(MemoryAddressAlternate as u64).is_multiple_of(page_size)
+            || !MemorySizeInBytes.is_multiple_of(page_size)
+        {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let err = self.fmm_set_memory_policy(
+            gpu_id,
+            default_policy,
+            alt_policy,
+            MemoryAddressAlternate as *mut u64,
+            MemorySizeInBytes,
+        );
+
+        if err != 0 {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsaKmtAvailableMemory(
+        &self,
+        Node: u32,
+        AvailableBytes: &mut u64,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if !self.check_kfd_minor_version(9) {
+            return HSAKMT_STATUS_NOT_SUPPORTED;
+        }
+
+        let mut args = kfd_ioctl_get_available_memory_args::default();
+
+        let result = self.hsakmt_validate_nodeid(Node, &mut args.gpu_id);
+        if result != HSAKMT_STATUS_SUCCESS {
+            println!("hsaKmtAvailableMemory: invalid node ID: {}", Node);
+            return result;
+        }
+
+        let r = hsakmt_ioctl(
+            self.hsakmt_kfd_fd,
+            AMDKFD_IOC_AVAILABLE_MEMORY,
+            &mut args as *mut _ as *mut std::os::raw::c_void,
+        );
+        if r != 0 {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        *AvailableBytes = args.available;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* Combined memory information of a node, see HsaNodeMemoryInfo */
+    pub unsafe fn hsaKmtGetNodeMemoryInfo(
+        &self,
+        NodeId: u32,
+        MemoryInfo: &mut HsaNodeMemoryInfo,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let mut gpu_id: u32 = 0;
+
+        let result = self.hsakmt_validate_nodeid(NodeId, &mut gpu_id);
+        if result != HSAKMT_STATUS_SUCCESS {
+            return result;
+        }
+
+        let mut info = HsaNodeMemoryInfo::default();
+
+        for bank in &self.topology.g_props[NodeId as usize].mem {
+            match bank.HeapType {
+                HSA_HEAPTYPE::HSA_HEAPTYPE_SYSTEM => info.SystemMemSize += bank.prop.SizeInBytes,
+                HSA_HEAPTYPE::HSA_HEAPTYPE_FRAME_BUFFER_PUBLIC
+                | HSA_HEAPTYPE::HSA_HEAPTYPE_FRAME_BUFFER_PRIVATE => {
+                    info.LocalMemSize += bank.prop.SizeInBytes
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(usage) = self.fmm.accounting.borrow().usage_by_node().get(&NodeId) {
+            info.ProcessUsage = *usage;
+        }
+
+        /* CPU node, KFD and amdgpu know nothing about it */
+        if gpu_id == 0 {
+            *MemoryInfo = info;
+            return HSAKMT_STATUS_SUCCESS;
+        }
+
+        let mut available: u64 = 0;
+        if self.hsaKmtAvailableMemory(NodeId, &mut available) == HSAKMT_STATUS_SUCCESS {
+            info.AvailableBytes = Some(available);
+        }
+
+        let mut handle: amdgpu_device_handle = std::ptr::null_mut();
+        if self.hsakmt_fmm_get_amdgpu_device_handle(NodeId, &mut handle) == HSAKMT_STATUS_SUCCESS {
+            info.VramUsage = amdgpu_query_usage(handle, AMDGPU_INFO_VRAM_USAGE);
+            info.VisibleVramUsage = amdgpu_query_usage(handle, AMDGPU_INFO_VIS_VRAM_USAGE);
+            info.GttUsage = amdgpu_query_usage(handle, AMDGPU_INFO_GTT_USAGE);
+        }
+
+        *MemoryInfo = info;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kfd_ioctl::AMDKFD_IOC_SET_MEMORY_POLICY;
+    use crate::libhsakmt::fake_kfd::{
+        fake_kfd_globals, fake_kfd_globals_close, FakeKfd, FAKE_GPU_ID,
+    };
+    use crate::libhsakmt::hsakmt_set_ioctl_hook;
+
+    #[test]
+    fn test_set_memory_policy() {
+        let kfd = FakeKfd::install(FakeKfd::default());
+
+        unsafe {
+            /* Kaveri */
+            let mut g = fake_kfd_globals(7, 0, 0);
+            let alternate = 0x10000 as *mut std::os::raw::c_void;
+
+            /* Only on APUs */
+            assert_eq!(
+                g.hsaKmtSetMemoryPolicy(
+                    1,
+                    HSA_CACHING_CACHED,
+                    HSA_CACHING_NONCACHED,
+                    alternate,
+                    0x4000
+                ),
+                HSAKMT_STATUS_NOT_IMPLEMENTED
+            );
+            assert!(kfd.borrow().requests.is_empty());
+
+            g.hsakmt_is_dgpu = false;
+
+            assert_eq!(
+                g.hsaKmtSetMemoryPolicy(
+                    1,
+                    HSA_CACHING_CACHED,
+                    HSA_CACHING_NONCACHED,
+                    alternate,
+                    0x4001
+                ),
+                HSAKMT_STATUS_INVALID_PARAMETER
+            );
+            assert_eq!(
+                g.hsaKmtSetMemoryPolicy(
+                    0,
+                    HSA_CACHING_CACHED,
+                    HSA_CACHING_NONCACHED,
+                    alternate,
+                    0x4000
+                ),
+                HSAKMT_STATUS_INVALID_NODE_UNIT
+            );
+            assert!(kfd.borrow().requests.is_empty());
+
+            assert_eq!(
+                g.hsaKmtSetMemoryPolicy(
+                    1,
+                    HSA_CACHING_CACHED,
+                    HSA_CACHING_NONCACHED,
+                    alternate,
+                    0x4000
+                ),
+                HSAKMT_STATUS_SUCCESS
+            );
+
+            let args = kfd.borrow().memory_policies[0].clone();
+            assert_eq!(args.gpu_id, FAKE_GPU_ID);
+            assert_eq!(args.default_policy, KFD_IOC_CACHE_POLICY_COHERENT as u32);
+            assert_eq!(
+                args.alternate_policy,
+                KFD_IOC_CACHE_POLICY_NONCOHERENT as u32
+            );
+            assert_eq!(args.alternate_aperture_base as u64, 0x10000);
+            assert_eq!(args.alternate_aperture_size, 0x4000);
+
+            let policy = g.hsakmt_fmm_get_memory_policy(1).unwrap();
+            assert_eq!(policy.alternate_aperture_base, 0x10000);
+
+            /* A refused policy isn't kept */
+            kfd.borrow_mut().failing.push(AMDKFD_IOC_SET_MEMORY_POLICY);
+            assert_eq!(
+                g.hsaKmtSetMemoryPolicy(
+                    1,
+                    HSA_CACHING_NONCACHED,
+                    HSA_CACHING_NONCACHED,
+                    alternate,
+                    0x8000
+                ),
+                HSAKMT_STATUS_ERROR
+            );
+            assert_eq!(g.hsakmt_fmm_get_memory_policy(1), Some(policy));
+
+            fake_kfd_globals_close(&g);
+        }
+
+        hsakmt_set_ioctl_hook(None);
+    }
+}
@@ -0,0 +1,272 @@
+#![allow(non_camel_case_types, non_snake_case, dead_code)]
+
+use crate::events::{kfd_create_event, kfd_destroy_event, kfd_wait_events};
+use crate::fmm_accounting::fmm_allocation_info_t;
+use crate::fmm_types::{manageable_aperture_t, vm_object_t};
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::HsakmtStatus::{HSAKMT_STATUS_ERROR, HSAKMT_STATUS_SUCCESS};
+use crate::hsakmttypes::HSA_EVENTID_MEMORYFLAGS::HSA_EVENTID_MEMORY_FATAL_PROCESS;
+use crate::hsakmttypes::{HsaAccessAttributeFailure, HsaMemoryAccessFault, HsakmtStatus};
+use crate::kfd_ioctl::{
+    kfd_event_data, kfd_hsa_memory_exception_data, kfd_ioctl_create_event_args,
+    KFD_IOC_EVENT_MEMORY, KFD_MEM_ERR_POISON_CONSUMED, KFD_MEM_ERR_SRAM_ECC,
+};
+
+/* A GPU memory access fault of this process, with the allocations
+ * around the faulting address
+ */
+#[derive(Clone)]
+pub struct HsaMemoryException {
+    pub Fault: HsaMemoryAccessFault,
+    pub GpuId: u32,
+    /* Allocation containing the faulting address */
+    pub Owner: Option<fmm_allocation_info_t>,
+    /* Allocation closest to the faulting address, when none contains it */
+    pub Nearest: Option<fmm_allocation_info_t>,
+}
+
+pub type HsaMemoryExceptionHandler = Box<dyn FnMut(&HsaMemoryException)>;
+
+pub fn hsakmt_decode_memory_exception(
+    data: &kfd_hsa_memory_exception_data,
+    node_id: u32,
+) -> HsaMemoryAccessFault {
+    HsaMemoryAccessFault {
+        NodeId: node_id,
+        VirtualAddress: data.va,
+        Failure: HsaAccessAttributeFailure {
+            NotPresent: data.failure.NotPresent,
+            ReadOnly: data.failure.ReadOnly,
+            NoExecute: data.failure.NoExecute,
+            Imprecise: data.failure.imprecise,
+            ErrorType: data.ErrorType,
+            ECC: (data.ErrorType == KFD_MEM_ERR_SRAM_ECC
+                || data.ErrorType == KFD_MEM_ERR_POISON_CONSUMED) as u32,
+            ..Default::default()
+        },
+        Flags: HSA_EVENTID_MEMORY_FATAL_PROCESS,
+    }
+}
+
+pub fn hsakmt_memory_fault_reason(failure: &HsaAccessAttributeFailure) -> &'static str {
+    if failure.NotPresent > 0 {
+        "Page not present"
+    } else if failure.ReadOnly > 0 {
+        "Writing to readonly page"
+    } else if failure.NoExecute > 0 {
+        "Execute to none-executable page"
+    } else if failure.ECC > 0 {
+        "RAS error"
+    } else {
+        "Unknown reason"
+    }
+}
+
+/* Bytes between address and [start, start + size), 0 inside */
+pub fn fmm_address_distance(address: u64, start: u64, size: u64) -> u64 {
+    if address < start {
+        start - address
+    } else {
+        address.saturating_sub(start.saturating_add(size).saturating_sub(1))
+    }
+}
+
+impl HsakmtGlobals {
+    /* Allocation containing address, or the closest one of any
+     * aperture if there is none
+     */
+    pub unsafe fn hsakmt_fmm_find_fault_owner(
+        &mut self,
+        address: u64,
+    ) -> (Option<fmm_allocation_info_t>, Option<fmm_allocation_info_t>) {
+        let mut aperture: *mut manageable_aperture_t = std::ptr::null_mut();
+
+        let object = self.vm_find_object(
+            address as *mut std::os::raw::c_void,
+            u64::MAX,
+            &mut aperture,
+        );
+        if !object.is_null() {
+            return (Some(self.fmm_allocation_info(aperture, object)), None);
+        }
+
+        let mut nearest: Option<(u64, *const manageable_aperture_t, *mut vm_object_t)> = None;
+
+        for aperture in self.fmm_apertures() {
+            let object = (*aperture).tree.find_nearest(address);
+            if object.is_null() {
+                continue;
+            }
+
+            let distance = fmm_address_distance(address, (*object).start as u64, (*object).size);
+            if nearest.is_none_or(|(d, _, _)| distance < d) {
+                nearest = Some((distance, aperture, object));
+            }
+        }
+
+        let nearest =
+            nearest.map(|(_, aperture, object)| self.fmm_allocation_info(aperture, object));
+
+        (None, nearest)
+    }
+
+    /* Decode a memory exception reported by KFD and find what is known
+     * about the faulting address
+     */
+    pub unsafe fn hsakmt_analyze_memory_exception(
+        &mut self,
+        data: &kfd_hsa_memory_exception_data,
+    ) -> HsaMemoryException {
+        let mut node_id = 0;
+        self.gpuid_to_nodeid(data.gpu_id, &mut node_id);
+
+        let fault = hsakmt_decode_memory_exception(data, node_id);
+
+        let (owner, nearest) = self.hsakmt_fmm_find_fault_owner(fault.VirtualAddress);
+
+        HsaMemoryException {
+            Fault: fault,
+            GpuId: data.gpu_id,
+            Owner: owner,
+            Nearest: nearest,
+        }
+    }
+
+    /* Deliver memory exceptions to handler, see
+     * hsakmt_poll_memory_exceptions. None stops the delivery.
+     */
+    pub unsafe fn hsakmt_set_memory_exception_handler(
+        &mut self,
+        handler: Option<HsaMemoryExceptionHandler>,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if handler.is_none() {
+            self.hsakmt_memory_exceptions_destroy();
+            return HSAKMT_STATUS_SUCCESS;
+        }
+
+        if self.exceptions.memory_event_id.is_none() {
+            let mut args = kfd_ioctl_create_event_args {
+                event_type: KFD_IOC_EVENT_MEMORY,
+                auto_reset: 1,
+                ..Default::default()
+            };
+
+            if kfd_create_event(self.hsakmt_kfd_fd, &mut args) != HSAKMT_STATUS_SUCCESS {
+                return HSAKMT_STATUS_ERROR;
+            }
+
+            self.exceptions.memory_event_id = Some(args.event_id);
+        }
+
+        self.exceptions.memory_handler = handler;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* Wait up to timeout_ms for a memory exception and pass it to the
+     * handler. Returns HSAKMT_STATUS_WAIT_TIMEOUT if there was none.
+     */
+    pub unsafe fn hsakmt_poll_memory_exceptions(&mut self, timeout_ms: u32) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let Some(event_id) = self.exceptions.memory_event_id else {
+            return HSAKMT_STATUS_ERROR;
+        };
+
+        let mut event_data = kfd_event_data {
+            event_id,
+            ..Default::default()
+        };
+
+        let ret = kfd_wait_events(
+            self.hsakmt_kfd_fd,
+            std::slice::from_mut(&mut event_data),
+            true,
+            timeout_ms,
+        );
+        if ret != HSAKMT_STATUS_SUCCESS {
+            return ret;
+        }
+
+        let data = event_data.data.memory_exception_data;
+
+        /* The event was signaled without exception data */
+        if data.gpu_id == 0 {
+            return HSAKMT_STATUS_SUCCESS;
+        }
+
+        let exception = self.hsakmt_analyze_memory_exception(&data);
+
+        if let Some(handler) = self.exceptions.memory_handler.as_mut() {
+            handler(&exception);
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsakmt_memory_exceptions_destroy(&mut self) {
+        if let Some(event_id) = self.exceptions.memory_event_id.take() {
+            kfd_destroy_event(self.hsakmt_kfd_fd, event_id);
+        }
+
+        self.exceptions.memory_handler = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kfd_ioctl::kfd_memory_exception_failure;
+
+    #[test]
+    fn test_decode_memory_exception() {
+        let data = kfd_hsa_memory_exception_data {
+            failure: kfd_memory_exception_failure {
+                NotPresent: 1,
+                ReadOnly: 0,
+                NoExecute: 0,
+                imprecise: 1,
+            },
+            va: 0x7fff_0000_1000,
+            gpu_id: 0x1234,
+            ErrorType: 0,
+        };
+
+        let fault = hsakmt_decode_memory_exception(&data, 2);
+        assert_eq!(fault.NodeId, 2);
+        assert_eq!(fault.VirtualAddress, 0x7fff_0000_1000);
+        assert_eq!(fault.Failure.NotPresent, 1);
+        assert_eq!(fault.Failure.Imprecise, 1);
+        assert_eq!(fault.Failure.ECC, 0);
+        assert_eq!(fault.Flags, HSA_EVENTID_MEMORY_FATAL_PROCESS);
+        assert_eq!(
+            hsakmt_memory_fault_reason(&fault.Failure),
+            "Page not present"
+        );
+
+        let data = kfd_hsa_memory_exception_data {
+            ErrorType: KFD_MEM_ERR_POISON_CONSUMED,
+            ..data
+        };
+        let mut fault = hsakmt_decode_memory_exception(&data, 0);
+        assert_eq!(fault.Failure.ECC, 1);
+
+        fault.Failure.NotPresent = 0;
+        assert_eq!(hsakmt_memory_fault_reason(&fault.Failure), "RAS error");
+        fault.Failure.ReadOnly = 1;
+        assert_eq!(
+            hsakmt_memory_fault_reason(&fault.Failure),
+            "Writing to readonly page"
+        );
+    }
+
+    #[test]
+    fn test_address_distance() {
+        assert_eq!(fmm_address_distance(0x1800, 0x1000, 0x1000), 0);
+        assert_eq!(fmm_address_distance(0x1fff, 0x1000, 0x1000), 0);
+        assert_eq!(fmm_address_distance(0x2000, 0x1000, 0x1000), 1);
+        assert_eq!(fmm_address_distance(0x800, 0x1000, 0x1000), 0x800);
+    }
+}
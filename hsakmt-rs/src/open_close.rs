@@ -92,8 +92,32 @@ impl HsakmtGlobals {
         HSAKMT_STATUS_SUCCESS
     }
 
-    pub unsafe fn hsaKmtCloseKFD(&self) -> HsakmtStatus {
-        // ...
+    pub unsafe fn hsaKmtCloseKFD(&mut self) -> HsakmtStatus {
+        if self.hsakmt_kfd_open_count == 0 {
+            return HSAKMT_STATUS_KERNEL_IO_CHANNEL_NOT_OPENED;
+        }
+
+        self.hsakmt_kfd_open_count -= 1;
+
+        if self.hsakmt_kfd_open_count == 0 {
+            // hsakmt_destroy_counter_props
+
+            // hsakmt_destroy_device_debugging_memory
+
+            self.hsakmt_memory_exceptions_destroy();
+
+            self.hsakmt_device_notifications_destroy();
+
+            self.hsakmt_destroy_process_doorbells();
+
+            self.hsakmt_fmm_destroy_process_apertures();
+
+            if self.hsakmt_kfd_fd >= 0 {
+                close(self.hsakmt_kfd_fd);
+                self.hsakmt_kfd_fd = -1;
+            }
+        }
+
         HSAKMT_STATUS_SUCCESS
     }
 }
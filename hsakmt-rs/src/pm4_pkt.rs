@@ -0,0 +1,574 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use crate::hsakmttypes::{
+    HsaNodeProperties, GFX_VERSION_ALDEBARAN, GFX_VERSION_AQUA_VANJARAM, GFX_VERSION_NAVI10,
+    GFX_VERSION_PLUM_BONITO, GFX_VERSION_VEGA10, HSA_GET_GFX_VERSION_FULL,
+};
+
+pub const PM4_TYPE_3: u32 = 3;
+
+pub const PACKET3_NOP: u32 = 0x10;
+pub const PACKET3_DISPATCH_DIRECT: u32 = 0x15;
+pub const PACKET3_DISPATCH_INDIRECT: u32 = 0x16;
+pub const PACKET3_WRITE_DATA: u32 = 0x37;
+pub const PACKET3_WAIT_REG_MEM: u32 = 0x3C;
+pub const PACKET3_INDIRECT_BUFFER: u32 = 0x3F;
+pub const PACKET3_RELEASE_MEM: u32 = 0x49;
+pub const PACKET3_ACQUIRE_MEM: u32 = 0x58;
+pub const PACKET3_SET_SH_REG: u32 = 0x76;
+
+/* Dword offset of the first SH register, SET_SH_REG takes offsets from
+ * here
+ */
+pub const PACKET3_SET_SH_REG_START: u32 = 0x2C00;
+pub const PACKET3_SET_SH_REG_END: u32 = 0x3000;
+
+/* Largest count of a type 3 header, the field is 14 bits */
+pub const PACKET3_MAX_COUNT: u32 = 0x3FFF;
+
+/* count is the number of dwords after the header, minus one. None if
+ * it is more than PACKET3_MAX_COUNT.
+ */
+pub fn PACKET3(op: u32, count: u32) -> Option<u32> {
+    if count > PACKET3_MAX_COUNT {
+        return None;
+    }
+
+    Some((PM4_TYPE_3 << 30) | (count << 16) | ((op & 0xFF) << 8))
+}
+
+/* COMPUTE_DISPATCH_INITIATOR */
+pub const COMPUTE_SHADER_EN: u32 = 1 << 0;
+pub const PARTIAL_TG_EN: u32 = 1 << 1;
+pub const FORCE_START_AT_000: u32 = 1 << 2;
+pub const ORDERED_APPEND_ENBL: u32 = 1 << 3;
+pub const USE_THREAD_DIMENSIONS: u32 = 1 << 5;
+pub const ORDER_MODE: u32 = 1 << 6;
+/* gfx10 on, waves of 32 threads */
+pub const CS_W32_EN: u32 = 1 << 15;
+
+/* WAIT_REG_MEM */
+pub const WAIT_REG_MEM_FUNCTION_ALWAYS: u32 = 0;
+pub const WAIT_REG_MEM_FUNCTION_LT: u32 = 1;
+pub const WAIT_REG_MEM_FUNCTION_LE: u32 = 2;
+pub const WAIT_REG_MEM_FUNCTION_EQ: u32 = 3;
+pub const WAIT_REG_MEM_FUNCTION_NE: u32 = 4;
+pub const WAIT_REG_MEM_FUNCTION_GE: u32 = 5;
+pub const WAIT_REG_MEM_FUNCTION_GT: u32 = 6;
+pub const WAIT_REG_MEM_MEM_SPACE_MEMORY: u32 = 1 << 4;
+
+/* WRITE_DATA */
+pub const WRITE_DATA_DST_SEL_MEMORY: u32 = 5 << 8;
+pub const WRITE_DATA_WR_CONFIRM: u32 = 1 << 20;
+
+/* INDIRECT_BUFFER */
+pub const INDIRECT_BUFFER_VALID: u32 = 1 << 23;
+pub const INDIRECT_BUFFER_MAX_SIZE: u32 = 0xFFFFF;
+
+/* RELEASE_MEM */
+pub const CACHE_FLUSH_AND_INV_TS_EVENT: u32 = 0x14;
+pub const BOTTOM_OF_PIPE_TS: u32 = 0x28;
+
+pub fn EVENT_TYPE(x: u32) -> u32 {
+    x & 0x3F
+}
+
+pub fn EVENT_INDEX(x: u32) -> u32 {
+    (x & 0xF) << 8
+}
+
+pub fn DST_SEL(x: u32) -> u32 {
+    (x & 0x3) << 16
+}
+
+pub fn INT_SEL(x: u32) -> u32 {
+    (x & 0x7) << 24
+}
+
+pub fn DATA_SEL(x: u32) -> u32 {
+    (x & 0x7) << 29
+}
+
+pub const DATA_SEL_NONE: u32 = 0;
+pub const DATA_SEL_32: u32 = 1;
+pub const DATA_SEL_64: u32 = 2;
+pub const INT_SEL_NONE: u32 = 0;
+/* Interrupt once the data write is confirmed */
+pub const INT_SEL_AFTER_WRITE_CONFIRM: u32 = 2;
+
+/* gfx8 and gfx9 end of pipe cache actions */
+pub const EOP_TCL1_VOL_ACTION_EN: u32 = 1 << 12;
+pub const EOP_TC_VOL_ACTION_EN: u32 = 1 << 13;
+pub const EOP_TC_WB_ACTION_EN: u32 = 1 << 15;
+pub const EOP_TCL1_ACTION_EN: u32 = 1 << 16;
+pub const EOP_TC_ACTION_EN: u32 = 1 << 17;
+pub const EOP_TC_NC_ACTION_EN: u32 = 1 << 19;
+pub const EOP_TC_MD_ACTION_EN: u32 = 1 << 21;
+
+/* gfx10 on end of pipe cache actions */
+pub const RELEASE_MEM_GCR_GLM_WB: u32 = 1 << 12;
+pub const RELEASE_MEM_GCR_GLM_INV: u32 = 1 << 13;
+pub const RELEASE_MEM_GCR_GLV_INV: u32 = 1 << 14;
+pub const RELEASE_MEM_GCR_GL1_INV: u32 = 1 << 15;
+pub const RELEASE_MEM_GCR_GL2_INV: u32 = 1 << 20;
+pub const RELEASE_MEM_GCR_GL2_WB: u32 = 1 << 21;
+pub const RELEASE_MEM_GCR_SEQ: u32 = 1 << 22;
+
+/* gfx8 and gfx9 CP_COHER_CNTL of ACQUIRE_MEM */
+pub const CP_COHER_CNTL_TC_NC_ACTION_ENA: u32 = 1 << 3;
+pub const CP_COHER_CNTL_TC_WB_ACTION_ENA: u32 = 1 << 18;
+pub const CP_COHER_CNTL_TCL1_ACTION_ENA: u32 = 1 << 22;
+pub const CP_COHER_CNTL_TC_ACTION_ENA: u32 = 1 << 23;
+pub const CP_COHER_CNTL_SH_KCACHE_ACTION_ENA: u32 = 1 << 27;
+pub const CP_COHER_CNTL_SH_ICACHE_ACTION_ENA: u32 = 1 << 29;
+
+/* gfx10 on GCR_CNTL of ACQUIRE_MEM */
+pub const GCR_CNTL_GLI_INV: u32 = 1 << 0;
+pub const GCR_CNTL_GLM_WB: u32 = 1 << 4;
+pub const GCR_CNTL_GLM_INV: u32 = 1 << 5;
+pub const GCR_CNTL_GLK_INV: u32 = 1 << 7;
+pub const GCR_CNTL_GLV_INV: u32 = 1 << 8;
+pub const GCR_CNTL_GL1_INV: u32 = 1 << 9;
+pub const GCR_CNTL_GL2_INV: u32 = 1 << 14;
+pub const GCR_CNTL_GL2_WB: u32 = 1 << 15;
+
+/* Dword offsets of the compute SH registers. Most are where gfx7 put
+ * them, gfx10 adds COMPUTE_PGM_RSRC3 and gfx11 the dispatch scratch
+ * base.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct pm4_compute_regs_t {
+    pub COMPUTE_DISPATCH_INITIATOR: u32,
+    pub COMPUTE_NUM_THREAD_X: u32,
+    pub COMPUTE_NUM_THREAD_Y: u32,
+    pub COMPUTE_NUM_THREAD_Z: u32,
+    pub COMPUTE_PGM_LO: u32,
+    pub COMPUTE_PGM_HI: u32,
+    pub COMPUTE_DISPATCH_SCRATCH_BASE_LO: Option<u32>,
+    pub COMPUTE_PGM_RSRC1: u32,
+    pub COMPUTE_PGM_RSRC2: u32,
+    pub COMPUTE_RESOURCE_LIMITS: u32,
+    pub COMPUTE_STATIC_THREAD_MGMT_SE0: u32,
+    pub COMPUTE_STATIC_THREAD_MGMT_SE1: u32,
+    pub COMPUTE_TMPRING_SIZE: u32,
+    pub COMPUTE_STATIC_THREAD_MGMT_SE2: u32,
+    pub COMPUTE_STATIC_THREAD_MGMT_SE3: u32,
+    pub COMPUTE_PGM_RSRC3: Option<u32>,
+    pub COMPUTE_USER_DATA_0: u32,
+    pub num_user_data: u32,
+}
+
+pub fn hsakmt_pm4_compute_regs(gfxv: u32) -> pm4_compute_regs_t {
+    pm4_compute_regs_t {
+        COMPUTE_DISPATCH_INITIATOR: 0x2E00,
+        COMPUTE_NUM_THREAD_X: 0x2E07,
+        COMPUTE_NUM_THREAD_Y: 0x2E08,
+        COMPUTE_NUM_THREAD_Z: 0x2E09,
+        COMPUTE_PGM_LO: 0x2E0C,
+        COMPUTE_PGM_HI: 0x2E0D,
+        COMPUTE_DISPATCH_SCRATCH_BASE_LO: if gfxv >= GFX_VERSION_PLUM_BONITO as u32 {
+            Some(0x2E10)
+        } else {
+            None
+        },
+        COMPUTE_PGM_RSRC1: 0x2E12,
+        COMPUTE_PGM_RSRC2: 0x2E13,
+        COMPUTE_RESOURCE_LIMITS: 0x2E15,
+        COMPUTE_STATIC_THREAD_MGMT_SE0: 0x2E16,
+        COMPUTE_STATIC_THREAD_MGMT_SE1: 0x2E17,
+        COMPUTE_TMPRING_SIZE: 0x2E18,
+        COMPUTE_STATIC_THREAD_MGMT_SE2: 0x2E19,
+        COMPUTE_STATIC_THREAD_MGMT_SE3: 0x2E1A,
+        /* gfx90a, then gfx94x on */
+        COMPUTE_PGM_RSRC3: if gfxv == GFX_VERSION_ALDEBARAN as u32
+            || gfxv >= GFX_VERSION_AQUA_VANJARAM as u32
+        {
+            Some(0x2E28)
+        } else {
+            None
+        },
+        COMPUTE_USER_DATA_0: 0x2E40,
+        num_user_data: 16,
+    }
+}
+
+/* PM4 type 3 packets for a compute queue in the order they run. The
+ * packet layouts that changed over time, RELEASE_MEM and ACQUIRE_MEM,
+ * are written for gfxv.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pm4PacketBuilder {
+    pub gfxv: u32,
+    pub regs: pm4_compute_regs_t,
+    dwords: Vec<u32>,
+}
+
+impl Pm4PacketBuilder {
+    pub fn new(gfxv: u32) -> Self {
+        Self {
+            gfxv,
+            regs: hsakmt_pm4_compute_regs(gfxv),
+            dwords: vec![],
+        }
+    }
+
+    pub fn for_node(props: &HsaNodeProperties) -> Self {
+        Self::new(unsafe { HSA_GET_GFX_VERSION_FULL(&props.EngineId.ui32) })
+    }
+
+    /* None if body is empty or too long for one packet */
+    fn packet(&mut self, op: u32, body: &[u32]) -> Option<&mut Self> {
+        let count = u32::try_from(body.len()).ok()?.checked_sub(1)?;
+
+        self.dwords.push(PACKET3(op, count)?);
+        self.dwords.extend_from_slice(body);
+
+        Some(self)
+    }
+
+    /* packet for bodies of a size known to fit */
+    fn fixed_packet<const N: usize>(&mut self, op: u32, body: [u32; N]) -> &mut Self {
+        const { assert!(N >= 1 && N <= PACKET3_MAX_COUNT as usize + 1) };
+
+        self.packet(op, &body).unwrap()
+    }
+
+    /* Write values to consecutive SH registers from reg, a dword offset
+     * such as regs.COMPUTE_PGM_LO. None if values is empty or runs past
+     * the SH registers.
+     */
+    pub fn set_sh_reg(&mut self, reg: u32, values: &[u32]) -> Option<&mut Self> {
+        if values.is_empty()
+            || reg < PACKET3_SET_SH_REG_START
+            || reg as u64 + values.len() as u64 > PACKET3_SET_SH_REG_END as u64
+        {
+            return None;
+        }
+
+        let mut body = vec![reg - PACKET3_SET_SH_REG_START];
+        body.extend_from_slice(values);
+
+        self.packet(PACKET3_SET_SH_REG, &body)
+    }
+
+    /* Dispatch a grid of workgroups, dims counts workgroups unless
+     * initiator has USE_THREAD_DIMENSIONS
+     */
+    pub fn dispatch_direct(&mut self, dims: [u32; 3], initiator: u32) -> &mut Self {
+        self.fixed_packet(
+            PACKET3_DISPATCH_DIRECT,
+            [dims[0], dims[1], dims[2], initiator],
+        )
+    }
+
+    /* Dispatch with the three dimensions read from addr */
+    pub fn dispatch_indirect(&mut self, addr: u64, initiator: u32) -> &mut Self {
+        self.fixed_packet(
+            PACKET3_DISPATCH_INDIRECT,
+            [addr as u32, (addr >> 32) as u32, initiator],
+        )
+    }
+
+    /* Write back and invalidate the GPU caches for all of memory before
+     * the packets after it run
+     */
+    pub fn acquire_mem(&mut self) -> &mut Self {
+        if self.gfxv >= GFX_VERSION_NAVI10 as u32 {
+            let gcr_cntl = GCR_CNTL_GLI_INV
+                | GCR_CNTL_GL1_INV
+                | GCR_CNTL_GLV_INV
+                | GCR_CNTL_GLK_INV
+                | GCR_CNTL_GLM_INV
+                | GCR_CNTL_GLM_WB
+                | GCR_CNTL_GL2_INV
+                | GCR_CNTL_GL2_WB;
+
+            self.fixed_packet(
+                PACKET3_ACQUIRE_MEM,
+                [0, 0xFFFFFFFF, 0xFFFFFF, 0, 0, 0xA, gcr_cntl],
+            )
+        } else {
+            let coher_cntl = CP_COHER_CNTL_SH_ICACHE_ACTION_ENA
+                | CP_COHER_CNTL_SH_KCACHE_ACTION_ENA
+                | CP_COHER_CNTL_TC_ACTION_ENA
+                | CP_COHER_CNTL_TCL1_ACTION_ENA
+                | CP_COHER_CNTL_TC_WB_ACTION_ENA;
+
+            /* CP_COHER_SIZE_HI is 8 bits before gfx9 */
+            let size_hi = if self.gfxv >= GFX_VERSION_VEGA10 as u32 {
+                0xFFFFFF
+            } else {
+                0xFF
+            };
+
+            self.fixed_packet(
+                PACKET3_ACQUIRE_MEM,
+                [coher_cntl, 0xFFFFFFFF, size_hi, 0, 0, 0xA],
+            )
+        }
+    }
+
+    /* Once everything before it has finished, flush the caches and write
+     * the 64 bit data to addr, 8 byte aligned. With int_ctxid the CP
+     * then raises an interrupt, gfx8 has no context id and raises it
+     * without.
+     */
+    pub fn release_mem(&mut self, addr: u64, data: u64, int_ctxid: Option<u32>) -> &mut Self {
+        let int_sel = if int_ctxid.is_some() {
+            INT_SEL_AFTER_WRITE_CONFIRM
+        } else {
+            INT_SEL_NONE
+        };
+        let event = EVENT_TYPE(CACHE_FLUSH_AND_INV_TS_EVENT) | EVENT_INDEX(5);
+        let sel = DATA_SEL(DATA_SEL_64) | INT_SEL(int_sel);
+
+        if self.gfxv < GFX_VERSION_VEGA10 as u32 {
+            return self.fixed_packet(
+                PACKET3_RELEASE_MEM,
+                [
+                    event | EOP_TCL1_ACTION_EN | EOP_TC_ACTION_EN | EOP_TC_WB_ACTION_EN,
+                    sel,
+                    addr as u32,
+                    (addr >> 32) as u32,
+                    data as u32,
+                    (data >> 32) as u32,
+                ],
+            );
+        }
+
+        let cache = if self.gfxv >= GFX_VERSION_NAVI10 as u32 {
+            RELEASE_MEM_GCR_SEQ
+                | RELEASE_MEM_GCR_GL2_WB
+                | RELEASE_MEM_GCR_GLM_INV
+                | RELEASE_MEM_GCR_GLM_WB
+        } else {
+            EOP_TCL1_ACTION_EN | EOP_TC_ACTION_EN | EOP_TC_WB_ACTION_EN | EOP_TC_MD_ACTION_EN
+        };
+
+        self.fixed_packet(
+            PACKET3_RELEASE_MEM,
+            [
+                event | cache,
+                sel | DST_SEL(0),
+                addr as u32,
+                (addr >> 32) as u32,
+                data as u32,
+                (data >> 32) as u32,
+                int_ctxid.unwrap_or(0),
+            ],
+        )
+    }
+
+    /* Stall until (*addr & mask) function reference holds, checking every
+     * poll_interval clocks
+     */
+    pub fn wait_reg_mem(
+        &mut self,
+        addr: u64,
+        function: u32,
+        reference: u32,
+        mask: u32,
+        poll_interval: u32,
+    ) -> &mut Self {
+        self.fixed_packet(
+            PACKET3_WAIT_REG_MEM,
+            [
+                WAIT_REG_MEM_MEM_SPACE_MEMORY | (function & 0x7),
+                addr as u32 & !0x3,
+                (addr >> 32) as u32,
+                reference,
+                mask,
+                poll_interval & 0xFFFF,
+            ],
+        )
+    }
+
+    /* Write data to addr, 4 byte aligned, and wait for the write to land.
+     * None if data is more than a packet holds.
+     */
+    pub fn write_data(&mut self, addr: u64, data: &[u32]) -> Option<&mut Self> {
+        let mut body = vec![
+            WRITE_DATA_DST_SEL_MEMORY | WRITE_DATA_WR_CONFIRM,
+            addr as u32 & !0x3,
+            (addr >> 32) as u32,
+        ];
+        body.extend_from_slice(data);
+
+        self.packet(PACKET3_WRITE_DATA, &body)
+    }
+
+    /* Run size_dw dwords of packets at addr, 4 byte aligned. None if
+     * size_dw is more than INDIRECT_BUFFER_MAX_SIZE.
+     */
+    pub fn indirect_buffer(&mut self, addr: u64, size_dw: u32) -> Option<&mut Self> {
+        if size_dw > INDIRECT_BUFFER_MAX_SIZE {
+            return None;
+        }
+
+        Some(self.fixed_packet(
+            PACKET3_INDIRECT_BUFFER,
+            [
+                addr as u32 & !0x3,
+                (addr >> 32) as u32 & 0xFFFF,
+                INDIRECT_BUFFER_VALID | size_dw,
+            ],
+        ))
+    }
+
+    /* count dwords of padding, one NOP packet. None if count is more
+     * than a packet holds.
+     */
+    pub fn nop(&mut self, count: usize) -> Option<&mut Self> {
+        match count {
+            0 => Some(self),
+            /* A type 3 NOP with count 0x3FFF is a header alone */
+            1 => {
+                self.dwords.push(PACKET3(PACKET3_NOP, PACKET3_MAX_COUNT)?);
+                Some(self)
+            }
+            _ => self.packet(PACKET3_NOP, &vec![0; count - 1]),
+        }
+    }
+
+    pub fn dwords(&self) -> &[u32] {
+        &self.dwords
+    }
+
+    pub fn clear(&mut self) {
+        self.dwords.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hsakmttypes::{
+        node_props_t, GFX_VERSION_ARCTURUS, GFX_VERSION_GFX1200, GFX_VERSION_RENOIR,
+        GFX_VERSION_SIENNA_CICHLID, GFX_VERSION_TONGA,
+    };
+
+    #[test]
+    fn test_pm4_dispatch() {
+        let mut pkts = Pm4PacketBuilder::new(GFX_VERSION_AQUA_VANJARAM as u32);
+        let regs = pkts.regs;
+
+        pkts.set_sh_reg(regs.COMPUTE_PGM_LO, &[0x1234_5600 >> 8, 0])
+            .unwrap()
+            .set_sh_reg(regs.COMPUTE_NUM_THREAD_X, &[64, 1, 1])
+            .unwrap()
+            .dispatch_direct([16, 2, 1], COMPUTE_SHADER_EN | FORCE_START_AT_000)
+            .dispatch_indirect(0x7f00_1234_5000, COMPUTE_SHADER_EN)
+            .write_data(0x2000, &[7])
+            .unwrap()
+            .wait_reg_mem(0x3000, WAIT_REG_MEM_FUNCTION_EQ, 1, 0xffffffff, 4)
+            .indirect_buffer(0x1_0000_4000, 32)
+            .unwrap()
+            .nop(3)
+            .unwrap();
+
+        assert_eq!(
+            pkts.dwords(),
+            &[
+                0xc0027600, 0x20c, 0x123456, 0, /* SET_SH_REG */
+                0xc0037600, 0x207, 64, 1, 1, /* SET_SH_REG */
+                0xc0031500, 16, 2, 1, 5, /* DISPATCH_DIRECT */
+                0xc0021600, 0x12345000, 0x7f00, 1, /* DISPATCH_INDIRECT */
+                0xc0033700, 0x100500, 0x2000, 0, 7, /* WRITE_DATA */
+                0xc0053c00, 0x13, 0x3000, 0, 1, 0xffffffff, 4, /* WAIT_REG_MEM */
+                0xc0023f00, 0x4000, 1, 0x800020, /* INDIRECT_BUFFER */
+                0xc0011000, 0, 0, /* NOP */
+            ]
+        );
+
+        pkts.clear();
+        pkts.nop(1);
+        assert_eq!(pkts.dwords(), &[0xffff1000]);
+
+        /* Bad registers and sizes are refused without writing a packet */
+        pkts.clear();
+        assert!(pkts.set_sh_reg(regs.COMPUTE_PGM_LO, &[]).is_none());
+        assert!(pkts.set_sh_reg(0x2000, &[1]).is_none());
+        assert!(pkts.set_sh_reg(0x2fff, &[1, 2]).is_none());
+        assert!(pkts
+            .indirect_buffer(0x4000, INDIRECT_BUFFER_MAX_SIZE + 1)
+            .is_none());
+        assert!(pkts.write_data(0x2000, &[0; 0x3FFE]).is_none());
+        assert!(pkts.nop(0x4002).is_none());
+        assert!(pkts.dwords().is_empty());
+        assert_eq!(PACKET3(PACKET3_NOP, PACKET3_MAX_COUNT + 1), None);
+
+        assert!(pkts.set_sh_reg(0x2fff, &[1]).is_some());
+        assert!(pkts.write_data(0x2000, &[0; 0x3FFD]).is_some());
+        assert!(pkts.nop(0x4001).is_some());
+        assert!(pkts
+            .indirect_buffer(0x4000, INDIRECT_BUFFER_MAX_SIZE)
+            .is_some());
+    }
+
+    #[test]
+    fn test_pm4_release_acquire_mem() {
+        /* gfx8 */
+        let mut pkts = Pm4PacketBuilder::new(GFX_VERSION_TONGA as u32);
+        pkts.acquire_mem()
+            .release_mem(0x1_0000_2000, 0x5_0000_0001, None);
+        assert_eq!(
+            pkts.dwords(),
+            &[
+                0xc0055800, 0x28c40000, 0xffffffff, 0xff, 0, 0, 0xa, /* ACQUIRE_MEM */
+                0xc0054900, 0x38514, 0x40000000, 0x2000, 1, 1, 5, /* RELEASE_MEM */
+            ]
+        );
+
+        /* gfx9 */
+        let mut pkts = Pm4PacketBuilder::new(GFX_VERSION_AQUA_VANJARAM as u32);
+        pkts.acquire_mem().release_mem(0x2000, 42, Some(0x77));
+        assert_eq!(
+            pkts.dwords(),
+            &[
+                0xc0055800, 0x28c40000, 0xffffffff, 0xffffff, 0, 0, 0xa, /* ACQUIRE_MEM */
+                0xc0064900, 0x238514, 0x42000000, 0x2000, 0, 42, 0, 0x77, /* RELEASE_MEM */
+            ]
+        );
+
+        /* gfx10 */
+        let mut pkts = Pm4PacketBuilder::new(GFX_VERSION_SIENNA_CICHLID as u32);
+        pkts.acquire_mem().release_mem(0x2000, 42, None);
+        assert_eq!(
+            pkts.dwords(),
+            &[
+                0xc0065800, 0, 0xffffffff, 0xffffff, 0, 0, 0xa, 0xc3b1, /* ACQUIRE_MEM */
+                0xc0064900, 0x603514, 0x40000000, 0x2000, 0, 42, 0, 0, /* RELEASE_MEM */
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pm4_regs() {
+        let mut props = node_props_t::new().node;
+        props.EngineId.ui32.Major = 12;
+        props.EngineId.ui32.Minor = 0;
+        props.EngineId.ui32.Stepping = 0;
+
+        let pkts = Pm4PacketBuilder::for_node(&props);
+        assert_eq!(pkts.gfxv, GFX_VERSION_GFX1200 as u32);
+        assert_eq!(pkts.regs.COMPUTE_PGM_RSRC3, Some(0x2E28));
+        assert_eq!(pkts.regs.COMPUTE_DISPATCH_SCRATCH_BASE_LO, Some(0x2E10));
+
+        let regs = hsakmt_pm4_compute_regs(GFX_VERSION_SIENNA_CICHLID as u32);
+        assert_eq!(regs.COMPUTE_PGM_RSRC3, Some(0x2E28));
+        assert_eq!(regs.COMPUTE_DISPATCH_SCRATCH_BASE_LO, None);
+
+        let regs = hsakmt_pm4_compute_regs(GFX_VERSION_AQUA_VANJARAM as u32);
+        assert_eq!(regs.COMPUTE_PGM_RSRC3, Some(0x2E28));
+        assert_eq!(regs.COMPUTE_PGM_LO, 0x2E0C);
+
+        let regs = hsakmt_pm4_compute_regs(GFX_VERSION_ALDEBARAN as u32);
+        assert_eq!(regs.COMPUTE_PGM_RSRC3, Some(0x2E28));
+
+        /* Not on the rest of gfx9 */
+        for gfxv in [GFX_VERSION_VEGA10, GFX_VERSION_ARCTURUS, GFX_VERSION_RENOIR] {
+            let regs = hsakmt_pm4_compute_regs(gfxv as u32);
+            assert_eq!(regs.COMPUTE_PGM_RSRC3, None);
+        }
+    }
+}
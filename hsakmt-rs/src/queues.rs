@@ -1,7 +1,62 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use crate::fmm::TONGA_PAGE_SIZE;
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::HsakmtStatus::{
+    HSAKMT_STATUS_ERROR, HSAKMT_STATUS_INVALID_NODE_UNIT, HSAKMT_STATUS_INVALID_PARAMETER,
+    HSAKMT_STATUS_NO_MEMORY, HSAKMT_STATUS_SUCCESS,
+};
+use crate::hsakmttypes::HSA_QUEUE_TYPE::{
+    HSA_QUEUE_COMPUTE, HSA_QUEUE_COMPUTE_AQL, HSA_QUEUE_SDMA, HSA_QUEUE_SDMA_BY_ENG_ID,
+    HSA_QUEUE_SDMA_XGMI,
+};
 use crate::hsakmttypes::{
-    GFX_VERSION_ALDEBARAN, GFX_VERSION_AQUA_VANJARAM, GFX_VERSION_ARCTURUS, GFX_VERSION_GFX1200,
-    GFX_VERSION_GFX1201, GFX_VERSION_PLUM_BONITO, GFX_VERSION_WHEAT_NAS,
+    HsaEvent, HsaMemFlagUnion, HsaMemFlags, HsaNodeProperties, HsaQueueResource, HsakmtStatus,
+    ALIGN_UP, GFX_VERSION_ALDEBARAN, GFX_VERSION_AQUA_VANJARAM, GFX_VERSION_ARCTURUS,
+    GFX_VERSION_GFX1200, GFX_VERSION_GFX1201, GFX_VERSION_PLUM_BONITO, GFX_VERSION_TONGA,
+    GFX_VERSION_VEGA10, GFX_VERSION_WHEAT_NAS, HSA_GET_GFX_VERSION_FULL, HSA_PAGE_SIZE_4KB,
+    HSA_QUEUEID, HSA_QUEUE_PRIORITY, HSA_QUEUE_TYPE,
 };
+use crate::kfd_ioctl::{
+    kfd_ioctl_create_queue_args, kfd_ioctl_destroy_queue_args, kfd_ioctl_set_cu_mask_args,
+    kfd_ioctl_update_queue_args, AMDKFD_IOC_CREATE_QUEUE, AMDKFD_IOC_DESTROY_QUEUE,
+    AMDKFD_IOC_SET_CU_MASK, AMDKFD_IOC_UPDATE_QUEUE, KFD_IOC_QUEUE_TYPE_COMPUTE,
+    KFD_IOC_QUEUE_TYPE_COMPUTE_AQL, KFD_IOC_QUEUE_TYPE_SDMA, KFD_IOC_QUEUE_TYPE_SDMA_BY_ENG_ID,
+    KFD_IOC_QUEUE_TYPE_SDMA_XGMI, KFD_MAX_QUEUE_PERCENTAGE,
+};
+use crate::libhsakmt::hsakmt_ioctl;
+use crate::queues_cwsr::queue_cwsr_layout_t;
+use libc::{mmap, munmap, off_t, strerror, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
+
+/* 1024 doorbells, 4 or 8 bytes each doorbell depending on ASIC generation */
+pub fn DOORBELL_SIZE(gfxv: u32) -> u32 {
+    if gfxv >= 0x90000 {
+        8
+    } else {
+        4
+    }
+}
+
+pub fn DOORBELLS_PAGE_SIZE(ds: u32) -> u32 {
+    1024 * ds
+}
+
+#[derive(Debug)]
+pub struct process_doorbells {
+    pub use_gpuvm: bool,
+    pub size: u32,
+    pub mapping: *mut std::os::raw::c_void,
+}
+
+impl Default for process_doorbells {
+    fn default() -> Self {
+        Self {
+            use_gpuvm: false,
+            size: 0,
+            mapping: std::ptr::null_mut(),
+        }
+    }
+}
 
 pub fn hsakmt_get_vgpr_size_per_cu(gfxv: u32) -> u32 {
     let mut vgpr_size = 0x40000;
@@ -21,3 +76,1404 @@ pub fn hsakmt_get_vgpr_size_per_cu(gfxv: u32) -> u32 {
 
     vgpr_size
 }
+
+pub fn IS_SOC15(gfxv: u32) -> bool {
+    gfxv >= GFX_VERSION_VEGA10 as u32
+}
+
+/* KFD priority of each HSA_QUEUE_PRIORITY, from MINIMUM to MAXIMUM */
+const PRIORITY_MAP: [u32; 7] = [0, 3, 5, 7, 9, 11, 15];
+
+pub fn kfd_queue_priority(Priority: HSA_QUEUE_PRIORITY) -> u32 {
+    PRIORITY_MAP[(Priority as i32 - HSA_QUEUE_PRIORITY::HSA_QUEUE_PRIORITY_MINIMUM as i32) as usize]
+}
+
+pub fn kfd_queue_type(Type: HSA_QUEUE_TYPE) -> Option<u32> {
+    match Type {
+        HSA_QUEUE_COMPUTE => Some(KFD_IOC_QUEUE_TYPE_COMPUTE),
+        HSA_QUEUE_SDMA => Some(KFD_IOC_QUEUE_TYPE_SDMA),
+        HSA_QUEUE_SDMA_XGMI => Some(KFD_IOC_QUEUE_TYPE_SDMA_XGMI),
+        HSA_QUEUE_SDMA_BY_ENG_ID => Some(KFD_IOC_QUEUE_TYPE_SDMA_BY_ENG_ID),
+        HSA_QUEUE_COMPUTE_AQL => Some(KFD_IOC_QUEUE_TYPE_COMPUTE_AQL),
+        _ => None,
+    }
+}
+
+/* Queue type of a KFD queue type */
+pub fn hsa_queue_type(queue_type: u32) -> Option<HSA_QUEUE_TYPE> {
+    match queue_type {
+        KFD_IOC_QUEUE_TYPE_COMPUTE => Some(HSA_QUEUE_COMPUTE),
+        KFD_IOC_QUEUE_TYPE_SDMA => Some(HSA_QUEUE_SDMA),
+        KFD_IOC_QUEUE_TYPE_SDMA_XGMI => Some(HSA_QUEUE_SDMA_XGMI),
+        KFD_IOC_QUEUE_TYPE_SDMA_BY_ENG_ID => Some(HSA_QUEUE_SDMA_BY_ENG_ID),
+        KFD_IOC_QUEUE_TYPE_COMPUTE_AQL => Some(HSA_QUEUE_COMPUTE_AQL),
+        _ => None,
+    }
+}
+
+/* Engines of a node are numbered PCIe optimized first, XGMI optimized
+ * after them. Returns the queue type of engine SdmaEngineId, None if
+ * the node has no such engine.
+ */
+pub fn hsakmt_sdma_engine_type(
+    props: &HsaNodeProperties,
+    SdmaEngineId: u32,
+) -> Option<HSA_QUEUE_TYPE> {
+    if SdmaEngineId < props.NumSdmaEngines {
+        Some(HSA_QUEUE_SDMA)
+    } else if SdmaEngineId < props.NumSdmaEngines + props.NumSdmaXgmiEngines {
+        Some(HSA_QUEUE_SDMA_XGMI)
+    } else {
+        None
+    }
+}
+
+/* Size of the end-of-pipe buffer the CP writes completed dispatches to */
+pub fn hsakmt_eop_buffer_size(gfxv: u32, Type: HSA_QUEUE_TYPE) -> u32 {
+    if gfxv == GFX_VERSION_TONGA as u32 {
+        TONGA_PAGE_SIZE as u32
+    } else if (gfxv & !0xff) == GFX_VERSION_AQUA_VANJARAM as u32 {
+        if Type == HSA_QUEUE_COMPUTE {
+            4096
+        } else {
+            0
+        }
+    } else if gfxv >= 0x80000 {
+        4096
+    } else {
+        0
+    }
+}
+
+/* Split the doorbell offset KFD returned for queue_id into the mmap
+ * offset of the doorbell page and the offset of the doorbell in it
+ */
+pub fn hsakmt_queue_doorbell_offsets(gfxv: u32, doorbell_offset: u64, queue_id: u32) -> (u64, u32) {
+    if IS_SOC15(gfxv) {
+        /* On SOC15 chips, the doorbell offset within the
+         * doorbell page is included in the doorbell offset
+         * returned by KFD. This allows CP queue doorbells to be
+         * allocated dynamically (while SDMA queue doorbells fixed)
+         * rather than based on the its process queue ID.
+         */
+        let mask = DOORBELLS_PAGE_SIZE(DOORBELL_SIZE(gfxv)) as u64 - 1;
+
+        (doorbell_offset & !mask, (doorbell_offset & mask) as u32)
+    } else {
+        /* On older chips, the doorbell offset within the
+         * doorbell page is based on the queue ID.
+         */
+        (doorbell_offset, queue_id * DOORBELL_SIZE(gfxv))
+    }
+}
+
+pub unsafe fn kfd_create_queue(fd: i32, args: &mut kfd_ioctl_create_queue_args) -> HsakmtStatus {
+    let err = hsakmt_ioctl(
+        fd,
+        AMDKFD_IOC_CREATE_QUEUE,
+        args as *mut _ as *mut std::os::raw::c_void,
+    );
+
+    if err != 0 {
+        return HSAKMT_STATUS_ERROR;
+    }
+
+    HSAKMT_STATUS_SUCCESS
+}
+
+pub unsafe fn kfd_destroy_queue(fd: i32, queue_id: u32) -> HsakmtStatus {
+    let mut args = kfd_ioctl_destroy_queue_args { queue_id, pad: 0 };
+
+    let err = hsakmt_ioctl(
+        fd,
+        AMDKFD_IOC_DESTROY_QUEUE,
+        &mut args as *mut _ as *mut std::os::raw::c_void,
+    );
+
+    if err != 0 {
+        let errno = std::io::Error::last_os_error().raw_os_error().unwrap();
+        println!("Failed to destroy queue: {:?}", strerror(errno));
+        return HSAKMT_STATUS_ERROR;
+    }
+
+    HSAKMT_STATUS_SUCCESS
+}
+
+pub unsafe fn kfd_update_queue(fd: i32, args: &mut kfd_ioctl_update_queue_args) -> HsakmtStatus {
+    let err = hsakmt_ioctl(
+        fd,
+        AMDKFD_IOC_UPDATE_QUEUE,
+        args as *mut _ as *mut std::os::raw::c_void,
+    );
+
+    if err != 0 {
+        return HSAKMT_STATUS_ERROR;
+    }
+
+    HSAKMT_STATUS_SUCCESS
+}
+
+/* KFD copies the first num_cu_mask bits of cu_mask */
+pub unsafe fn kfd_set_cu_mask(
+    fd: i32,
+    queue_id: u32,
+    num_cu_mask: u32,
+    cu_mask: &[u32],
+) -> HsakmtStatus {
+    let mut args = kfd_ioctl_set_cu_mask_args {
+        queue_id,
+        num_cu_mask,
+        cu_mask_ptr: cu_mask.as_ptr() as u64,
+    };
+
+    let err = hsakmt_ioctl(
+        fd,
+        AMDKFD_IOC_SET_CU_MASK,
+        &mut args as *mut _ as *mut std::os::raw::c_void,
+    );
+
+    if err != 0 {
+        return HSAKMT_STATUS_ERROR;
+    }
+
+    HSAKMT_STATUS_SUCCESS
+}
+
+/* Thunk side of a queue, HSA_QUEUEID is its address */
+#[derive(Debug)]
+pub struct queue {
+    pub queue_id: u32,
+    pub node_id: u32,
+    pub gpu_id: u32,
+    pub gfxv: u32,
+    pub Type: HSA_QUEUE_TYPE,
+    /* Address of the doorbell, 0 until the queue is created */
+    pub doorbell: u64,
+    /* GPU accessible page with the read and write pointers of PM4 queues */
+    pub pointers: *mut std::os::raw::c_void,
+    pub eop_buffer: *mut std::os::raw::c_void,
+    pub eop_buffer_size: u32,
+    /* CWSR area of all XCCs, see queue_cwsr_layout_t */
+    pub ctx_save_restore: *mut std::os::raw::c_void,
+    pub cwsr: Option<queue_cwsr_layout_t>,
+    /* Scheduling state as last set by create, update or set CU mask */
+    pub ring_base: u64,
+    pub ring_size: u32,
+    pub queue_percentage: u32,
+    pub priority: HSA_QUEUE_PRIORITY,
+    /* CUs the queue may run on, empty for SDMA queues */
+    pub cu_mask: Vec<u32>,
+}
+
+/* A queue together with its ring buffer, see hsakmt_queue_create */
+#[derive(Debug)]
+pub struct Queue {
+    pub NodeId: u32,
+    pub Type: HSA_QUEUE_TYPE,
+    pub RingBase: *mut std::os::raw::c_void,
+    pub RingSize: u64,
+    /* Read and write pointers of AQL queues, PM4 queues keep them in
+     * the thunk queue
+     */
+    pub(crate) pointers: *mut std::os::raw::c_void,
+    pub Resource: HsaQueueResource,
+}
+
+impl Queue {
+    pub fn queue_id(&self) -> HSA_QUEUEID {
+        self.Resource.QueueId
+    }
+
+    /* Id KFD gave the queue */
+    pub fn kfd_queue_id(&self) -> u32 {
+        unsafe { (*(self.Resource.QueueId as *const queue)).queue_id }
+    }
+
+    pub fn doorbell(&self) -> *mut std::os::raw::c_void {
+        self.Resource.QueueDoorBell as *mut std::os::raw::c_void
+    }
+
+    /* Bytes of the doorbell, see DOORBELL_SIZE */
+    pub fn doorbell_size(&self) -> u32 {
+        DOORBELL_SIZE(unsafe { (*(self.Resource.QueueId as *const queue)).gfxv })
+    }
+
+    pub fn read_pointer(&self) -> *mut u64 {
+        self.Resource.QueueRptrValue as *mut u64
+    }
+
+    pub fn write_pointer(&self) -> *mut u64 {
+        self.Resource.QueueWptrValue as *mut u64
+    }
+}
+
+impl HsakmtGlobals {
+    pub fn hsakmt_get_gfxv_by_node_id(&mut self, node_id: u32) -> u32 {
+        let props = self.hsakmt_topology_get_node_props(node_id);
+
+        unsafe { HSA_GET_GFX_VERSION_FULL(&props.EngineId.ui32) }
+    }
+
+    pub fn hsakmt_init_process_doorbells(&mut self, NumNodes: u32) -> HsakmtStatus {
+        /* doorbells[] is accessed using Topology NodeId. This means doorbells[0],
+         * which corresponds to CPU only Node, might not be used
+         */
+        self.queues.doorbells = (0..NumNodes)
+            .map(|_| process_doorbells::default())
+            .collect();
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    fn get_doorbell_map_info(&mut self, node_id: u32) {
+        /*
+         * GPUVM doorbell on Tonga requires a workaround for VM TLB ACTIVE bit
+         * lookup bug. Remove ASIC check when this is implemented in amdgpu.
+         */
+        let gfxv = self.hsakmt_get_gfxv_by_node_id(node_id);
+        let page_size = self.PAGE_SIZE() as u32;
+
+        let doorbell = &mut self.queues.doorbells[node_id as usize];
+        doorbell.use_gpuvm = self.hsakmt_is_dgpu && gfxv != GFX_VERSION_TONGA as u32;
+        doorbell.size = DOORBELLS_PAGE_SIZE(DOORBELL_SIZE(gfxv));
+
+        if doorbell.size < page_size {
+            doorbell.size = page_size;
+        }
+    }
+
+    pub unsafe fn hsakmt_destroy_process_doorbells(&mut self) {
+        for i in 0..self.queues.doorbells.len() {
+            let size = self.queues.doorbells[i].size;
+            let mapping = self.queues.doorbells[i].mapping;
+
+            if size == 0 {
+                continue;
+            }
+
+            if self.queues.doorbells[i].use_gpuvm {
+                self.hsakmt_fmm_unmap_from_gpu(mapping);
+                self.hsakmt_fmm_release(mapping);
+            } else {
+                munmap(mapping, size as usize);
+            }
+        }
+
+        self.queues.doorbells.clear();
+    }
+
+    unsafe fn map_doorbell_apu(&mut self, NodeId: u32, doorbell_mmap_offset: u64) -> HsakmtStatus {
+        let ptr = mmap(
+            std::ptr::null_mut(),
+            self.queues.doorbells[NodeId as usize].size as usize,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED,
+            self.hsakmt_kfd_fd,
+            doorbell_mmap_offset as off_t,
+        );
+
+        if ptr == MAP_FAILED {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        self.queues.doorbells[NodeId as usize].mapping = ptr;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    unsafe fn map_doorbell_dgpu(
+        &mut self,
+        NodeId: u32,
+        gpu_id: u32,
+        doorbell_mmap_offset: u64,
+    ) -> HsakmtStatus {
+        let size = self.queues.doorbells[NodeId as usize].size as u64;
+
+        let ptr = self.hsakmt_fmm_allocate_doorbell(gpu_id, size, doorbell_mmap_offset);
+        if ptr.is_null() {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        /* map for GPU access */
+        if self.hsakmt_fmm_map_to_gpu(ptr, size, std::ptr::null_mut()) != HSAKMT_STATUS_SUCCESS {
+            self.hsakmt_fmm_release(ptr);
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        self.queues.doorbells[NodeId as usize].mapping = ptr;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsakmt_map_doorbell(
+        &mut self,
+        NodeId: u32,
+        gpu_id: u32,
+        doorbell_mmap_offset: u64,
+    ) -> HsakmtStatus {
+        if NodeId as usize >= self.queues.doorbells.len() {
+            return HSAKMT_STATUS_INVALID_NODE_UNIT;
+        }
+
+        if self.queues.doorbells[NodeId as usize].size > 0 {
+            return HSAKMT_STATUS_SUCCESS;
+        }
+
+        self.get_doorbell_map_info(NodeId);
+
+        let mut status;
+
+        if self.queues.doorbells[NodeId as usize].use_gpuvm {
+            status = self.map_doorbell_dgpu(NodeId, gpu_id, doorbell_mmap_offset);
+            if status != HSAKMT_STATUS_SUCCESS {
+                /* Fall back to the old method if KFD doesn't
+                 * support doorbells in GPUVM
+                 */
+                self.queues.doorbells[NodeId as usize].use_gpuvm = false;
+                status = self.map_doorbell_apu(NodeId, doorbell_mmap_offset);
+            }
+        } else {
+            status = self.map_doorbell_apu(NodeId, doorbell_mmap_offset);
+        }
+
+        if status != HSAKMT_STATUS_SUCCESS {
+            self.queues.doorbells[NodeId as usize].size = 0;
+        }
+
+        status
+    }
+
+    /* Doorbell address of the queue KFD knows as QueueId on node NodeId.
+     * Where the doorbell is in the doorbell page of the node depends on
+     * the offset KFD returned when the queue was created, see
+     * hsakmt_queue_doorbell_offsets.
+     */
+    pub unsafe fn hsakmt_get_doorbell_address(
+        &mut self,
+        NodeId: u32,
+        QueueId: u32,
+        DoorbellAddress: &mut *mut std::os::raw::c_void,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let mut gpu_id: u32 = 0;
+
+        let ret = self.hsakmt_validate_nodeid(NodeId, &mut gpu_id);
+        if ret != HSAKMT_STATUS_SUCCESS {
+            return ret;
+        }
+
+        if gpu_id == 0 {
+            return HSAKMT_STATUS_INVALID_NODE_UNIT;
+        }
+
+        let q = self
+            .queues
+            .queues
+            .iter()
+            .map(|id| *id as *const queue)
+            .find(|q| (**q).node_id == NodeId && (**q).queue_id == QueueId);
+
+        let Some(q) = q else {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        };
+
+        *DoorbellAddress = (*q).doorbell as *mut std::os::raw::c_void;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* Memory the GPU of NodeId can execute from: VRAM if DeviceLocal,
+     * system memory otherwise. Mapped to the GPUs on return.
+     */
+    pub(crate) unsafe fn allocate_exec_aligned_memory(
+        &mut self,
+        size: u32,
+        NodeId: u32,
+        nonPaged: bool,
+        DeviceLocal: bool,
+        Uncached: bool,
+    ) -> *mut std::os::raw::c_void {
+        let page_size = self.PAGE_SIZE() as u64;
+        let mut mem: *mut std::os::raw::c_void = std::ptr::null_mut();
+        let mut cpu_id = 0;
+
+        let mut flags = HsaMemFlags {
+            st: HsaMemFlagUnion {
+                ui32: std::mem::zeroed(),
+            },
+        };
+        flags.st.ui32.HostAccess = !DeviceLocal as u32;
+        flags.st.ui32.ExecuteAccess = 1;
+        flags.st.ui32.NonPaged = nonPaged as u32;
+        flags.st.ui32.PageSize = HSA_PAGE_SIZE_4KB;
+        flags.st.ui32.CoarseGrain = DeviceLocal as u32;
+        flags.st.ui32.Uncached = Uncached as u32;
+
+        /* Get the closest cpu_id to GPU NodeId for system memory allocation
+         * nonPaged=1 system memory allocation uses GTT path
+         */
+        if !DeviceLocal && !nonPaged {
+            match self.fmm_numa_closest_node(NodeId) {
+                Some(cpu) => cpu_id = cpu,
+                None => flags.st.ui32.NoNUMABind = 1,
+            }
+        } else if !DeviceLocal && self.hsakmt_is_dgpu {
+            /* GTT, the kernel places it whatever the NUMA policy */
+            flags.st.ui32.NoNUMABind = 1;
+        }
+
+        let size = ALIGN_UP(size as u64, page_size);
+
+        let ret = self.hsaKmtAllocMemoryAlign(
+            if DeviceLocal { NodeId } else { cpu_id },
+            size,
+            page_size,
+            flags,
+            &mut mem,
+        );
+        if ret != HSAKMT_STATUS_SUCCESS {
+            return std::ptr::null_mut();
+        }
+
+        if self.hsaKmtMapMemoryToGPU(mem, size, std::ptr::null_mut()) != HSAKMT_STATUS_SUCCESS {
+            self.hsaKmtFreeMemory(mem, size);
+            return std::ptr::null_mut();
+        }
+
+        mem
+    }
+
+    pub(crate) unsafe fn free_exec_aligned_memory(
+        &mut self,
+        addr: *mut std::os::raw::c_void,
+        size: u32,
+    ) {
+        let size = ALIGN_UP(size as u64, self.PAGE_SIZE() as u64);
+
+        if self.hsaKmtUnmapMemoryToGPU(addr) == HSAKMT_STATUS_SUCCESS {
+            self.hsaKmtFreeMemory(addr, size);
+        }
+    }
+
+    unsafe fn free_queue(&mut self, q: *mut queue) {
+        let q = Box::from_raw(q);
+
+        if !q.eop_buffer.is_null() {
+            self.free_exec_aligned_memory(q.eop_buffer, q.eop_buffer_size);
+        }
+
+        if !q.pointers.is_null() {
+            self.free_exec_aligned_memory(q.pointers, self.PAGE_SIZE() as u32);
+        }
+
+        if let Some(layout) = q.cwsr {
+            self.free_exec_aligned_memory(q.ctx_save_restore, layout.total_mem_alloc_size);
+        }
+    }
+
+    /* Create a user mode queue with the ring buffer QueueAddress of
+     * QueueSizeInBytes bytes. PM4 queues get their read and write
+     * pointers from the thunk, AQL queues take them from QueueResource.
+     * Compute queues get an EOP buffer and a CWSR area sized for the
+     * node, whose header names Event, if not null, and ErrorReason of
+     * QueueResource for reporting queue errors. On success QueueResource
+     * holds the queue id and the doorbell.
+     */
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn hsaKmtCreateQueue(
+        &mut self,
+        NodeId: u32,
+        Type: HSA_QUEUE_TYPE,
+        QueuePercentage: u32,
+        Priority: HSA_QUEUE_PRIORITY,
+        QueueAddress: *mut std::os::raw::c_void,
+        QueueSizeInBytes: u64,
+        Event: *mut HsaEvent,
+        QueueResource: &mut HsaQueueResource,
+    ) -> HsakmtStatus {
+        self.hsaKmtCreateQueueExt(
+            NodeId,
+            Type,
+            QueuePercentage,
+            Priority,
+            0,
+            QueueAddress,
+            QueueSizeInBytes,
+            Event,
+            QueueResource,
+        )
+    }
+
+    /* hsaKmtCreateQueue that puts HSA_QUEUE_SDMA_BY_ENG_ID queues on
+     * SDMA engine SdmaEngineId, see hsakmt_sdma_engine_type
+     */
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn hsaKmtCreateQueueExt(
+        &mut self,
+        NodeId: u32,
+        Type: HSA_QUEUE_TYPE,
+        QueuePercentage: u32,
+        Priority: HSA_QUEUE_PRIORITY,
+        SdmaEngineId: u32,
+        QueueAddress: *mut std::os::raw::c_void,
+        QueueSizeInBytes: u64,
+        Event: *mut HsaEvent,
+        QueueResource: &mut HsaQueueResource,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let mut gpu_id: u32 = 0;
+
+        let result = self.hsakmt_validate_nodeid(NodeId, &mut gpu_id);
+        if result != HSAKMT_STATUS_SUCCESS {
+            return result;
+        }
+
+        if gpu_id == 0 {
+            return HSAKMT_STATUS_INVALID_NODE_UNIT;
+        }
+
+        let Some(queue_type) = kfd_queue_type(Type) else {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        };
+
+        if QueuePercentage > KFD_MAX_QUEUE_PERCENTAGE
+            || QueueSizeInBytes > u32::MAX as u64
+            || (QueueSizeInBytes > 0 && !QueueSizeInBytes.is_power_of_two())
+        {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        if Type == HSA_QUEUE_SDMA_BY_ENG_ID
+            && hsakmt_sdma_engine_type(self.hsakmt_topology_get_node_props(NodeId), SdmaEngineId)
+                .is_none()
+        {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let gfxv = self.hsakmt_get_gfxv_by_node_id(NodeId);
+
+        let q = Box::into_raw(Box::new(queue {
+            queue_id: 0,
+            node_id: NodeId,
+            gpu_id,
+            gfxv,
+            Type,
+            doorbell: 0,
+            pointers: std::ptr::null_mut(),
+            eop_buffer: std::ptr::null_mut(),
+            eop_buffer_size: hsakmt_eop_buffer_size(gfxv, Type),
+            ctx_save_restore: std::ptr::null_mut(),
+            cwsr: None,
+            ring_base: QueueAddress as u64,
+            ring_size: QueueSizeInBytes as u32,
+            queue_percentage: QueuePercentage,
+            priority: Priority,
+            cu_mask: vec![],
+        }));
+
+        let mut args = kfd_ioctl_create_queue_args {
+            gpu_id,
+            queue_type,
+            ..Default::default()
+        };
+
+        if queue_type == KFD_IOC_QUEUE_TYPE_SDMA_BY_ENG_ID {
+            args.sdma_engine_id = SdmaEngineId;
+        }
+
+        if Type != HSA_QUEUE_COMPUTE_AQL {
+            (*q).pointers = self.allocate_exec_aligned_memory(
+                self.PAGE_SIZE() as u32,
+                NodeId,
+                true,
+                false,
+                true,
+            );
+            if (*q).pointers.is_null() {
+                self.free_queue(q);
+                return HSAKMT_STATUS_NO_MEMORY;
+            }
+
+            QueueResource.QueueRptrValue = (*q).pointers as u64;
+            QueueResource.QueueWptrValue = (*q).pointers as u64 + std::mem::size_of::<u64>() as u64;
+        }
+
+        if (queue_type == KFD_IOC_QUEUE_TYPE_COMPUTE
+            || queue_type == KFD_IOC_QUEUE_TYPE_COMPUTE_AQL)
+            && (*q).eop_buffer_size > 0
+        {
+            (*q).eop_buffer =
+                self.allocate_exec_aligned_memory((*q).eop_buffer_size, NodeId, true, true, false);
+            if (*q).eop_buffer.is_null() {
+                self.free_queue(q);
+                return HSAKMT_STATUS_NO_MEMORY;
+            }
+
+            args.eop_buffer_address = (*q).eop_buffer as u64;
+            args.eop_buffer_size = (*q).eop_buffer_size as u64;
+        }
+
+        if queue_type == KFD_IOC_QUEUE_TYPE_COMPUTE || queue_type == KFD_IOC_QUEUE_TYPE_COMPUTE_AQL
+        {
+            if let Some(layout) = self.hsakmt_get_cwsr_layout(NodeId) {
+                (*q).ctx_save_restore = self.allocate_exec_aligned_memory(
+                    layout.total_mem_alloc_size,
+                    NodeId,
+                    false,
+                    false,
+                    false,
+                );
+                if (*q).ctx_save_restore.is_null() {
+                    self.free_queue(q);
+                    return HSAKMT_STATUS_NO_MEMORY;
+                }
+                (*q).cwsr = Some(layout);
+
+                let ErrorEventId = if Event.is_null() { 0 } else { (*Event).EventId };
+
+                self.hsakmt_init_cwsr_headers(
+                    (*q).ctx_save_restore,
+                    &layout,
+                    QueueResource.ErrorReason,
+                    ErrorEventId,
+                );
+
+                args.ctx_save_restore_address = (*q).ctx_save_restore as u64;
+                args.ctx_save_restore_size = layout.ctx_save_restore_size;
+                args.ctl_stack_size = layout.ctl_stack_size;
+            }
+
+            /* KFD starts compute queues on every CU */
+            if let Some(geometry) = self.hsakmt_get_cu_mask_geometry(NodeId) {
+                (*q).cu_mask = geometry.all();
+            }
+        }
+
+        args.read_pointer_address = QueueResource.QueueRptrValue;
+        args.write_pointer_address = QueueResource.QueueWptrValue;
+        args.ring_base_address = QueueAddress as u64;
+        args.ring_size = QueueSizeInBytes as u32;
+        args.queue_percentage = QueuePercentage;
+        args.queue_priority = kfd_queue_priority(Priority);
+
+        if kfd_create_queue(self.hsakmt_kfd_fd, &mut args) != HSAKMT_STATUS_SUCCESS {
+            self.free_queue(q);
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        (*q).queue_id = args.queue_id;
+
+        let (doorbell_mmap_offset, doorbell_offset) =
+            hsakmt_queue_doorbell_offsets(gfxv, args.doorbell_offset, args.queue_id);
+
+        let err = self.hsakmt_map_doorbell(NodeId, gpu_id, doorbell_mmap_offset);
+        if err != HSAKMT_STATUS_SUCCESS {
+            kfd_destroy_queue(self.hsakmt_kfd_fd, args.queue_id);
+            self.free_queue(q);
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        let doorbell = &self.queues.doorbells[NodeId as usize];
+
+        (*q).doorbell = doorbell.mapping as u64 + doorbell_offset as u64;
+
+        QueueResource.QueueId = q as HSA_QUEUEID;
+        QueueResource.QueueDoorBell = (*q).doorbell;
+
+        self.queues.queues.push(QueueResource.QueueId);
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsaKmtDestroyQueue(&mut self, QueueId: HSA_QUEUEID) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let q = QueueId as *mut queue;
+
+        if q.is_null() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let ret = kfd_destroy_queue(self.hsakmt_kfd_fd, (*q).queue_id);
+        if ret != HSAKMT_STATUS_SUCCESS {
+            return ret;
+        }
+
+        self.queues.queues.retain(|id| *id != QueueId);
+
+        self.free_queue(q);
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* Move the queue to a new ring buffer, or change its share of the
+     * hardware queue time and its priority. QueueAddress may be null
+     * with a QueueSizeInBytes of 0 to keep the queue inactive.
+     */
+    pub unsafe fn hsaKmtUpdateQueue(
+        &mut self,
+        QueueId: HSA_QUEUEID,
+        QueuePercentage: u32,
+        Priority: HSA_QUEUE_PRIORITY,
+        QueueAddress: *mut std::os::raw::c_void,
+        QueueSize: u64,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let q = QueueId as *mut queue;
+
+        if q.is_null() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        if QueuePercentage > KFD_MAX_QUEUE_PERCENTAGE
+            || QueueSize > u32::MAX as u64
+            || (QueueSize > 0 && !QueueSize.is_power_of_two())
+            || (QueueAddress.is_null() != (QueueSize == 0))
+        {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let mut args = kfd_ioctl_update_queue_args {
+            ring_base_address: QueueAddress as u64,
+            queue_id: (*q).queue_id,
+            ring_size: QueueSize as u32,
+            queue_percentage: QueuePercentage,
+            queue_priority: kfd_queue_priority(Priority),
+        };
+
+        if kfd_update_queue(self.hsakmt_kfd_fd, &mut args) != HSAKMT_STATUS_SUCCESS {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        (*q).ring_base = args.ring_base_address;
+        (*q).ring_size = args.ring_size;
+        (*q).queue_percentage = QueuePercentage;
+        (*q).priority = Priority;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* Restrict a compute queue to the CUs set in the first CUMaskCount
+     * bits of QueueCUMask, numbered as described in cu_mask_geometry_t.
+     * CUMaskCount is a multiple of 32 and the mask may only name CUs
+     * the node has.
+     */
+    pub unsafe fn hsaKmtSetQueueCUMask(
+        &mut self,
+        QueueId: HSA_QUEUEID,
+        CUMaskCount: u32,
+        QueueCUMask: &[u32],
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let q = QueueId as *mut queue;
+
+        if q.is_null() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let Some(geometry) = self.hsakmt_get_cu_mask_geometry((*q).node_id) else {
+            return HSAKMT_STATUS_INVALID_NODE_UNIT;
+        };
+
+        let result = geometry.validate(CUMaskCount, QueueCUMask);
+        if result != HSAKMT_STATUS_SUCCESS {
+            return result;
+        }
+
+        let mask = &QueueCUMask[..(CUMaskCount / 32) as usize];
+
+        let result = kfd_set_cu_mask(self.hsakmt_kfd_fd, (*q).queue_id, CUMaskCount, mask);
+        if result != HSAKMT_STATUS_SUCCESS {
+            return result;
+        }
+
+        (*q).cu_mask = mask.to_vec();
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* Change the priority of q, keeping its ring and queue percentage */
+    pub unsafe fn hsakmt_queue_set_priority(
+        &mut self,
+        q: &Queue,
+        Priority: HSA_QUEUE_PRIORITY,
+    ) -> HsakmtStatus {
+        let percentage = (*(q.queue_id() as *const queue)).queue_percentage;
+
+        self.hsaKmtUpdateQueue(q.queue_id(), percentage, Priority, q.RingBase, q.RingSize)
+    }
+
+    /* Create a queue with a ring buffer of RingSizeInBytes bytes,
+     * a power of 2, in system memory. Release it with
+     * hsakmt_queue_destroy.
+     */
+    pub unsafe fn hsakmt_queue_create(
+        &mut self,
+        NodeId: u32,
+        Type: HSA_QUEUE_TYPE,
+        RingSizeInBytes: u64,
+        Priority: HSA_QUEUE_PRIORITY,
+        NewQueue: &mut Option<Queue>,
+    ) -> HsakmtStatus {
+        self.hsakmt_queue_create_ext(NodeId, Type, 0, RingSizeInBytes, Priority, NewQueue)
+    }
+
+    /* hsakmt_queue_create for any queue type KFD schedules, with the
+     * SDMA engine of HSA_QUEUE_SDMA_BY_ENG_ID queues
+     */
+    pub unsafe fn hsakmt_queue_create_ext(
+        &mut self,
+        NodeId: u32,
+        Type: HSA_QUEUE_TYPE,
+        SdmaEngineId: u32,
+        RingSizeInBytes: u64,
+        Priority: HSA_QUEUE_PRIORITY,
+        NewQueue: &mut Option<Queue>,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        if kfd_queue_type(Type).is_none() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        if RingSizeInBytes == 0
+            || RingSizeInBytes > u32::MAX as u64
+            || !RingSizeInBytes.is_power_of_two()
+        {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let ring =
+            self.allocate_exec_aligned_memory(RingSizeInBytes as u32, NodeId, true, false, false);
+        if ring.is_null() {
+            return HSAKMT_STATUS_NO_MEMORY;
+        }
+
+        let mut resource = HsaQueueResource::default();
+        let mut pointers: *mut std::os::raw::c_void = std::ptr::null_mut();
+
+        if Type == HSA_QUEUE_COMPUTE_AQL {
+            pointers = self.allocate_exec_aligned_memory(
+                self.PAGE_SIZE() as u32,
+                NodeId,
+                true,
+                false,
+                true,
+            );
+            if pointers.is_null() {
+                self.free_exec_aligned_memory(ring, RingSizeInBytes as u32);
+                return HSAKMT_STATUS_NO_MEMORY;
+            }
+
+            resource.QueueRptrValue = pointers as u64;
+            resource.QueueWptrValue = pointers as u64 + std::mem::size_of::<u64>() as u64;
+        }
+
+        let ret = self.hsaKmtCreateQueueExt(
+            NodeId,
+            Type,
+            KFD_MAX_QUEUE_PERCENTAGE,
+            Priority,
+            SdmaEngineId,
+            ring,
+            RingSizeInBytes,
+            std::ptr::null_mut(),
+            &mut resource,
+        );
+
+        if ret != HSAKMT_STATUS_SUCCESS {
+            if !pointers.is_null() {
+                self.free_exec_aligned_memory(pointers, self.PAGE_SIZE() as u32);
+            }
+            self.free_exec_aligned_memory(ring, RingSizeInBytes as u32);
+            return ret;
+        }
+
+        *NewQueue = Some(Queue {
+            NodeId,
+            Type,
+            RingBase: ring,
+            RingSize: RingSizeInBytes,
+            pointers,
+            Resource: resource,
+        });
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub unsafe fn hsakmt_queue_destroy(&mut self, q: Queue) -> HsakmtStatus {
+        let ret = self.hsaKmtDestroyQueue(q.queue_id());
+        if ret != HSAKMT_STATUS_SUCCESS {
+            return ret;
+        }
+
+        if !q.pointers.is_null() {
+            self.free_exec_aligned_memory(q.pointers, self.PAGE_SIZE() as u32);
+        }
+        self.free_exec_aligned_memory(q.RingBase, q.RingSize as u32);
+
+        HSAKMT_STATUS_SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kfd_ioctl::{
+        AMDKFD_IOC_ALLOC_MEMORY_OF_GPU, AMDKFD_IOC_FREE_MEMORY_OF_GPU,
+        AMDKFD_IOC_MAP_MEMORY_TO_GPU, AMDKFD_IOC_UNMAP_MEMORY_FROM_GPU, KFD_MMAP_GPU_ID,
+        KFD_MMAP_TYPE_DOORBELL,
+    };
+    use crate::libhsakmt::fake_kfd::{
+        fake_kfd_globals, fake_kfd_globals_close, FakeKfd, FAKE_GPU_ID,
+    };
+    use crate::libhsakmt::hsakmt_set_ioctl_hook;
+
+    #[test]
+    fn test_doorbells_page_size() {
+        /* gfx8: 4 byte doorbells */
+        assert_eq!(DOORBELL_SIZE(GFX_VERSION_TONGA as u32), 4);
+        assert_eq!(DOORBELLS_PAGE_SIZE(DOORBELL_SIZE(0x80003)), 4096);
+
+        /* gfx9 and later: 8 byte doorbells */
+        assert_eq!(DOORBELL_SIZE(0x90000), 8);
+        assert_eq!(DOORBELLS_PAGE_SIZE(DOORBELL_SIZE(0x90a00)), 8192);
+    }
+
+    #[test]
+    fn test_eop_buffer_size() {
+        assert_eq!(
+            hsakmt_eop_buffer_size(GFX_VERSION_TONGA as u32, HSA_QUEUE_COMPUTE),
+            0x8000
+        );
+        assert_eq!(hsakmt_eop_buffer_size(0x70000, HSA_QUEUE_COMPUTE), 0);
+        assert_eq!(hsakmt_eop_buffer_size(0x90a00, HSA_QUEUE_COMPUTE_AQL), 4096);
+
+        /* AQL queues of gfx940 and later use the MES EOP buffer */
+        assert_eq!(hsakmt_eop_buffer_size(0x90402, HSA_QUEUE_COMPUTE), 4096);
+        assert_eq!(hsakmt_eop_buffer_size(0x90402, HSA_QUEUE_COMPUTE_AQL), 0);
+    }
+
+    #[test]
+    fn test_queue_doorbell_offsets() {
+        /* gfx8: doorbell page per process, indexed by queue id */
+        assert_eq!(
+            hsakmt_queue_doorbell_offsets(0x80003, 0x1_0000_0000, 3),
+            (0x1_0000_0000, 12)
+        );
+
+        /* gfx9: KFD includes the offset in the page */
+        assert_eq!(
+            hsakmt_queue_doorbell_offsets(0x90a00, 0x1_0000_0000 + 0x18, 3),
+            (0x1_0000_0000, 0x18)
+        );
+    }
+
+    #[test]
+    fn test_kfd_queue_priority() {
+        use HSA_QUEUE_PRIORITY::*;
+
+        assert_eq!(kfd_queue_priority(HSA_QUEUE_PRIORITY_MINIMUM), 0);
+        assert_eq!(kfd_queue_priority(HSA_QUEUE_PRIORITY_NORMAL), 7);
+        assert_eq!(kfd_queue_priority(HSA_QUEUE_PRIORITY_MAXIMUM), 15);
+        assert_eq!(kfd_queue_type(HSA_QUEUE_TYPE::HSA_QUEUE_DMA_AQL), None);
+
+        for Type in [HSA_QUEUE_COMPUTE, HSA_QUEUE_SDMA, HSA_QUEUE_COMPUTE_AQL] {
+            assert_eq!(hsa_queue_type(kfd_queue_type(Type).unwrap()), Some(Type));
+        }
+        assert_eq!(hsa_queue_type(42), None);
+    }
+
+    #[test]
+    fn test_create_destroy_queue_ioctls() {
+        let kfd = FakeKfd::install(FakeKfd::default());
+
+        let mut args = kfd_ioctl_create_queue_args {
+            gpu_id: 0x1234,
+            queue_type: kfd_queue_type(HSA_QUEUE_COMPUTE_AQL).unwrap(),
+            ring_size: 0x1000,
+            queue_priority: kfd_queue_priority(HSA_QUEUE_PRIORITY::HSA_QUEUE_PRIORITY_NORMAL),
+            ..Default::default()
+        };
+
+        unsafe {
+            assert_eq!(kfd_create_queue(-1, &mut args), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(args.queue_id, 0);
+
+            args.queue_id = 0;
+            assert_eq!(kfd_create_queue(-1, &mut args), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(args.queue_id, 1);
+
+            let (mmap_offset, offset) =
+                hsakmt_queue_doorbell_offsets(0x90a00, args.doorbell_offset, args.queue_id);
+            assert_eq!(
+                mmap_offset,
+                KFD_MMAP_TYPE_DOORBELL | KFD_MMAP_GPU_ID(0x1234)
+            );
+            assert_eq!(offset, 8);
+
+            args.ring_size = 0x1800;
+            assert_eq!(kfd_create_queue(-1, &mut args), HSAKMT_STATUS_ERROR);
+
+            let mask = [0x5555_5555, 0x0555_5555, 0xffff_ffff];
+            assert_eq!(kfd_set_cu_mask(-1, 1, 64, &mask), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(kfd_set_cu_mask(-1, 7, 64, &mask), HSAKMT_STATUS_ERROR);
+
+            assert_eq!(kfd_destroy_queue(-1, 0), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(kfd_destroy_queue(-1, 0), HSAKMT_STATUS_ERROR);
+        }
+
+        hsakmt_set_ioctl_hook(None);
+
+        let kfd = kfd.borrow();
+        assert_eq!(kfd.queues, vec![1]);
+        assert_eq!(
+            kfd.requests,
+            vec![
+                AMDKFD_IOC_CREATE_QUEUE,
+                AMDKFD_IOC_CREATE_QUEUE,
+                AMDKFD_IOC_CREATE_QUEUE,
+                AMDKFD_IOC_SET_CU_MASK,
+                AMDKFD_IOC_SET_CU_MASK,
+                AMDKFD_IOC_DESTROY_QUEUE,
+                AMDKFD_IOC_DESTROY_QUEUE,
+            ]
+        );
+        assert_eq!(kfd.cu_mask, vec![0x5555_5555, 0x0555_5555]);
+    }
+
+    /* The fake gfx90a dGPU with its doorbell page in doorbells */
+    unsafe fn fake_kfd_queue_globals(doorbells: &mut [u64]) -> Box<HsakmtGlobals> {
+        let mut g = fake_kfd_globals(9, 0xa, 0);
+
+        g.queues.doorbells[1] = process_doorbells {
+            use_gpuvm: false,
+            size: std::mem::size_of_val(doorbells) as u32,
+            mapping: doorbells.as_mut_ptr() as *mut std::os::raw::c_void,
+        };
+
+        g
+    }
+
+    #[test]
+    fn test_create_destroy_queue() {
+        use crate::kfd_ioctl::{KFD_IOC_ALLOC_MEM_FLAGS_GTT, KFD_IOC_ALLOC_MEM_FLAGS_VRAM};
+
+        let mut doorbells = vec![0u64; 1024];
+        let mut ring = vec![0u32; 1024];
+
+        unsafe {
+            let mut g = fake_kfd_queue_globals(&mut doorbells);
+
+            let kfd = FakeKfd::install(FakeKfd {
+                next_queue_id: 3,
+                ..Default::default()
+            });
+
+            let mut resource = HsaQueueResource::default();
+            let ret = g.hsaKmtCreateQueue(
+                1,
+                HSA_QUEUE_COMPUTE,
+                100,
+                HSA_QUEUE_PRIORITY::HSA_QUEUE_PRIORITY_HIGH,
+                ring.as_mut_ptr() as *mut std::os::raw::c_void,
+                4096,
+                std::ptr::null_mut(),
+                &mut resource,
+            );
+            assert_eq!(ret, HSAKMT_STATUS_SUCCESS);
+
+            {
+                let kfd = kfd.borrow();
+                assert_eq!(kfd.created.len(), 1);
+                let args = &kfd.created[0];
+
+                assert_eq!(args.gpu_id, FAKE_GPU_ID);
+                assert_eq!(args.queue_type, KFD_IOC_QUEUE_TYPE_COMPUTE);
+                assert_eq!(args.ring_base_address, ring.as_ptr() as u64);
+                assert_eq!(args.ring_size, 4096);
+                assert_eq!(args.queue_percentage, 100);
+                assert_eq!(args.queue_priority, 11);
+
+                /* PM4 read and write pointers share a GTT page */
+                let pointers = &kfd.bos[0];
+                assert_ne!(pointers.flags & KFD_IOC_ALLOC_MEM_FLAGS_GTT as u32, 0);
+                assert_eq!(pointers.size, 4096);
+                assert_eq!(pointers.mapped, vec![FAKE_GPU_ID]);
+                assert_eq!(args.read_pointer_address, pointers.va);
+                assert_eq!(args.write_pointer_address, pointers.va + 8);
+                assert_eq!(resource.QueueRptrValue, args.read_pointer_address);
+                assert_eq!(resource.QueueWptrValue, args.write_pointer_address);
+
+                /* gfx90a EOP buffer in VRAM */
+                let eop = &kfd.bos[1];
+                assert_ne!(eop.flags & KFD_IOC_ALLOC_MEM_FLAGS_VRAM as u32, 0);
+                assert_eq!(eop.mapped, vec![FAKE_GPU_ID]);
+                assert_eq!(args.eop_buffer_address, eop.va);
+                assert_eq!(args.eop_buffer_size, 4096);
+
+                /* No CU information, no CWSR area */
+                assert_eq!(args.ctx_save_restore_address, 0);
+            }
+
+            /* gfx9 doorbells are 8 bytes, KFD picked the first slot */
+            assert_eq!(resource.QueueDoorBell, doorbells.as_ptr() as u64);
+
+            let mut doorbell = std::ptr::null_mut();
+            assert_eq!(
+                g.hsakmt_get_doorbell_address(1, 3, &mut doorbell),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!(doorbell as u64, resource.QueueDoorBell);
+            assert_eq!(
+                g.hsakmt_get_doorbell_address(1, 4, &mut doorbell),
+                HSAKMT_STATUS_INVALID_PARAMETER
+            );
+            assert_eq!(g.queues.queues, vec![resource.QueueId]);
+
+            let ret = g.hsaKmtDestroyQueue(resource.QueueId);
+            assert_eq!(ret, HSAKMT_STATUS_SUCCESS);
+            assert!(g.queues.queues.is_empty());
+
+            hsakmt_set_ioctl_hook(None);
+
+            /* The queue is gone and its buffers are unmapped and freed */
+            let kfd = kfd.borrow();
+            assert!(kfd.queues.is_empty());
+            assert!(kfd.bos.is_empty());
+            assert_eq!(
+                kfd.requests,
+                vec![
+                    AMDKFD_IOC_ALLOC_MEMORY_OF_GPU,
+                    AMDKFD_IOC_MAP_MEMORY_TO_GPU,
+                    AMDKFD_IOC_ALLOC_MEMORY_OF_GPU,
+                    AMDKFD_IOC_MAP_MEMORY_TO_GPU,
+                    AMDKFD_IOC_CREATE_QUEUE,
+                    AMDKFD_IOC_DESTROY_QUEUE,
+                    AMDKFD_IOC_UNMAP_MEMORY_FROM_GPU,
+                    AMDKFD_IOC_FREE_MEMORY_OF_GPU,
+                    AMDKFD_IOC_UNMAP_MEMORY_FROM_GPU,
+                    AMDKFD_IOC_FREE_MEMORY_OF_GPU,
+                ]
+            );
+
+            fake_kfd_globals_close(&g);
+        }
+    }
+
+    #[test]
+    fn test_update_queue_and_cu_mask() {
+        use crate::kfd_ioctl::{AMDKFD_IOC_SET_CU_MASK, AMDKFD_IOC_UPDATE_QUEUE};
+        use HSA_QUEUE_PRIORITY::{HSA_QUEUE_PRIORITY_LOW, HSA_QUEUE_PRIORITY_NORMAL};
+
+        let mut doorbells = vec![0u64; 1024];
+        let mut ring = vec![0u32; 1024];
+        let mut new_ring = vec![0u32; 2048];
+
+        unsafe {
+            let mut g = fake_kfd_queue_globals(&mut doorbells);
+            let kfd = FakeKfd::install(FakeKfd::default());
+
+            let mut resource = HsaQueueResource::default();
+            let ret = g.hsaKmtCreateQueue(
+                1,
+                HSA_QUEUE_COMPUTE,
+                100,
+                HSA_QUEUE_PRIORITY_NORMAL,
+                ring.as_mut_ptr() as *mut std::os::raw::c_void,
+                4096,
+                std::ptr::null_mut(),
+                &mut resource,
+            );
+            assert_eq!(ret, HSAKMT_STATUS_SUCCESS);
+
+            let id = resource.QueueId;
+            let q = id as *mut queue;
+            let new_base = new_ring.as_mut_ptr() as *mut std::os::raw::c_void;
+
+            /* Refused before KFD sees them */
+            for (percentage, base, size) in [
+                (101, new_base, 8192),
+                (50, new_base, 6000),
+                (50, std::ptr::null_mut(), 8192),
+                (50, new_base, 0),
+            ] {
+                assert_eq!(
+                    g.hsaKmtUpdateQueue(id, percentage, HSA_QUEUE_PRIORITY_LOW, base, size),
+                    HSAKMT_STATUS_INVALID_PARAMETER
+                );
+            }
+            assert!(kfd.borrow().updated.is_empty());
+
+            assert_eq!(
+                g.hsaKmtUpdateQueue(id, 50, HSA_QUEUE_PRIORITY_LOW, new_base, 8192),
+                HSAKMT_STATUS_SUCCESS
+            );
+            {
+                let kfd = kfd.borrow();
+                let args = &kfd.updated[0];
+                assert_eq!(args.queue_id, (*q).queue_id);
+                assert_eq!(args.ring_base_address, new_base as u64);
+                assert_eq!(args.ring_size, 8192);
+                assert_eq!(args.queue_percentage, 50);
+                assert_eq!(
+                    args.queue_priority,
+                    kfd_queue_priority(HSA_QUEUE_PRIORITY_LOW)
+                );
+            }
+            assert_eq!((*q).ring_base, new_base as u64);
+            assert_eq!((*q).ring_size, 8192);
+            assert_eq!((*q).queue_percentage, 50);
+            assert_eq!((*q).priority, HSA_QUEUE_PRIORITY_LOW);
+
+            /* A refused update leaves the queue as it was */
+            kfd.borrow_mut().failing.push(AMDKFD_IOC_UPDATE_QUEUE);
+            assert_eq!(
+                g.hsaKmtUpdateQueue(id, 0, HSA_QUEUE_PRIORITY_NORMAL, std::ptr::null_mut(), 0),
+                HSAKMT_STATUS_ERROR
+            );
+            assert_eq!((*q).ring_base, new_base as u64);
+            assert_eq!((*q).ring_size, 8192);
+            assert_eq!((*q).queue_percentage, 50);
+            assert_eq!((*q).priority, HSA_QUEUE_PRIORITY_LOW);
+
+            /* An inactive queue has no ring */
+            kfd.borrow_mut().failing.clear();
+            assert_eq!(
+                g.hsaKmtUpdateQueue(id, 0, HSA_QUEUE_PRIORITY_NORMAL, std::ptr::null_mut(), 0),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!((*q).ring_base, 0);
+            assert_eq!((*q).ring_size, 0);
+
+            /* No CU information, no CU masks */
+            assert_eq!(
+                g.hsaKmtSetQueueCUMask(id, 32, &[1]),
+                HSAKMT_STATUS_INVALID_NODE_UNIT
+            );
+
+            /* 4 engines of 10 CUs */
+            let props = &mut g.topology.g_props[1].node;
+            props.NumSIMDPerCU = 4;
+            props.NumFComputeCores = 40 * 4;
+            props.NumXcc = 1;
+            props.NumShaderBanks = 4;
+            props.NumArrays = 1;
+            props.NumCUPerArray = 10;
+
+            for (count, mask) in [
+                (0, &[0xf, 0][..]),
+                (48, &[0xf, 0]),
+                (64, &[0xf]),
+                (64, &[0, 0]),
+                (64, &[0xf, 1 << 8]),
+            ] {
+                assert_eq!(
+                    g.hsaKmtSetQueueCUMask(id, count, mask),
+                    HSAKMT_STATUS_INVALID_PARAMETER
+                );
+            }
+            assert!(kfd.borrow().cu_mask.is_empty());
+
+            /* Words past CUMaskCount are ignored */
+            assert_eq!(
+                g.hsaKmtSetQueueCUMask(id, 64, &[0xf, 0xff, 0xdead]),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!(kfd.borrow().cu_mask, vec![0xf, 0xff]);
+            assert_eq!((*q).cu_mask, vec![0xf, 0xff]);
+
+            kfd.borrow_mut().failing.push(AMDKFD_IOC_SET_CU_MASK);
+            assert_eq!(g.hsaKmtSetQueueCUMask(id, 32, &[0xf0]), HSAKMT_STATUS_ERROR);
+            assert_eq!((*q).cu_mask, vec![0xf, 0xff]);
+
+            kfd.borrow_mut().failing.clear();
+            assert_eq!(g.hsaKmtDestroyQueue(id), HSAKMT_STATUS_SUCCESS);
+
+            hsakmt_set_ioctl_hook(None);
+            fake_kfd_globals_close(&g);
+        }
+    }
+
+    #[test]
+    fn test_create_queue_error_event() {
+        use crate::hsakmttypes::{HsaEventDescriptor, HsaSyncVar, HsaUserContextSaveAreaHeader};
+
+        let mut doorbells = vec![0u64; 1024];
+        let mut ring = vec![0u32; 1024];
+        let mut error_reason = 0i64;
+
+        unsafe {
+            let mut g = fake_kfd_queue_globals(&mut doorbells);
+            let kfd = FakeKfd::install(FakeKfd::default());
+
+            /* One CU, enough for a CWSR area */
+            let props = &mut g.topology.g_props[1].node;
+            props.NumSIMDPerCU = 4;
+            props.NumFComputeCores = 4;
+            props.NumShaderBanks = 1;
+            props.NumArrays = 1;
+            props.NumCUPerArray = 1;
+            props.LDSSizeInKB = 64;
+            props.NumXcc = 1;
+            props.NumCpQueues = 24;
+
+            /* An id other than the 0 of no event */
+            kfd.borrow_mut().events.push(None);
+
+            let mut event = std::ptr::null_mut();
+            let signal = HsaEventDescriptor::Signal {
+                SyncVar: HsaSyncVar {
+                    UserData: 0,
+                    SyncVarSize: 0,
+                },
+            };
+            assert_eq!(
+                g.hsaKmtCreateEvent(&signal, false, false, &mut event),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!((*event).EventId, 1);
+
+            let mut resource = HsaQueueResource {
+                ErrorReason: &mut error_reason,
+                ..Default::default()
+            };
+            let ret = g.hsaKmtCreateQueue(
+                1,
+                HSA_QUEUE_COMPUTE_AQL,
+                100,
+                HSA_QUEUE_PRIORITY::HSA_QUEUE_PRIORITY_NORMAL,
+                ring.as_mut_ptr() as *mut std::os::raw::c_void,
+                4096,
+                event,
+                &mut resource,
+            );
+            assert_eq!(ret, HSAKMT_STATUS_SUCCESS);
+
+            /* KFD learns about errors of the queue through the header */
+            let q = resource.QueueId as *const queue;
+            assert!((*q).cwsr.is_some());
+            assert_eq!(
+                kfd.borrow().created[0].ctx_save_restore_address,
+                (*q).ctx_save_restore as u64
+            );
+
+            let header = &*((*q).ctx_save_restore as *const HsaUserContextSaveAreaHeader);
+            assert_eq!(header.ErrorEventId, (*event).EventId);
+            assert_eq!(header.ErrorReason, &mut error_reason as *mut i64);
+
+            assert_eq!(
+                g.hsaKmtDestroyQueue(resource.QueueId),
+                HSAKMT_STATUS_SUCCESS
+            );
+            assert_eq!(g.hsaKmtDestroyEvent(event), HSAKMT_STATUS_SUCCESS);
+
+            hsakmt_set_ioctl_hook(None);
+            fake_kfd_globals_close(&g);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::HsaNodeProperties;
+use crate::hsakmttypes::HsakmtStatus;
+use crate::hsakmttypes::HsakmtStatus::{HSAKMT_STATUS_INVALID_PARAMETER, HSAKMT_STATUS_SUCCESS};
+
+/* Compute units of a node as KFD numbers them in queue CU masks.
+ *
+ * KFD spreads the bits of a mask over the hardware: consecutive bits go
+ * to consecutive XCCs first, then shader engines, then shader arrays,
+ * then the CUs of an array. With N XCCs, 4 engines and 1 array per
+ * engine bit 0 is CU 0 of engine 0 of XCC 0, bit 1 the same CU of XCC
+ * 1, bit N CU 0 of engine 1 of XCC 0 and bit 4 * N CU 1 of engine 0 of
+ * XCC 0. Harvested CUs are skipped, so only the first num_cu bits name
+ * a CU.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct cu_mask_geometry_t {
+    pub num_xcc: u32,
+    /* Shader engines per XCC */
+    pub num_se: u32,
+    /* Shader arrays per engine */
+    pub num_sh: u32,
+    /* Most CUs of one shader array */
+    pub num_cu_per_sh: u32,
+    /* Active CUs of the node */
+    pub num_cu: u32,
+}
+
+pub fn hsakmt_cu_mask_geometry(props: &HsaNodeProperties) -> Option<cu_mask_geometry_t> {
+    if props.NumFComputeCores == 0 || props.NumSIMDPerCU == 0 {
+        return None;
+    }
+
+    let num_xcc = props.NumXcc.max(1);
+
+    /* NumShaderBanks counts the engines of all XCCs */
+    if props.NumShaderBanks < num_xcc || props.NumArrays == 0 || props.NumCUPerArray == 0 {
+        return None;
+    }
+
+    Some(cu_mask_geometry_t {
+        num_xcc,
+        num_se: props.NumShaderBanks / num_xcc,
+        num_sh: props.NumArrays,
+        num_cu_per_sh: props.NumCUPerArray,
+        num_cu: props.NumFComputeCores / props.NumSIMDPerCU,
+    })
+}
+
+impl cu_mask_geometry_t {
+    /* Bits of a mask naming every CU, a multiple of 32 */
+    pub fn cu_mask_count(&self) -> u32 {
+        self.num_cu.div_ceil(32) * 32
+    }
+
+    /* Mask bit of CU cu of array sh of engine se of XCC xcc, assuming
+     * no CU before it is harvested
+     */
+    pub fn bit(&self, xcc: u32, se: u32, sh: u32, cu: u32) -> u32 {
+        ((cu * self.num_sh + sh) * self.num_se + se) * self.num_xcc + xcc
+    }
+
+    fn mask_of_bits(&self, bits: impl Iterator<Item = u32>) -> Vec<u32> {
+        let mut mask = vec![0; (self.cu_mask_count() / 32) as usize];
+
+        for bit in bits.filter(|bit| *bit < self.num_cu) {
+            mask[(bit / 32) as usize] |= 1 << (bit % 32);
+        }
+
+        mask
+    }
+
+    /* Every CU of the node */
+    pub fn all(&self) -> Vec<u32> {
+        self.mask_of_bits(0..self.num_cu)
+    }
+
+    /* count CUs, as evenly spread over XCCs, engines and arrays as
+     * possible
+     */
+    pub fn spread(&self, count: u32) -> Vec<u32> {
+        self.mask_of_bits(0..count.min(self.num_cu))
+    }
+
+    /* Every CU of the engines set in se_mask, on all XCCs */
+    pub fn engines(&self, se_mask: u32) -> Vec<u32> {
+        let bits = (0..self.num_cu_per_sh).flat_map(move |cu| {
+            (0..self.num_sh).flat_map(move |sh| {
+                (0..self.num_se)
+                    .filter(move |se| se_mask & (1 << se) != 0)
+                    .flat_map(move |se| (0..self.num_xcc).map(move |xcc| self.bit(xcc, se, sh, cu)))
+            })
+        });
+
+        self.mask_of_bits(bits)
+    }
+
+    /* Every CU of XCC xcc */
+    pub fn xcc(&self, xcc: u32) -> Vec<u32> {
+        self.mask_of_bits((xcc..self.num_cu).step_by(self.num_xcc as usize))
+    }
+
+    /* The first CUMaskCount bits of mask name at least one CU and
+     * only CUs that exist
+     */
+    pub fn validate(&self, CUMaskCount: u32, mask: &[u32]) -> HsakmtStatus {
+        if CUMaskCount == 0
+            || !CUMaskCount.is_multiple_of(32)
+            || mask.len() * 32 < CUMaskCount as usize
+        {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let mask = &mask[..(CUMaskCount / 32) as usize];
+        let mut any = false;
+
+        for (i, word) in mask.iter().enumerate() {
+            for bit in 0..32 {
+                if word & (1 << bit) == 0 {
+                    continue;
+                }
+
+                if i as u32 * 32 + bit >= self.num_cu {
+                    return HSAKMT_STATUS_INVALID_PARAMETER;
+                }
+
+                any = true;
+            }
+        }
+
+        if !any {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+}
+
+impl HsakmtGlobals {
+    pub fn hsakmt_get_cu_mask_geometry(&mut self, NodeId: u32) -> Option<cu_mask_geometry_t> {
+        if NodeId as usize >= self.topology.g_props.len() {
+            return None;
+        }
+
+        hsakmt_cu_mask_geometry(self.hsakmt_topology_get_node_props(NodeId))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hsakmttypes::node_props_t;
+
+    fn geometry(num_xcc: u32, num_se: u32, num_sh: u32, num_cu_per_sh: u32) -> cu_mask_geometry_t {
+        let mut props = node_props_t::new().node;
+
+        props.NumSIMDPerCU = 4;
+        props.NumFComputeCores = num_xcc * num_se * num_sh * num_cu_per_sh * 4;
+        props.NumXcc = num_xcc;
+        props.NumShaderBanks = num_xcc * num_se;
+        props.NumArrays = num_sh;
+        props.NumCUPerArray = num_cu_per_sh;
+
+        hsakmt_cu_mask_geometry(&props).unwrap()
+    }
+
+    #[test]
+    fn test_cu_mask_spread() {
+        /* 4 engines, 1 array, 15 CUs per array */
+        let g = geometry(1, 4, 1, 15);
+
+        assert_eq!(g.num_cu, 60);
+        assert_eq!(g.cu_mask_count(), 64);
+        assert_eq!(g.all(), vec![0xffff_ffff, 0x0fff_ffff]);
+
+        /* 6 CUs: 2 on engines 0 and 1, 1 on engines 2 and 3 */
+        assert_eq!(g.spread(6), vec![0b11_1111, 0]);
+        assert_eq!(g.bit(0, 1, 0, 1), 5);
+
+        /* Engine 2 only: bits 2, 6, 10, ... */
+        let mask = g.engines(0b100);
+        assert_eq!(mask, vec![0x4444_4444, 0x0444_4444]);
+        assert_eq!(mask.iter().map(|w| w.count_ones()).sum::<u32>(), 15);
+    }
+
+    #[test]
+    fn test_cu_mask_xcc() {
+        /* gfx942: 8 XCCs of 4 engines, 1 array of 10 CUs */
+        let g = geometry(8, 4, 1, 10);
+
+        assert_eq!(g.num_cu, 320);
+        assert_eq!(g.bit(3, 0, 0, 0), 3);
+        assert_eq!(g.bit(0, 1, 0, 0), 8);
+
+        let mask = g.xcc(1);
+        assert_eq!(mask[0], 0x0202_0202);
+        assert_eq!(mask.iter().map(|w| w.count_ones()).sum::<u32>(), 40);
+
+        /* The same engine on every XCC */
+        let mask = g.engines(0b1);
+        assert_eq!(mask[0], 0x0000_00ff);
+        assert_eq!(mask.iter().map(|w| w.count_ones()).sum::<u32>(), 80);
+    }
+
+    #[test]
+    fn test_cu_mask_validate() {
+        let g = geometry(1, 4, 1, 15);
+
+        assert_eq!(g.validate(64, &g.all()), HSAKMT_STATUS_SUCCESS);
+        assert_eq!(g.validate(32, &[0x1]), HSAKMT_STATUS_SUCCESS);
+
+        /* CU 60 does not exist */
+        assert_eq!(
+            g.validate(64, &[0, 0x1000_0000]),
+            HSAKMT_STATUS_INVALID_PARAMETER
+        );
+        /* No CU at all */
+        assert_eq!(g.validate(64, &[0, 0]), HSAKMT_STATUS_INVALID_PARAMETER);
+        /* Count not a multiple of 32 or larger than the mask */
+        assert_eq!(g.validate(48, &[1, 0]), HSAKMT_STATUS_INVALID_PARAMETER);
+        assert_eq!(g.validate(96, &[1, 0]), HSAKMT_STATUS_INVALID_PARAMETER);
+    }
+}
@@ -0,0 +1,359 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::{
+    HsaNodeProperties, HsaUserContextSaveAreaHeader, ALIGN_UP, GFX_VERSION_CARRIZO,
+    GFX_VERSION_NAVI10, HSA_GET_GFX_VERSION_FULL, MIN, SGPR_SIZE_PER_CU,
+};
+use crate::queues::hsakmt_get_vgpr_size_per_cu;
+
+pub const HWREG_SIZE_PER_CU: u32 = 0x1000;
+pub const DEBUGGER_BYTES_ALIGN: u32 = 64;
+pub const DEBUGGER_BYTES_PER_WAVE: u32 = 32;
+
+pub fn CNTL_STACK_BYTES_PER_WAVE(gfxv: u32) -> u32 {
+    if gfxv >= GFX_VERSION_NAVI10 as u32 {
+        12
+    } else {
+        8
+    }
+}
+
+/* Compute wave save/restore (CWSR) area of one queue. The area of each
+ * XCC is the control stack, starting with HsaUserContextSaveAreaHeader,
+ * followed by the wave state. One debugger area, shared by all XCCs,
+ * comes after the areas of all XCCs.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct queue_cwsr_layout_t {
+    pub num_xcc: u32,
+    /* Per XCC */
+    pub cu_num: u32,
+    pub wave_num: u32,
+    pub ctl_stack_size: u32,
+    pub vgpr_size: u32,
+    pub sgpr_size: u32,
+    pub lds_size: u32,
+    pub hwreg_size: u32,
+    pub wave_state_size: u32,
+    pub ctx_save_restore_size: u32,
+    pub debug_memory_size: u32,
+    /* All XCCs of one queue */
+    pub total_mem_alloc_size: u32,
+    /* All NumCpQueues compute queues of the node */
+    pub node_mem_size: u64,
+}
+
+impl queue_cwsr_layout_t {
+    /* Offset of the area of XCC xcc */
+    pub fn ctx_save_restore_offset(&self, xcc: u32) -> u32 {
+        xcc * self.ctx_save_restore_size
+    }
+
+    /* Offset of the shared debugger area from the start of the area of
+     * XCC xcc, as KFD expects it in the header
+     */
+    pub fn debug_offset(&self, xcc: u32) -> u32 {
+        (self.num_xcc - xcc) * self.ctx_save_restore_size
+    }
+
+    /* Size of the shared debugger area */
+    pub fn debug_size(&self) -> u32 {
+        self.debug_memory_size * self.num_xcc
+    }
+}
+
+/* CWSR layout for the compute queues of a node, None if the node has no
+ * compute units or a GPU without CWSR
+ */
+pub fn hsakmt_cwsr_layout(
+    props: &HsaNodeProperties,
+    page_size: u32,
+) -> Option<queue_cwsr_layout_t> {
+    let gfxv = unsafe { HSA_GET_GFX_VERSION_FULL(&props.EngineId.ui32) };
+
+    if gfxv < GFX_VERSION_CARRIZO as u32 {
+        return None;
+    }
+
+    if props.NumFComputeCores == 0 || props.NumSIMDPerCU == 0 {
+        return None;
+    }
+
+    let num_xcc = props.NumXcc.max(1);
+    let cu_num = props.NumFComputeCores / props.NumSIMDPerCU / num_xcc;
+
+    let wave_num = if gfxv < GFX_VERSION_NAVI10 as u32 {
+        if props.NumArrays == 0 {
+            return None;
+        }
+
+        MIN(
+            cu_num as u64 * 40,
+            (props.NumShaderBanks / props.NumArrays * 512) as u64,
+        ) as u32
+    } else {
+        cu_num * 32
+    };
+
+    let mut ctl_stack_size = ALIGN_UP(
+        (std::mem::size_of::<HsaUserContextSaveAreaHeader>() as u32
+            + wave_num * CNTL_STACK_BYTES_PER_WAVE(gfxv)
+            + 8) as u64,
+        page_size as u64,
+    ) as u32;
+
+    if (gfxv & 0x3f0000) == 0xA0000 {
+        /* HW design limits control stack size to 0x7000.
+         * This is insufficient for theoretical PM4 cases
+         * but sufficient for AQL, limited by SPI events.
+         */
+        ctl_stack_size = ctl_stack_size.min(0x7000);
+    }
+
+    let vgpr_size = cu_num * hsakmt_get_vgpr_size_per_cu(gfxv);
+    let sgpr_size = cu_num * SGPR_SIZE_PER_CU as u32;
+    let lds_size = cu_num * props.LDSSizeInKB * 1024;
+    let hwreg_size = cu_num * HWREG_SIZE_PER_CU;
+
+    let wave_state_size = ALIGN_UP(
+        (vgpr_size + sgpr_size + lds_size + hwreg_size) as u64,
+        page_size as u64,
+    ) as u32;
+
+    let ctx_save_restore_size = ctl_stack_size + wave_state_size;
+
+    let debug_memory_size = ALIGN_UP(
+        (wave_num * DEBUGGER_BYTES_PER_WAVE) as u64,
+        DEBUGGER_BYTES_ALIGN as u64,
+    ) as u32;
+
+    let total_mem_alloc_size = (ctx_save_restore_size + debug_memory_size) * num_xcc;
+
+    Some(queue_cwsr_layout_t {
+        num_xcc,
+        cu_num,
+        wave_num,
+        ctl_stack_size,
+        vgpr_size,
+        sgpr_size,
+        lds_size,
+        hwreg_size,
+        wave_state_size,
+        ctx_save_restore_size,
+        debug_memory_size,
+        total_mem_alloc_size,
+        node_mem_size: total_mem_alloc_size as u64 * props.NumCpQueues as u64,
+    })
+}
+
+impl HsakmtGlobals {
+    pub fn hsakmt_get_cwsr_layout(&mut self, NodeId: u32) -> Option<queue_cwsr_layout_t> {
+        let page_size = self.PAGE_SIZE() as u32;
+
+        if NodeId as usize >= self.topology.g_props.len() {
+            return None;
+        }
+
+        hsakmt_cwsr_layout(self.hsakmt_topology_get_node_props(NodeId), page_size)
+    }
+
+    /* Write the header of the area of every XCC. KFD fills in the
+     * control stack and wave state fields when the queue is created.
+     */
+    pub unsafe fn hsakmt_init_cwsr_headers(
+        &self,
+        ctx_save_restore: *mut std::os::raw::c_void,
+        layout: &queue_cwsr_layout_t,
+        ErrorReason: *mut i64,
+        ErrorEventId: u32,
+    ) {
+        for i in 0..layout.num_xcc {
+            let header = (ctx_save_restore as *mut u8)
+                .add(layout.ctx_save_restore_offset(i) as usize)
+                as *mut HsaUserContextSaveAreaHeader;
+
+            (*header).ErrorEventId = ErrorEventId;
+            (*header).ErrorReason = ErrorReason;
+            (*header).DebugOffset = layout.debug_offset(i);
+            (*header).DebugSize = layout.debug_size();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hsakmttypes::*;
+
+    /* ctl_stack_size, ctx_save_restore_size, debug_memory_size */
+    type CwsrSizes = Option<(u32, u32, u32)>;
+
+    fn gpu_props(gfxv: usize, cu_num: u32, num_xcc: u32) -> HsaNodeProperties {
+        let mut props = node_props_t::new().node;
+
+        props.EngineId.ui32.Major = (gfxv >> 16) as u32;
+        props.EngineId.ui32.Minor = ((gfxv >> 8) & 0xff) as u32;
+        props.EngineId.ui32.Stepping = (gfxv & 0xff) as u32;
+        props.NumSIMDPerCU = if gfxv >= GFX_VERSION_NAVI10 { 2 } else { 4 };
+        props.NumFComputeCores = cu_num * props.NumSIMDPerCU * num_xcc;
+        props.NumShaderBanks = 4;
+        props.NumArrays = 1;
+        props.LDSSizeInKB = 64;
+        props.NumXcc = num_xcc;
+        props.NumCpQueues = 24;
+
+        props
+    }
+
+    #[test]
+    fn test_cwsr_layout_gfx_versions() {
+        /* 60 CUs on one XCC */
+        let expected: [(usize, CwsrSizes); 31] = [
+            (GFX_VERSION_KAVERI, None),
+            (GFX_VERSION_HAWAII, None),
+            (GFX_VERSION_CARRIZO, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_TONGA, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_FIJI, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_POLARIS10, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_POLARIS11, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_POLARIS12, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_VEGAM, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_VEGA10, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_RAVEN, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_VEGA12, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_VEGA20, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_ARCTURUS, Some((0x5000, 0x22f1000, 0x10000))),
+            (GFX_VERSION_ALDEBARAN, Some((0x5000, 0x22f1000, 0x10000))),
+            (
+                GFX_VERSION_AQUA_VANJARAM,
+                Some((0x5000, 0x22f1000, 0x10000)),
+            ),
+            (GFX_VERSION_RENOIR, Some((0x5000, 0x13f1000, 0x10000))),
+            (GFX_VERSION_NAVI10, Some((0x6000, 0x13f2000, 0xf000))),
+            (GFX_VERSION_NAVI12, Some((0x6000, 0x13f2000, 0xf000))),
+            (GFX_VERSION_NAVI14, Some((0x6000, 0x13f2000, 0xf000))),
+            (
+                GFX_VERSION_CYAN_SKILLFISH,
+                Some((0x6000, 0x13f2000, 0xf000)),
+            ),
+            (
+                GFX_VERSION_SIENNA_CICHLID,
+                Some((0x6000, 0x13f2000, 0xf000)),
+            ),
+            (GFX_VERSION_NAVY_FLOUNDER, Some((0x6000, 0x13f2000, 0xf000))),
+            (
+                GFX_VERSION_DIMGREY_CAVEFISH,
+                Some((0x6000, 0x13f2000, 0xf000)),
+            ),
+            (GFX_VERSION_VANGOGH, Some((0x6000, 0x13f2000, 0xf000))),
+            (GFX_VERSION_BEIGE_GOBY, Some((0x6000, 0x13f2000, 0xf000))),
+            (GFX_VERSION_YELLOW_CARP, Some((0x6000, 0x13f2000, 0xf000))),
+            (GFX_VERSION_PLUM_BONITO, Some((0x6000, 0x1b72000, 0xf000))),
+            (GFX_VERSION_WHEAT_NAS, Some((0x6000, 0x1b72000, 0xf000))),
+            (GFX_VERSION_GFX1200, Some((0x6000, 0x1b72000, 0xf000))),
+            (GFX_VERSION_GFX1201, Some((0x6000, 0x1b72000, 0xf000))),
+        ];
+
+        for (gfxv, sizes) in expected {
+            let layout = hsakmt_cwsr_layout(&gpu_props(gfxv, 60, 1), 4096);
+
+            assert_eq!(
+                layout.map(|l| (
+                    l.ctl_stack_size,
+                    l.ctx_save_restore_size,
+                    l.debug_memory_size
+                )),
+                sizes,
+                "gfxv {:#x}",
+                gfxv
+            );
+
+            if let Some(layout) = layout {
+                assert_eq!(layout.cu_num, 60);
+                assert_eq!(layout.lds_size, 60 * 0x10000);
+                assert_eq!(layout.hwreg_size, 60 * HWREG_SIZE_PER_CU);
+                assert_eq!(
+                    layout.total_mem_alloc_size,
+                    layout.ctx_save_restore_size + layout.debug_memory_size
+                );
+                assert_eq!(
+                    layout.node_mem_size,
+                    layout.total_mem_alloc_size as u64 * 24
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_cwsr_layout_xcc() {
+        /* gfx942 with 6 XCCs of 38 CUs each */
+        let props = gpu_props(0x90402, 38, 6);
+        let layout = hsakmt_cwsr_layout(&props, 4096).unwrap();
+
+        assert_eq!(layout.num_xcc, 6);
+        assert_eq!(layout.cu_num, 38);
+        assert_eq!(layout.wave_num, 38 * 40);
+        assert_eq!(
+            layout.total_mem_alloc_size,
+            (layout.ctx_save_restore_size + layout.debug_memory_size) * 6
+        );
+
+        /* Every XCC points at the debugger area after the last area */
+        for xcc in 0..6 {
+            assert_eq!(
+                layout.ctx_save_restore_offset(xcc),
+                xcc * layout.ctx_save_restore_size
+            );
+            assert_eq!(
+                layout.ctx_save_restore_offset(xcc) + layout.debug_offset(xcc),
+                6 * layout.ctx_save_restore_size
+            );
+        }
+        assert_eq!(layout.debug_size(), 6 * layout.debug_memory_size);
+        assert_eq!(
+            6 * layout.ctx_save_restore_size + layout.debug_size(),
+            layout.total_mem_alloc_size
+        );
+
+        /* gfx10 control stack is limited to 0x7000 bytes */
+        let layout = hsakmt_cwsr_layout(&gpu_props(GFX_VERSION_NAVI10, 80, 1), 4096).unwrap();
+        assert_eq!(layout.ctl_stack_size, 0x7000);
+
+        /* No compute units */
+        let mut props = gpu_props(GFX_VERSION_VEGA10, 60, 1);
+        props.NumFComputeCores = 0;
+        assert_eq!(hsakmt_cwsr_layout(&props, 4096), None);
+    }
+
+    #[test]
+    fn test_cwsr_headers() {
+        let layout = hsakmt_cwsr_layout(&gpu_props(0x90402, 2, 3), 4096).unwrap();
+        let mut area = vec![0u64; layout.total_mem_alloc_size as usize / 8];
+        let mut error_reason = 0i64;
+
+        unsafe {
+            HsakmtGlobals::without_kfd().hsakmt_init_cwsr_headers(
+                area.as_mut_ptr() as *mut std::os::raw::c_void,
+                &layout,
+                &mut error_reason,
+                7,
+            );
+        }
+
+        for xcc in 0..3 {
+            let header = unsafe {
+                &*((area.as_ptr() as *const u8).add(layout.ctx_save_restore_offset(xcc) as usize)
+                    as *const HsaUserContextSaveAreaHeader)
+            };
+
+            assert_eq!(header.ErrorEventId, 7);
+            assert_eq!(header.ErrorReason, &mut error_reason as *mut i64);
+            assert_eq!(
+                layout.ctx_save_restore_offset(xcc) + header.DebugOffset,
+                3 * layout.ctx_save_restore_size
+            );
+            assert_eq!(header.DebugSize, 3 * layout.debug_memory_size);
+        }
+    }
+}
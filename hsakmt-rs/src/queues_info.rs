@@ -0,0 +1,451 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use crate::globals::HsakmtGlobals;
+use crate::hsakmttypes::HsakmtStatus;
+use crate::hsakmttypes::HsakmtStatus::{
+    HSAKMT_STATUS_ERROR, HSAKMT_STATUS_INVALID_PARAMETER, HSAKMT_STATUS_SUCCESS,
+};
+use crate::hsakmttypes::HSA_QUEUE_TYPE::HSA_QUEUE_COMPUTE_AQL;
+use crate::hsakmttypes::{HsaQueueInfo, HsaUserContextSaveAreaHeader, HSA_QUEUEID, HSA_QUEUE_TYPE};
+use crate::kfd_ioctl::{
+    kfd_ioctl_dbg_trap_args, kfd_ioctl_dbg_trap_queue_snapshot_args,
+    kfd_ioctl_get_queue_wave_state_args, kfd_queue_snapshot_entry, AMDKFD_IOC_DBG_TRAP,
+    AMDKFD_IOC_GET_QUEUE_WAVE_STATE, KFD_IOC_DBG_TRAP_GET_QUEUE_SNAPSHOT,
+};
+use crate::libhsakmt::hsakmt_ioctl;
+use crate::queues::{hsa_queue_type, queue, IS_SOC15};
+use std::fmt;
+
+pub unsafe fn kfd_get_queue_wave_state(
+    fd: i32,
+    args: &mut kfd_ioctl_get_queue_wave_state_args,
+) -> HsakmtStatus {
+    let err = hsakmt_ioctl(
+        fd,
+        AMDKFD_IOC_GET_QUEUE_WAVE_STATE,
+        args as *mut _ as *mut std::os::raw::c_void,
+    );
+
+    if err != 0 {
+        return HSAKMT_STATUS_ERROR;
+    }
+
+    HSAKMT_STATUS_SUCCESS
+}
+
+/* Snapshot of every queue of process pid, clearing ExceptionsToClear
+ * on them. KFD reports how many queues there are when the buffer is too
+ * small, so this asks again until they all fit. KFD also reports the
+ * size of the entries it wrote, a kernel with other entries is refused.
+ */
+pub unsafe fn kfd_queue_snapshot(
+    fd: i32,
+    pid: u32,
+    ExceptionsToClear: u64,
+    entries: &mut Vec<kfd_queue_snapshot_entry>,
+) -> HsakmtStatus {
+    entries.clear();
+
+    loop {
+        let mut args = kfd_ioctl_dbg_trap_args {
+            pid,
+            op: KFD_IOC_DBG_TRAP_GET_QUEUE_SNAPSHOT,
+            queue_snapshot: kfd_ioctl_dbg_trap_queue_snapshot_args {
+                exception_mask: ExceptionsToClear,
+                snapshot_buf_ptr: entries.as_mut_ptr() as u64,
+                num_queues: entries.len() as u32,
+                entry_size: std::mem::size_of::<kfd_queue_snapshot_entry>() as u32,
+            },
+        };
+
+        let err = hsakmt_ioctl(
+            fd,
+            AMDKFD_IOC_DBG_TRAP,
+            &mut args as *mut _ as *mut std::os::raw::c_void,
+        );
+
+        if err != 0
+            || args.queue_snapshot.entry_size as usize
+                != std::mem::size_of::<kfd_queue_snapshot_entry>()
+        {
+            entries.clear();
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        let num_queues = args.queue_snapshot.num_queues as usize;
+
+        if num_queues <= entries.len() {
+            entries.truncate(num_queues);
+            return HSAKMT_STATUS_SUCCESS;
+        }
+
+        entries.resize(num_queues, Default::default());
+    }
+}
+
+/* Where the control stack and the wave state of the last context save of
+ * q are, from KFD's GET_QUEUE_WAVE_STATE answer for the first XCC
+ */
+pub fn hsakmt_queue_info(
+    q: &queue,
+    wave_state: &kfd_ioctl_get_queue_wave_state_args,
+) -> Option<HsaQueueInfo> {
+    let layout = q.cwsr?;
+    let ctl_stack_end = wave_state.ctl_stack_address + layout.ctl_stack_size as u64;
+
+    Some(HsaQueueInfo {
+        QueueDetailError: 0,
+        QueueTypeExtended: 0,
+        /* CUMaskCount of the last hsaKmtSetQueueCUMask */
+        NumCUAssigned: q.cu_mask.len() as u32 * 32,
+        CUMaskInfo: q.cu_mask.as_ptr() as *mut u32,
+        UserContextSaveArea: ctl_stack_end as *mut u32,
+        SaveAreaSizeInBytes: wave_state.save_area_used_size as u64,
+        ControlStackTop: (ctl_stack_end - wave_state.ctl_stack_used_size as u64) as *mut u32,
+        ControlStackUsedInBytes: wave_state.ctl_stack_used_size as u64,
+        SaveAreaHeader: q.ctx_save_restore as *mut HsaUserContextSaveAreaHeader,
+        Reserved2: 0,
+    })
+}
+
+/* One queue of the process in a queue snapshot. QueueId, DoorBell and
+ * the pointer values are only known for queues created through this
+ * library and are 0 or None otherwise.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HsaQueueSnapshotEntry {
+    pub QueueId: HSA_QUEUEID,
+    pub NodeId: Option<u32>,
+    pub GpuId: u32,
+    pub KfdQueueId: u32,
+    pub QueueType: Option<HSA_QUEUE_TYPE>,
+    pub RingBaseAddress: u64,
+    pub RingSize: u32,
+    pub ReadPointerAddress: u64,
+    pub WritePointerAddress: u64,
+    pub ReadPointer: Option<u64>,
+    pub WritePointer: Option<u64>,
+    pub DoorBell: u64,
+    pub CtxSaveRestoreAddress: u64,
+    pub CtxSaveRestoreSize: u32,
+    pub ExceptionStatus: u64,
+}
+
+impl HsaQueueSnapshotEntry {
+    /* Entry of a KFD snapshot entry, joined with the queue of this
+     * library it belongs to if any
+     */
+    pub unsafe fn new(entry: &kfd_queue_snapshot_entry, q: Option<&queue>) -> Self {
+        let mut snapshot = Self {
+            QueueId: 0,
+            NodeId: None,
+            GpuId: entry.gpu_id,
+            KfdQueueId: entry.queue_id,
+            QueueType: hsa_queue_type(entry.queue_type),
+            RingBaseAddress: entry.ring_base_address,
+            RingSize: entry.ring_size,
+            ReadPointerAddress: entry.read_pointer_address,
+            WritePointerAddress: entry.write_pointer_address,
+            ReadPointer: None,
+            WritePointer: None,
+            DoorBell: 0,
+            CtxSaveRestoreAddress: entry.ctx_save_restore_address,
+            CtxSaveRestoreSize: entry.ctx_save_restore_area_size,
+            ExceptionStatus: entry.exception_status,
+        };
+
+        let Some(q) = q else {
+            return snapshot;
+        };
+
+        snapshot.QueueId = q as *const queue as HSA_QUEUEID;
+        snapshot.NodeId = Some(q.node_id);
+        snapshot.DoorBell = q.doorbell;
+
+        /* Pointers of AQL and SOC15 queues are 64 bit, older PM4 and SDMA
+         * ones 32 bit
+         */
+        let read = |address: u64| {
+            if address == 0 {
+                None
+            } else if q.Type == HSA_QUEUE_COMPUTE_AQL || IS_SOC15(q.gfxv) {
+                Some(std::ptr::read_volatile(address as *const u64))
+            } else {
+                Some(std::ptr::read_volatile(address as *const u32) as u64)
+            }
+        };
+
+        snapshot.ReadPointer = read(entry.read_pointer_address);
+        snapshot.WritePointer = read(entry.write_pointer_address);
+
+        snapshot
+    }
+}
+
+impl fmt::Display for HsaQueueSnapshotEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pointer = |value: Option<u64>| match value {
+            Some(value) => format!("{:#x}", value),
+            None => "?".to_string(),
+        };
+
+        write!(f, "queue {} gpu {:#x}", self.KfdQueueId, self.GpuId)?;
+        if let Some(NodeId) = self.NodeId {
+            write!(f, " node {}", NodeId)?;
+        }
+        match self.QueueType {
+            Some(Type) => writeln!(f, " {:?}", Type)?,
+            None => writeln!(f, " unknown type")?,
+        }
+
+        writeln!(
+            f,
+            "  ring {:#x} size {:#x} doorbell {:#x}",
+            self.RingBaseAddress, self.RingSize, self.DoorBell
+        )?;
+        writeln!(
+            f,
+            "  rptr {} at {:#x} wptr {} at {:#x}",
+            pointer(self.ReadPointer),
+            self.ReadPointerAddress,
+            pointer(self.WritePointer),
+            self.WritePointerAddress
+        )?;
+        write!(
+            f,
+            "  ctx save {:#x} size {:#x} exceptions {:#x}",
+            self.CtxSaveRestoreAddress, self.CtxSaveRestoreSize, self.ExceptionStatus
+        )
+    }
+}
+
+impl HsakmtGlobals {
+    /* Where the last context save of a compute queue left its control
+     * stack and wave state, and the CUs the queue runs on
+     */
+    pub unsafe fn hsaKmtGetQueueInfo(
+        &self,
+        QueueId: HSA_QUEUEID,
+        QueueInfo: &mut HsaQueueInfo,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let q = QueueId as *const queue;
+
+        if q.is_null() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        if (*q).ctx_save_restore.is_null() {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        let mut args = kfd_ioctl_get_queue_wave_state_args {
+            ctl_stack_address: (*q).ctx_save_restore as u64,
+            queue_id: (*q).queue_id,
+            ..Default::default()
+        };
+
+        if kfd_get_queue_wave_state(self.hsakmt_kfd_fd, &mut args) != HSAKMT_STATUS_SUCCESS {
+            return HSAKMT_STATUS_ERROR;
+        }
+
+        match hsakmt_queue_info(&*q, &args) {
+            Some(info) => *QueueInfo = info,
+            None => return HSAKMT_STATUS_ERROR,
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    /* Every queue KFD has for this process, with doorbells and pointer
+     * values filled in for the queues created here. KFD only answers
+     * once the debugger is enabled for the process.
+     */
+    pub unsafe fn hsaKmtGetQueueSnapshot(
+        &self,
+        ExceptionsToClear: u64,
+        Snapshot: &mut Vec<HsaQueueSnapshotEntry>,
+    ) -> HsakmtStatus {
+        self.check_kfd_open_and_panic();
+
+        let mut entries = vec![];
+
+        let ret = kfd_queue_snapshot(
+            self.hsakmt_kfd_fd,
+            libc::getpid() as u32,
+            ExceptionsToClear,
+            &mut entries,
+        );
+        if ret != HSAKMT_STATUS_SUCCESS {
+            return ret;
+        }
+
+        *Snapshot = entries
+            .iter()
+            .map(|entry| {
+                let q = self
+                    .queues
+                    .queues
+                    .iter()
+                    .map(|id| &*(*id as *const queue))
+                    .find(|q| q.gpu_id == entry.gpu_id && q.queue_id == entry.queue_id);
+
+                HsaQueueSnapshotEntry::new(entry, q)
+            })
+            .collect();
+
+        HSAKMT_STATUS_SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hsakmttypes::HSA_QUEUE_PRIORITY;
+    use crate::hsakmttypes::HSA_QUEUE_TYPE::{HSA_QUEUE_COMPUTE, HSA_QUEUE_SDMA};
+    use crate::kfd_ioctl::{KFD_IOC_QUEUE_TYPE_COMPUTE_AQL, KFD_IOC_QUEUE_TYPE_SDMA};
+    use crate::libhsakmt::fake_kfd::FakeKfd;
+    use crate::libhsakmt::hsakmt_set_ioctl_hook;
+    use crate::queues_cwsr::queue_cwsr_layout_t;
+
+    fn test_queue(Type: HSA_QUEUE_TYPE, gfxv: u32) -> queue {
+        queue {
+            queue_id: 3,
+            node_id: 1,
+            gpu_id: 0x1234,
+            gfxv,
+            Type,
+            doorbell: 0x7000_1018,
+            pointers: std::ptr::null_mut(),
+            eop_buffer: std::ptr::null_mut(),
+            eop_buffer_size: 0,
+            ctx_save_restore: std::ptr::null_mut(),
+            cwsr: None,
+            ring_base: 0,
+            ring_size: 0,
+            queue_percentage: 100,
+            priority: HSA_QUEUE_PRIORITY::HSA_QUEUE_PRIORITY_NORMAL,
+            cu_mask: vec![0xffff_ffff, 0xf],
+        }
+    }
+
+    #[test]
+    fn test_queue_info() {
+        let mut q = test_queue(HSA_QUEUE_COMPUTE, 0x90a00);
+        let wave_state = kfd_ioctl_get_queue_wave_state_args {
+            ctl_stack_address: 0x10000,
+            ctl_stack_used_size: 0x100,
+            save_area_used_size: 0x2000,
+            queue_id: 3,
+            pad: 0,
+        };
+
+        /* No CWSR area */
+        assert!(hsakmt_queue_info(&q, &wave_state).is_none());
+
+        q.ctx_save_restore = 0x10000 as *mut std::os::raw::c_void;
+        q.cwsr = Some(queue_cwsr_layout_t {
+            ctl_stack_size: 0x1000,
+            ..Default::default()
+        });
+
+        let info = hsakmt_queue_info(&q, &wave_state).unwrap();
+        assert_eq!(info.ControlStackTop as u64, 0x10f00);
+        assert_eq!(info.UserContextSaveArea as u64, 0x11000);
+        assert_eq!(info.SaveAreaSizeInBytes, 0x2000);
+        assert_eq!(info.ControlStackUsedInBytes, 0x100);
+        assert_eq!(info.NumCUAssigned, 64);
+        assert_eq!(info.SaveAreaHeader as u64, 0x10000);
+
+        /* Same layout as the C struct */
+        assert_eq!(std::mem::offset_of!(HsaQueueInfo, NumCUAssigned), 8);
+        assert_eq!(std::mem::offset_of!(HsaQueueInfo, CUMaskInfo), 16);
+        assert_eq!(std::mem::offset_of!(HsaQueueInfo, SaveAreaHeader), 56);
+        assert_eq!(std::mem::size_of::<HsaQueueInfo>(), 72);
+    }
+
+    #[test]
+    fn test_queue_snapshot() {
+        let mut pointers = [0x40u64, 0x80];
+        let snapshot = vec![
+            kfd_queue_snapshot_entry {
+                ring_base_address: 0x7f00_0000_0000,
+                read_pointer_address: &mut pointers[0] as *mut u64 as u64,
+                write_pointer_address: &mut pointers[1] as *mut u64 as u64,
+                queue_id: 3,
+                gpu_id: 0x1234,
+                ring_size: 0x1000,
+                queue_type: KFD_IOC_QUEUE_TYPE_COMPUTE_AQL,
+                ..Default::default()
+            },
+            kfd_queue_snapshot_entry {
+                queue_id: 4,
+                gpu_id: 0x1234,
+                ring_size: 0x100,
+                queue_type: KFD_IOC_QUEUE_TYPE_SDMA,
+                exception_status: 0x2,
+                ..Default::default()
+            },
+        ];
+        let kfd = FakeKfd::install(FakeKfd {
+            snapshot,
+            ..Default::default()
+        });
+
+        let mut entries = vec![];
+        unsafe {
+            assert_eq!(
+                kfd_queue_snapshot(-1, 1, 0, &mut entries),
+                HSAKMT_STATUS_SUCCESS
+            );
+        }
+
+        /* Asked again once the buffer was big enough */
+        assert_eq!(kfd.borrow().requests, vec![AMDKFD_IOC_DBG_TRAP; 2]);
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].exception_status, 0x2);
+
+        let q = test_queue(HSA_QUEUE_COMPUTE_AQL, 0x90a00);
+        let (aql, sdma) = unsafe {
+            (
+                HsaQueueSnapshotEntry::new(&entries[0], Some(&q)),
+                HsaQueueSnapshotEntry::new(&entries[1], None),
+            )
+        };
+
+        assert_eq!(aql.QueueType, Some(HSA_QUEUE_COMPUTE_AQL));
+        assert_eq!(
+            (aql.ReadPointer, aql.WritePointer),
+            (Some(0x40), Some(0x80))
+        );
+        assert_eq!(aql.DoorBell, 0x7000_1018);
+        assert_eq!(sdma.QueueType, Some(HSA_QUEUE_SDMA));
+        assert_eq!(sdma.QueueId, 0);
+
+        assert_eq!(
+            sdma.to_string(),
+            "queue 4 gpu 0x1234 HSA_QUEUE_SDMA\n\
+             \x20 ring 0x0 size 0x100 doorbell 0x0\n\
+             \x20 rptr ? at 0x0 wptr ? at 0x0\n\
+             \x20 ctx save 0x0 size 0x0 exceptions 0x2"
+        );
+        assert!(aql
+            .to_string()
+            .starts_with("queue 3 gpu 0x1234 node 1 HSA_QUEUE_COMPUTE_AQL\n"));
+        assert!(aql.to_string().contains("rptr 0x40 at "));
+
+        /* Entries of another size */
+        kfd.borrow_mut().snapshot_entry_size =
+            std::mem::size_of::<kfd_queue_snapshot_entry>() as u32 - 8;
+        unsafe {
+            assert_eq!(
+                kfd_queue_snapshot(-1, 1, 0, &mut entries),
+                HSAKMT_STATUS_ERROR
+            );
+        }
+        hsakmt_set_ioctl_hook(None);
+        assert!(entries.is_empty());
+    }
+}
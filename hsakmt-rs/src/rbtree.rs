@@ -89,7 +89,6 @@ pub fn rbtree_init(tree: &mut rbtree_t) {
     tree.root = &mut tree.sentinel as *mut rbtree_node_t;
 }
 
-#[allow(unused_assignments)]
 unsafe fn hsakmt_rbtree_insert_value(
     mut temp: *mut rbtree_node_t,
     node: *mut rbtree_node_t,
@@ -97,437 +96,319 @@ unsafe fn hsakmt_rbtree_insert_value(
 ) {
     let mut p: *mut *mut rbtree_node_t;
 
-    // let temp_st = &mut *(temp);
-    // let node_st = &mut *(node);
-    // let sentinel_st = &mut *(sentinel);
-
-    // println!("temp_st: {:?}", temp_st);
-    // println!("node_st: {:?}", node_st);
-    // println!("sentinel_st: {:?}", sentinel_st);
-
     loop {
-        let temp_st = &mut (*temp);
-        let node_st = &mut (*node);
-
-        let b = rbtree_key_compare(LKP_ALL() as u32, &node_st.key, &temp_st.key);
-
-        // println!("root temp_st {:#?}", temp_st);
-
-        p = if b < 0 {
-            let temp_st = &mut (*temp);
-
-            // println!("temp_st.left");
-            if temp_st.left.is_null() {
-                temp_st.left = sentinel;
-            }
-            &mut temp_st.left
+        p = if rbtree_key_compare(LKP_ALL() as u32, &(*node).key, &(*temp).key) < 0 {
+            &mut (*temp).left
         } else {
-            let temp_st = &mut (*temp);
-
-            // println!("temp_st.right");
-            if temp_st.right.is_null() {
-                temp_st.right = sentinel;
-            }
-            &mut temp_st.right
+            &mut (*temp).right
         };
 
-        // println!("sentinel: {:?}", sentinel);
-
         if *p == sentinel {
             break;
         }
 
-        // let v = *p;
+        temp = *p;
+    }
+
+    *p = node;
+    (*node).parent = temp;
+    (*node).left = sentinel;
+    (*node).right = sentinel;
+    rbt_red(&mut *node);
+}
 
-        // println!("v {:#?}", v.is_null());
-        // if v.is_null() {
-        //     *p = sentinel;
-        // }
+pub unsafe fn hsakmt_rbtree_insert(tree: &mut rbtree_s, mut node: *mut rbtree_node_s) {
+    /* a binary tree insert */
+    let root = &mut tree.root as *mut *mut rbtree_node_t;
+    let sentinel = &mut tree.sentinel as *mut rbtree_node_t;
 
-        temp = *p;
+    if (*root).is_null() || *root == sentinel {
+        (*node).parent = std::ptr::null_mut();
+        (*node).left = sentinel;
+        (*node).right = sentinel;
+        rbt_black(&mut *node);
+        *root = node;
 
-        // break;
+        return;
     }
 
-    *p = node;
+    hsakmt_rbtree_insert_value(*root, node, sentinel);
 
-    let node_st = &mut (*node);
+    /* re-balance tree */
 
-    node_st.parent = temp;
-    node_st.left = sentinel;
-    node_st.right = sentinel;
+    while node != *root && rbt_is_red(&*(*node).parent) {
+        let parent = (*node).parent;
+        let grandparent = (*parent).parent;
 
-    rbt_red(node_st);
-}
+        if parent == (*grandparent).left {
+            let temp = (*grandparent).right;
 
-pub unsafe fn hsakmt_rbtree_insert(tree: &mut rbtree_s, mut node: *mut rbtree_node_s) {
-    /* a binary tree insert */
-    let root_st = &mut (*tree.root);
+            if rbt_is_red(&*temp) {
+                rbt_black(&mut *parent);
+                rbt_black(&mut *temp);
+                rbt_red(&mut *grandparent);
+                node = grandparent;
+            } else {
+                if node == (*parent).right {
+                    node = parent;
+                    rbtree_left_rotate(root, sentinel, node);
+                }
+
+                rbt_black(&mut *(*node).parent);
+                rbt_red(&mut *(*(*node).parent).parent);
+                rbtree_right_rotate(root, sentinel, (*(*node).parent).parent);
+            }
+        } else {
+            let temp = (*grandparent).left;
 
+            if rbt_is_red(&*temp) {
+                rbt_black(&mut *parent);
+                rbt_black(&mut *temp);
+                rbt_red(&mut *grandparent);
+                node = grandparent;
+            } else {
+                if node == (*parent).left {
+                    node = parent;
+                    rbtree_right_rotate(root, sentinel, node);
+                }
+
+                rbt_black(&mut *(*node).parent);
+                rbt_red(&mut *(*(*node).parent).parent);
+                rbtree_left_rotate(root, sentinel, (*(*node).parent).parent);
+            }
+        }
+    }
+
+    rbt_black(&mut **root);
+}
+
+pub unsafe fn hsakmt_rbtree_delete(tree: &mut rbtree_s, node: *mut rbtree_node_s) {
+    let root = &mut tree.root as *mut *mut rbtree_node_t;
     let sentinel = &mut tree.sentinel as *mut rbtree_node_t;
-    let node_st = &mut (*node);
 
-    if root_st.key == tree.sentinel.key {
-        node_st.parent = std::ptr::null_mut();
-        node_st.left = sentinel;
-        node_st.right = sentinel;
-        rbt_black(node_st);
+    let mut temp: *mut rbtree_node_t;
+    let subst: *mut rbtree_node_t;
 
-        let root = &mut tree.root as *mut *mut rbtree_node_t;
+    /* a binary tree delete */
 
-        *root = node;
+    if (*node).left == sentinel {
+        temp = (*node).right;
+        subst = node;
+    } else if (*node).right == sentinel {
+        temp = (*node).left;
+        subst = node;
+    } else {
+        subst = rbtree_min((*node).right, sentinel);
 
-        // println!("first node");
-        // println!("node_st: {:#?}", node_st);
+        if (*subst).left != sentinel {
+            temp = (*subst).left;
+        } else {
+            temp = (*subst).right;
+        }
+    }
 
-        // println!("first node inserted {:#?}", &(**root));
+    if subst == *root {
+        *root = temp;
+        (*temp).parent = std::ptr::null_mut();
+        rbt_black(&mut *temp);
+
+        (*node).left = std::ptr::null_mut();
+        (*node).right = std::ptr::null_mut();
+        (*node).parent = std::ptr::null_mut();
 
         return;
     }
 
-    // println!("TODO hsakmt_rbtree_insert_value ");
+    let red = rbt_is_red(&*subst);
 
-    let root = &mut tree.root as *mut *mut rbtree_node_t;
+    if subst == (*(*subst).parent).left {
+        (*(*subst).parent).left = temp;
+    } else {
+        (*(*subst).parent).right = temp;
+    }
 
-    // println!("sentinel: {:#?}", tree.sentinel);
+    if subst == node {
+        (*temp).parent = (*subst).parent;
+    } else {
+        if (*subst).parent == node {
+            (*temp).parent = subst;
+        } else {
+            (*temp).parent = (*subst).parent;
+        }
 
-    hsakmt_rbtree_insert_value(*root, node, sentinel);
+        (*subst).left = (*node).left;
+        (*subst).right = (*node).right;
+        (*subst).parent = (*node).parent;
+        rbt_copy_color(&mut *subst, &*node);
 
-    // println!("node inserted {:#?}", &(**root));
+        if node == *root {
+            *root = subst;
+        } else if node == (*(*node).parent).left {
+            (*(*node).parent).left = subst;
+        } else {
+            (*(*node).parent).right = subst;
+        }
 
-    /* re-balance tree */
+        if (*subst).left != sentinel {
+            (*(*subst).left).parent = subst;
+        }
 
-    while node != *root && rbt_is_red(&(*node_st.parent)) {
-        let node_parent = &mut (*node_st.parent);
-        let node_parent_parent = &mut (*node_parent.parent);
+        if (*subst).right != sentinel {
+            (*(*subst).right).parent = subst;
+        }
+    }
 
-        if node_st.parent == node_parent_parent.left {
-            // let temp = node_parent_parent.right;
-            let temp_st = &mut (*node_parent_parent.right);
+    (*node).left = std::ptr::null_mut();
+    (*node).right = std::ptr::null_mut();
+    (*node).parent = std::ptr::null_mut();
 
-            if rbt_is_red(temp_st) {
-                rbt_black(node_parent);
-                rbt_black(temp_st);
-                rbt_red(node_parent_parent);
+    if red {
+        return;
+    }
 
-                node = node_parent_parent;
-            } else {
-                if node == node_parent.right {
-                    node = node_st.parent;
+    /* a delete fixup */
 
-                    rbtree_left_rotate(root, sentinel, node_st);
-                }
+    while temp != *root && rbt_is_black(&*temp) {
+        let parent = (*temp).parent;
 
-                rbt_black(node_parent);
-                rbt_red(node_parent_parent);
+        if temp == (*parent).left {
+            let mut w = (*parent).right;
 
-                rbtree_right_rotate(root, sentinel, node_parent_parent);
+            if rbt_is_red(&*w) {
+                rbt_black(&mut *w);
+                rbt_red(&mut *parent);
+                rbtree_left_rotate(root, sentinel, parent);
+                w = (*parent).right;
             }
-        } else {
-            let node_parent = &mut (*node_st.parent);
-            let node_parent_parent = &mut (*node_parent.parent);
 
-            // let temp = node_parent_parent.left;
-            let temp_st = &mut (*node_parent_parent.left);
+            if rbt_is_black(&*(*w).left) && rbt_is_black(&*(*w).right) {
+                rbt_red(&mut *w);
+                temp = parent;
+            } else {
+                if rbt_is_black(&*(*w).right) {
+                    rbt_black(&mut *(*w).left);
+                    rbt_red(&mut *w);
+                    rbtree_right_rotate(root, sentinel, w);
+                    w = (*parent).right;
+                }
 
-            if rbt_is_red(temp_st) {
-                rbt_black(node_parent);
-                rbt_black(temp_st);
-                rbt_red(node_parent_parent);
+                rbt_copy_color(&mut *w, &*parent);
+                rbt_black(&mut *parent);
+                rbt_black(&mut *(*w).right);
+                rbtree_left_rotate(root, sentinel, parent);
+                temp = *root;
+            }
+        } else {
+            let mut w = (*parent).left;
 
-                node = node_parent_parent;
-            } else {
-                if node == node_parent.left {
-                    node = node_st.parent;
+            if rbt_is_red(&*w) {
+                rbt_black(&mut *w);
+                rbt_red(&mut *parent);
+                rbtree_right_rotate(root, sentinel, parent);
+                w = (*parent).left;
+            }
 
-                    rbtree_right_rotate(root, sentinel, node_st);
+            if rbt_is_black(&*(*w).left) && rbt_is_black(&*(*w).right) {
+                rbt_red(&mut *w);
+                temp = parent;
+            } else {
+                if rbt_is_black(&*(*w).left) {
+                    rbt_black(&mut *(*w).right);
+                    rbt_red(&mut *w);
+                    rbtree_left_rotate(root, sentinel, w);
+                    w = (*parent).left;
                 }
 
-                rbt_black(node_parent);
-                rbt_red(node_parent_parent);
-
-                rbtree_left_rotate(root, sentinel, node_parent_parent);
+                rbt_copy_color(&mut *w, &*parent);
+                rbt_black(&mut *parent);
+                rbt_black(&mut *(*w).left);
+                rbtree_right_rotate(root, sentinel, parent);
+                temp = *root;
             }
         }
-
-        println!("while node {:#?}", node);
     }
 
-    let root_st = &mut *(tree.root);
-
-    rbt_black(root_st);
-}
-
-#[allow(unused_assignments)]
-pub unsafe fn hsakmt_rbtree_delete(tree: &mut rbtree_s, node: *mut rbtree_node_s) {
-    // // let root_st = &mut *(tree.root);
-    //
-    // let sentinel = &mut tree.sentinel;
-    // let root = &mut tree.root as *mut *mut rbtree_node_t;
-    //
-    // let node_st = &mut *(node);
-    //
-    // let mut temp: *mut rbtree_node_t = std::ptr::null_mut();
-    // let mut subst: *mut rbtree_node_t = std::ptr::null_mut();
-    //
-    // /* a binary tree delete */
-    //
-    // if node_st.left == sentinel {
-    //     temp = node_st.right;
-    //     subst = node;
-    // } else if node_st.right == sentinel {
-    //     temp = node_st.left;
-    //     subst = node;
-    // } else {
-    //     let subst = rbtree_min(node_st.right, sentinel);
-    //
-    //     let subst_st = &mut (*subst);
-    //
-    //     if subst_st.left != sentinel {
-    //         temp = subst_st.left;
-    //     } else {
-    //         temp = subst_st.right;
-    //     }
-    // }
-    //
-    // if subst == *root {
-    //     *root = temp;
-    //     rbt_black(&mut *temp);
-    //
-    //     return;
-    // }
-    //
-    // let subst_st = &mut (*subst);
-    // let subst_parent = &mut (*subst_st.parent);
-    //
-    // let temp_st = &mut (*temp);
-    //
-    // let red = rbt_is_red(subst_st);
-    //
-    // if subst == subst_parent.left {
-    //     subst_parent.left = temp;
-    // } else {
-    //     subst_parent.right = temp;
-    // }
-    //
-    // if subst == node {
-    //     temp_st.parent = subst_st.parent;
-    // } else {
-    //     if subst_st.parent == node {
-    //         temp_st.parent = subst;
-    //     } else {
-    //         temp_st.parent = subst_st.parent;
-    //     }
-    //
-    //     subst_st.left = node_st.left;
-    //     subst_st.right = node_st.right;
-    //     subst_st.parent = node_st.parent;
-    //     rbt_copy_color(subst_st, node_st);
-    //
-    //     let node_parent = &mut *(node_st.parent);
-    //
-    //     if node == *root {
-    //         *root = subst;
-    //     } else {
-    //         if node == node_parent.left {
-    //             node_parent.left = subst;
-    //         } else {
-    //             node_parent.right = subst;
-    //         }
-    //     }
-    //
-    //     let subst_left = &mut *(subst_st.left);
-    //     let subst_right = &mut *(subst_st.right);
-    //
-    //     if subst_st.left != sentinel {
-    //         subst_left.parent = subst;
-    //     }
-    //
-    //     if subst_st.right != sentinel {
-    //         subst_right.parent = subst;
-    //     }
-    // }
-    //
-    // if red {
-    //     return;
-    // }
-    //
-    // /* a delete fixup */
-    //
-    // while temp != *root && rbt_is_black(temp_st) {
-    //     let temp_parent = &mut *(temp_st.parent);
-    //
-    //     if temp_st == &(*temp_parent.left) {
-    //         let mut w = temp_parent.right;
-    //         let w_st = &mut (*w);
-    //
-    //         if rbt_is_red(w_st) {
-    //             rbt_black(w_st);
-    //             rbt_red(&mut *temp_st.parent);
-    //             rbtree_left_rotate(root, sentinel, &mut *temp_st.parent);
-    //             w = temp_parent.right;
-    //         }
-    //
-    //         let w_left = &mut (*w_st.left);
-    //         let w_right = &mut (*w_st.right);
-    //
-    //         if rbt_is_black(w_left) && rbt_is_black(w_right) {
-    //             rbt_red(w_st);
-    //             temp = temp_st.parent;
-    //         } else {
-    //             if rbt_is_black(w_right) {
-    //                 rbt_black(w_left);
-    //                 rbt_red(w_st);
-    //
-    //                 rbtree_right_rotate(root, sentinel, w_st);
-    //                 w = temp_parent.right;
-    //             }
-    //
-    //             rbt_copy_color(w_st, temp_parent);
-    //             rbt_black(temp_parent);
-    //             rbt_black(w_right);
-    //
-    //             rbtree_left_rotate(root, sentinel, temp_parent);
-    //             temp = *root;
-    //         }
-    //     } else {
-    //         let mut w = temp_parent.left;
-    //         let w_st = &mut (*w);
-    //
-    //         if rbt_is_red(w_st) {
-    //             rbt_black(w_st);
-    //             rbt_red(temp_parent);
-    //             rbtree_right_rotate(root, sentinel, temp_parent);
-    //             w = temp_parent.left;
-    //         }
-    //
-    //         let w_left = &mut (*w_st.left);
-    //         let w_right = &mut (*w_st.right);
-    //
-    //         if rbt_is_black(w_left) && rbt_is_black(w_right) {
-    //             rbt_red(w_st);
-    //             temp = temp_parent;
-    //         } else {
-    //             if rbt_is_black(w_left) {
-    //                 rbt_black(w_right);
-    //                 rbt_red(w_st);
-    //                 rbtree_left_rotate(root, sentinel, w_st);
-    //                 w = temp_parent.left;
-    //             }
-    //
-    //             rbt_copy_color(w_st, temp_parent);
-    //             rbt_black(temp_parent);
-    //             rbt_black(w_left);
-    //             rbtree_right_rotate(root, sentinel, temp_parent);
-    //             temp = *root;
-    //         }
-    //     }
-    // }
-    //
-    // rbt_black(temp_st);
+    rbt_black(&mut *temp);
 }
 
 pub unsafe fn rbtree_left_rotate(
     root: *mut *mut rbtree_node_t,
     sentinel: *mut rbtree_node_t,
-    node: &mut rbtree_node_t,
+    node: *mut rbtree_node_t,
 ) {
-    let temp = node.right;
-    let temp_st = &mut (*node.right);
-
-    node.right = temp_st.left;
-
-    let temp_left = &mut (*temp_st.left);
+    let temp = (*node).right;
 
-    if temp_st.left.is_null() {
-        temp_st.left = sentinel;
-    }
+    (*node).right = (*temp).left;
 
-    if temp_st.left != sentinel {
-        temp_left.parent = node;
+    if (*temp).left != sentinel {
+        (*(*temp).left).parent = node;
     }
 
-    temp_st.parent = node.parent;
-
-    let node_parent = &mut (*node.parent);
+    (*temp).parent = (*node).parent;
 
-    if node == &(**root) {
+    if node == *root {
         *root = temp;
-    } else if node == &mut (*node_parent.left) {
-        node_parent.left = temp;
+    } else if node == (*(*node).parent).left {
+        (*(*node).parent).left = temp;
     } else {
-        node_parent.right = temp;
+        (*(*node).parent).right = temp;
     }
 
-    temp_st.left = node;
-    node.parent = temp;
+    (*temp).left = node;
+    (*node).parent = temp;
 }
 
 pub unsafe fn rbtree_right_rotate(
     root: *mut *mut rbtree_node_t,
     sentinel: *mut rbtree_node_t,
-    node: &mut rbtree_node_t,
+    node: *mut rbtree_node_t,
 ) {
-    let temp = node.left;
-    let temp_st = &mut *(node.left);
+    let temp = (*node).left;
 
-    node.left = temp_st.right;
-
-    let temp_right = &mut (*temp_st.right);
-
-    if temp_st.right.is_null() {
-        temp_st.right = sentinel;
-    }
+    (*node).left = (*temp).right;
 
-    if temp_st.right != sentinel {
-        temp_right.parent = node;
+    if (*temp).right != sentinel {
+        (*(*temp).right).parent = node;
     }
 
-    temp_st.parent = node.parent;
+    (*temp).parent = (*node).parent;
 
-    let node_parent = &mut (*node.parent);
-
-    if node == &(**root) {
+    if node == *root {
         *root = temp;
-    } else if node == &mut (*node_parent.right) {
-        node_parent.right = temp;
+    } else if node == (*(*node).parent).right {
+        (*(*node).parent).right = temp;
     } else {
-        node_parent.left = temp;
+        (*(*node).parent).left = temp;
     }
 
-    temp_st.right = node;
-    node.parent = temp;
+    (*temp).right = node;
+    (*node).parent = temp;
 }
 
 pub unsafe fn hsakmt_rbtree_prev(
     tree: &mut rbtree_t,
     mut node: *mut rbtree_node_t,
 ) -> *mut rbtree_node_t {
-    // rbtree_node_t  *root, *sentinel, *parent;
-
-    let sentinel = &mut tree.sentinel;
-    let node_st = &mut (*node);
+    let sentinel = &mut tree.sentinel as *mut rbtree_node_t;
 
-    if node_st.left != sentinel {
-        return rbtree_max(node_st.left, sentinel);
+    if (*node).left != sentinel {
+        return rbtree_max((*node).left, sentinel);
     }
 
     let root = tree.root;
 
     loop {
-        let parent = node_st.parent;
+        let parent = (*node).parent;
 
         if node == root {
             return std::ptr::null_mut();
         }
 
-        let parent_st = &mut (*parent);
-
-        if node == parent_st.right {
+        if node == (*parent).right {
             return parent;
         }
 
-        node = parent
+        node = parent;
     }
 }
 
@@ -558,7 +439,9 @@ pub unsafe fn print_tree(tree: &rbtree_s) {
 #[cfg(test)]
 mod tests_rbtree {
     use super::*;
-    use crate::rbtree_amd::{rbtree_lookup_nearest, rbtree_min_max, LEFT, RIGHT};
+    use crate::rbtree_amd::{rbtree_key, rbtree_lookup_nearest, rbtree_min_max, LEFT, RIGHT};
+    use quickcheck::QuickCheck;
+    use std::collections::BTreeMap;
 
     #[test]
     fn test_insert() {
@@ -646,4 +529,153 @@ mod tests_rbtree {
             println!("tree.root.left.right: {:#?}", root_right_right);
         }
     }
+
+    /* Checks the red-black invariants of the subtree at node and
+     * returns its black height
+     */
+    unsafe fn check_subtree(
+        node: *mut rbtree_node_t,
+        parent: *mut rbtree_node_t,
+        sentinel: *mut rbtree_node_t,
+        keys: &mut Vec<(u64, u64)>,
+    ) -> usize {
+        if node == sentinel {
+            return 1;
+        }
+
+        assert_eq!((*node).parent, parent, "broken parent link");
+
+        if rbt_is_red(&*node) {
+            assert!(rbt_is_black(&*(*node).left), "red node with red child");
+            assert!(rbt_is_black(&*(*node).right), "red node with red child");
+        }
+
+        let left = check_subtree((*node).left, node, sentinel, keys);
+        keys.push(((*node).key.addr, (*node).key.size));
+        let right = check_subtree((*node).right, node, sentinel, keys);
+
+        assert_eq!(left, right, "black height differs");
+
+        left + if rbt_is_black(&*node) { 1 } else { 0 }
+    }
+
+    /* Red-black invariants, returns the keys in order */
+    unsafe fn check_rbtree(tree: &mut rbtree_s) -> Vec<(u64, u64)> {
+        let sentinel = &mut tree.sentinel as *mut rbtree_node_t;
+        let mut keys = vec![];
+
+        assert!(rbt_is_black(&*sentinel), "sentinel must be black");
+
+        if tree.root.is_null() || tree.root == sentinel {
+            return keys;
+        }
+
+        assert!(rbt_is_black(&*tree.root), "root must be black");
+        check_subtree(tree.root, std::ptr::null_mut(), sentinel, &mut keys);
+
+        keys
+    }
+
+    fn nearest(
+        reference: &BTreeMap<(u64, u64), usize>,
+        key: (u64, u64),
+        lr: i32,
+    ) -> Option<(u64, u64)> {
+        if reference.contains_key(&key) {
+            return Some(key);
+        }
+
+        if lr == RIGHT as i32 {
+            reference.range(key..).next().map(|(k, _)| *k)
+        } else {
+            reference.range(..key).next_back().map(|(k, _)| *k)
+        }
+    }
+
+    /* Each op is (kind, addr, size): kinds 0 and 1 insert, 2 deletes
+     * and 3 looks up the nearest node on both sides
+     */
+    fn prop_rbtree_model(ops: Vec<(u8, u8, u8)>) -> bool {
+        let mut tree = rbtree_s {
+            root: std::ptr::null_mut(),
+            sentinel: Default::default(),
+        };
+
+        /* nodes must not move once inserted */
+        let mut nodes: Vec<Box<rbtree_node_t>> = vec![];
+        let mut reference: BTreeMap<(u64, u64), usize> = BTreeMap::new();
+
+        rbtree_init(&mut tree);
+
+        for (kind, addr, size) in ops {
+            let key = ((addr % 64) as u64 * 0x1000, (size % 4 + 1) as u64 * 0x1000);
+
+            unsafe {
+                match kind % 4 {
+                    0 | 1 => {
+                        /* the tree accepts duplicated keys, the map doesn't */
+                        if reference.contains_key(&key) {
+                            continue;
+                        }
+
+                        let mut node = Box::new(rbtree_node_t {
+                            key: rbtree_key(key.0, key.1),
+                            ..Default::default()
+                        });
+                        hsakmt_rbtree_insert(&mut tree, &mut *node);
+                        reference.insert(key, nodes.len());
+                        nodes.push(node);
+                    }
+                    2 => {
+                        let Some(i) = reference.remove(&key) else {
+                            continue;
+                        };
+                        hsakmt_rbtree_delete(&mut tree, &mut *nodes[i]);
+                    }
+                    _ => {
+                        for lr in [LEFT as i32, RIGHT as i32] {
+                            let n = rbtree_lookup_nearest(
+                                &mut tree,
+                                &rbtree_key(key.0, key.1),
+                                LKP_ALL() as u32,
+                                lr,
+                            );
+                            let found = if n.is_null() {
+                                None
+                            } else {
+                                Some(((*n).key.addr, (*n).key.size))
+                            };
+
+                            assert_eq!(found, nearest(&reference, key, lr));
+                        }
+                    }
+                }
+
+                let keys = check_rbtree(&mut tree);
+                assert_eq!(keys, reference.keys().copied().collect::<Vec<_>>());
+
+                let min = rbtree_min_max(&mut tree, LEFT as i32);
+                let max = rbtree_min_max(&mut tree, RIGHT as i32);
+
+                if reference.is_empty() {
+                    assert!(min.is_null() && max.is_null());
+                } else {
+                    assert_eq!(((*min).key.addr, (*min).key.size), keys[0]);
+                    assert_eq!(((*max).key.addr, (*max).key.size), keys[keys.len() - 1]);
+                }
+            }
+        }
+
+        true
+    }
+
+    #[test]
+    fn test_rbtree_model() {
+        /* Deleting the root leaves its only child as the new root */
+        assert!(prop_rbtree_model(vec![(0, 1, 0), (0, 2, 0), (2, 1, 0)]));
+
+        QuickCheck::new()
+            .tests(500)
+            .quickcheck(prop_rbtree_model as fn(Vec<(u8, u8, u8)>) -> bool);
+    }
 }
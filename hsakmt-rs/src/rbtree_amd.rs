@@ -43,12 +43,12 @@ pub fn LKP_ALL() -> u64 {
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct rbtree_key_s {
     pub addr: u64,
-    pub size: i64,
+    pub size: u64,
 }
 
 pub type rbtree_key_t = rbtree_key_s;
 
-pub fn rbtree_key(addr: u64, size: i64) -> rbtree_key_t {
+pub fn rbtree_key(addr: u64, size: u64) -> rbtree_key_t {
     rbtree_key_t { addr, size }
 }
 
@@ -130,32 +130,17 @@ pub unsafe fn rbtree_lookup_nearest(
     lr: i32,
 ) -> *mut rbtree_node_t {
     let mut n: *mut rbtree_node_t = std::ptr::null_mut();
-    let mut node: *mut rbtree_node_t = std::ptr::null_mut();
-
-    node = rbtree.root.clone();
-    let sentinel = &mut rbtree.sentinel;
-
-    // let mut count = 0;
+    let mut node = rbtree.root;
+    let sentinel = &mut rbtree.sentinel as *mut rbtree_node_t;
 
     while node != sentinel {
-        // let node_st = &(*node);
         let rc = rbtree_key_compare(type_v, key, &(*node).key);
 
-        // if count == 20 {
-        //     break;
-        // }
-
-        // count += 1;
-
         if rc < 0 {
             if lr == RIGHT as i32 {
                 n = node;
             }
-
-            let node_st = &mut (*node);
-            node = node_st.left;
-            println!("node_st left ref {:#?}", node_st);
-
+            node = (*node).left;
             continue;
         }
 
@@ -163,17 +148,10 @@ pub unsafe fn rbtree_lookup_nearest(
             if lr == LEFT as i32 {
                 n = node;
             }
-
-            let node_st = &mut (*node);
-
-            node = node_st.right;
-            println!("node_st right ref {:#?}", node_st.right);
-
-            break;
+            node = (*node).right;
             continue;
         }
 
-        // println!("nearest node {:?}", node);
         return node;
     }
 
@@ -0,0 +1,342 @@
+#![allow(non_camel_case_types, non_snake_case)]
+
+use crate::hsakmttypes::HsakmtStatus;
+use crate::hsakmttypes::HsakmtStatus::{
+    HSAKMT_STATUS_INVALID_PARAMETER, HSAKMT_STATUS_OUT_OF_RESOURCES, HSAKMT_STATUS_SUCCESS,
+};
+use crate::hsakmttypes::HSA_QUEUE_TYPE::{
+    HSA_QUEUE_SDMA, HSA_QUEUE_SDMA_BY_ENG_ID, HSA_QUEUE_SDMA_XGMI,
+};
+use crate::queues::{queue, Queue, IS_SOC15};
+use std::sync::atomic::{fence, Ordering};
+
+pub const SDMA_OP_NOP: u32 = 0;
+pub const SDMA_OP_COPY: u32 = 1;
+pub const SDMA_OP_WRITE: u32 = 2;
+pub const SDMA_OP_FENCE: u32 = 5;
+pub const SDMA_OP_TRAP: u32 = 6;
+pub const SDMA_OP_POLL_REGMEM: u32 = 8;
+pub const SDMA_OP_CONST_FILL: u32 = 11;
+
+pub const SDMA_SUBOP_COPY_LINEAR: u32 = 0;
+
+/* CONST_FILL fillsize: 2 fills with dwords */
+pub const SDMA_CONST_FILL_DWORD: u32 = 2;
+
+/* Largest copy or fill one packet can do, the count field is 22 bits
+ * on every SDMA version
+ */
+pub const SDMA_MAX_COPY_SIZE: u64 = 0x3fffe0;
+pub const SDMA_MAX_FILL_SIZE: u64 = 0x3fffe0;
+
+pub fn SDMA_PKT_HEADER(op: u32, sub_op: u32, extra: u32) -> u32 {
+    (op & 0xff) | ((sub_op & 0xff) << 8) | ((extra & 0xffff) << 16)
+}
+
+/* SDMA packets in the order they run. Byte counts are encoded minus one
+ * from gfx9 on.
+ */
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SdmaPacketBuilder {
+    pub gfxv: u32,
+    dwords: Vec<u32>,
+}
+
+impl SdmaPacketBuilder {
+    pub fn new(gfxv: u32) -> Self {
+        Self {
+            gfxv,
+            dwords: vec![],
+        }
+    }
+
+    fn count(&self, size: u64) -> u32 {
+        if IS_SOC15(self.gfxv) {
+            size as u32 - 1
+        } else {
+            size as u32
+        }
+    }
+
+    /* Copy size bytes from src to dst, split in as many packets as
+     * needed
+     */
+    pub fn copy_linear(&mut self, dst: u64, src: u64, size: u64) -> &mut Self {
+        let mut offset = 0;
+
+        while offset < size {
+            let chunk = (size - offset).min(SDMA_MAX_COPY_SIZE);
+            let (s, d) = (src + offset, dst + offset);
+
+            self.dwords.extend_from_slice(&[
+                SDMA_PKT_HEADER(SDMA_OP_COPY, SDMA_SUBOP_COPY_LINEAR, 0),
+                self.count(chunk),
+                0, /* parameter: linear swizzle on both sides */
+                s as u32,
+                (s >> 32) as u32,
+                d as u32,
+                (d >> 32) as u32,
+            ]);
+
+            offset += chunk;
+        }
+
+        self
+    }
+
+    /* Fill size bytes at dst with data. dst and size must be multiples
+     * of 4.
+     */
+    pub fn constant_fill(&mut self, dst: u64, data: u32, size: u64) -> &mut Self {
+        let mut offset = 0;
+
+        while offset < size {
+            let chunk = (size - offset).min(SDMA_MAX_FILL_SIZE);
+            let d = dst + offset;
+
+            self.dwords.extend_from_slice(&[
+                SDMA_PKT_HEADER(SDMA_OP_CONST_FILL, 0, SDMA_CONST_FILL_DWORD << 14),
+                d as u32,
+                (d >> 32) as u32,
+                data,
+                self.count(chunk),
+            ]);
+
+            offset += chunk;
+        }
+
+        self
+    }
+
+    /* Write data to addr, 4 byte aligned, once the packets before are
+     * done
+     */
+    pub fn fence(&mut self, addr: u64, data: u32) -> &mut Self {
+        self.dwords.extend_from_slice(&[
+            SDMA_PKT_HEADER(SDMA_OP_FENCE, 0, 0),
+            addr as u32,
+            (addr >> 32) as u32,
+            data,
+        ]);
+
+        self
+    }
+
+    /* Raise an interrupt with int_context, which KFD turns into a signal
+     * of the queue's events
+     */
+    pub fn trap(&mut self, int_context: u32) -> &mut Self {
+        self.dwords.extend_from_slice(&[
+            SDMA_PKT_HEADER(SDMA_OP_TRAP, 0, 0),
+            int_context & 0x0fffffff,
+        ]);
+
+        self
+    }
+
+    pub fn nop(&mut self, count: usize) -> &mut Self {
+        self.dwords.extend(std::iter::repeat_n(
+            SDMA_PKT_HEADER(SDMA_OP_NOP, 0, 0),
+            count,
+        ));
+
+        self
+    }
+
+    pub fn dwords(&self) -> &[u32] {
+        &self.dwords
+    }
+
+    pub fn clear(&mut self) {
+        self.dwords.clear();
+    }
+}
+
+impl Queue {
+    /* Copy packets to the ring of an SDMA queue and ring its doorbell.
+     * Packets are not wrapped around the end of the ring, the space left
+     * there is filled with NOPs. Returns HSAKMT_STATUS_OUT_OF_RESOURCES
+     * if the engine hasn't consumed enough of the ring yet.
+     */
+    pub unsafe fn sdma_submit(&self, packets: &[u32]) -> HsakmtStatus {
+        if self.Type != HSA_QUEUE_SDMA
+            && self.Type != HSA_QUEUE_SDMA_XGMI
+            && self.Type != HSA_QUEUE_SDMA_BY_ENG_ID
+        {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        let gfxv = (*(self.Resource.QueueId as *const queue)).gfxv;
+        let ring_size = self.RingSize;
+        let size = (packets.len() * 4) as u64;
+
+        /* One dword stays free so a full ring doesn't look empty */
+        if size == 0 || size >= ring_size {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        /* SOC15 pointers are 64 bit and never wrap, older ones are 32 bit
+         * ring offsets
+         */
+        let (wptr, rptr) = if IS_SOC15(gfxv) {
+            (
+                std::ptr::read_volatile(self.write_pointer()),
+                std::ptr::read_volatile(self.read_pointer()),
+            )
+        } else {
+            (
+                std::ptr::read_volatile(self.write_pointer() as *const u32) as u64,
+                std::ptr::read_volatile(self.read_pointer() as *const u32) as u64,
+            )
+        };
+
+        let offset = wptr % ring_size;
+        let used = (wptr.wrapping_sub(rptr)) % ring_size;
+        let pad = if offset + size > ring_size {
+            ring_size - offset
+        } else {
+            0
+        };
+
+        if used + pad + size >= ring_size {
+            return HSAKMT_STATUS_OUT_OF_RESOURCES;
+        }
+
+        let ring = self.RingBase as *mut u32;
+        let mut index = offset / 4;
+
+        for _ in 0..pad / 4 {
+            std::ptr::write_volatile(ring.add(index as usize), SDMA_PKT_HEADER(SDMA_OP_NOP, 0, 0));
+            index += 1;
+        }
+        index %= ring_size / 4;
+
+        for dword in packets {
+            std::ptr::write_volatile(ring.add(index as usize), *dword);
+            index += 1;
+        }
+
+        /* The packets must be visible before the engine sees the new
+         * write pointer
+         */
+        fence(Ordering::Release);
+
+        if IS_SOC15(gfxv) {
+            let wptr = wptr + pad + size;
+
+            std::ptr::write_volatile(self.write_pointer(), wptr);
+            std::ptr::write_volatile(self.doorbell() as *mut u64, wptr);
+        } else {
+            let wptr = ((offset + pad + size) % ring_size) as u32;
+
+            std::ptr::write_volatile(self.write_pointer() as *mut u32, wptr);
+            std::ptr::write_volatile(self.doorbell() as *mut u32, wptr);
+        }
+
+        HSAKMT_STATUS_SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hsakmttypes::{HsaQueueResource, HSA_QUEUEID, HSA_QUEUE_PRIORITY};
+
+    #[test]
+    fn test_sdma_packets() {
+        let mut pkts = SdmaPacketBuilder::new(0x90a00);
+
+        pkts.copy_linear(0x1_2345_6000, 0x7f00_0000_1000, 0x1000)
+            .constant_fill(0x2000, 0xdeadbeef, 0x100)
+            .fence(0x3000, 7)
+            .trap(0x1234);
+
+        assert_eq!(
+            pkts.dwords(),
+            &[
+                0x00000001, 0xfff, 0, 0x1000, 0x7f00, 0x23456000, 0x1, /* copy */
+                0x8000000b, 0x2000, 0, 0xdeadbeef, 0xff, /* fill */
+                0x00000005, 0x3000, 0, 7, /* fence */
+                0x00000006, 0x1234, /* trap */
+            ]
+        );
+
+        /* gfx8 counts are not minus one */
+        let mut pkts = SdmaPacketBuilder::new(0x80003);
+        pkts.copy_linear(0x2000, 0x1000, 0x100);
+        assert_eq!(pkts.dwords()[1], 0x100);
+
+        /* Large copies are split */
+        let mut pkts = SdmaPacketBuilder::new(0x90a00);
+        pkts.copy_linear(0x1000_0000, 0, SDMA_MAX_COPY_SIZE + 0x20);
+        assert_eq!(pkts.dwords().len(), 14);
+        assert_eq!(pkts.dwords()[8], 0x1f);
+        assert_eq!(pkts.dwords()[10], SDMA_MAX_COPY_SIZE as u32);
+        assert_eq!(pkts.dwords()[12], 0x1000_0000 + SDMA_MAX_COPY_SIZE as u32);
+    }
+
+    #[test]
+    fn test_sdma_submit() {
+        let mut ring = vec![0xffff_ffffu32; 16];
+        let mut pointers = [0u64; 2];
+        let mut doorbell = 0u64;
+        let q = Box::new(queue {
+            queue_id: 0,
+            node_id: 1,
+            gpu_id: 0x1234,
+            gfxv: 0x90a00,
+            Type: HSA_QUEUE_SDMA,
+            doorbell: 0,
+            pointers: std::ptr::null_mut(),
+            eop_buffer: std::ptr::null_mut(),
+            eop_buffer_size: 0,
+            ctx_save_restore: std::ptr::null_mut(),
+            cwsr: None,
+            ring_base: 0,
+            ring_size: 64,
+            queue_percentage: 100,
+            priority: HSA_QUEUE_PRIORITY::HSA_QUEUE_PRIORITY_NORMAL,
+            cu_mask: vec![],
+        });
+
+        let sdma = Queue {
+            NodeId: 1,
+            Type: HSA_QUEUE_SDMA,
+            RingBase: ring.as_mut_ptr() as *mut std::os::raw::c_void,
+            RingSize: 64,
+            pointers: std::ptr::null_mut(),
+            Resource: HsaQueueResource {
+                QueueId: &*q as *const queue as HSA_QUEUEID,
+                QueueDoorBell: &mut doorbell as *mut u64 as u64,
+                QueueRptrValue: &mut pointers[0] as *mut u64 as u64,
+                QueueWptrValue: &mut pointers[1] as *mut u64 as u64,
+                ErrorReason: std::ptr::null_mut(),
+            },
+        };
+
+        let mut pkts = SdmaPacketBuilder::new(0x90a00);
+        pkts.fence(0x3000, 1).trap(0);
+
+        unsafe {
+            assert_eq!(sdma.sdma_submit(pkts.dwords()), HSAKMT_STATUS_SUCCESS);
+            assert_eq!((pointers[1], doorbell), (24, 24));
+            assert_eq!(&ring[..6], pkts.dwords());
+
+            assert_eq!(sdma.sdma_submit(pkts.dwords()), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(pointers[1], 48);
+
+            /* Ring full until the engine catches up */
+            assert_eq!(
+                sdma.sdma_submit(pkts.dwords()),
+                HSAKMT_STATUS_OUT_OF_RESOURCES
+            );
+            pointers[0] = 48;
+
+            /* Wraps with NOPs at the end of the ring */
+            assert_eq!(sdma.sdma_submit(pkts.dwords()), HSAKMT_STATUS_SUCCESS);
+            assert_eq!(pointers[1], 64 + 24);
+            assert_eq!(&ring[12..16], &[0; 4]);
+            assert_eq!(&ring[..6], pkts.dwords());
+        }
+    }
+}
@@ -16,9 +16,12 @@ use crate::hsakmttypes::{
     HSA_GET_GFX_VERSION_FULL, HSA_IOLINKTYPE, SGPR_SIZE_PER_CU,
 };
 use crate::queues::hsakmt_get_vgpr_size_per_cu;
-use crate::topology_utils::{num_subdirs, KFD_SYSFS_PATH_GENERATION_ID, KFD_SYSFS_PATH_NODES};
+use crate::topology_utils::{
+    kfd_sysfs_generation, kfd_sysfs_gpu_ids, num_subdirs, KFD_SYSFS_PATH_GENERATION_ID,
+    KFD_SYSFS_PATH_NODES, KFD_SYSFS_PATH_TOPOLOGY,
+};
 use amdgpu_drm_sys::bindings::{
-    amdgpu_device, amdgpu_device_deinitialize, amdgpu_device_handle, amdgpu_device_initialize,
+    amdgpu_device_deinitialize, amdgpu_device_handle, amdgpu_device_initialize,
     amdgpu_get_marketing_name, amdgpu_gpu_info, amdgpu_query_gpu_info, AMDGPU_IDS_FLAGS_FUSION,
 };
 use libc::{
@@ -27,6 +30,7 @@ use libc::{
 use std::ffi::{CStr, CString};
 use std::fs;
 use std::mem::MaybeUninit;
+use std::path::Path;
 use std::thread::available_parallelism;
 use xf86drm_sys::bindings::{drmClose, drmOpenRender};
 
@@ -610,7 +614,7 @@ pub fn topology_add_io_link_for_node(
 pub fn gpu_get_direct_link_cpu(gpu_node: u32, node_props: &mut [node_props_t]) -> i32 {
     let props = &node_props[gpu_node as usize].link;
 
-    if !node_props[gpu_node as usize].node.KFDGpuID > 0
+    if node_props[gpu_node as usize].node.KFDGpuID == 0
         || props.is_empty()
         || node_props[gpu_node as usize].node.NumIOLinks == 0
     {
@@ -899,7 +903,7 @@ impl HsakmtGlobals {
 
         self.fmm.drm_render_fds[index] = fd;
 
-        let mut device_handle: MaybeUninit<amdgpu_device> = MaybeUninit::uninit();
+        let mut device_handle: amdgpu_device_handle = std::ptr::null_mut();
         let mut major_drm: MaybeUninit<u32> = MaybeUninit::zeroed();
         let mut minor_drm: MaybeUninit<u32> = MaybeUninit::zeroed();
 
@@ -907,12 +911,14 @@ impl HsakmtGlobals {
             fd,
             major_drm.as_mut_ptr(),
             minor_drm.as_mut_ptr(),
-            &mut device_handle.as_mut_ptr(),
+            &mut device_handle,
         );
         if ret != 0 {
             panic!("amdgpu_device_initialize failed");
         }
 
+        self.fmm.amdgpu_handle[index] = device_handle;
+
         fd
     }
 
@@ -1650,9 +1656,12 @@ impl HsakmtGlobals {
             return err;
         }
 
-        // err = hsakmt_init_process_doorbells(g_system->NumNodes);
-        // if (err != HSAKMT_STATUS_SUCCESS)
-        // goto init_doorbells_failed;
+        let err = self.hsakmt_init_process_doorbells(self.topology.g_system.NumNodes);
+        if err != HSAKMT_STATUS_SUCCESS {
+            println!("hsakmt_init_process_doorbells error");
+            self.topology_drop_snapshot();
+            return err;
+        }
 
         *system_properties = self.topology.g_system;
 
@@ -1675,6 +1684,96 @@ impl HsakmtGlobals {
         &self.topology.g_props[NodeId as usize].link
     }
 
+    pub fn hsakmt_validate_nodeid(&self, nodeid: u32, gpu_id: &mut u32) -> HsakmtStatus {
+        if self.topology.g_props.is_empty() || self.topology.g_system.NumNodes <= nodeid {
+            return HSAKMT_STATUS_INVALID_NODE_UNIT;
+        }
+
+        if !self.hsakmt_topology_node_available(nodeid) {
+            return HSAKMT_STATUS_INVALID_NODE_UNIT;
+        }
+
+        *gpu_id = self.topology.g_props[nodeid as usize].node.KFDGpuID;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
+    pub fn hsakmt_topology_node_available(&self, NodeId: u32) -> bool {
+        !self.topology.unavailable_nodes.contains(&NodeId)
+    }
+
+    /* Look for GPU nodes whose device left sysfs, or came back with the
+     * same gpu_id, since the last look. Nodes that left are refused by
+     * hsakmt_validate_nodeid until they are back. Returns the nodes that
+     * became unavailable and the ones available again.
+     */
+    pub fn hsakmt_topology_refresh_node_availability(&mut self) -> (Vec<u32>, Vec<u32>) {
+        self.topology_refresh_node_availability(Path::new(KFD_SYSFS_PATH_TOPOLOGY))
+    }
+
+    /* A device coming back may get another sysfs node id, so the nodes
+     * are looked up by gpu_id among all of them. Nothing changed as
+     * long as generation_id is the same.
+     */
+    pub fn topology_refresh_node_availability(
+        &mut self,
+        topology_dir: &Path,
+    ) -> (Vec<u32>, Vec<u32>) {
+        let mut lost = vec![];
+        let mut back = vec![];
+
+        let generation = kfd_sysfs_generation(topology_dir);
+        if generation.is_some() && generation == self.topology.availability_generation {
+            return (lost, back);
+        }
+
+        let gpu_ids = kfd_sysfs_gpu_ids(&topology_dir.join("nodes"));
+
+        for (NodeId, props) in self.topology.g_props.iter().enumerate() {
+            let gpu_id = props.node.KFDGpuID;
+            if gpu_id == 0 {
+                continue;
+            }
+
+            let present = gpu_ids.contains(&gpu_id);
+            let NodeId = NodeId as u32;
+
+            if !present && self.topology.unavailable_nodes.insert(NodeId) {
+                lost.push(NodeId);
+            } else if present && self.topology.unavailable_nodes.remove(&NodeId) {
+                back.push(NodeId);
+            }
+        }
+
+        self.topology.availability_generation = generation;
+
+        (lost, back)
+    }
+
+    pub fn hsakmt_validate_nodeid_array(
+        &self,
+        gpu_id_array: &mut Vec<u32>,
+        NodeArray: &[u32],
+    ) -> HsakmtStatus {
+        if NodeArray.is_empty() {
+            return HSAKMT_STATUS_INVALID_PARAMETER;
+        }
+
+        /* Translate Node IDs to gpu_ids */
+        let mut gpu_ids = vec![0u32; NodeArray.len()];
+
+        for (i, node_id) in NodeArray.iter().enumerate() {
+            let ret = self.hsakmt_validate_nodeid(*node_id, &mut gpu_ids[i]);
+            if ret != HSAKMT_STATUS_SUCCESS {
+                return ret;
+            }
+        }
+
+        *gpu_id_array = gpu_ids;
+
+        HSAKMT_STATUS_SUCCESS
+    }
+
     pub fn hsakmt_topology_setup_is_dgpu_param(&mut self, props: &HsaNodeProperties) {
         /* if we found a dGPU node, then treat the whole system as dGPU */
         // println!("DeviceId {} hsakmt_is_dgpu = true", props.DeviceId);
@@ -1711,4 +1810,56 @@ mod tests {
         println!("{:#?}", c);
         // TODO assert
     }
+
+    #[test]
+    fn test_topology_refresh_node_availability() {
+        let topology_dir =
+            std::env::temp_dir().join(format!("hsakmt-availability-{}", std::process::id()));
+        let nodes_dir = topology_dir.join("nodes");
+        let set_node = |node: &str, gpu_id: u32, generation: u32| {
+            fs::create_dir_all(nodes_dir.join(node)).unwrap();
+            fs::write(nodes_dir.join(node).join("gpu_id"), gpu_id.to_string()).unwrap();
+            fs::write(topology_dir.join("generation_id"), generation.to_string()).unwrap();
+        };
+
+        let mut g = HsakmtGlobals::without_kfd();
+        for gpu_id in [0, 0x1234, 0x4321] {
+            let mut props = node_props_t::new();
+            props.node.KFDGpuID = gpu_id;
+            g.topology.g_props.push(props);
+        }
+
+        set_node("0", 0, 1);
+        set_node("1", 0x1234, 1);
+        set_node("2", 0x4321, 1);
+        assert_eq!(
+            g.topology_refresh_node_availability(&topology_dir),
+            (vec![], vec![])
+        );
+
+        /* Node 2 leaves, but nothing is looked at before generation_id
+         * changes
+         */
+        fs::remove_dir_all(nodes_dir.join("2")).unwrap();
+        assert_eq!(
+            g.topology_refresh_node_availability(&topology_dir),
+            (vec![], vec![])
+        );
+        set_node("0", 0, 2);
+        assert_eq!(
+            g.topology_refresh_node_availability(&topology_dir),
+            (vec![2], vec![])
+        );
+        assert!(!g.hsakmt_topology_node_available(2));
+
+        /* It comes back under another sysfs node id */
+        set_node("3", 0x4321, 3);
+        assert_eq!(
+            g.topology_refresh_node_availability(&topology_dir),
+            (vec![], vec![2])
+        );
+        assert!(g.hsakmt_topology_node_available(2));
+
+        fs::remove_dir_all(topology_dir).unwrap();
+    }
 }
@@ -1,6 +1,8 @@
+use std::collections::BTreeSet;
 use std::fs;
 use std::path::Path;
 
+pub const KFD_SYSFS_PATH_TOPOLOGY: &str = "/sys/devices/virtual/kfd/kfd/topology";
 pub const KFD_SYSFS_PATH_GENERATION_ID: &str =
     "/sys/devices/virtual/kfd/kfd/topology/generation_id";
 
@@ -307,6 +309,38 @@ impl SysDevicesVirtualKfd {
     }
 }
 
+/* gpu_id of sysfs node sysfs_node_id under nodes_dir, None once the
+ * node is gone
+ */
+pub fn kfd_sysfs_node_gpu_id(nodes_dir: &Path, sysfs_node_id: usize) -> Option<u32> {
+    let path = nodes_dir.join(sysfs_node_id.to_string()).join("gpu_id");
+
+    fs::read_to_string(path).ok()?.trim().parse::<u32>().ok()
+}
+
+/* gpu_ids of all GPU nodes under nodes_dir, whatever their node id */
+pub fn kfd_sysfs_gpu_ids(nodes_dir: &Path) -> BTreeSet<u32> {
+    let Ok(entries) = fs::read_dir(nodes_dir) else {
+        return BTreeSet::new();
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<usize>().ok())
+        .filter_map(|sysfs_node_id| kfd_sysfs_node_gpu_id(nodes_dir, sysfs_node_id))
+        .filter(|gpu_id| *gpu_id != 0)
+        .collect()
+}
+
+/* generation_id under topology_dir, KFD bumps it on every topology
+ * change. None if it can't be read.
+ */
+pub fn kfd_sysfs_generation(topology_dir: &Path) -> Option<u32> {
+    let path = topology_dir.join("generation_id");
+
+    fs::read_to_string(path).ok()?.trim().parse::<u32>().ok()
+}
+
 pub fn num_subdirs(path: &str, text: &str) -> usize {
     let mut count = 0;
 
@@ -335,6 +369,40 @@ mod tests {
         // TODO assert
     }
 
+    #[test]
+    fn test_sysfs_node_gpu_id() {
+        let nodes_dir = std::env::temp_dir().join(format!("hsakmt-nodes-{}", std::process::id()));
+        fs::create_dir_all(nodes_dir.join("1")).unwrap();
+        fs::write(nodes_dir.join("1").join("gpu_id"), "4660\n").unwrap();
+
+        assert_eq!(kfd_sysfs_node_gpu_id(&nodes_dir, 1), Some(4660));
+        assert_eq!(kfd_sysfs_node_gpu_id(&nodes_dir, 2), None);
+
+        fs::remove_dir_all(nodes_dir).unwrap();
+    }
+
+    #[test]
+    fn test_sysfs_gpu_ids() {
+        let topology_dir =
+            std::env::temp_dir().join(format!("hsakmt-topology-{}", std::process::id()));
+        let nodes_dir = topology_dir.join("nodes");
+
+        assert!(kfd_sysfs_gpu_ids(&nodes_dir).is_empty());
+        assert_eq!(kfd_sysfs_generation(&topology_dir), None);
+
+        for (node, gpu_id) in [("0", "0"), ("1", "4660"), ("3", "17185")] {
+            fs::create_dir_all(nodes_dir.join(node)).unwrap();
+            fs::write(nodes_dir.join(node).join("gpu_id"), gpu_id).unwrap();
+        }
+        fs::create_dir_all(nodes_dir.join("2")).unwrap();
+        fs::write(topology_dir.join("generation_id"), "5\n").unwrap();
+
+        assert_eq!(kfd_sysfs_gpu_ids(&nodes_dir), BTreeSet::from([4660, 17185]));
+        assert_eq!(kfd_sysfs_generation(&topology_dir), Some(5));
+
+        fs::remove_dir_all(topology_dir).unwrap();
+    }
+
     #[test]
     fn test_num_subdirs() {
         let p = "/sys/devices/system/node/node0";
@@ -0,0 +1,484 @@
+#![allow(non_camel_case_types)]
+
+use crate::fmm_types::vm_object_t;
+use std::collections::BTreeMap;
+
+/*
+ * Ordered set of vm objects of an aperture, keyed by (start, size).
+ * The object pointer is part of the key so that objects sharing the
+ * same range (overlapping userptrs) can live in the same tree. The
+ * tree does not own the objects, it never dereferences them.
+ *
+ * Lookups follow the semantics of the rbtree_lookup_nearest() based
+ * lookups of libhsakmt.
+ */
+#[derive(Debug, Default, PartialEq)]
+pub struct vm_tree_s {
+    nodes: BTreeMap<(u64, u64, usize), *mut vm_object_t>,
+    /* Number of objects per size, the largest size bounds the scans
+     * for objects ending below an address
+     */
+    sizes: BTreeMap<u64, usize>,
+}
+
+pub type vm_tree_t = vm_tree_s;
+
+impl vm_tree_s {
+    pub fn new() -> Self {
+        Self {
+            nodes: BTreeMap::new(),
+            sizes: BTreeMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.sizes.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn insert(&mut self, start: u64, size: u64, object: *mut vm_object_t) {
+        if self
+            .nodes
+            .insert((start, size, object as usize), object)
+            .is_none()
+        {
+            *self.sizes.entry(size).or_insert(0) += 1;
+        }
+    }
+
+    pub fn remove(&mut self, start: u64, size: u64, object: *mut vm_object_t) -> bool {
+        if self.nodes.remove(&(start, size, object as usize)).is_none() {
+            return false;
+        }
+
+        if let Some(count) = self.sizes.get_mut(&size) {
+            *count -= 1;
+            if *count == 0 {
+                self.sizes.remove(&size);
+            }
+        }
+
+        true
+    }
+
+    fn max_size(&self) -> u64 {
+        self.sizes.keys().next_back().copied().unwrap_or(0)
+    }
+
+    /* All objects, ordered by start address */
+    pub fn objects(&self) -> impl Iterator<Item = *mut vm_object_t> + '_ {
+        self.nodes.values().copied()
+    }
+
+    /* Any object of the tree, NULL if the tree is empty */
+    pub fn any(&self) -> *mut vm_object_t {
+        self.nodes
+            .values()
+            .next()
+            .copied()
+            .unwrap_or(std::ptr::null_mut())
+    }
+
+    /* Object starting at start. If size is 0 the object must be the only
+     * one starting at start, otherwise its size must match too.
+     */
+    pub fn find_exact(&self, start: u64, size: u64) -> *mut vm_object_t {
+        let Some((&key, &object)) = self.nodes.range((start, size, 0)..).next() else {
+            return std::ptr::null_mut();
+        };
+
+        if key.0 != start {
+            return std::ptr::null_mut();
+        }
+
+        if size > 0 {
+            return if key.1 == size {
+                object
+            } else {
+                std::ptr::null_mut()
+            };
+        }
+
+        /* size is 0, make sure there is only one object at start */
+        match self
+            .nodes
+            .range(..=(start, u64::MAX, usize::MAX))
+            .next_back()
+        {
+            Some((&last, _)) if last == key => object,
+            _ => std::ptr::null_mut(),
+        }
+    }
+
+    /* Object containing address. Objects of the tree normally don't
+     * overlap and only the closest object starting at or below address
+     * is checked. With overlapping set, all objects below address are
+     * walked from right to left, as userptrs might overlap.
+     */
+    pub fn find_containing(&self, address: u64, overlapping: bool) -> *mut vm_object_t {
+        /* all objects might sit on the left side of address, in that
+         * case start from the rightmost one
+         */
+        let right = self
+            .nodes
+            .range((address, 0, 0)..)
+            .next()
+            .or_else(|| self.nodes.iter().next_back());
+
+        let Some((&right, _)) = right else {
+            return std::ptr::null_mut();
+        };
+
+        let left = if overlapping {
+            self.nodes.keys().next()
+        } else {
+            self.nodes
+                .range(..=(address, u64::MAX, usize::MAX))
+                .next_back()
+                .map(|(key, _)| key)
+        };
+
+        let Some(&left) = left else {
+            return std::ptr::null_mut();
+        };
+
+        for (&key, &object) in self.nodes.range(..=right).rev() {
+            if address >= key.0 && address - key.0 < key.1 {
+                return object;
+            }
+
+            if key == left {
+                break;
+            }
+        }
+
+        std::ptr::null_mut()
+    }
+
+    /* Object closest to address: the one containing it, else the one
+     * with the smallest gap between its end or start and address. NULL
+     * if the tree is empty.
+     *
+     * Objects below address are walked from right to left, the walk
+     * stops once no object starting further left can end closer to
+     * address than the best one so far, given the largest object size
+     * in the tree. Fault lookups thus only visit the objects that start
+     * within one largest-object size of address.
+     */
+    pub fn find_nearest(&self, address: u64) -> *mut vm_object_t {
+        let max_size = self.max_size();
+        let mut below: Option<(u64, *mut vm_object_t)> = None;
+
+        for (&key, &object) in self.nodes.range(..=(address, u64::MAX, usize::MAX)).rev() {
+            if let Some((gap, _)) = below {
+                if key.0.saturating_add(max_size) < address - gap {
+                    break;
+                }
+            }
+
+            let end = key.0.saturating_add(key.1);
+            if end > address {
+                /* the object contains address */
+                return object;
+            }
+
+            /* on a tie the leftmost object wins */
+            if below.is_none_or(|(gap, _)| address - end <= gap) {
+                below = Some((address - end, object));
+            }
+        }
+
+        let above = self
+            .nodes
+            .range((address, u64::MAX, usize::MAX)..)
+            .next()
+            .map(|(key, object)| (key.0 - address, *object));
+
+        match (below, above) {
+            (Some(below), Some(above)) if above.0 < below.0 => above.1,
+            (Some(below), _) => below.1,
+            (None, Some(above)) => above.1,
+            (None, None) => std::ptr::null_mut(),
+        }
+    }
+
+    /* All objects overlapping [start, start + size) */
+    pub fn find_overlapping(&self, start: u64, size: u64) -> Vec<*mut vm_object_t> {
+        let end = start.saturating_add(size);
+
+        self.nodes
+            .range(..(end, 0, 0))
+            .filter(|(key, _)| key.0.saturating_add(key.1) > start)
+            .map(|(_, object)| *object)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rbtree::{
+        hsakmt_rbtree_delete, hsakmt_rbtree_insert, hsakmt_rbtree_prev, rbtree_init, rbtree_node_t,
+        rbtree_s,
+    };
+    use crate::rbtree_amd::{
+        rbtree_key, rbtree_lookup_nearest, rbtree_min_max, LEFT, LKP_ALL, RIGHT,
+    };
+
+    /* Lookups on the intrusive rbtree, as done by fmm before vm_tree */
+    unsafe fn rbtree_find_exact(
+        tree: &mut rbtree_s,
+        address: u64,
+        size: u64,
+    ) -> *mut rbtree_node_t {
+        let key = rbtree_key(address, size);
+        let n = rbtree_lookup_nearest(tree, &key, LKP_ALL() as u32, RIGHT as i32);
+
+        if n.is_null() || (*n).key.addr != address {
+            return std::ptr::null_mut();
+        }
+
+        if size > 0 {
+            return if (*n).key.size == size {
+                n
+            } else {
+                std::ptr::null_mut()
+            };
+        }
+
+        let key = rbtree_key(address, u64::MAX);
+        let rn = rbtree_lookup_nearest(tree, &key, LKP_ALL() as u32, LEFT as i32);
+
+        if rn != n {
+            return std::ptr::null_mut();
+        }
+
+        n
+    }
+
+    unsafe fn rbtree_find_containing(
+        tree: &mut rbtree_s,
+        address: u64,
+        overlapping: bool,
+    ) -> *mut rbtree_node_t {
+        let key = rbtree_key(address, 0);
+        let mut rn = rbtree_lookup_nearest(tree, &key, LKP_ALL() as u32, RIGHT as i32);
+
+        if rn.is_null() {
+            rn = rbtree_min_max(tree, RIGHT as i32);
+        }
+
+        let ln = if overlapping {
+            rbtree_min_max(tree, LEFT as i32)
+        } else {
+            let key = rbtree_key(address, u64::MAX);
+            rbtree_lookup_nearest(tree, &key, LKP_ALL() as u32, LEFT as i32)
+        };
+
+        if ln.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        while !rn.is_null() {
+            let (start, size) = ((*rn).key.addr, (*rn).key.size);
+
+            if address >= start && address < start + size {
+                return rn;
+            }
+
+            if ln == rn {
+                break;
+            }
+
+            rn = hsakmt_rbtree_prev(tree, rn);
+        }
+
+        std::ptr::null_mut()
+    }
+
+    /* xorshift, good enough to generate ranges */
+    fn next_rand(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn fake_object(i: usize) -> *mut vm_object_t {
+        i as *mut vm_object_t
+    }
+
+    /* The rbtree can't tell apart objects with the same key, compare keys */
+    fn key_of(vm_tree: &[(u64, u64)], object: *mut vm_object_t) -> Option<(u64, u64)> {
+        if object.is_null() {
+            None
+        } else {
+            Some(vm_tree[object as usize - 1])
+        }
+    }
+
+    unsafe fn node_key(node: *mut rbtree_node_t) -> Option<(u64, u64)> {
+        if node.is_null() {
+            None
+        } else {
+            Some(((*node).key.addr, (*node).key.size))
+        }
+    }
+
+    fn differential(overlapping: bool, seed: u64) {
+        let mut state = seed;
+        let count = 200;
+
+        /* object i is represented by the fake pointer i + 1 */
+        let mut ranges: Vec<(u64, u64)> = Vec::with_capacity(count);
+        let mut next_start = 0x1000u64;
+
+        for _ in 0..count {
+            let size = (next_rand(&mut state) % 16 + 1) * 0x1000;
+
+            let start = if overlapping {
+                (next_rand(&mut state) % 256) * 0x1000
+            } else {
+                let start = next_start + (next_rand(&mut state) % 4) * 0x1000;
+                next_start = start + size;
+                start
+            };
+
+            ranges.push((start, size));
+        }
+
+        /* duplicated keys behave differently in the two trees */
+        if overlapping {
+            ranges.sort_unstable();
+            ranges.dedup();
+        }
+
+        let mut nodes: Vec<rbtree_node_t> = ranges
+            .iter()
+            .map(|&(start, size)| rbtree_node_t {
+                key: rbtree_key(start, size),
+                ..Default::default()
+            })
+            .collect();
+
+        let mut tree = rbtree_s {
+            root: std::ptr::null_mut(),
+            sentinel: Default::default(),
+        };
+        let mut vm_tree = vm_tree_t::new();
+
+        unsafe {
+            rbtree_init(&mut tree);
+
+            for (i, &(start, size)) in ranges.iter().enumerate() {
+                hsakmt_rbtree_insert(&mut tree, &mut nodes[i]);
+                vm_tree.insert(start, size, fake_object(i + 1));
+            }
+
+            /* drop a third of the objects */
+            for (i, &(start, size)) in ranges.iter().enumerate() {
+                if next_rand(&mut state).is_multiple_of(3) {
+                    hsakmt_rbtree_delete(&mut tree, &mut nodes[i]);
+                    assert!(vm_tree.remove(start, size, fake_object(i + 1)));
+                }
+            }
+
+            let limit = ranges.iter().map(|(s, l)| s + l).max().unwrap() + 0x2000;
+
+            for _ in 0..2000 {
+                let address = (next_rand(&mut state) % limit) & !0xff;
+
+                assert_eq!(
+                    key_of(&ranges, vm_tree.find_containing(address, overlapping)),
+                    node_key(rbtree_find_containing(&mut tree, address, overlapping)),
+                    "find_containing {:#x}",
+                    address
+                );
+
+                let address = address & !0xfff;
+                let size = if next_rand(&mut state).is_multiple_of(2) {
+                    0
+                } else {
+                    (next_rand(&mut state) % 16 + 1) * 0x1000
+                };
+
+                assert_eq!(
+                    key_of(&ranges, vm_tree.find_exact(address, size)),
+                    node_key(rbtree_find_exact(&mut tree, address, size)),
+                    "find_exact {:#x} {:#x}",
+                    address,
+                    size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_differential_objects() {
+        for seed in 1..=8u64 {
+            differential(false, seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        }
+    }
+
+    #[test]
+    fn test_differential_userptrs() {
+        for seed in 1..=8u64 {
+            differential(true, seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        }
+    }
+
+    #[test]
+    fn test_find_overlapping() {
+        let mut vm_tree = vm_tree_t::new();
+
+        vm_tree.insert(0x1000, 0x1000, fake_object(1));
+        vm_tree.insert(0x3000, 0x2000, fake_object(2));
+        vm_tree.insert(0x8000, 0x1000, fake_object(3));
+
+        assert_eq!(
+            vm_tree.find_overlapping(0x1800, 0x2000),
+            vec![fake_object(1), fake_object(2)]
+        );
+        assert!(vm_tree.find_overlapping(0x5000, 0x3000).is_empty());
+        assert_eq!(vm_tree.find_overlapping(0, u64::MAX).len(), vm_tree.len());
+    }
+
+    #[test]
+    fn test_find_nearest() {
+        let mut vm_tree = vm_tree_t::new();
+
+        assert!(vm_tree.find_nearest(0x1000).is_null());
+
+        vm_tree.insert(0x1000, 0x1000, fake_object(1));
+        vm_tree.insert(0x3000, 0x2000, fake_object(2));
+        vm_tree.insert(0x8000, 0x1000, fake_object(3));
+
+        assert_eq!(vm_tree.find_nearest(0x3800), fake_object(2));
+        assert_eq!(vm_tree.find_nearest(0x0), fake_object(1));
+        assert_eq!(vm_tree.find_nearest(0x2100), fake_object(1));
+        assert_eq!(vm_tree.find_nearest(0x2f00), fake_object(2));
+        assert_eq!(vm_tree.find_nearest(0x7000), fake_object(3));
+        assert_eq!(vm_tree.find_nearest(0x10000), fake_object(3));
+
+        /* a big object below ends closer than a small one starting
+         * closer to address
+         */
+        vm_tree.insert(0x9000, 0x100, fake_object(4));
+        vm_tree.insert(0x8800, 0x6000, fake_object(5));
+        assert_eq!(vm_tree.find_nearest(0xf000), fake_object(5));
+
+        /* removing the biggest object shrinks the scan again */
+        assert!(vm_tree.remove(0x8800, 0x6000, fake_object(5)));
+        assert_eq!(vm_tree.max_size(), 0x2000);
+        assert_eq!(vm_tree.find_nearest(0xf000), fake_object(4));
+        assert_eq!(vm_tree.find_nearest(0x8400), fake_object(3));
+    }
+}